@@ -1,7 +1,14 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::path::{Path, PathBuf};
 use std::time::{Instant, Duration};
-use crossterm::event::{KeyEvent, KeyCode};
-use crate::evaluator::Value;
+use crossterm::event::{KeyEvent, KeyCode, KeyModifiers};
+use crate::evaluator::{apply_binary_op, Context, Trace, Value};
+use crate::parser::{Expr, Op};
+use crate::lexer::{tokenize, is_keyword, TokenKind};
+use crate::ui::{HighlightCache, Theme};
+use crate::clipboard::Clipboard;
+use crate::include::{load_file, IncludeCache, LoadOptions};
+use crate::locale::{self, DateStyle, Locale};
 
 pub struct App {
     pub lines: Vec<String>,
@@ -12,17 +19,177 @@ pub struct App {
     pub last_keystroke: Instant,       // Time of last keystroke
     pub debounce_period: Duration,     // Debounce period for showing errors
     pub status_message: Option<String>, // Status message to display in the status bar
+    pub modified: bool,                // Text or pins changed since the last save or load
     pub input_mode: InputMode,         // Current input mode
     pub status_input: String,          // Input text for status bar when in input mode
     pub panel_focus: PanelFocus,       // Which panel is currently focused
     pub output_selected_idx: usize,    // Selected index in output panel when output is focused
+    pub output_anchor: Option<usize>,  // Other end of a selection of several results, if any
+    pub output_visual: bool,           // `V` mode: moving the selection extends it
+    pub read_only: bool,               // Keys that would change the sheet are ignored
     status_time: Option<Instant>,      // When the status message was set
     modified_lines: HashSet<usize>,    // Track which lines were modified since last evaluation
-    cached_variables: HashMap<String, Value>, // Cache variables from previous evaluations
+    line_states: Vec<LineState>,       // What the last evaluation learned about each line
+    definitions: HashMap<String, BTreeSet<usize>>, // Variable -> lines that assign it
+    readers: HashMap<String, BTreeSet<usize>>,     // Variable -> lines that mention it
+    index_stale: bool,                 // Lines were inserted/removed, so the indexes above need rebuilding
+    removed_definitions: HashSet<String>, // Variables whose defining line was deleted
+    pub lines_evaluated: usize,        // Number of single-line evaluations performed so far
+    pub lines_parsed: usize,           // Number of those that couldn't reuse the line's last parse
     pub input_panel_area: Option<(u16, u16, u16, u16)>,  // (x, y, width, height) of input panel
     pub output_panel_area: Option<(u16, u16, u16, u16)>, // (x, y, width, height) of output panel
     pub input_scroll: usize,           // Scroll position for input panel
+    pub selection: Option<(usize, std::ops::Range<usize>)>, // Selected bytes of a line, from a double-click
+    copy_offer: Option<String>,        // Text shown in the status bar that `y` copies next, if any
+    discard_offered: bool,             // Asked to press Ctrl+N again to drop unsaved changes
+    rate_failure_shown: bool,          // A failed exchange rate update is told once a session
+    errors_report_due: bool,           // A file was loaded; count its errors once it's all worked out
+    unit_name_warnings: Vec<(usize, String)>, // Variables named like units, told together once evaluated
+    last_click: Option<(Instant, u16, u16)>, // When and where the last single click landed
+    jump_back: Vec<(usize, usize)>,    // Cursor positions left by go-to-definition, newest last
+    jump_forward: Vec<(usize, usize)>, // Positions left by jumping back, to return to
+    pub rename_from: Option<String>,   // Variable being renamed while in InputMode::Rename
+    pub convert_from: Option<Value>,   // Result being re-expressed while in InputMode::Convert
+    pub convert_error: Option<String>, // Why the last unit typed for it didn't work
+    pub file_format: FileFormat,       // How the loaded file was written, to save it the same way
     pub output_scroll: usize,          // Scroll position for output panel
+    pub full_result: Option<usize>,    // Line whose whole result is shown over the panels, if any
+    pub show_log: bool,                // Whether the log view (F12) is over the panels
+    pub log: Vec<String>,              // What the log view shows, read when it opens or more is logged
+    log_read: (usize, u64),            // Records logged and bytes of stderr when `log` was read
+    pub stderr_log: Option<PathBuf>,   // File stderr goes to while the notebook is up
+    pub needs_redraw: bool,            // Whether something visible changed since the last draw
+    pub highlight_cache: HighlightCache, // Highlighted lines reused across draws
+    pub theme: Theme,                  // Colors of results, the selection and errors
+    clipboard: Clipboard,              // Remembers which clipboard method works in this session
+    pub section_totals: HashMap<usize, Value>, // `#=` header line -> total of its section
+    running_total_currency: Option<String>, // From `set running total USD`, if the sheet has it
+    pub running_total: Option<String>, // The sum of the sheet's money for the header, formatted
+    recent_changes: bool,              // Some line has a change marker that hasn't expired yet
+    default_locale: Locale,            // Locale used when no `set locale` line overrides it
+    default_base_currency: Option<String>, // From CALI_BASE_CURRENCY, when no `set base currency` line overrides it
+    setting_lines_removed: bool,       // A deleted line may have held a `set` directive
+    default_units: BTreeMap<usize, Option<String>>, // `set default unit` lines -> the unit, None for `unset default`
+    pub align: Align,                  // How results line up in the output panel
+    pub color: bool,                   // False with NO_COLOR or --no-color: no colors, ASCII only
+    seeds: crate::random::Rng,         // Hands out each line's random seed
+    pub context: Context,              // Today's date and the exchange rates lines are evaluated with
+    pub base_dir: Option<PathBuf>,     // Directory of the open file, which `include` paths are relative to
+    includes: IncludeCache,            // Files read by `include` lines
+    preloaded: HashMap<String, Value>, // Variables from `--preload` files, seen by every line
+    preloaded_units: Vec<(String, f64, String)>, // Units the `--preload` files defined
+    pub focused: bool,                 // The terminal has focus, as far as its focus events tell
+}
+
+// What the engine knows about one line after evaluating it
+#[derive(Default, Clone)]
+struct LineState {
+    value: Option<Value>,          // Result of the last evaluation, None for blank and comment lines
+    defines: Vec<String>,          // Variables assigned on this line: one, or those of an include
+    included: Vec<(String, Value)>, // Definitions an `include` line brought in, in order
+    message: Option<String>,       // Shown in place of a result: "Included 3 definitions"
+    references: HashSet<String>,   // Names mentioned on this line that could be variables
+    forward_reference: bool,       // Result is a "defined later on line N" error
+    trace: Trace,                  // Variables and rates used, for the status bar summary
+    pinned: Option<String>,        // Result snapshot the user pinned for comparison
+    change: Option<(Change, Instant)>, // How the result moved in a recent re-evaluation, and when
+    hints: Option<Vec<&'static str>>, // Units to suggest while a conversion target is missing or unknown
+    is_setting: bool,              // Line is a `set locale`, `set align` or other `set` directive
+    draft: bool,                   // Line is marked `~` or `??`: worked out, but kept out of variables and totals
+    unit: Option<(String, f64, String)>, // Custom unit from a `defunit` line: 1 name = factor unit
+    label: Option<String>,         // Shown before the result: "x" for a solved equation, "1 sprint" for defunit
+    pending: bool,                 // Loaded but not evaluated yet; see `catch_up`
+    parsed: Option<(ParseKey, Expr)>, // The line's last parse and what it depended on
+    roll: Option<(String, u64)>,   // Random seed for rand() and dice, and the text it was drawn for
+    memo: Vec<Memo>,               // Recent evaluations, newest last, to flip between what-ifs quickly
+}
+
+// Everything the result of a line depends on. Flipping an input back to an earlier value
+// finds the result it had then instead of working it out, and its conversions, again.
+#[derive(Clone, PartialEq)]
+struct EvalKey {
+    text: String,
+    inputs: Vec<(String, Value)>, // The values of the names the line mentions, sorted by name
+    locale: Locale,
+    units_version: u64,
+    rates_generation: u64,
+    today: chrono::NaiveDate,
+    round_currency: bool,
+    base_currency: Option<String>,
+    default_unit: Option<String>,
+    seed: u64,
+}
+
+// A result kept for an EvalKey, with what was worked out along with it
+#[derive(Clone)]
+struct Memo {
+    key: EvalKey,
+    result: Value,
+    trace: Trace,
+    hints: Option<Vec<&'static str>>,
+}
+
+// Results remembered per line
+const MEMO_SIZE: usize = 4;
+
+// Everything a parse depends on besides the parser itself. Variables aren't among them:
+// the parse keeps every reading they could pick, so a change upstream doesn't need a new one.
+#[derive(Clone, PartialEq)]
+struct ParseKey {
+    text: String,
+    locale: Locale,      // Decides how numbers are read
+    units_version: u64,  // Custom units change what counts as a unit
+}
+
+// How a line's result moved when something above it changed
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    Delta(Value), // Same kind of value, by this much (new - old)
+    Flash,        // Different unit or type, so there's no meaningful difference
+}
+
+// How long a changed result stays marked
+const CHANGE_HIGHLIGHT: Duration = Duration::from_secs(3);
+
+// Longest gap between the two clicks of a double-click
+const DOUBLE_CLICK: Duration = Duration::from_millis(400);
+
+// Lines evaluated per step while catching up on a freshly loaded file
+pub const CATCH_UP_CHUNK: usize = 500;
+
+// How often the main loop wakes up to show held-back errors and fade change markers, and
+// how rarely it does while the terminal window isn't focused
+const TICK_RATE: Duration = Duration::from_millis(100);
+const UNFOCUSED_TICK_RATE: Duration = Duration::from_secs(1);
+
+impl LineState {
+    // The parse of `line`, reused when nothing the parser looks at has changed. Also says
+    // whether it had to be parsed again.
+    fn parse(&mut self, line: &str) -> (Expr, bool) {
+        let key = ParseKey {
+            text: line.to_string(),
+            locale: locale::current(),
+            units_version: crate::evaluator::custom_units_version(),
+        };
+        if let Some((cached, expr)) = &self.parsed
+            && *cached == key
+        {
+            return (expr.clone(), false);
+        }
+        
+        let expr = crate::parser::parse_line(line);
+        self.parsed = Some((key, expr.clone()));
+        (expr, true)
+    }
+
+    // The value this line assigns to a variable, if any. A later definition in an included
+    // file wins over an earlier one.
+    fn defined_value(&self, name: &str) -> Option<&Value> {
+        match &self.value {
+            Some(Value::Assignment(assigned, value)) if assigned == name => Some(value),
+            _ => self.included.iter().rev().find(|(included, _)| included == name).map(|(_, value)| value),
+        }
+    }
 }
 
 // Input mode for the application
@@ -30,6 +197,22 @@ pub struct App {
 pub enum InputMode {
     Normal,    // Regular calculator mode
     FilePath,  // Entering a file path in the status bar
+    Rename,    // Entering the new name for `rename_from` in the status bar
+    Convert,   // Entering the unit to show `convert_from` in
+}
+
+// The byte-level conventions of a file, kept so saving doesn't rewrite every line of a
+// file that came from Windows
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct FileFormat {
+    pub crlf: bool, // Lines end in \r\n rather than \n
+    pub bom: bool,  // The file starts with a UTF-8 byte order mark
+}
+
+impl FileFormat {
+    pub fn line_ending(self) -> &'static str {
+        if self.crlf { "\r\n" } else { "\n" }
+    }
 }
 
 // Track which panel has focus
@@ -39,8 +222,26 @@ pub enum PanelFocus {
     Output,
 }
 
+impl PanelFocus {
+    // The order Tab moves through; Shift+Tab goes the other way
+    pub const ORDER: [PanelFocus; 2] = [PanelFocus::Input, PanelFocus::Output];
+}
+
+// The target after `current` in `order`, wrapping around at either end
+pub fn cycle_focus<T: Copy + PartialEq>(order: &[T], current: T, forward: bool) -> T {
+    let Some(idx) = order.iter().position(|&target| target == current) else {
+        return order.first().copied().unwrap_or(current);
+    };
+    let next = if forward { (idx + 1) % order.len() } else { (idx + order.len() - 1) % order.len() };
+    order[next]
+}
+
 impl App {
     pub fn new() -> Self {
+        let default_locale = Locale::from_env();
+        locale::set(default_locale);
+        let default_base_currency = std::env::var("CALI_BASE_CURRENCY").ok()
+            .and_then(|code| base_currency_directive(&format!("set base currency {code}"))?.ok());
         Self {
             lines: vec![String::new()],
             cursor_pos: (0, 0),
@@ -50,17 +251,99 @@ impl App {
             last_keystroke: Instant::now(),
             debounce_period: Duration::from_millis(500),
             status_message: None,
+            modified: false,
             input_mode: InputMode::Normal,
             status_input: String::new(),
             panel_focus: PanelFocus::Input,
             output_selected_idx: 0,
+            output_anchor: None,
+            output_visual: false,
+            read_only: false,
             status_time: None,
             modified_lines: HashSet::new(),
-            cached_variables: HashMap::new(),
+            line_states: vec![LineState::default()],
+            definitions: HashMap::new(),
+            readers: HashMap::new(),
+            index_stale: false,
+            removed_definitions: HashSet::new(),
+            lines_evaluated: 0,
+            lines_parsed: 0,
             input_panel_area: None,
             output_panel_area: None,
             input_scroll: 0,
+            selection: None,
+            copy_offer: None,
+            discard_offered: false,
+            full_result: None,
+            show_log: false,
+            log: Vec::new(),
+            log_read: (0, 0),
+            stderr_log: None,
+            rate_failure_shown: false,
+            errors_report_due: false,
+            unit_name_warnings: Vec::new(),
+            focused: true,
+            last_click: None,
+            jump_back: Vec::new(),
+            jump_forward: Vec::new(),
+            rename_from: None,
+            convert_from: None,
+            convert_error: None,
+            file_format: FileFormat::default(),
             output_scroll: 0,
+            needs_redraw: true,
+            highlight_cache: HighlightCache::default(),
+            theme: Theme::default(),
+            clipboard: Clipboard::new(),
+            section_totals: HashMap::new(),
+            running_total_currency: None,
+            running_total: None,
+            recent_changes: false,
+            default_locale,
+            default_base_currency: default_base_currency.clone(),
+            setting_lines_removed: false,
+            default_units: BTreeMap::new(),
+            align: Align::default(),
+            color: true,
+            seeds: crate::random::Rng::from_time(),
+            context: Context { base_currency: default_base_currency, ..Context::default() },
+            base_dir: None,
+            includes: IncludeCache::default(),
+            preloaded: HashMap::new(),
+            preloaded_units: Vec::new(),
+        }
+    }
+
+    // Turn colors off for NO_COLOR/--no-color. Values and panel decorations also stick to
+    // ASCII then, since such terminals often can't show much else either.
+    pub fn disable_color(&mut self) {
+        self.color = false;
+        locale::set_ascii(true);
+        self.needs_redraw = true;
+    }
+
+    // Follow the terminal's focus events. Out of focus the main loop ticks slowly and rates
+    // aren't fetched again; back in focus the screen is drawn afresh.
+    pub fn set_focused(&mut self, focused: bool) {
+        if self.focused == focused {
+            return;
+        }
+        self.focused = focused;
+        crate::currency::defer_refresh(!focused);
+        if focused {
+            self.needs_redraw = true;
+        }
+    }
+
+    // How long the main loop waits for input before the next tick. A file still being
+    // evaluated only waits between chunks.
+    pub fn poll_timeout(&self) -> Duration {
+        if self.pending_lines() > 0 {
+            Duration::ZERO
+        } else if self.focused {
+            TICK_RATE
+        } else {
+            UNFOCUSED_TICK_RATE
         }
     }
 
@@ -106,12 +389,112 @@ impl App {
     pub fn set_status_message(&mut self, message: String) {
         self.status_message = Some(message);
         self.status_time = Some(Instant::now());
+        self.needs_redraw = true;
+    }
+    
+    // Report a failure in the status bar. It says so in words, so it doesn't depend on the
+    // error color being told apart.
+    pub fn set_status_error(&mut self, message: impl std::fmt::Display) {
+        self.set_status_message(format!("Error: {message}"));
     }
     
     // Clear the status message
     pub fn clear_status_message(&mut self) {
         self.status_message = None;
         self.status_time = None;
+        // An offer to copy goes with its message, and so does the question about unsaved changes
+        self.copy_offer = None;
+        self.discard_offered = false;
+        self.needs_redraw = true;
+    }
+
+    // Remove every line and forget everything derived from them
+    pub fn clear(&mut self) {
+        self.lines.clear();
+        self.results.clear();
+        self.debounced_results.clear();
+        self.variables.clone_from(&self.preloaded);
+        self.modified_lines.clear();
+        self.line_states.clear();
+        self.definitions.clear();
+        self.readers.clear();
+        self.index_stale = false;
+        self.removed_definitions.clear();
+        self.sync_units();
+        // The next sheet starts from the default settings again
+        self.setting_lines_removed = true;
+        self.modified = false;
+        self.cursor_pos = (0, 0);
+    }
+
+    // Start over with a single empty line. Unsaved changes need a second Ctrl+N while the
+    // status bar asks about them; returns whether the sheet was cleared.
+    pub fn new_sheet(&mut self) -> bool {
+        if !self.can_edit() {
+            return false;
+        }
+        if self.modified && !self.discard_offered {
+            self.set_status_message("Unsaved changes: press Ctrl+N again to discard them".to_string());
+            self.discard_offered = true;
+            return false;
+        }
+        self.clear();
+        self.add_line(String::new());
+        self.evaluate_expressions();
+        self.base_dir = None;
+        self.file_format = FileFormat::default();
+        self.set_status_message("New sheet".to_string());
+        self.discard_offered = false;
+        true
+    }
+
+    // Replace the sheet with the tutorial, unmodified, so Ctrl+N leaves it without asking
+    pub fn open_tutorial(&mut self) {
+        self.clear();
+        for line in crate::tutorial::TUTORIAL.lines() {
+            self.add_line(line.to_string());
+        }
+        self.evaluate_expressions();
+        self.base_dir = None;
+        self.modified = false;
+        self.set_status_message("This is the tutorial: press Ctrl+N for a blank sheet".to_string());
+    }
+
+    // Evaluate a `--preload` file ahead of the sheet, whose lines then see its variables,
+    // exchange rates and units without showing its lines. Returns how many definitions it
+    // made; the first line that fails stops it.
+    pub fn preload(&mut self, path: &Path) -> Result<usize, String> {
+        let options = LoadOptions { stop_at_error: true, collect_units: true };
+        let loaded = load_file(path, self.preloaded.clone(), options, &self.context, &mut Vec::new(), &mut Vec::new());
+        if let Some(error) = loaded.error {
+            return Err(error);
+        }
+        let count = loaded.definitions.len() + loaded.units.len() + loaded.rates;
+        self.preloaded.extend(loaded.definitions);
+        self.preloaded_units.extend(loaded.units);
+        self.variables.extend(self.preloaded.clone());
+        Ok(count)
+    }
+
+    // Add a line read from a file, restoring a pin saved in its trailing comment
+    pub fn add_saved_line(&mut self, line: &str) {
+        match line.rsplit_once(PIN_MARKER).filter(|(_, pin)| !pin.trim().is_empty()) {
+            Some((text, pin)) => {
+                self.add_line(text.to_string());
+                if let Some(state) = self.line_states.last_mut() {
+                    state.pinned = Some(pin.trim().to_string());
+                }
+            }
+            None => self.add_line(line.to_string()),
+        }
+    }
+
+    // A line as it is written to a file, with its pin kept in a trailing comment
+    pub fn line_to_save(&self, line_idx: usize) -> String {
+        match self.pinned_result(line_idx) {
+            Some(pin) => format!("{}{}{}", self.lines[line_idx], PIN_MARKER, pin),
+            None => self.lines[line_idx].clone(),
+        }
     }
 
     // Add a new line of text to the app
@@ -120,12 +503,132 @@ impl App {
         self.lines.push(line);
         self.results.push(String::new());
         self.debounced_results.push(String::new());
+        self.line_states.push(LineState::default());
         self.modified_lines.insert(line_index);
     }
 
+    // Leave the lines added so far for `catch_up` to evaluate a chunk at a time, so a huge
+    // file can be drawn before all of it has been worked out
+    pub fn defer_evaluation(&mut self) {
+        for line_idx in self.modified_lines.drain() {
+            if let Some(state) = self.line_states.get_mut(line_idx) {
+                state.pending = true;
+            }
+        }
+    }
+
+    // Evaluate up to `budget` of the deferred lines, in document order. Lines edited in the
+    // meantime were already evaluated with the edit, and lines that read a deferred
+    // definition are re-evaluated once it's in, so the end result is the same as
+    // evaluating everything at once. Returns true while lines are still waiting.
+    pub fn catch_up(&mut self, budget: usize) -> bool {
+        let chunk: Vec<usize> = self.line_states.iter()
+            .enumerate()
+            .filter(|(_, state)| state.pending)
+            .map(|(line_idx, _)| line_idx)
+            .take(budget)
+            .collect();
+        if !chunk.is_empty() {
+            self.modified_lines.extend(chunk);
+            self.evaluate_expressions();
+            self.needs_redraw = true;
+        }
+        let waiting = self.pending_lines() > 0;
+        if !waiting && std::mem::take(&mut self.errors_report_due) {
+            self.report_errors();
+        }
+        waiting
+    }
+
+    // Once the file just loaded is worked out, say in the status bar how many of its lines
+    // failed. A sheet written for another version of cali can have errors all through it.
+    pub fn report_errors_when_loaded(&mut self) {
+        self.errors_report_due = true;
+    }
+
+    fn report_errors(&mut self) {
+        let errors = self.error_lines().len();
+        if errors > 0 {
+            let lines = self.lines.len();
+            self.set_status_message(format!(
+                "Loaded {lines} line{}, {errors} with errors (press F4 to jump to the first error)",
+                if lines == 1 { "" } else { "s" },
+            ));
+        }
+    }
+
+    // Lines whose result is an error, in document order
+    fn error_lines(&self) -> Vec<usize> {
+        self.debounced_results.iter()
+            .enumerate()
+            .filter(|(_, result)| result.starts_with("Error:"))
+            .map(|(line_idx, _)| line_idx)
+            .collect()
+    }
+
+    // Move to the next line whose result is an error (F4), or the previous one (Shift+F4),
+    // going round at either end. The cursor and the output selection both follow.
+    pub fn jump_to_error(&mut self, forward: bool) {
+        let errors = self.error_lines();
+        let current = match self.panel_focus {
+            PanelFocus::Input => self.cursor_pos.0,
+            PanelFocus::Output => self.output_selected_idx,
+        };
+        let target = if forward {
+            errors.iter().find(|&&line_idx| line_idx > current).or(errors.first())
+        } else {
+            errors.iter().rev().find(|&&line_idx| line_idx < current).or(errors.last())
+        };
+        let Some(&target) = target else {
+            self.set_status_message("No lines with errors".to_string());
+            return;
+        };
+        
+        let wrapped = if forward { target <= current } else { target >= current };
+        let position = errors.iter().position(|&line_idx| line_idx == target).unwrap_or(0) + 1;
+        self.set_status_message(format!(
+            "Error {position} of {} on line {}{}",
+            errors.len(),
+            target + 1,
+            match (wrapped, forward) {
+                (false, _) => "",
+                (true, true) => " (went round to the top)",
+                (true, false) => " (went round to the bottom)",
+            },
+        ));
+        self.cursor_pos = (target, self.lines[target].len());
+        self.ensure_cursor_visible();
+        self.collapse_output_selection();
+        self.output_selected_idx = target;
+        self.scroll_output_to_selection();
+    }
+
+    // Number of deferred lines not evaluated yet
+    pub fn pending_lines(&self) -> usize {
+        self.line_states.iter().filter(|state| state.pending).count()
+    }
+
     pub fn handle_key(&mut self, key: KeyEvent) {
+        // Right after evaluating a selection, `y` copies the result instead of typing
+        if self.take_copy_offer(&key) {
+            return;
+        }
+        
+        // In read-only mode a key is handled like any other, and undone below if it changed
+        // the sheet
+        let before = self.read_only.then(|| (
+            self.lines.clone(),
+            self.results.clone(),
+            self.debounced_results.clone(),
+            self.line_states.clone(),
+            self.cursor_pos,
+            self.input_scroll,
+            self.modified,
+        ));
+        
         // Update last keystroke time
         self.last_keystroke = Instant::now();
+        let selection = self.selection.take();
         
         // Track which line is being modified
         let current_line = self.cursor_pos.0;
@@ -206,166 +709,794 @@ impl App {
                 }
                 self.ensure_cursor_visible();
             }
+            // Ctrl+/ arrives as Ctrl+/ , Ctrl+_ or Ctrl+7 depending on the terminal
+            KeyCode::Char('/' | '_' | '7') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                self.toggle_comment();
+            }
+            KeyCode::Char('.') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                self.toggle_draft();
+            }
+            // Likewise Ctrl+] may arrive as Ctrl+5
+            KeyCode::Char(']' | '5') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                self.go_to_definition();
+            }
+            // Ctrl+Shift+O arrives with Shift set or as an uppercase O, depending on the terminal
+            KeyCode::Char(c @ ('o' | 'O'))
+                if key.modifiers.contains(KeyModifiers::CONTROL) && (c == 'O' || key.modifiers.contains(KeyModifiers::SHIFT)) =>
+            {
+                self.jump_forward();
+            }
+            KeyCode::Char('o') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                self.jump_back();
+            }
+            KeyCode::Char('r') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                self.start_rename();
+            }
+            KeyCode::Char('g') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                self.reroll();
+            }
+            KeyCode::Char('e') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                self.evaluate_selection(selection);
+            }
             KeyCode::Char(c) => {
                 self.insert_char(c);
             }
             _ => {}
         }
+        
+        if let Some((lines, results, debounced_results, line_states, cursor_pos, input_scroll, modified)) = before
+            && lines != self.lines
+        {
+            self.lines = lines;
+            self.results = results;
+            self.debounced_results = debounced_results;
+            self.line_states = line_states;
+            self.cursor_pos = cursor_pos;
+            self.input_scroll = input_scroll;
+            self.modified = modified;
+            self.index_stale = true;
+            self.sync_units();
+            self.can_edit();
+        }
 
         // Evaluate the expressions after any change
         self.evaluate_expressions();
     }
 
-    // Make the evaluate_expressions method public so it can be called from outside
+    // The one check every change to the sheet goes through. In read-only mode it says so
+    // in the status bar and refuses.
+    pub fn can_edit(&mut self) -> bool {
+        if self.read_only {
+            self.set_status_message("Read-only mode (Ctrl+Shift+L to edit)".to_string());
+        }
+        !self.read_only
+    }
+    
+    pub fn toggle_read_only(&mut self) {
+        self.read_only = !self.read_only;
+        self.set_status_message(format!("Read-only mode {}", if self.read_only { "on" } else { "off" }));
+    }
+
+    // Re-evaluate the modified lines plus every line downstream that reads a variable whose
+    // value changed. Lines are always taken in document order and each line only sees the
+    // definitions above it, so a pass never has to revisit a line it already evaluated.
     pub fn evaluate_expressions(&mut self) {
-        // Clone the current variables state for comparing after evaluation
-        let prev_variables = self.variables.clone();
-        
         // If there are no modified lines, nothing to do
-        if self.modified_lines.is_empty() {
+        if self.modified_lines.is_empty() && self.removed_definitions.is_empty() {
             return;
         }
         
-        // Get a sorted list of modified lines
-        let mut modified: Vec<usize> = self.modified_lines.iter().cloned().collect();
-        modified.sort();
+        // Lines still to evaluate, popped in document order
+        let line_count = self.lines.len();
+        let mut pending: BTreeSet<usize> = self.modified_lines
+            .drain()
+            .filter(|&line_idx| line_idx < line_count)
+            .collect();
+        // Lines being typed on change all the time; only the lines they affect get marked
+        let edited = pending.clone();
+        
+        // Settings apply to the whole sheet; a different locale changes how every number
+        // reads and displays
+        let settings_touched = std::mem::take(&mut self.setting_lines_removed) || pending.iter().any(|&line_idx| {
+            self.line_states[line_idx].is_setting || setting_directive(&self.lines[line_idx]).is_some()
+        });
+        if settings_touched {
+            self.align = self.lines.iter()
+                .rev()
+                .find_map(|line| align_directive(line)?.ok())
+                .unwrap_or_default();
+            
+            let sheet_locale = self.lines.iter()
+                .rev()
+                .find_map(|line| locale_directive(line)?.ok())
+                .unwrap_or(self.default_locale);
+            if sheet_locale != locale::current() {
+                locale::set(sheet_locale);
+                self.highlight_cache = HighlightCache::default();
+                // Deferred lines get the new locale when their turn comes
+                pending.extend((0..line_count).filter(|&line_idx| !self.line_states[line_idx].pending));
+                self.set_status_message(format!("Locale set to {}", sheet_locale.name()));
+            }
+            
+            let date_style = self.lines.iter()
+                .rev()
+                .find_map(|line| dates_directive(line)?.ok())
+                .unwrap_or_default();
+            if date_style != locale::date_style() {
+                locale::set_date_style(date_style);
+                pending.extend((0..line_count).filter(|&line_idx| !self.line_states[line_idx].pending));
+                self.set_status_message(format!("Dates shown in the {} style", date_style.name()));
+            }
+            
+            let round_currency = self.lines.iter()
+                .rev()
+                .find_map(|line| rounding_directive(line)?.ok())
+                .unwrap_or_default();
+            if round_currency != self.context.round_currency {
+                self.context.round_currency = round_currency;
+                pending.extend((0..line_count).filter(|&line_idx| !self.line_states[line_idx].pending));
+                self.set_status_message(format!("Currency rounding {}", if round_currency { "on" } else { "off" }));
+            }
+            
+            let base_currency = self.lines.iter()
+                .rev()
+                .find_map(|line| base_currency_directive(line)?.ok())
+                .or_else(|| self.default_base_currency.clone());
+            if base_currency != self.context.base_currency {
+                self.set_status_message(match &base_currency {
+                    Some(code) => format!("Mixed currencies add up in {code}"),
+                    None => "Mixed currencies add up in the first one's currency".to_string(),
+                });
+                self.context.base_currency = base_currency;
+                pending.extend((0..line_count).filter(|&line_idx| !self.line_states[line_idx].pending));
+            }
+            
+            let running_total_currency = self.lines.iter()
+                .rev()
+                .find_map(|line| running_total_directive(line)?.ok())
+                .flatten();
+            if running_total_currency != self.running_total_currency {
+                self.set_status_message(match &running_total_currency {
+                    Some(code) => format!("Running total in {code}"),
+                    None => "Running total off".to_string(),
+                });
+                self.running_total_currency = running_total_currency;
+            }
+        }
+        
+        // Default units cover the lines below them, so they move with inserted and removed lines
+        if settings_touched || self.index_stale {
+            let default_units: BTreeMap<usize, Option<String>> = self.lines.iter()
+                .enumerate()
+                .filter_map(|(line_idx, line)| Some((line_idx, default_unit_directive(line)?.ok()?)))
+                .collect();
+            if default_units != self.default_units {
+                self.default_units = default_units;
+                pending.extend((0..line_count).filter(|&line_idx| !self.line_states[line_idx].pending));
+            }
+        }
         
-        // First pass: evaluate just the modified lines to update variables
-        self.evaluate_modified_lines(&modified);
+        if self.index_stale {
+            self.rebuild_index();
+            // Forward-reference errors name a line number, which may just have shifted, and
+            // `line N` now points at whatever line is numbered N
+            pending.extend((0..line_count).filter(|&line_idx| {
+                let state = &self.line_states[line_idx];
+                state.forward_reference || state.references.iter().any(|name| crate::evaluator::line_key_number(name).is_some())
+            }));
+        }
         
-        // Second pass: find variables that changed and evaluate dependent lines
-        self.evaluate_dependent_lines(&prev_variables);
+        // A deleted definition invalidates everything that read it
+        for name in std::mem::take(&mut self.removed_definitions) {
+            self.refresh_variable(&name);
+            if let Some(readers) = self.readers.get(&name) {
+                pending.extend(readers.iter().copied());
+            }
+        }
         
-        // Clear the modified lines set
-        self.modified_lines.clear();
+        // Values only flow downward: a line sees the nearest definition above it. When a
+        // definition appears or disappears, readers above it are re-evaluated too, since
+        // their forward-reference error names the defining line.
+        while let Some(line_idx) = pending.pop_first() {
+            let old_value = self.line_value(line_idx).cloned();
+            let changes = self.evaluate_line(line_idx);
+            if !edited.contains(&line_idx)
+                && let (Some(old), Some(new)) = (old_value, self.line_value(line_idx))
+                && &old != new
+            {
+                let change = value_change(&old, new);
+                self.recent_changes |= change.is_some();
+                self.line_states[line_idx].change = change.map(|change| (change, Instant::now()));
+            }
+            
+            for (name, redefined) in changes {
+                self.refresh_variable(&name);
+                if let Some(readers) = self.readers.get(&name) {
+                    if redefined {
+                        pending.extend(readers.range(..line_idx).copied());
+                    }
+                    pending.extend(readers.range(line_idx + 1..).copied());
+                }
+            }
+        }
         
-        // Store the current variables state for the next comparison
-        self.cached_variables = self.variables.clone();
+        self.update_section_totals();
+        self.update_running_total();
+        self.warn_about_unit_names();
+    }
+
+    // Say which new variables are named like units, all in one message once the deferred
+    // lines of a file are in too
+    fn warn_about_unit_names(&mut self) {
+        if self.unit_name_warnings.is_empty() || self.pending_lines() > 0 {
+            return;
+        }
+        let mut warnings = std::mem::take(&mut self.unit_name_warnings);
+        warnings.sort();
+        warnings.dedup();
+        let message = match warnings.as_slice() {
+            [(_, name)] => format!("Warning: '{name}' is also a unit; after a number it still means the unit"),
+            _ => {
+                let names: Vec<String> = warnings.iter()
+                    .map(|(line_idx, name)| format!("'{name}' on line {}", line_idx + 1))
+                    .collect();
+                format!(
+                    "Warning: {} variables are also units ({}); after a number they still mean the unit",
+                    warnings.len(),
+                    names.join(", "),
+                )
+            }
+        };
+        self.set_status_message(message);
     }
 
-    // Evaluate the modified lines to update variables
-    fn evaluate_modified_lines(&mut self, modified_lines: &[usize]) {
-        for &line_idx in modified_lines {
-            if line_idx < self.lines.len() {
-                let line = &self.lines[line_idx];
-                // Skip empty lines and comments
-                let trimmed = line.trim();
-                if trimmed.is_empty() || trimmed.starts_with('#') {
-                    continue;
+    // Total up the results under each `#=` header, until the next header or blank line.
+    // Only results of the same kind as the first one are added, so a section of prices
+    // skips a stray plain number and vice versa.
+    fn update_section_totals(&mut self) {
+        self.section_totals.clear();
+        let mut section: Option<(usize, Option<Value>)> = None;
+        
+        for line_idx in 0..self.lines.len() {
+            let header = section_header(&self.lines[line_idx]);
+            if header.is_some() || self.lines[line_idx].trim().is_empty() {
+                if let Some((header_idx, Some(total))) = section.take() {
+                    self.section_totals.insert(header_idx, total);
                 }
-                
-                // Parse and evaluate this line
-                let expr = crate::parser::parse_line(line, &self.variables);
-                let result = crate::evaluator::evaluate(&expr, &mut self.variables);
-                
-                // Update the result for this line
-                self.update_result_for_line(line_idx, &result);
+                if header.is_some_and(|header| header.totals) {
+                    section = Some((line_idx, None));
+                }
+                continue;
             }
+            
+            let Some((_, total)) = &mut section else { continue };
+            let Some(value) = self.line_value(line_idx).filter(|_| !self.is_draft(line_idx)) else { continue };
+            *total = match (total.take(), value) {
+                (None, Value::Number(_) | Value::Unit(_, _)) => Some(value.clone()),
+                (Some(sum @ Value::Number(_)), Value::Number(_)) | (Some(sum @ Value::Unit(_, _)), Value::Unit(_, _)) => {
+                    match crate::evaluator::in_context(&self.context, || apply_binary_op(sum.clone(), &Op::Add, value.clone())) {
+                        Value::Error(_) => Some(sum),
+                        new_sum => Some(new_sum),
+                    }
+                }
+                (sum, _) => sum,
+            };
+        }
+        
+        if let Some((header_idx, Some(total))) = section {
+            self.section_totals.insert(header_idx, total);
         }
     }
 
-    // Update the result for a specific line
-    fn update_result_for_line(&mut self, line_idx: usize, result: &crate::evaluator::Value) {
-        if line_idx < self.results.len() {
-            // If it's an assignment, store the variable
-            if let crate::evaluator::Value::Assignment(name, value) = result {
-                self.variables.insert(name.clone(), (**value).clone());
-            }
+    // The sum `set running total USD` shows in the header: every line's own result in money,
+    // converted to the chosen currency. Each line counts once with the result it shows, and
+    // variables aren't followed back, so `rent = 1800 USD` adds 1800 and a later `rent * 12`
+    // line adds its 21600 as well. Drafts, errors and anything that isn't money are skipped.
+    fn update_running_total(&mut self) {
+        self.running_total = self.running_total_currency.as_ref().map(|currency| {
+            // A line that only restates a variable set further up was counted there already
+            let mut defined = HashSet::new();
+            let sum: f64 = (0..self.lines.len())
+                .filter(|&line_idx| {
+                    let restated = defined.contains(self.lines[line_idx].trim());
+                    defined.extend(self.line_states[line_idx].defines.iter().map(String::as_str));
+                    !restated && !self.is_draft(line_idx)
+                })
+                .filter_map(|line_idx| match self.line_value(line_idx)? {
+                    Value::Unit(amount, unit) if unit == currency => Some(*amount),
+                    Value::Unit(amount, unit) if crate::lexer::is_currency_code(unit) => Some(amount * self.context.rates.rate(unit, currency)?),
+                    _ => None,
+                })
+                .sum();
+            Value::Unit(sum, currency.clone()).to_string()
+        });
+    }
 
-            // Format the result
-            let result_str = if self.last_keystroke.elapsed() < self.debounce_period && matches!(result, crate::evaluator::Value::Error(_)) {
-                String::new() // Hide errors during debounce period
-            } else {
-                match result {
-                    crate::evaluator::Value::Error(msg) => format!("Error: {}", msg),
-                    _ => format!("{}", result)
+    // Evaluate a single line, update its result and indexes, and return the names of the
+    // variables whose value changed because of it, flagging names this line started or
+    // stopped defining
+    fn evaluate_line(&mut self, line_idx: usize) -> Vec<(String, bool)> {
+        self.lines_evaluated += 1;
+        
+        let line = &self.lines[line_idx];
+        let trimmed = line.trim();
+        self.line_states[line_idx].is_setting = false;
+        self.line_states[line_idx].pending = false;
+        let was_draft = std::mem::take(&mut self.line_states[line_idx].draft);
+        let mut defined_unit = None;
+        let mut label = None;
+        let mut included = Vec::new();
+        let mut message = None;
+        let mut forward_reference = false;
+        let (value, references) = if let Some(directive) = setting_directive(line) {
+            // Applied to the whole sheet by evaluate_expressions; only a bad name shows here
+            self.line_states[line_idx].is_setting = true;
+            self.line_states[line_idx].hints = None;
+            (directive.err().map(Value::Error), HashSet::new())
+        } else if let Some(path) = crate::include::directive(line) {
+            // Brings in another file's definitions without showing its lines
+            self.line_states[line_idx].hints = None;
+            let path = crate::include::resolve(path, self.base_dir.as_deref());
+            let loaded = self.includes.load(&path, &self.context);
+            let count = loaded.definitions.iter().map(|(name, _)| name).collect::<HashSet<_>>().len();
+            message = Some(format!("Included {count} definition{}", if count == 1 { "" } else { "s" }));
+            included = loaded.definitions;
+            (loaded.error.map(Value::Error), HashSet::new())
+        } else if trimmed.is_empty() || trimmed.starts_with('#') {
+            // Blank lines and comments have no result
+            self.line_states[line_idx].hints = None;
+            (None, HashSet::new())
+        } else {
+            // A draft line is worked out from the text after its marker
+            let draft = draft_body(trimmed);
+            self.line_states[line_idx].draft = draft.is_some();
+            let trimmed = draft.unwrap_or(trimmed);
+            let references = referenced_names(trimmed);
+            let mut scope = self.scope_for(line_idx, &references);
+            // Spaces around the text mean nothing, so adding them neither reparses nor rerolls
+            let (expr, reparsed) = self.line_states[line_idx].parse(trimmed);
+            self.lines_parsed += usize::from(reparsed);
+            // What the line is, a defunit or an equation say, can hang on its variables
+            let expr = expr.reading(&scope).clone();
+            // The same numbers every time until the line is edited or rerolled
+            let seed = match &self.line_states[line_idx].roll {
+                Some((text, seed)) if text == trimmed => *seed,
+                _ => {
+                    let seed = self.seeds.next_u64();
+                    self.line_states[line_idx].roll = Some((trimmed.to_string(), seed));
+                    seed
                 }
             };
-            
-            // Update the results
-            self.results[line_idx] = result_str;
-            self.debounced_results[line_idx] = match result {
-                crate::evaluator::Value::Error(msg) => format!("Error: {}", msg),
-                _ => format!("{}", result)
+            let mut inputs: Vec<(String, Value)> = scope.iter().map(|(name, value)| (name.clone(), value.clone())).collect();
+            inputs.sort_by(|a, b| a.0.cmp(&b.0));
+            let key = EvalKey {
+                text: trimmed.to_string(),
+                inputs,
+                locale: locale::current(),
+                units_version: crate::evaluator::custom_units_version(),
+                rates_generation: self.context.rates.generation(),
+                today: (self.context.today)(),
+                round_currency: self.context.round_currency,
+                base_currency: self.context.base_currency.clone(),
+                default_unit: self.default_unit_at(line_idx),
+                seed,
+            };
+            let state = &mut self.line_states[line_idx];
+            let (result, trace, hints) = match state.memo.iter().find(|memo| memo.key == key) {
+                Some(memo) => (memo.result.clone(), memo.trace.clone(), memo.hints.clone()),
+                None => {
+                    crate::random::seed(seed);
+                    let (result, trace) = crate::evaluator::evaluate_with_trace(&expr, &mut scope, &self.context);
+                    let result = with_default_unit(result, key.default_unit.as_deref());
+                    let hints = conversion_hints(trimmed, &scope, &result, &self.context);
+                    // setrate and defunit do their work while being evaluated, and running out
+                    // of time says nothing about the next try
+                    let repeatable = !matches!(expr, Expr::SetRate(..) | Expr::DefineUnit(..))
+                        && error_message(&result) != Some("Evaluation took too long");
+                    if repeatable {
+                        if state.memo.len() == MEMO_SIZE {
+                            state.memo.remove(0);
+                        }
+                        state.memo.push(Memo { key, result: result.clone(), trace: trace.clone(), hints: hints.clone() });
+                    }
+                    (result, trace, hints)
+                }
+            };
+            defined_unit = match (&expr, &result) {
+                (_, Value::Error(_)) => None,
+                _ if draft.is_some() => None,
+                (Expr::DefineUnit(name, factor, unit), _) => Some((name.clone(), *factor, unit.clone())),
+                _ => None,
             };
+            label = match &expr {
+                _ if error_message(&result).is_some() => None,
+                Expr::DefineUnit(name, _, _) => Some(format!("1 {name}")),
+                Expr::Solve(unknown, _, _) => Some(unknown.clone()),
+                Expr::Assignment(name, inner) if matches!(inner.reading(&scope), Expr::Solve(..)) => Some(name.clone()),
+                _ => None,
+            };
+            let explained = self.forward_reference_error(line_idx, &references, trace.not_found.as_deref(), &result);
+            forward_reference = explained.is_some();
+            self.line_states[line_idx].trace = trace;
+            self.line_states[line_idx].hints = hints;
+            (Some(explained.unwrap_or(result)), references)
+        };
+        self.line_states[line_idx].forward_reference = forward_reference;
+        let old_unit = std::mem::replace(&mut self.line_states[line_idx].unit, defined_unit);
+        self.line_states[line_idx].label = label;
+        self.line_states[line_idx].message = message;
+        
+        self.update_result_for_line(line_idx, value.as_ref());
+        
+        // Keep the reader index in sync with what the line mentions now
+        let old_references = std::mem::replace(&mut self.line_states[line_idx].references, references);
+        for name in old_references.difference(&self.line_states[line_idx].references) {
+            if let Some(lines) = self.readers.get_mut(name) {
+                lines.remove(&line_idx);
+            }
+        }
+        for name in &self.line_states[line_idx].references {
+            self.readers.entry(name.clone()).or_default().insert(line_idx);
+        }
+        
+        // Compare what the line defines before and after, one value at a time
+        let state = &self.line_states[line_idx];
+        let old_defines: Vec<(String, Option<Value>)> = state.defines.iter()
+            .map(|name| (name.clone(), state.defined_value(name).cloned()))
+            .collect();
+        let state = &mut self.line_states[line_idx];
+        state.defines = match &value {
+            Some(Value::Assignment(name, _)) if !state.draft => vec![name.clone()],
+            _ => Vec::new(),
+        };
+        for (name, _) in &included {
+            if !state.defines.contains(name) {
+                state.defines.push(name.clone());
+            }
+        }
+        state.included = included;
+        let result_changed = state.value != value || state.draft != was_draft;
+        state.value = value;
+        
+        let mut changed = Vec::new();
+        
+        // Lines below that refer to this one by number
+        if result_changed {
+            changed.push((crate::evaluator::line_key(line_idx + 1), false));
+        }
+        
+        // Units are shared by the whole sheet, so lines above and below that use a unit
+        // this line (re)defined or dropped need another look
+        if old_unit != state.unit {
+            for (name, _, _) in old_unit.iter().chain(&state.unit) {
+                changed.push((name.clone(), true));
+            }
+            self.sync_units();
+        }
+        let state = &self.line_states[line_idx];
+        let new_defines = state.defines.clone();
+        
+        for (old_name, old_value) in &old_defines {
+            if !new_defines.contains(old_name) {
+                if let Some(lines) = self.definitions.get_mut(old_name) {
+                    lines.remove(&line_idx);
+                }
+                changed.push((old_name.clone(), true));
+            } else if old_value.as_ref() != state.defined_value(old_name) {
+                changed.push((old_name.clone(), false));
+            }
+        }
+        for new_name in new_defines {
+            if old_defines.iter().any(|(old_name, _)| *old_name == new_name) {
+                continue;
+            }
+            self.definitions.entry(new_name.clone()).or_default().insert(line_idx);
+            if crate::evaluator::is_unit_name(&new_name) {
+                self.unit_name_warnings.push((line_idx, new_name.clone()));
+            }
+            changed.push((new_name, true));
         }
+        
+        changed
     }
 
-    // Find variables that changed and evaluate dependent lines
-    fn evaluate_dependent_lines(&mut self, prev_variables: &HashMap<String, crate::evaluator::Value>) {
-        // Check which variables changed
-        let changed_vars = self.find_changed_variables(prev_variables);
-        
-        // If any variables changed, re-evaluate all lines that use those variables
-        if !changed_vars.is_empty() {
-            self.reevaluate_dependent_lines(&changed_vars);
+    // Register the `defunit` units of the sheet from scratch, so a removed or edited
+    // definition is forgotten. A later definition of the same unit wins, and the sheet's
+    // win over preloaded ones.
+    fn sync_units(&self) {
+        crate::evaluator::clear_custom_units();
+        let sheet_units = self.line_states.iter().filter_map(|state| state.unit.as_ref());
+        for (name, factor, unit) in self.preloaded_units.iter().chain(sheet_units) {
+            let _ = crate::evaluator::define_unit(name, *factor, unit);
         }
     }
 
-    // Find which variables changed compared to previous state
-    fn find_changed_variables(&self, prev_variables: &HashMap<String, crate::evaluator::Value>) -> HashSet<String> {
-        let mut changed_vars = HashSet::new();
+    // A name that isn't visible on this line but is defined further down gets an error that
+    // points at the defining line instead of "'total' not found", for a result that failed
+    // on `not_found`
+    fn forward_reference_error(&self, line_idx: usize, references: &HashSet<String>, not_found: Option<&str>, value: &Value) -> Option<Value> {
+        error_message(value)?;
+        // The parser reports the first word of an unknown name, which may be multi-word
+        let word = not_found?;
+        let defined_later = references.iter()
+            .filter(|name| name.split(' ').next() == Some(word))
+            .filter_map(|name| {
+                let def_idx = *self.definitions.get(name)?.range(line_idx + 1..).next()?;
+                Some((name, def_idx))
+            })
+            .max_by_key(|(name, _)| name.len());
+        let (name, def_idx) = defined_later?;
         
-        for (var, val) in &self.variables {
-            if !prev_variables.contains_key(var) || prev_variables.get(var) != Some(val) {
-                changed_vars.insert(var.clone());
-            }
+        let error = Value::Error(format!("variable '{name}' is defined later on line {}", def_idx + 1));
+        Some(match value {
+            Value::Assignment(assigned, _) => Value::Assignment(assigned.clone(), Box::new(error)),
+            _ => error,
+        })
+    }
+
+    // A breakdown of how a line was evaluated, with more room than the output panel has:
+    // "total = $151.20  (a=$120, b=$31.20)" or "= 3.11 mi  rate 1 km = 0.6214 mi".
+    // None when the line used no variables or conversions, so there's nothing to add.
+    // A `#=` section header gets its total: "Groceries total = $35.20".
+    pub fn line_summary(&self, line_idx: usize) -> Option<String> {
+        if let Some(total) = self.section_totals.get(&line_idx)
+            && let Some(header) = section_header(&self.lines[line_idx])
+        {
+            return Some(format!("{} total = {}", header.title, total));
+        }
+        
+        let state = self.line_states.get(line_idx)?;
+        let trace = &state.trace;
+        if trace.variables.is_empty() && trace.rates.is_empty() {
+            return None;
         }
         
-        changed_vars
+        let mut summary = match state.value.as_ref()? {
+            Value::Assignment(name, value) => format!("{name} = {value}"),
+            value => format!("= {value}"),
+        };
+        if !trace.variables.is_empty() {
+            let operands: Vec<String> = trace.variables.iter()
+                .map(|(name, value)| format!("{name}={value}"))
+                .collect();
+            summary.push_str(&format!("  ({})", operands.join(", ")));
+        }
+        for (from, rate, to) in &trace.rates {
+            // Up to 4 decimals, without trailing zeros
+            let rate = format!("{rate:.4}");
+            let rate = locale::localize(rate.trim_end_matches('0').trim_end_matches('.'));
+            summary.push_str(&format!("  rate 1 {from} = {rate} {to}"));
+        }
+        Some(summary)
     }
 
-    // Re-evaluate lines that depend on changed variables
-    fn reevaluate_dependent_lines(&mut self, changed_vars: &HashSet<String>) {
-        // Simple approach: re-evaluate all lines that contain any of the changed variables
-        for i in 0..self.lines.len() {
-            let line = &self.lines[i];
-            
-            // Check if this line contains any of the changed variables
-            // This is a simple string-based check, might have false positives
-            let needs_eval = changed_vars.iter().any(|var| line.contains(var));
-            
-            if needs_eval {
-                // Parse and evaluate this line
-                let expr = crate::parser::parse_line(line, &self.variables);
-                let result = crate::evaluator::evaluate(&expr, &mut self.variables);
-                
-                // Update the result for this line
-                self.update_result_for_line(i, &result);
+    // The variables a line can see: for each name it mentions, the nearest definition above it.
+    // `line N` references get the result of line N, which has to be above this line.
+    fn scope_for(&self, line_idx: usize, references: &HashSet<String>) -> HashMap<String, Value> {
+        let mut scope = HashMap::new();
+        for name in references {
+            if let Some(n) = crate::evaluator::line_key_number(name) {
+                let value = match (n - 1).cmp(&line_idx) {
+                    std::cmp::Ordering::Equal => Some(Value::Error(format!("Line {n} refers to itself"))),
+                    std::cmp::Ordering::Greater => Some(Value::Error(format!("Line {n} is below this line"))),
+                    // A draft's result is only its own, so `sum of lines` skips it too
+                    std::cmp::Ordering::Less if self.is_draft(n - 1) => None,
+                    std::cmp::Ordering::Less => match self.line_value(n - 1) {
+                        Some(Value::Error(msg)) => Some(Value::Error(format!("Line {n}: {msg}"))),
+                        value => value.cloned(),
+                    },
+                };
+                if let Some(value) = value {
+                    scope.insert(name.clone(), value);
+                }
+                continue;
+            }
+
+            let visible = self.definitions
+                .get(name)
+                .and_then(|lines| lines.range(..line_idx).next_back())
+                .and_then(|&def_idx| self.line_states[def_idx].defined_value(name))
+                .or_else(|| self.preloaded.get(name));
+            if let Some(value) = visible {
+                scope.insert(name.clone(), value.clone());
             }
         }
+        scope
+    }
+
+    // The unit bare numbers on this line are in: that of the nearest `set default` line above
+    fn default_unit_at(&self, line_idx: usize) -> Option<String> {
+        self.default_units.range(..line_idx).next_back().and_then(|(_, unit)| unit.clone())
+    }
+
+    // Point `variables` at the value from the last line that defines `name`, or the
+    // preloaded one when no line does
+    fn refresh_variable(&mut self, name: &str) {
+        // A definition that failed still tells the lines reading it why, through their
+        // scope, but the map only holds values that can be used
+        let last_value = self.definitions
+            .get(name)
+            .and_then(|lines| lines.last())
+            .and_then(|&def_idx| self.line_states[def_idx].defined_value(name))
+            .filter(|value| !matches!(value, Value::Error(_) | Value::Assignment(..)))
+            .or_else(|| self.preloaded.get(name))
+            .cloned();
+        match last_value {
+            Some(value) => {
+                self.variables.insert(name.to_string(), value);
+            }
+            None => {
+                self.variables.remove(name);
+            }
+        }
+    }
+
+    // Line numbers shift when lines are inserted or removed, so rebuild both indexes
+    fn rebuild_index(&mut self) {
+        self.definitions.clear();
+        self.readers.clear();
+        for (line_idx, state) in self.line_states.iter().enumerate() {
+            for name in &state.defines {
+                self.definitions.entry(name.clone()).or_default().insert(line_idx);
+            }
+            for name in &state.references {
+                self.readers.entry(name.clone()).or_default().insert(line_idx);
+            }
+        }
+        self.index_stale = false;
+    }
+
+    // Update the result for a specific line
+    fn update_result_for_line(&mut self, line_idx: usize, result: Option<&Value>) {
+        if line_idx < self.results.len() {
+            let hints = self.line_states.get(line_idx).and_then(|state| state.hints.as_ref());
+            let message = self.line_states.get(line_idx).and_then(|state| state.message.clone());
+            let full_result = match (result, hints) {
+                (None, _) => message.unwrap_or_default(),
+                // The hints take the place of the error; they are still held back while typing
+                (Some(_), Some(hints)) => format_hints(hints),
+                (Some(Value::Error(msg)), None) => format!("Error: {}", msg),
+                (Some(value), None) => match self.line_states.get(line_idx).and_then(|state| state.label.as_ref()) {
+                    Some(label) => format!("{} = {}", label, value),
+                    None => format!("{}", value),
+                },
+            };
+            let full_result = match result {
+                Some(value) if self.is_draft(line_idx) && error_message(value).is_none() => format!("(draft) {}", full_result),
+                _ => full_result,
+            };
+            
+            // Hide errors during debounce period, including assignments of an error
+            let is_error = result.and_then(error_message).is_some();
+            self.results[line_idx] = if self.last_keystroke.elapsed() < self.debounce_period && is_error {
+                String::new()
+            } else {
+                full_result.clone()
+            };
+            self.debounced_results[line_idx] = full_result;
+        }
+    }
+
+    // Keep per-line state aligned when a line is inserted at `line_idx`
+    fn insert_line_state(&mut self, line_idx: usize) {
+        self.line_states.insert(line_idx, LineState::default());
+        self.index_stale = true;
+    }
+
+    // Keep per-line state aligned when the line at `line_idx` is removed
+    fn remove_line_state(&mut self, line_idx: usize) {
+        let state = self.line_states.remove(line_idx);
+        self.setting_lines_removed |= state.is_setting;
+        self.removed_definitions.extend(state.defines);
+        if let Some((name, _, _)) = state.unit {
+            self.sync_units();
+            self.removed_definitions.insert(name);
+        }
+        self.index_stale = true;
     }
 
     // Check if it's time to show errors (called on tick)
     pub fn update_on_tick(&mut self) {
+        // Work through a freshly loaded file a chunk at a time
+        self.catch_up(CATCH_UP_CHUNK);
+        
         // If the debounce period has passed since the last keystroke,
         // update results to show any pending errors
-        if self.last_keystroke.elapsed() >= self.debounce_period {
+        if self.last_keystroke.elapsed() >= self.debounce_period && self.results != self.debounced_results {
             self.results = self.debounced_results.clone();
+            self.needs_redraw = true;
         }
         
-        // Clear status message after 3 seconds
-        if let Some(time) = self.status_time {
-            if time.elapsed() >= Duration::from_secs(3) {
-                self.clear_status_message();
+        // Rates are first fetched when a currency is first converted; say so if that failed
+        if !self.rate_failure_shown
+            && let Some(failure) = self.context.rates.fetch_failure()
+        {
+            self.rate_failure_shown = true;
+            self.set_status_message(failure);
+        }
+        
+        // Let change markers fade once they've been seen for a while
+        if self.recent_changes {
+            self.recent_changes = false;
+            for state in &mut self.line_states {
+                if let Some((_, at)) = state.change {
+                    if at.elapsed() >= CHANGE_HIGHLIGHT {
+                        state.change = None;
+                        self.needs_redraw = true;
+                    } else {
+                        self.recent_changes = true;
+                    }
+                }
             }
         }
+        
+        // Anything logged while the log view is up shows in it
+        if self.show_log && self.log_size() != self.log_read {
+            self.read_log();
+            self.needs_redraw = true;
+        }
+        
+        // Clear status message after 3 seconds
+        if let Some(time) = self.status_time
+            && time.elapsed() >= Duration::from_secs(3)
+        {
+            self.clear_status_message();
+        }
     }
 
     // Cursor movement and text manipulation methods
-    fn insert_char(&mut self, c: char) {
+
+    // Comment out the current line with a leading "# ", or remove the "# " again. Only that
+    // exact prefix is removed, so "## header" or "#note" get commented out rather than
+    // losing their own hashes. The cursor stays on the same character.
+    fn toggle_comment(&mut self) {
+        self.modified = true;
         let line = &mut self.lines[self.cursor_pos.0];
-        if self.cursor_pos.1 >= line.len() {
-            line.push(c);
+        if line.starts_with("# ") {
+            line.replace_range(..2, "");
+            self.cursor_pos.1 = self.cursor_pos.1.saturating_sub(2);
         } else {
-            line.insert(self.cursor_pos.1, c);
+            line.insert_str(0, "# ");
+            self.cursor_pos.1 += 2;
         }
-        self.cursor_pos.1 += 1;
     }
 
-    fn delete_char_before_cursor(&mut self) {
-        if self.cursor_pos.1 > 0 {
-            let line = &mut self.lines[self.cursor_pos.0];
+    // Mark the current line as a draft with `~ `, or take the marker (`~` or `??`) off
+    fn toggle_draft(&mut self) {
+        self.modified = true;
+        let line = &mut self.lines[self.cursor_pos.0];
+        let start = line.len() - line.trim_start().len();
+        let rest = &line[start..];
+        let marker = if rest.starts_with("??") { 2 } else if rest.starts_with('~') { 1 } else { 0 };
+        if marker == 0 {
+            line.insert_str(start, "~ ");
+            if self.cursor_pos.1 >= start {
+                self.cursor_pos.1 += 2;
+            }
+            return;
+        }
+        // The marker goes with the spaces after it
+        let end = line.len() - rest[marker..].trim_start().len();
+        line.replace_range(start..end, "");
+        let cursor = self.cursor_pos.1;
+        self.cursor_pos.1 = if cursor >= end { cursor - (end - start) } else { cursor.min(start) };
+    }
+
+    fn insert_char(&mut self, c: char) {
+        self.modified = true;
+        let line = &mut self.lines[self.cursor_pos.0];
+        if self.cursor_pos.1 >= line.len() {
+            line.push(c);
+        } else {
+            line.insert(self.cursor_pos.1, c);
+        }
+        self.cursor_pos.1 += 1;
+    }
+
+    fn delete_char_before_cursor(&mut self) {
+        if self.cursor_pos.1 > 0 {
+            self.modified = true;
+            let line = &mut self.lines[self.cursor_pos.0];
             line.remove(self.cursor_pos.1 - 1);
             self.cursor_pos.1 -= 1;
         }
@@ -375,10 +1506,12 @@ impl App {
         let line = &mut self.lines[self.cursor_pos.0];
         if self.cursor_pos.1 < line.len() {
             line.remove(self.cursor_pos.1);
+            self.modified = true;
         }
     }
 
     fn insert_newline(&mut self) {
+        self.modified = true;
         let current_line = &self.lines[self.cursor_pos.0];
         let new_line = if self.cursor_pos.1 >= current_line.len() {
             String::new()
@@ -390,6 +1523,7 @@ impl App {
         self.lines.insert(self.cursor_pos.0 + 1, new_line);
         self.results.insert(self.cursor_pos.0 + 1, String::new());
         self.debounced_results.insert(self.cursor_pos.0 + 1, String::new());
+        self.insert_line_state(self.cursor_pos.0 + 1);
         self.cursor_pos.0 += 1;
         self.cursor_pos.1 = 0;
         
@@ -399,9 +1533,11 @@ impl App {
 
     fn join_with_previous_line(&mut self) {
         if self.cursor_pos.0 > 0 {
+            self.modified = true;
             let current_line = self.lines.remove(self.cursor_pos.0);
             self.results.remove(self.cursor_pos.0);
             self.debounced_results.remove(self.cursor_pos.0);
+            self.remove_line_state(self.cursor_pos.0);
             let prev_line_idx = self.cursor_pos.0 - 1;
             let prev_line_len = self.lines[prev_line_idx].len();
             self.lines[prev_line_idx].push_str(&current_line);
@@ -412,9 +1548,11 @@ impl App {
 
     fn join_with_next_line(&mut self) {
         if self.cursor_pos.0 < self.lines.len() - 1 {
+            self.modified = true;
             let next_line = self.lines.remove(self.cursor_pos.0 + 1);
             self.results.remove(self.cursor_pos.0 + 1);
             self.debounced_results.remove(self.cursor_pos.0 + 1);
+            self.remove_line_state(self.cursor_pos.0 + 1);
             self.lines[self.cursor_pos.0].push_str(&next_line);
         }
     }
@@ -488,177 +1626,634 @@ impl App {
         self.cursor_pos.1 == self.lines[self.cursor_pos.0].len()
     }
 
-    // Toggle panel focus between input and output
+    // Move focus to the next panel, or the previous one when `forward` is false
     pub fn toggle_panel_focus(&mut self, forward: bool) {
-        self.panel_focus = match (self.panel_focus, forward) {
-            (PanelFocus::Input, true) | (PanelFocus::Input, false) => {
-                if !self.results.is_empty() {
-                    self.output_selected_idx = self.output_selected_idx.min(self.results.len() - 1);
-                } else {
-                    self.output_selected_idx = 0;
-                }
-                PanelFocus::Output
-            },
-            (PanelFocus::Output, true) | (PanelFocus::Output, false) => {
-                PanelFocus::Input
-            },
+        self.panel_focus = cycle_focus(&PanelFocus::ORDER, self.panel_focus, forward);
+        if self.panel_focus == PanelFocus::Output {
+            self.output_selected_idx = self.output_selected_idx.min(self.results.len().saturating_sub(1));
+        }
+    }
+    
+    // Handle navigation in the output panel: a line at a time, a page at a time (half a page
+    // with Ctrl+U/Ctrl+D), or to either end
+    // With Shift, or in `V` mode, the selection grows from where it was.
+    pub fn navigate_output_panel(&mut self, key: KeyEvent) {
+        let Some(last) = self.results.len().checked_sub(1) else { return };
+        let page = self.output_visible_lines().max(1);
+        let selected = self.output_selected_idx.min(last);
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        let target = match key.code {
+            KeyCode::Char('u') if ctrl => selected.saturating_sub(page.div_ceil(2)),
+            KeyCode::Char('d') if ctrl => (selected + page.div_ceil(2)).min(last),
+            KeyCode::Up | KeyCode::Char('k') => selected.saturating_sub(1),
+            KeyCode::Down | KeyCode::Char('j') => (selected + 1).min(last),
+            KeyCode::PageUp => selected.saturating_sub(page),
+            KeyCode::PageDown => (selected + page).min(last),
+            // vim gg and G
+            KeyCode::Home | KeyCode::Char('g') => 0,
+            KeyCode::End | KeyCode::Char('G') => last,
+            _ => return,
         };
+        if key.modifiers.contains(KeyModifiers::SHIFT) && matches!(key.code, KeyCode::Up | KeyCode::Down) {
+            self.output_anchor.get_or_insert(selected);
+        } else if !self.output_visual {
+            self.output_anchor = None;
+        }
+        self.output_selected_idx = target;
+        self.scroll_output_to_selection();
     }
     
-    // Handle navigation in the output panel
-    pub fn navigate_output_panel(&mut self, key: KeyCode) {
-        match key {
-            KeyCode::Up | KeyCode::Char('k') => {
-                if self.output_selected_idx > 0 {
-                    self.output_selected_idx -= 1;
-                    // Adjust scroll position if selection moves above visible area
-                    if let Some((_, _, _, h)) = self.output_panel_area {
-                        let _visible_lines = h.saturating_sub(2) as usize; // Subtract 2 for borders
-                        if self.output_selected_idx < self.output_scroll {
-                            self.output_scroll = self.output_selected_idx;
-                        }
-                    }
-                }
-            },
-            KeyCode::Down | KeyCode::Char('j') => {
-                if !self.results.is_empty() && self.output_selected_idx < self.results.len() - 1 {
-                    self.output_selected_idx += 1;
-                    // Adjust scroll position if selection moves below visible area
-                    if let Some((_, _, _, h)) = self.output_panel_area {
-                        let visible_lines = h.saturating_sub(2) as usize; // Subtract 2 for borders
-                        if self.output_selected_idx >= self.output_scroll + visible_lines {
-                            self.output_scroll = self.output_selected_idx.saturating_sub(visible_lines) + 1;
-                        }
-                    }
-                }
-            },
-            KeyCode::Home | KeyCode::Char('g') => {
-                // Go to top (vim gg)
-                self.output_selected_idx = 0;
-                self.output_scroll = 0;
-            },
-            KeyCode::End | KeyCode::Char('G') => {
-                // Go to bottom (vim G)
-                if !self.results.is_empty() {
-                    self.output_selected_idx = self.results.len() - 1;
-                    // Adjust scroll position
-                    if let Some((_, _, _, h)) = self.output_panel_area {
-                        let visible_lines = h.saturating_sub(2) as usize;
-                        self.output_scroll = self.output_selected_idx.saturating_sub(visible_lines.saturating_sub(1));
+    // `V` starts selecting several results from the selected one, and ends it again
+    pub fn toggle_output_visual(&mut self) {
+        self.output_visual = !self.output_visual;
+        self.output_anchor = self.output_visual.then_some(self.output_selected_idx);
+    }
+    
+    // Back to a single selected result
+    pub fn collapse_output_selection(&mut self) {
+        self.output_visual = false;
+        self.output_anchor = None;
+    }
+    
+    // The results selected in the output panel: one, or a range from the anchor
+    pub fn output_selection(&self) -> std::ops::RangeInclusive<usize> {
+        let last = self.results.len().saturating_sub(1);
+        let selected = self.output_selected_idx.min(last);
+        let anchor = self.output_anchor.map_or(selected, |anchor| anchor.min(last));
+        selected.min(anchor)..=selected.max(anchor)
+    }
+    
+    // "Sum: $1,350 | Avg: $450 | Count: 3 | 4 selected", for a selection of several results.
+    // Results of another kind than the first number or amount (a length among prices, a
+    // date) are left out of the sum but are still selected; currencies are converted.
+    pub fn output_selection_summary(&self) -> Option<String> {
+        let selection = self.output_selection();
+        if selection.start() == selection.end() {
+            return None;
+        }
+        let selected = selection.clone().count();
+        let mut sum: Option<Value> = None;
+        let mut count = 0;
+        for line_idx in selection {
+            let Some(value @ (Value::Number(_) | Value::Unit(_, _))) = self.line_value(line_idx) else { continue };
+            if self.is_draft(line_idx) {
+                continue;
+            }
+            let new_sum = match &sum {
+                None => value.clone(),
+                Some(total) if std::mem::discriminant(total) == std::mem::discriminant(value) => {
+                    match crate::evaluator::in_context(&self.context, || apply_binary_op(total.clone(), &Op::Add, value.clone())) {
+                        Value::Error(_) => continue,
+                        new_sum => new_sum,
                     }
                 }
-            },
-            _ => {}
+                Some(_) => continue,
+            };
+            sum = Some(new_sum);
+            count += 1;
+        }
+        let Some(sum) = sum else {
+            return Some(format!("Count: 0 | {selected} selected"));
+        };
+        let average = crate::evaluator::in_context(&self.context, || apply_binary_op(sum.clone(), &Op::Divide, Value::Number(count as f64)));
+        Some(format!("Sum: {sum} | Avg: {average} | Count: {count} | {selected} selected"))
+    }
+    
+    // Lines of results that fit inside the output panel's borders
+    fn output_visible_lines(&self) -> usize {
+        self.output_panel_area.map_or(0, |(_, _, _, h)| h.saturating_sub(2) as usize)
+    }
+    
+    // Scroll just enough to show the selected result, never past the last one: a document
+    // shorter than the panel doesn't scroll at all
+    fn scroll_output_to_selection(&mut self) {
+        let visible_lines = self.output_visible_lines();
+        if visible_lines == 0 {
+            return;
+        }
+        if self.output_selected_idx < self.output_scroll {
+            self.output_scroll = self.output_selected_idx;
+        } else if self.output_selected_idx >= self.output_scroll + visible_lines {
+            self.output_scroll = self.output_selected_idx + 1 - visible_lines;
+        }
+        self.output_scroll = self.output_scroll.min(self.results.len().saturating_sub(visible_lines));
+    }
+    
+    // The value a line evaluated to, with assignments unwrapped to the assigned value
+    pub fn line_value(&self, line_idx: usize) -> Option<&Value> {
+        match self.line_states.get(line_idx)?.value.as_ref()? {
+            Value::Assignment(_, value) => Some(value),
+            value => Some(value),
         }
     }
     
-    // Copy selected output to clipboard
-    pub fn copy_selected_output_to_clipboard(&self) -> Result<(), String> {
+    // Whether a line is marked as a draft, so nothing else sees its result
+    pub fn is_draft(&self, line_idx: usize) -> bool {
+        self.line_states.get(line_idx).is_some_and(|state| state.draft)
+    }
+    
+    // The variable a line assigns, if it is an assignment
+    pub fn line_name(&self, line_idx: usize) -> Option<&str> {
+        match self.line_states.get(line_idx)?.value.as_ref()? {
+            Value::Assignment(name, _) => Some(name),
+            _ => None,
+        }
+    }
+    
+    // How the line's result moved in a recent re-evaluation, while that's still worth showing
+    pub fn line_change(&self, line_idx: usize) -> Option<&Change> {
+        match &self.line_states.get(line_idx)?.change {
+            Some((change, at)) if at.elapsed() < CHANGE_HIGHLIGHT => Some(change),
+            _ => None,
+        }
+    }
+
+    // Units suggested in place of a conversion error, if any
+    pub fn conversion_hints(&self, line_idx: usize) -> Option<&[&'static str]> {
+        self.line_states.get(line_idx)?.hints.as_deref()
+    }
+
+    // The pinned result shown next to a line's live result
+    pub fn pinned_result(&self, line_idx: usize) -> Option<&str> {
+        self.line_states.get(line_idx)?.pinned.as_deref()
+    }
+
+    // Pin the selected line's current result, or unpin it if it's already pinned.
+    // Returns the newly pinned result, or None when the pin was removed.
+    pub fn toggle_pin_selected(&mut self) -> Result<Option<String>, String> {
+        let line_idx = self.output_selected_idx;
+        let result = self.debounced_results.get(line_idx).cloned().unwrap_or_default();
+        let Some(state) = self.line_states.get_mut(line_idx) else {
+            return Err("No output selected to pin".to_string());
+        };
+        
+        if state.pinned.take().is_some() {
+            self.modified = true;
+            return Ok(None);
+        }
+        if result.is_empty() {
+            return Err("Selected output is empty".to_string());
+        }
+        if result.starts_with("Error:") || state.hints.is_some() {
+            return Err("Cannot pin error messages".to_string());
+        }
+        state.pinned = Some(result.clone());
+        self.modified = true;
+        Ok(Some(result))
+    }
+
+    // Remove every pin and return how many there were
+    pub fn clear_pins(&mut self) -> usize {
+        let count = self.line_states.iter_mut().filter_map(|state| state.pinned.take()).count();
+        self.modified |= count > 0;
+        count
+    }
+
+    // Copy selected output to clipboard, as displayed. Returns the copied text and the
+    // clipboard method that was used.
+    pub fn copy_selected_output_to_clipboard(&mut self) -> Result<(String, &'static str), String> {
+        let output = self.selected_output_text()?;
+        let method = self.clipboard.copy(&output)?;
+        Ok((output, method.name()))
+    }
+    
+    // The text `y` copies from the output panel
+    pub fn selected_output_text(&self) -> Result<String, String> {
         if self.results.is_empty() || self.output_selected_idx >= self.results.len() {
             return Err("No output selected to copy".to_string());
         }
         
+        // Several results are copied a line each, as displayed
+        let selection = self.output_selection();
+        if selection.start() != selection.end() {
+            return Ok(self.results[selection].join("\n"));
+        }
+        
         let output = &self.results[self.output_selected_idx];
         if output.is_empty() {
             return Err("Selected output is empty".to_string());
         }
 
-        // Don't copy error messages
-        if output.starts_with("Error:") {
+        // Don't copy error messages, or the unit suggestions shown instead of one
+        if output.starts_with("Error:") || self.conversion_hints(self.output_selected_idx).is_some() {
             return Err("Cannot copy error messages".to_string());
         }
         
-        // In WSL, simply use clip.exe which is the most reliable method
-        if let Ok(_) = std::env::var("WSL_DISTRO_NAME") {
-            match std::process::Command::new("clip.exe")
-                .stdin(std::process::Stdio::piped())
-                .spawn() 
-            {
-                Ok(mut child) => {
-                    if let Some(stdin) = child.stdin.as_mut() {
-                        match std::io::Write::write_all(stdin, output.as_bytes()) {
-                            Ok(_) => {
-                                // Wait for the process to complete to ensure the text is copied
-                                if let Ok(_) = child.wait() {
-                                    return Ok(());
-                                }
-                            },
-                            Err(e) => return Err(format!("Failed to write to clip.exe: {}", e)),
-                        }
-                    }
-                    return Err("Failed to access clip.exe stdin".to_string());
-                },
-                Err(e) => return Err(format!("Failed to launch clip.exe: {}", e)),
+        // Dates are copied in the ISO form, whatever the display style
+        Ok(match self.line_value(self.output_selected_idx) {
+            Some(date @ Value::Date(_)) => date.to_raw_string().unwrap_or_default(),
+            _ => output.clone(),
+        })
+    }
+    
+    // Copy the selected result in a machine-friendly form (plain number, ISO date)
+    // for pasting into spreadsheets
+    pub fn copy_selected_raw_value_to_clipboard(&mut self) -> Result<(String, &'static str), String> {
+        let value = self.line_value(self.output_selected_idx)
+            .ok_or_else(|| "Selected output is empty".to_string())?;
+        let raw = value.to_raw_string()
+            .ok_or_else(|| "Cannot copy error messages".to_string())?;
+        let method = self.clipboard.copy(&raw)?;
+        Ok((raw, method.name()))
+    }
+
+    // Evaluate just the selected part of a line and show the result in the status bar. The
+    // selection sees the variables its line sees, in a scope of its own, so an assignment
+    // inside it changes nothing.
+    fn evaluate_selection(&mut self, selection: Option<(usize, std::ops::Range<usize>)>) {
+        let selected = selection.and_then(|(line_idx, range)| {
+            let text = self.lines.get(line_idx)?.get(range)?.trim();
+            (!text.is_empty()).then(|| (line_idx, text.to_string()))
+        });
+        let Some((line_idx, text)) = selected else {
+            self.set_status_message("Select part of a line to evaluate it (double-click a word)".to_string());
+            return;
+        };
+        let mut scope = self.scope_for(line_idx, &referenced_names(&text));
+        let expr = crate::parser::parse_line(&text);
+        let value = match crate::evaluator::evaluate(&expr, &mut scope, &self.context) {
+            Value::Assignment(_, value) => *value,
+            value => value,
+        };
+        match value {
+            Value::Error(msg) => self.set_status_error(format!("'{}': {}", text, msg)),
+            value => {
+                self.set_status_message(format!("{} = {}   (y: copy)", text, value));
+                self.copy_offer = Some(value.to_string());
             }
         }
-        
-        // For non-WSL environments, try arboard
-        match arboard::Clipboard::new() {
-            Ok(mut clipboard) => {
-                match clipboard.set_text(output.clone()) {
-                    Ok(_) => return Ok(()),
-                    Err(e) => return Err(format!("Clipboard error: {}", e)),
+    }
+
+    // Ask for a unit to show the selected result in, without changing the sheet
+    pub fn start_convert(&mut self) {
+        match self.line_value(self.output_selected_idx) {
+            Some(value @ (Value::Number(_) | Value::Unit(..))) => {
+                self.convert_from = Some(value.clone());
+                self.convert_error = None;
+                self.status_input.clear();
+                self.set_input_mode(InputMode::Convert);
+            }
+            _ => self.set_status_message("Only numbers and amounts can be converted".to_string()),
+        }
+    }
+
+    // Complete a conversion started with `start_convert`: "$151.20 = €139.45" in the status
+    // bar, for `y` to copy. A unit that doesn't fit keeps the prompt open and says why.
+    pub fn finish_convert(&mut self, target: &str) {
+        let Some(value) = self.convert_from.clone() else { return };
+        let target = target.trim();
+        // A unit is a word or a few, as after `in`: km, EUR, EUR per month
+        let converted = if target.is_empty() {
+            Value::Error("Missing unit to convert to".to_string())
+        } else if tokenize(target).iter().any(|t| !matches!(t.kind, TokenKind::Word | TokenKind::Whitespace)) {
+            Value::Error(format!("'{target}' isn't a unit"))
+        } else {
+            crate::evaluator::convert(value.clone(), target, &self.context)
+        };
+        match converted {
+            Value::Error(msg) => {
+                self.convert_error = Some(msg);
+                self.status_input = target.to_string();
+                self.set_input_mode(InputMode::Convert);
+            }
+            converted => {
+                self.convert_from = None;
+                self.convert_error = None;
+                self.set_status_message(format!("{} = {}   (y: copy)", value, converted));
+                self.copy_offer = Some(converted.to_string());
+            }
+        }
+    }
+
+    // Show how the selected output line's result came about: the variables it read and the
+    // rates its conversions used
+    pub fn explain_selected(&mut self) {
+        match self.explain_line(self.output_selected_idx) {
+            Ok(explanation) => {
+                self.set_status_message(format!("{}   (y: copy)", explanation));
+                self.copy_offer = Some(explanation);
+            }
+            Err(e) => self.set_status_error(e),
+        }
+    }
+
+    // "10 USD in EUR = €8.50; 1 USD = 0.85 EUR (rates from 2024-05-17 14:05)", or
+    // "a + b = $151.20; a = $120, b = $31.20"
+    pub fn explain_line(&self, line_idx: usize) -> Result<String, String> {
+        let state = self.line_states.get(line_idx).ok_or("No output selected to explain")?;
+        let value = match state.value.as_ref().ok_or("Nothing to explain on this line")? {
+            Value::Assignment(_, value) => value,
+            value => value,
+        };
+        if error_message(value).is_some() {
+            return Err("Errors have nothing to explain".to_string());
+        }
+        let line = self.lines[line_idx].split(" #").next().unwrap_or_default().trim();
+        let mut explanation = format!("{} = {}", line, value);
+        let trace = &state.trace;
+        let mut parts: Vec<String> = Vec::new();
+        if !trace.variables.is_empty() {
+            let operands: Vec<String> = trace.variables.iter().map(|(name, value)| format!("{name} = {value}")).collect();
+            parts.push(operands.join(", "));
+        }
+        for (i, (from, rate, to)) in trace.rates.iter().enumerate() {
+            let mut part = format!("1 {} = {} {}", from, Value::Number(*rate), to);
+            if let Some(Some(source)) = trace.rate_sources.get(i) {
+                part.push_str(&format!(" ({source})"));
+            }
+            parts.push(part);
+        }
+        if !parts.is_empty() {
+            explanation.push_str("; ");
+            explanation.push_str(&parts.join("; "));
+        }
+        Ok(explanation)
+    }
+
+    // When an offer to copy is showing, `y` takes it up. Any other key withdraws it, and
+    // false is returned for the key to be handled as usual.
+    pub fn take_copy_offer(&mut self, key: &KeyEvent) -> bool {
+        let Some(text) = self.copy_offer.take() else { return false };
+        if key.code != KeyCode::Char('y') || !key.modifiers.is_empty() {
+            return false;
+        }
+        match self.clipboard.copy(&text) {
+            Ok(method) => self.set_status_message(format!("Copied '{}' via {}", text, method.name())),
+            Err(e) => self.set_status_error(e),
+        }
+        true
+    }
+
+    // Whether a result is wider than the output panel, so it shows cut short
+    pub fn result_truncated(&self, line_idx: usize) -> bool {
+        let Some((_, _, width, _)) = self.output_panel_area else { return false };
+        // The marks in front take room too: `▶ ` on selected rows and `✖ ` on errors
+        let selected = self.panel_focus == PanelFocus::Output && self.output_selection().contains(&line_idx);
+        self.results.get(line_idx).is_some_and(|result| {
+            let marks = 2 * (usize::from(selected) + usize::from(result.starts_with("Error:")));
+            column_at(result, result.len()) + marks > width.saturating_sub(2) as usize
+        })
+    }
+
+    // Show the whole of the selected result when the output panel cuts it short. Returns
+    // false, doing nothing, when it fits already.
+    pub fn open_full_result(&mut self) -> bool {
+        if !self.result_truncated(self.output_selected_idx) {
+            return false;
+        }
+        self.full_result = Some(self.output_selected_idx);
+        true
+    }
+
+    // Keys while a whole result is showing: Esc closes it and `y` copies it, uncut
+    pub fn handle_full_result_key(&mut self, key: KeyEvent) {
+        match key.code {
+            KeyCode::Esc => self.full_result = None,
+            KeyCode::Char('y') => {
+                let Some(text) = self.full_result.and_then(|line_idx| self.results.get(line_idx)).cloned() else { return };
+                match self.clipboard.copy(&text) {
+                    Ok(method) => self.set_status_message(format!("Copied the whole result via {}", method.name())),
+                    Err(e) => self.set_status_error(e),
                 }
-            },
-            Err(e) => return Err(format!("Failed to access clipboard: {}", e)),
+            }
+            _ => {}
+        }
+    }
+
+    // Show the log view, with what went to the log, then anything dependencies printed to
+    // stderr, oldest first
+    pub fn open_log(&mut self) {
+        self.show_log = true;
+        self.read_log();
+    }
+
+    fn read_log(&mut self) {
+        self.log = crate::logbook::lines();
+        if let Some(text) = self.stderr_log.as_ref().and_then(|path| std::fs::read_to_string(path).ok()) {
+            self.log.extend(text.lines().map(|line| format!("stderr: {}", line)));
+        }
+        self.log_read = self.log_size();
+    }
+
+    // How much has been logged, to tell whether the log view is out of date without reading it
+    fn log_size(&self) -> (usize, u64) {
+        let stderr = self.stderr_log.as_ref().and_then(|path| std::fs::metadata(path).ok()).map_or(0, |file| file.len());
+        (crate::logbook::logged(), stderr)
+    }
+
+    // Keys while the log view is showing: Esc or F12 closes it
+    pub fn handle_log_key(&mut self, key: KeyEvent) {
+        if matches!(key.code, KeyCode::Esc | KeyCode::F(12)) {
+            self.show_log = false;
+        }
+    }
+
+    // New random numbers for the current line
+    pub fn reroll(&mut self) {
+        let line = self.cursor_pos.0;
+        self.line_states[line].roll = None;
+        self.modified_lines.insert(line);
+        self.set_status_message("Rolled again".to_string());
+    }
+
+    // Start handing out seeds from `seed` and reroll every line, for repeatable numbers in tests
+    #[cfg(test)]
+    pub fn seed_random(&mut self, seed: u64) {
+        self.seeds = crate::random::Rng::new(seed);
+        for (line, state) in self.line_states.iter_mut().enumerate() {
+            state.roll = None;
+            self.modified_lines.insert(line);
+        }
+        self.evaluate_expressions();
+    }
+
+    // Ask for a new name for the variable under the cursor
+    pub fn start_rename(&mut self) {
+        if !self.can_edit() {
+            return;
+        }
+        match self.name_at_cursor() {
+            Some(name) if self.definitions.get(&name).is_some_and(|lines| !lines.is_empty()) => {
+                self.rename_from = Some(name);
+                self.set_input_mode(InputMode::Rename);
+            }
+            Some(name) => self.set_status_message(format!("'{}' isn't a variable", name)),
+            None => self.set_status_message("No variable under the cursor".to_string()),
+        }
+    }
+
+    // Complete a rename started with `start_rename`
+    pub fn finish_rename(&mut self, new_name: &str) {
+        let Some(old_name) = self.rename_from.take() else { return };
+        match self.rename_variable(&old_name, new_name.trim()) {
+            Ok(count) => self.set_status_message(format!(
+                "Renamed {} occurrence{} of '{}' to '{}'", count, if count == 1 { "" } else { "s" }, old_name, new_name.trim()
+            )),
+            Err(e) => self.set_status_error(format!("Can't rename: {}", e)),
+        }
+    }
+
+    // Rename a variable on every line. Only whole names are replaced, so renaming `cost`
+    // leaves `shipping_cost` and comments alone. Returns how many were replaced.
+    pub fn rename_variable(&mut self, old_name: &str, new_name: &str) -> Result<usize, String> {
+        if new_name == old_name {
+            return Ok(0);
+        }
+        let valid = matches!(
+            crate::parser::parse_line(&format!("{} = 1", new_name)),
+            Expr::Assignment(name, _) if name == new_name
+        );
+        if !valid || is_keyword(new_name) {
+            return Err(format!("'{}' isn't a valid variable name", new_name));
+        }
+        if crate::evaluator::is_unit_name(new_name) {
+            return Err(format!("'{}' is a unit", new_name));
+        }
+        if self.definitions.get(new_name).is_some_and(|lines| !lines.is_empty()) {
+            return Err(format!("'{}' is already a variable", new_name));
         }
+        
+        let old_words: Vec<&str> = old_name.split(' ').collect();
+        let mut count = 0;
+        for line_idx in 0..self.lines.len() {
+            let (renamed, replaced) = rename_in_line(&self.lines[line_idx], &old_words, new_name);
+            if replaced > 0 {
+                self.lines[line_idx] = renamed;
+                self.modified_lines.insert(line_idx);
+                count += replaced;
+            }
+        }
+        if count > 0 {
+            self.modified = true;
+            let (line_idx, byte) = self.cursor_pos;
+            self.jump_to((line_idx, byte));
+            self.evaluate_expressions();
+        }
+        Ok(count)
+    }
+
+    // Jump to the nearest line above that assigns the variable under (or just before) the
+    // cursor. Ctrl+O comes back.
+    pub fn go_to_definition(&mut self) {
+        let (line_idx, byte) = self.cursor_pos;
+        let Some(name) = self.name_at_cursor() else {
+            self.set_status_message("No variable under the cursor".to_string());
+            return;
+        };
+        let Some(defining) = self.definitions.get(&name).filter(|lines| !lines.is_empty()) else {
+            self.set_status_message(format!("'{}' isn't defined", name));
+            return;
+        };
+        let Some(&def_idx) = defining.range(..line_idx).next_back() else {
+            let below = defining.iter().next().map_or(0, |&idx| idx + 1);
+            self.set_status_message(format!("'{}' is only defined below, on line {}", name, below));
+            return;
+        };
+        
+        // A name assigned more than once is easy to get wrong, so say so
+        if defining.len() > 1 {
+            let lines: Vec<String> = defining.iter().map(|idx| (idx + 1).to_string()).collect();
+            self.set_status_message(format!("'{}' is defined on lines {}; jumped to line {}", name, lines.join(", "), def_idx + 1));
+        } else {
+            self.set_status_message(format!("'{}' is defined on line {}", name, def_idx + 1));
+        }
+        self.jump_back.push((line_idx, byte));
+        self.jump_forward.clear();
+        self.cursor_pos = (def_idx, 0);
+        self.ensure_cursor_visible();
+    }
+
+    pub fn jump_back(&mut self) {
+        if let Some(position) = self.jump_back.pop() {
+            self.jump_forward.push(self.cursor_pos);
+            self.jump_to(position);
+        }
+    }
+
+    pub fn jump_forward(&mut self) {
+        if let Some(position) = self.jump_forward.pop() {
+            self.jump_back.push(self.cursor_pos);
+            self.jump_to(position);
+        }
+    }
+
+    // Lines may have changed since the position was saved, so keep it inside the text
+    fn jump_to(&mut self, (line_idx, byte): (usize, usize)) {
+        let line_idx = line_idx.min(self.lines.len().saturating_sub(1));
+        let line = &self.lines[line_idx];
+        let mut byte = byte.min(line.len());
+        while !line.is_char_boundary(byte) {
+            byte -= 1;
+        }
+        self.cursor_pos = (line_idx, byte);
+        self.ensure_cursor_visible();
+    }
+
+    // The defined name under the cursor or right before it, preferring the longest
+    // multi-word name that covers it ("monthly rent" over "rent")
+    fn name_at_cursor(&self) -> Option<String> {
+        let (line_idx, byte) = self.cursor_pos;
+        let line = self.lines.get(line_idx)?;
+        let word = word_at(line, byte)
+            .or_else(|| word_at(line, byte.checked_sub(1)?))
+            .filter(|word| !line[word.clone()].starts_with(|c: char| c.is_ascii_digit()))?;
+        let longer = self.definitions
+            .keys()
+            .filter(|name| name.contains(' '))
+            .filter(|name| line.match_indices(name.as_str()).any(|(start, _)| start <= word.start && word.end <= start + name.len()))
+            .max_by_key(|name| name.len());
+        Some(longer.cloned().unwrap_or_else(|| line[word].to_string()))
     }
 
     // Handle mouse click events
     pub fn handle_mouse_click(&mut self, x: u16, y: u16, area: (u16, u16, u16, u16)) -> bool {
-        let (input_x, input_y, input_width, input_height) = area;
+        let view = TextView { area, scroll: self.input_scroll };
+        let Some((row, column)) = view.text_position(x, y) else {
+            return false;
+        };
+        self.panel_focus = PanelFocus::Input;
+        self.selection = None;
         
-        // Check if click is within input panel bounds (including borders)
-        if x >= input_x && x < input_x + input_width && 
-           y >= input_y && y < input_y + input_height {
-            // Switch focus to input panel
-            self.panel_focus = PanelFocus::Input;
-            
-            // If click is within the content area (excluding borders)
-            if x > input_x && x < input_x + input_width - 1 && 
-               y > input_y && y < input_y + input_height - 1 {
-                // Convert screen coordinates to text coordinates (accounting for borders)
-                let text_x = (x - input_x - 1) as usize;
-                let text_y = (y - input_y - 1) as usize + self.input_scroll;
-                
-                // Check if we have a line at this y position
-                if text_y < self.lines.len() {
-                    // Set cursor position
-                    self.cursor_pos.0 = text_y;
-                    // Set x position, clamped to line length
-                    self.cursor_pos.1 = text_x.min(self.lines[text_y].len());
-                }
-            }
+        // Below the last line, the click lands at the end of the text
+        let line_idx = row.min(self.lines.len().saturating_sub(1));
+        let Some(line) = self.lines.get(line_idx) else {
             return true;
-        }
+        };
+        let byte = byte_at_column(line, column);
+        self.cursor_pos = (line_idx, byte);
         
-        false
+        // A second click in the same place selects the word under the pointer
+        let double = self.last_click.is_some_and(|(at, last_x, last_y)| {
+            at.elapsed() < DOUBLE_CLICK && (last_x, last_y) == (x, y)
+        });
+        self.last_click = (!double).then(|| (Instant::now(), x, y));
+        if double && let Some(word) = word_at(line, byte) {
+            self.cursor_pos.1 = word.end;
+            self.selection = Some((line_idx, word));
+        }
+        true
     }
 
     // Handle mouse click in output panel
     pub fn handle_output_mouse_click(&mut self, x: u16, y: u16, area: (u16, u16, u16, u16)) -> bool {
-        let (output_x, output_y, output_width, output_height) = area;
-        
-        // Check if click is within output panel bounds
-        if x >= output_x && x < output_x + output_width && 
-           y >= output_y && y < output_y + output_height {
-            // Switch focus to output panel
-            self.panel_focus = PanelFocus::Output;
-            
-            // If click is within the content area (excluding borders)
-            if x > output_x && x < output_x + output_width - 1 && 
-               y > output_y && y < output_y + output_height - 1 {
-                let text_y = (y - output_y - 1) as usize + self.output_scroll;
-                
-                // Check if we have a result at this y position
-                if text_y < self.results.len() {
-                    self.output_selected_idx = text_y;
-                }
-            }
-            return true;
+        let view = TextView { area, scroll: self.output_scroll };
+        let Some((row, _)) = view.text_position(x, y) else {
+            return false;
+        };
+        self.panel_focus = PanelFocus::Output;
+        self.collapse_output_selection();
+        if !self.results.is_empty() {
+            self.output_selected_idx = row.min(self.results.len() - 1);
         }
-        
-        false
+        true
+    }
+
+    // Go to the line that produced the selected result, to edit it
+    pub fn edit_selected_output_line(&mut self) {
+        let line_idx = self.output_selected_idx.min(self.lines.len().saturating_sub(1));
+        self.panel_focus = PanelFocus::Input;
+        self.cursor_pos = (line_idx, self.lines.get(line_idx).map_or(0, String::len));
+        self.ensure_cursor_visible();
     }
 
     pub fn ensure_cursor_visible(&mut self) {
@@ -675,4 +2270,368 @@ impl App {
             }
         }
     }
-} 
\ No newline at end of file
+}
+
+// A comment line that starts a section: a decorated title such as `# --- Groceries ---`
+// or `## Groceries`, or `#= Groceries` to also total up the section
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SectionHeader<'a> {
+    pub title: &'a str,
+    pub totals: bool,
+}
+
+// The text of a draft line, one marked with a leading `~` or `??`
+pub fn draft_body(line: &str) -> Option<&str> {
+    let line = line.trim_start();
+    let rest = line.strip_prefix("??").or_else(|| line.strip_prefix('~'))?;
+    Some(rest.trim())
+}
+
+pub fn section_header(line: &str) -> Option<SectionHeader<'_>> {
+    let rest = line.trim().strip_prefix('#')?;
+    let totals = rest.starts_with('=');
+    let decorated = totals || rest.trim_start().starts_with(['#', '-', '=']);
+    let title = rest.trim_matches(|c: char| c == '#' || c == '-' || c == '=' || c.is_whitespace());
+    
+    if decorated && title.chars().any(char::is_alphanumeric) {
+        Some(SectionHeader { title, totals })
+    } else {
+        None
+    }
+}
+
+// Compare a line's result before and after re-evaluating it, when it changed. Numbers and
+// values in the same unit get a delta; any other change of a non-error result just flashes.
+fn value_change(old: &Value, new: &Value) -> Option<Change> {
+    match (old, new) {
+        (Value::Error(_), _) | (_, Value::Error(_)) => None,
+        (Value::Number(a), Value::Number(b)) => Some(Change::Delta(Value::Number(b - a))),
+        (Value::Unit(a, unit_a), Value::Unit(b, unit_b)) if unit_a == unit_b => {
+            Some(Change::Delta(Value::Unit(b - a, unit_b.clone())))
+        }
+        _ => Some(Change::Flash),
+    }
+}
+
+// How results line up in the output panel
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Align {
+    Left,
+    #[default]
+    Right, // Numbers, units and dates; errors and hints stay on the left
+}
+
+// Any `set` directive line, and whether it is valid
+fn setting_directive(line: &str) -> Option<Result<(), String>> {
+    locale_directive(line)
+        .map(|directive| directive.map(|_| ()))
+        .or_else(|| align_directive(line).map(|directive| directive.map(|_| ())))
+        .or_else(|| dates_directive(line).map(|directive| directive.map(|_| ())))
+        .or_else(|| rounding_directive(line).map(|directive| directive.map(|_| ())))
+        .or_else(|| base_currency_directive(line).map(|directive| directive.map(|_| ())))
+        .or_else(|| default_unit_directive(line).map(|directive| directive.map(|_| ())))
+        .or_else(|| running_total_directive(line).map(|directive| directive.map(|_| ())))
+}
+
+// A `set running total USD` line: the currency the header adds up the sheet's money in.
+// `set running total off` takes the total away again.
+fn running_total_directive(line: &str) -> Option<Result<Option<String>, String>> {
+    let mut words = line.split_whitespace();
+    if !words.next()?.eq_ignore_ascii_case("set")
+        || !words.next()?.eq_ignore_ascii_case("running")
+        || !words.next()?.eq_ignore_ascii_case("total")
+    {
+        return None;
+    }
+    Some(match (words.next().map(str::to_uppercase), words.next()) {
+        (Some(off), None) if off == "OFF" => Ok(None),
+        (Some(code), None) if crate::lexer::is_currency_code(&code) => Ok(Some(code)),
+        _ => Err("Usage: set running total USD or set running total off".to_string()),
+    })
+}
+
+// A `set default currency EUR` or `set default unit kg` line: the unit that bare numbers on
+// the lines below it are in, up to the next such line. `unset default` is back to plain numbers.
+fn default_unit_directive(line: &str) -> Option<Result<Option<String>, String>> {
+    let mut words = line.split_whitespace();
+    let first = words.next()?;
+    let unset = first.eq_ignore_ascii_case("unset");
+    if !(unset || first.eq_ignore_ascii_case("set")) || !words.next()?.eq_ignore_ascii_case("default") {
+        return None;
+    }
+    let kind = words.next().map(str::to_lowercase);
+    Some(match (unset, kind.as_deref(), words.next(), words.next()) {
+        (true, None, _, _) => Ok(None),
+        (false, Some("currency"), Some(code), None) if crate::lexer::is_currency_code(&code.to_uppercase()) => Ok(Some(code.to_uppercase())),
+        (false, Some("unit"), Some(unit), None) if crate::evaluator::is_unit_name(unit) => Ok(Some(unit.to_string())),
+        _ => Err("Usage: set default currency EUR, set default unit kg or unset default".to_string()),
+    })
+}
+
+// A bare number, or a variable set to one, taken to be in the line's default unit
+fn with_default_unit(value: Value, unit: Option<&str>) -> Value {
+    match (value, unit) {
+        (Value::Number(n), Some(unit)) => Value::Unit(n, unit.to_string()),
+        (Value::Assignment(name, value), Some(_)) => Value::Assignment(name, Box::new(with_default_unit(*value, unit))),
+        (value, _) => value,
+    }
+}
+
+// A `set base currency EUR` line: the currency sums of mixed currencies come out in
+fn base_currency_directive(line: &str) -> Option<Result<String, String>> {
+    let mut words = line.split_whitespace();
+    if !words.next()?.eq_ignore_ascii_case("set") || !words.next()?.eq_ignore_ascii_case("base") {
+        return None;
+    }
+    Some(match (words.next(), words.next().map(str::to_uppercase), words.next()) {
+        (Some(word), Some(code), None) if word.eq_ignore_ascii_case("currency") && crate::lexer::is_currency_code(&code) => Ok(code),
+        _ => Err("Usage: set base currency EUR".to_string()),
+    })
+}
+
+// A `set currency rounding on` line: whether money is rounded to cents at every step
+fn rounding_directive(line: &str) -> Option<Result<bool, String>> {
+    let mut words = line.split_whitespace();
+    if !words.next()?.eq_ignore_ascii_case("set") || !words.next()?.eq_ignore_ascii_case("currency") {
+        return None;
+    }
+    Some(match (words.next(), words.next().map(str::to_lowercase).as_deref(), words.next()) {
+        (Some(word), Some("on"), None) if word.eq_ignore_ascii_case("rounding") => Ok(true),
+        (Some(word), Some("off"), None) if word.eq_ignore_ascii_case("rounding") => Ok(false),
+        _ => Err("Usage: set currency rounding on|off".to_string()),
+    })
+}
+
+// A `set dates long` line: how dates display, or why it can't
+fn dates_directive(line: &str) -> Option<Result<DateStyle, String>> {
+    let mut words = line.split_whitespace();
+    if !words.next()?.eq_ignore_ascii_case("set") || !words.next()?.eq_ignore_ascii_case("dates") {
+        return None;
+    }
+    Some(match (words.next().and_then(DateStyle::from_name), words.next()) {
+        (Some(style), None) => Ok(style),
+        _ => Err("Usage: set dates iso|weekday|long".to_string()),
+    })
+}
+
+// A `set align left` line: the alignment it selects, or why it can't
+fn align_directive(line: &str) -> Option<Result<Align, String>> {
+    let mut words = line.split_whitespace();
+    if !words.next()?.eq_ignore_ascii_case("set") || !words.next()?.eq_ignore_ascii_case("align") {
+        return None;
+    }
+    Some(match (words.next().map(str::to_lowercase).as_deref(), words.next()) {
+        (Some("left"), None) => Ok(Align::Left),
+        (Some("right"), None) => Ok(Align::Right),
+        _ => Err("Usage: set align left|right".to_string()),
+    })
+}
+
+// A `set locale de` line: the locale it selects, or why it can't
+fn locale_directive(line: &str) -> Option<Result<Locale, String>> {
+    let mut words = line.split_whitespace();
+    if !words.next()?.eq_ignore_ascii_case("set") || !words.next()?.eq_ignore_ascii_case("locale") {
+        return None;
+    }
+    Some(match (words.next(), words.next()) {
+        (Some(name), None) if !name.starts_with('#') => {
+            Locale::from_name(name).ok_or_else(|| format!("Unknown locale '{name}'; use en or de"))
+        }
+        _ => Err("Usage: set locale en|de".to_string()),
+    })
+}
+
+// Most units listed in a line's result slot
+const MAX_HINTS: usize = 8;
+
+// Units to suggest when a line fails because its conversion target is missing (`250 km in`)
+// or unknown (`250 km in me`). The value before the last `in`/`to` is evaluated on its own
+// and whatever was typed after it narrows the suggestions down.
+fn conversion_hints(line: &str, scope: &HashMap<String, Value>, result: &Value, context: &Context) -> Option<Vec<&'static str>> {
+    let msg = error_message(result)?;
+    if msg != "Missing unit to convert to" && !msg.starts_with("Can't convert ") {
+        return None;
+    }
+    
+    let tokens = tokenize(line);
+    let keyword = tokens.iter().rev().find(|t| t.kind == TokenKind::Word && (t.text == "in" || t.text == "to"))?;
+    let source = &line[..keyword.start];
+    let prefix = line[keyword.start + keyword.text.len()..].trim();
+    
+    let mut scope = scope.clone();
+    let expr = crate::parser::parse_line(source);
+    let unit = match crate::evaluator::evaluate(&expr, &mut scope, context) {
+        Value::Unit(_, unit) => unit,
+        Value::Assignment(_, value) => match *value {
+            Value::Unit(_, unit) => unit,
+            _ => return None,
+        },
+        _ => return None,
+    };
+    
+    let targets = crate::evaluator::conversion_targets(&unit, prefix);
+    (!targets.is_empty()).then_some(targets)
+}
+
+fn format_hints(hints: &[&str]) -> String {
+    let mut text = format!("→ {}", hints.iter().take(MAX_HINTS).copied().collect::<Vec<_>>().join(", "));
+    if hints.len() > MAX_HINTS {
+        text.push_str(", …");
+    }
+    text
+}
+
+// Where the text of a bordered panel sits on screen, scrolled down by `scroll` lines. Cursor
+// drawing and mouse clicks both go through here so they always agree.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TextView {
+    pub area: (u16, u16, u16, u16), // (x, y, width, height) of the panel, border included
+    pub scroll: usize,
+}
+
+impl TextView {
+    // The screen cell of a line and display column, if it's in view
+    pub fn screen_cell(&self, line_idx: usize, column: usize) -> Option<(u16, u16)> {
+        let (x, y, width, height) = self.area;
+        let row = line_idx.checked_sub(self.scroll)?;
+        if row >= height.saturating_sub(2) as usize || column >= width.saturating_sub(2) as usize {
+            return None;
+        }
+        Some((x + 1 + column as u16, y + 1 + row as u16))
+    }
+
+    // The line and display column under a screen cell, or None outside the panel. A click
+    // on the border counts as a click on the nearest text cell, so it's never dead.
+    pub fn text_position(&self, x: u16, y: u16) -> Option<(usize, usize)> {
+        let (left, top, width, height) = self.area;
+        if width < 3 || height < 3 || !(left..left + width).contains(&x) || !(top..top + height).contains(&y) {
+            return None;
+        }
+        let column = x.clamp(left + 1, left + width - 2) - left - 1;
+        let row = y.clamp(top + 1, top + height - 2) - top - 1;
+        Some((self.scroll + row as usize, column as usize))
+    }
+}
+
+// Display column of a byte offset in `line`, with wide characters taking two cells
+pub fn column_at(line: &str, byte: usize) -> usize {
+    use unicode_width::UnicodeWidthStr;
+    line[..byte.min(line.len())].width()
+}
+
+// Byte offset of the character at a display column, or the end of the line past it
+pub fn byte_at_column(line: &str, column: usize) -> usize {
+    use unicode_width::UnicodeWidthChar;
+    let mut used = 0;
+    for (byte, c) in line.char_indices() {
+        let width = c.width().unwrap_or(0);
+        if used + width > column {
+            return byte;
+        }
+        used += width;
+    }
+    line.len()
+}
+
+// Replace each whole occurrence of a (possibly multi-word) name in a line. Returns the new
+// line and the number of replacements.
+fn rename_in_line(line: &str, old_words: &[&str], new_name: &str) -> (String, usize) {
+    let tokens: Vec<_> = tokenize(line).into_iter().filter(|token| token.kind != TokenKind::Whitespace).collect();
+    let mut renamed = String::new();
+    let mut copied = 0;
+    let mut count = 0;
+    let mut i = 0;
+    while i + old_words.len() <= tokens.len() {
+        let candidate = &tokens[i..i + old_words.len()];
+        let matches = candidate.iter().zip(old_words).all(|(token, word)| token.kind == TokenKind::Word && token.text == *word)
+            // The words of a multi-word name are separated by whitespace only
+            && candidate.windows(2).all(|pair| line[pair[0].start + pair[0].text.len()..pair[1].start].trim().is_empty());
+        if !matches {
+            i += 1;
+            continue;
+        }
+        let last = candidate[candidate.len() - 1];
+        renamed.push_str(&line[copied..candidate[0].start]);
+        renamed.push_str(new_name);
+        copied = last.start + last.text.len();
+        count += 1;
+        i += old_words.len();
+    }
+    renamed.push_str(&line[copied..]);
+    (renamed, count)
+}
+
+// The word or number around a byte offset, for double-click selection
+fn word_at(line: &str, byte: usize) -> Option<std::ops::Range<usize>> {
+    tokenize(line)
+        .into_iter()
+        .filter(|token| matches!(token.kind, TokenKind::Word | TokenKind::Number))
+        .map(|token| token.start..token.start + token.text.len())
+        .find(|range| range.contains(&byte))
+}
+
+// Saved files keep pins in a trailing comment: `total  #[pinned] $148.90`. It is spelled
+// so that a comment a user writes isn't taken for one.
+const PIN_MARKER: &str = "  #[pinned] ";
+
+// The error a line produced, including one assigned to a variable
+fn error_message(value: &Value) -> Option<&str> {
+    match value {
+        Value::Error(msg) => Some(msg),
+        Value::Assignment(_, inner) => match inner.as_ref() {
+            Value::Error(msg) => Some(msg),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// The lines a line refers to by number: `line 3` and the `2..5` of `sum of lines 2..5`
+fn referenced_lines(line: &str) -> Vec<usize> {
+    let tokens: Vec<_> = tokenize(line).into_iter().filter(|t| t.kind != TokenKind::Whitespace).collect();
+    let number = |i: usize| tokens.get(i).filter(|t| t.kind == TokenKind::Number)?.text.parse::<usize>().ok().filter(|&n| n > 0);
+    let mut lines = Vec::new();
+    
+    for (i, token) in tokens.iter().enumerate() {
+        if token.text.eq_ignore_ascii_case("line") && let Some(n) = number(i + 1) {
+            lines.push(n);
+        }
+        if token.text.eq_ignore_ascii_case("lines")
+            && let (Some(from), Some(to)) = (number(i + 1), number(i + 4))
+            && tokens[i + 2].text == "."
+            && tokens[i + 3].text == "."
+            && from <= to
+            && to - from < crate::parser::MAX_LINE_RANGE
+        {
+            lines.extend(from..=to);
+        }
+    }
+    
+    lines
+}
+
+// Every word on a line that could name a variable. This is taken from the tokens rather
+// than the parsed expression because a name that isn't defined yet doesn't produce a
+// Variable node, but the line still has to be re-evaluated once it is defined.
+// Runs of words separated only by whitespace also contribute every multi-word name
+// they contain, so "monthly rent" is found in "monthly rent * 12".
+// References to other lines are included as `line N` names.
+fn referenced_names(line: &str) -> HashSet<String> {
+    let mut names: HashSet<String> = referenced_lines(line).into_iter().map(crate::evaluator::line_key).collect();
+    let mut run: Vec<&str> = Vec::new();
+    
+    for token in tokenize(line) {
+        match token.kind {
+            TokenKind::Whitespace => continue,
+            TokenKind::Word if !is_keyword(token.text) => {
+                run.push(token.text);
+                for start in 0..run.len() {
+                    names.insert(run[start..].join(" "));
+                }
+            }
+            _ => run.clear(),
+        }
+    }
+    
+    names
+}
@@ -0,0 +1,210 @@
+// Command-line arguments. The flags live in one table that drives both parsing and the
+// OPTIONS section of `--help`, so adding a flag is one entry plus one line in `apply`.
+
+// A flag: its short and long names, the placeholder of its value if it takes one, and
+// its line in the help text
+struct Flag {
+    short: Option<char>,
+    long: &'static str,
+    value: Option<&'static str>,
+    help: &'static str,
+}
+
+const FLAGS: &[Flag] = &[
+    Flag { short: None, long: "repl", value: None, help: "A one-line prompt with history instead of the notebook" },
+    Flag { short: None, long: "tutorial", value: None, help: "Open the tutorial, a sheet showing what cali understands" },
+    Flag { short: None, long: "preload", value: Some("FILE"), help: "Evaluate FILE first so the sheet can use what it defines (repeatable)" },
+    Flag { short: None, long: "diff", value: Some("OLD"), help: "Print the results that differ between OLD and FILE, then exit" },
+    Flag { short: None, long: "read-only", value: None, help: "Show the sheet without letting keys change it (Ctrl+Shift+L toggles)" },
+    Flag { short: None, long: "high-contrast", value: None, help: "Colors that stay apart without telling red from gray" },
+    Flag { short: None, long: "no-color", value: None, help: "No colors and ASCII-only symbols (same as NO_COLOR=1)" },
+    Flag { short: Some('v'), long: "version", value: None, help: "Display version information" },
+    Flag { short: Some('h'), long: "help", value: None, help: "Display this help message" },
+];
+
+// What the command line asked for
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Options {
+    pub help: bool,
+    pub version: bool,
+    pub no_color: bool,
+    pub high_contrast: bool,
+    pub read_only: bool,
+    pub repl: bool,
+    pub tutorial: bool,
+    pub preload: Vec<String>,
+    pub diff: Option<String>,
+    pub file: Option<String>,
+}
+
+// Parse the arguments after the program name. Flags may come before or after the file,
+// short flags can be combined (`-hv`), and everything after `--` is taken literally.
+pub fn parse_args<I: IntoIterator<Item = String>>(args: I) -> Result<Options, String> {
+    let mut options = Options::default();
+    let mut args = args.into_iter();
+    let mut positional = Vec::new();
+
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            positional.extend(args.by_ref());
+        } else if let Some(long) = arg.strip_prefix("--") {
+            let (name, inline) = match long.split_once('=') {
+                Some((name, value)) => (name, Some(value.to_string())),
+                None => (long, None),
+            };
+            let flag = FLAGS.iter()
+                .find(|flag| flag.long == name)
+                .ok_or_else(|| format!("unknown flag '--{}'", name))?;
+            let value = take_value(flag, inline, &mut args)?;
+            apply(&mut options, flag, value);
+        } else if let Some(shorts) = arg.strip_prefix('-').filter(|shorts| !shorts.is_empty()) {
+            for (i, c) in shorts.char_indices() {
+                let flag = FLAGS.iter()
+                    .find(|flag| flag.short == Some(c))
+                    .ok_or_else(|| format!("unknown flag '-{}'", c))?;
+                // A flag with a value takes the rest of the group: -p3
+                let rest = &shorts[i + c.len_utf8()..];
+                let inline = (flag.value.is_some() && !rest.is_empty()).then(|| rest.to_string());
+                let done = inline.is_some();
+                let value = take_value(flag, inline, &mut args)?;
+                apply(&mut options, flag, value);
+                if done {
+                    break;
+                }
+            }
+        } else {
+            positional.push(arg);
+        }
+    }
+
+    // --help and --version answer on their own, whatever else is there
+    if options.help || options.version {
+        return Ok(options);
+    }
+    let mut positional = positional.into_iter();
+    options.file = positional.next();
+    if let Some(extra) = positional.next() {
+        return Err(format!("unexpected argument '{}'; only one file can be opened", extra));
+    }
+    if let Some(old) = &options.diff {
+        if options.file.is_none() {
+            return Err(format!("--diff '{}' needs a second file to compare it with: cali --diff OLD NEW", old));
+        }
+        if options.repl || options.tutorial {
+            return Err("--diff prints a report; it can't be combined with --repl or --tutorial".to_string());
+        }
+    }
+    if options.repl && let Some(file) = &options.file {
+        return Err(format!("unexpected argument '{}'; --repl doesn't open a file", file));
+    }
+    if options.repl && let Some(file) = options.preload.first() {
+        return Err(format!("--preload '{}' is for the notebook; --repl doesn't take it", file));
+    }
+    if options.tutorial && let Some(file) = &options.file {
+        return Err(format!("unexpected argument '{}'; --tutorial opens the tutorial instead of a file", file));
+    }
+    Ok(options)
+}
+
+// The value of a flag that takes one, from `--flag=value`/`-fvalue` or the next argument
+fn take_value(flag: &Flag, inline: Option<String>, args: &mut impl Iterator<Item = String>) -> Result<Option<String>, String> {
+    match (flag.value, inline) {
+        (None, None) => Ok(None),
+        (None, Some(_)) => Err(format!("'--{}' doesn't take a value", flag.long)),
+        (Some(_), Some(value)) => Ok(Some(value)),
+        (Some(placeholder), None) => args.next()
+            .map(Some)
+            .ok_or_else(|| format!("'--{}' needs a value: --{} {}", flag.long, flag.long, placeholder)),
+    }
+}
+
+fn apply(options: &mut Options, flag: &Flag, value: Option<String>) {
+    match flag.long {
+        "help" => options.help = true,
+        "version" => options.version = true,
+        "no-color" => options.no_color = true,
+        "high-contrast" => options.high_contrast = true,
+        "read-only" => options.read_only = true,
+        "repl" => options.repl = true,
+        "tutorial" => options.tutorial = true,
+        "preload" => options.preload.extend(value),
+        "diff" => options.diff = value,
+        other => unreachable!("flag '--{}' is in the table but not handled", other),
+    }
+}
+
+// The OPTIONS section of the help text, one line per flag
+pub fn options_help() -> String {
+    FLAGS.iter()
+        .map(|flag| {
+            let mut names = match flag.short {
+                Some(short) => format!("-{}, --{}", short, flag.long),
+                None => format!("--{}", flag.long),
+            };
+            if let Some(placeholder) = flag.value {
+                names.push(' ');
+                names.push_str(placeholder);
+            }
+            format!("  {:<22}  {}\n", names, flag.help)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Options, String> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn test_existing_invocations() {
+        assert_eq!(parse(&[]), Ok(Options::default()));
+        assert_eq!(parse(&["budget.txt"]), Ok(Options { file: Some("budget.txt".to_string()), ..Options::default() }));
+        assert_eq!(parse(&["-v"]), Ok(Options { version: true, ..Options::default() }));
+        assert_eq!(parse(&["--version"]), Ok(Options { version: true, ..Options::default() }));
+        assert_eq!(parse(&["-h"]), Ok(Options { help: true, ..Options::default() }));
+        assert_eq!(parse(&["--help"]), Ok(Options { help: true, ..Options::default() }));
+        assert_eq!(parse(&["--version", "extra"]), Ok(Options { version: true, ..Options::default() }));
+    }
+
+    #[test]
+    fn test_flags_anywhere_combined_and_after_double_dash() {
+        assert_eq!(parse(&["budget.txt", "--no-color"]),
+            Ok(Options { no_color: true, file: Some("budget.txt".to_string()), ..Options::default() }));
+        assert_eq!(parse(&["-hv"]), Ok(Options { help: true, version: true, ..Options::default() }));
+        assert_eq!(parse(&["--high-contrast"]), Ok(Options { high_contrast: true, ..Options::default() }));
+        assert_eq!(parse(&["--", "-v"]), Ok(Options { file: Some("-v".to_string()), ..Options::default() }));
+        assert_eq!(parse(&["--read-only", "demo.cali"]),
+            Ok(Options { read_only: true, file: Some("demo.cali".to_string()), ..Options::default() }));
+        assert_eq!(parse(&["--preload", "prelude.cali", "--preload=rates.cali", "sheet.cali"]), Ok(Options {
+            preload: vec!["prelude.cali".to_string(), "rates.cali".to_string()],
+            file: Some("sheet.cali".to_string()),
+            ..Options::default()
+        }));
+        assert_eq!(parse(&["--diff", "old.cali", "new.cali"]),
+            Ok(Options { diff: Some("old.cali".to_string()), file: Some("new.cali".to_string()), ..Options::default() }));
+    }
+
+    #[test]
+    fn test_bad_arguments_are_reported() {
+        assert_eq!(parse(&["--colour"]), Err("unknown flag '--colour'".to_string()));
+        assert_eq!(parse(&["-vx"]), Err("unknown flag '-x'".to_string()));
+        assert_eq!(parse(&["--no-color=yes"]), Err("'--no-color' doesn't take a value".to_string()));
+        assert_eq!(parse(&["a.txt", "b.txt"]), Err("unexpected argument 'b.txt'; only one file can be opened".to_string()));
+        assert_eq!(parse(&["--repl", "a.txt"]), Err("unexpected argument 'a.txt'; --repl doesn't open a file".to_string()));
+        assert_eq!(parse(&["--preload"]), Err("'--preload' needs a value: --preload FILE".to_string()));
+        assert_eq!(parse(&["--repl", "--preload", "a.cali"]), Err("--preload 'a.cali' is for the notebook; --repl doesn't take it".to_string()));
+        assert_eq!(parse(&["--diff", "old.cali"]), Err("--diff 'old.cali' needs a second file to compare it with: cali --diff OLD NEW".to_string()));
+        assert_eq!(parse(&["--repl", "--diff", "a.cali", "b.cali"]), Err("--diff prints a report; it can't be combined with --repl or --tutorial".to_string()));
+        assert_eq!(parse(&["a.txt", "--tutorial"]), Err("unexpected argument 'a.txt'; --tutorial opens the tutorial instead of a file".to_string()));
+    }
+
+    #[test]
+    fn test_options_help_lists_every_flag() {
+        let help = options_help();
+        assert_eq!(help.lines().count(), FLAGS.len());
+        assert!(help.contains("  -v, --version           Display version information"));
+    }
+}
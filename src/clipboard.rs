@@ -0,0 +1,332 @@
+use std::io::{self, Write};
+use std::process::{Command, Stdio};
+
+// Largest payload sent over OSC 52; many terminals ignore anything bigger
+const OSC52_MAX_BYTES: usize = 100_000;
+
+// One way of getting text onto the clipboard
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    Arboard,
+    WlCopy,
+    Xclip,
+    Xsel,
+    Pbcopy,
+    ClipExe,
+    Osc52,
+}
+
+impl Method {
+    // Name shown in the status bar and in error messages
+    pub fn name(self) -> &'static str {
+        match self {
+            Method::Arboard => "system clipboard",
+            Method::WlCopy => "wl-copy",
+            Method::Xclip => "xclip",
+            Method::Xsel => "xsel",
+            Method::Pbcopy => "pbcopy",
+            Method::ClipExe => "clip.exe",
+            Method::Osc52 => "OSC 52 (terminal)",
+        }
+    }
+
+    // The external program (and its arguments) behind this method, if any
+    fn command(self) -> Option<(&'static str, &'static [&'static str])> {
+        match self {
+            Method::WlCopy => Some(("wl-copy", &[])),
+            Method::Xclip => Some(("xclip", &["-selection", "clipboard"])),
+            Method::Xsel => Some(("xsel", &["--clipboard", "--input"])),
+            Method::Pbcopy => Some(("pbcopy", &[])),
+            Method::ClipExe => Some(("clip.exe", &[])),
+            Method::Arboard | Method::Osc52 => None,
+        }
+    }
+}
+
+// Why a single method didn't work
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CopyError {
+    Missing,        // The program isn't installed; not worth more than a mention
+    Failed(String),
+}
+
+// The session facts that decide which methods are worth trying
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Environment {
+    pub prefer_osc52: bool, // CALI_CLIPBOARD=osc52
+    pub wayland: bool,
+    pub macos: bool,
+    pub wsl: bool,
+}
+
+impl Environment {
+    pub fn detect() -> Self {
+        Self {
+            prefer_osc52: std::env::var("CALI_CLIPBOARD").is_ok_and(|method| method.eq_ignore_ascii_case("osc52")),
+            wayland: std::env::var_os("WAYLAND_DISPLAY").is_some(),
+            macos: cfg!(target_os = "macos"),
+            wsl: std::env::var_os("WSL_DISTRO_NAME").is_some(),
+        }
+    }
+}
+
+// The methods to try, in order. OSC 52 always comes last because the terminal never
+// reports whether it honoured the request.
+pub fn strategies(env: &Environment) -> Vec<Method> {
+    if env.prefer_osc52 {
+        return vec![Method::Osc52];
+    }
+
+    let mut methods = vec![Method::Arboard];
+    if env.wayland {
+        methods.push(Method::WlCopy);
+    }
+    methods.push(Method::Xclip);
+    methods.push(Method::Xsel);
+    if env.macos {
+        methods.push(Method::Pbcopy);
+    }
+    if env.wsl {
+        methods.push(Method::ClipExe);
+    }
+    methods.push(Method::Osc52);
+    methods
+}
+
+// Everything that touches the outside world, so the selection logic can be tested
+pub trait Backend {
+    fn run_command(&mut self, program: &str, args: &[&str], text: &str) -> Result<(), CopyError>;
+    fn set_arboard(&mut self, text: &str) -> Result<(), CopyError>;
+    fn write_terminal(&mut self, sequence: &str) -> Result<(), CopyError>;
+}
+
+// The real clipboard
+pub struct System;
+
+impl Backend for System {
+    fn run_command(&mut self, program: &str, args: &[&str], text: &str) -> Result<(), CopyError> {
+        // Keep the program's output off the TUI
+        let mut child = match Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+        {
+            Ok(child) => child,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Err(CopyError::Missing),
+            Err(e) => return Err(CopyError::Failed(e.to_string())),
+        };
+
+        // Dropping stdin closes it so the program sees the end of the text
+        if let Some(mut stdin) = child.stdin.take()
+            && let Err(e) = stdin.write_all(text.as_bytes())
+        {
+            let _ = child.wait();
+            return Err(CopyError::Failed(e.to_string()));
+        }
+
+        match child.wait() {
+            Ok(status) if status.success() => Ok(()),
+            Ok(status) => Err(CopyError::Failed(format!("exited with {}", status))),
+            Err(e) => Err(CopyError::Failed(e.to_string())),
+        }
+    }
+
+    fn set_arboard(&mut self, text: &str) -> Result<(), CopyError> {
+        let mut clipboard = arboard::Clipboard::new().map_err(|e| CopyError::Failed(e.to_string()))?;
+        clipboard.set_text(text.to_string()).map_err(|e| CopyError::Failed(e.to_string()))
+    }
+
+    fn write_terminal(&mut self, sequence: &str) -> Result<(), CopyError> {
+        let mut stdout = io::stdout();
+        stdout.write_all(sequence.as_bytes())
+            .and_then(|_| stdout.flush())
+            .map_err(|e| CopyError::Failed(e.to_string()))
+    }
+}
+
+// Copies text with the first method that works and sticks with it for the rest of the
+// session
+pub struct Clipboard<B: Backend = System> {
+    backend: B,
+    env: Environment,
+    in_tmux: bool,
+    working: Option<Method>,
+}
+
+impl Clipboard {
+    pub fn new() -> Self {
+        Self::with_backend(System, Environment::detect(), std::env::var_os("TMUX").is_some())
+    }
+}
+
+impl<B: Backend> Clipboard<B> {
+    pub fn with_backend(backend: B, env: Environment, in_tmux: bool) -> Self {
+        Self { backend, env, in_tmux, working: None }
+    }
+
+    // Put text on the clipboard and return the method that worked
+    pub fn copy(&mut self, text: &str) -> Result<Method, String> {
+        // Try the remembered method first; if it stopped working, fall back to the rest of
+        // the chain
+        let remembered = self.working.take();
+        let mut tried = Vec::new();
+        for method in remembered.into_iter().chain(strategies(&self.env).into_iter().filter(|&method| Some(method) != remembered)) {
+            match self.try_method(method, text) {
+                Ok(()) => {
+                    self.working = Some(method);
+                    return Ok(method);
+                }
+                Err(CopyError::Missing) => tried.push(format!("{} (not installed)", method.name())),
+                Err(CopyError::Failed(e)) => tried.push(format!("{} ({})", method.name(), e)),
+            }
+        }
+        Err(format!("Could not copy to clipboard; tried {}", tried.join(", ")))
+    }
+
+    fn try_method(&mut self, method: Method, text: &str) -> Result<(), CopyError> {
+        match method {
+            Method::Arboard => self.backend.set_arboard(text),
+            Method::Osc52 => {
+                let sequence = osc52_sequence(text, self.in_tmux).map_err(CopyError::Failed)?;
+                self.backend.write_terminal(&sequence)
+            }
+            _ => {
+                let (program, args) = method.command().expect("command-based clipboard method");
+                self.backend.run_command(program, args, text)
+            }
+        }
+    }
+}
+
+// The OSC 52 escape sequence that puts `text` on the clipboard. Inside tmux it has to be
+// wrapped in a passthrough sequence to reach the outer terminal.
+pub fn osc52_sequence(text: &str, in_tmux: bool) -> Result<String, String> {
+    use base64::Engine;
+
+    let encoded = base64::engine::general_purpose::STANDARD.encode(text);
+    if encoded.len() > OSC52_MAX_BYTES {
+        return Err(format!("too large to copy through the terminal ({} bytes encoded, at most {})", encoded.len(), OSC52_MAX_BYTES));
+    }
+
+    let sequence = format!("\x1b]52;c;{}\x07", encoded);
+    if in_tmux {
+        Ok(format!("\x1bPtmux;\x1b{}\x1b\\", sequence))
+    } else {
+        Ok(sequence)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Pretends to be the outside world: programs listed in `installed` succeed, other
+    // programs are missing, and arboard/the terminal succeed only when told to
+    #[derive(Default)]
+    struct Fake {
+        installed: Vec<&'static str>,
+        arboard_works: bool,
+        terminal_works: bool,
+        calls: Vec<String>,
+        copied: Vec<String>,
+    }
+
+    impl Backend for Fake {
+        fn run_command(&mut self, program: &str, _args: &[&str], text: &str) -> Result<(), CopyError> {
+            self.calls.push(program.to_string());
+            if self.installed.contains(&program) {
+                self.copied.push(text.to_string());
+                Ok(())
+            } else {
+                Err(CopyError::Missing)
+            }
+        }
+
+        fn set_arboard(&mut self, text: &str) -> Result<(), CopyError> {
+            self.calls.push("arboard".to_string());
+            if self.arboard_works {
+                self.copied.push(text.to_string());
+                Ok(())
+            } else {
+                Err(CopyError::Failed("no display".to_string()))
+            }
+        }
+
+        fn write_terminal(&mut self, sequence: &str) -> Result<(), CopyError> {
+            self.calls.push("osc52".to_string());
+            if self.terminal_works {
+                self.copied.push(sequence.to_string());
+                Ok(())
+            } else {
+                Err(CopyError::Failed("not a terminal".to_string()))
+            }
+        }
+    }
+
+    fn wayland() -> Environment {
+        Environment { wayland: true, ..Environment::default() }
+    }
+
+    #[test]
+    fn test_strategy_order_depends_on_environment() {
+        assert_eq!(strategies(&Environment::default()),
+            vec![Method::Arboard, Method::Xclip, Method::Xsel, Method::Osc52]);
+        assert_eq!(strategies(&Environment { wayland: true, macos: true, wsl: true, prefer_osc52: false }),
+            vec![Method::Arboard, Method::WlCopy, Method::Xclip, Method::Xsel, Method::Pbcopy, Method::ClipExe, Method::Osc52]);
+        assert_eq!(strategies(&Environment { prefer_osc52: true, wayland: true, ..Environment::default() }),
+            vec![Method::Osc52]);
+    }
+
+    #[test]
+    fn test_first_working_method_is_used_and_remembered() {
+        let fake = Fake { installed: vec!["wl-copy"], ..Fake::default() };
+        let mut clipboard = Clipboard::with_backend(fake, wayland(), false);
+
+        assert_eq!(clipboard.copy("42"), Ok(Method::WlCopy));
+        assert_eq!(clipboard.backend.calls, vec!["arboard", "wl-copy"]);
+
+        // The next copy goes straight to the method that worked
+        clipboard.backend.calls.clear();
+        assert_eq!(clipboard.copy("43"), Ok(Method::WlCopy));
+        assert_eq!(clipboard.backend.calls, vec!["wl-copy"]);
+        assert_eq!(clipboard.backend.copied, vec!["42", "43"]);
+    }
+
+    #[test]
+    fn test_remembered_method_that_stops_working_falls_back() {
+        let fake = Fake { installed: vec!["xclip"], terminal_works: true, ..Fake::default() };
+        let mut clipboard = Clipboard::with_backend(fake, Environment::default(), false);
+        assert_eq!(clipboard.copy("1"), Ok(Method::Xclip));
+
+        // xclip disappears, e.g. the X server went away
+        clipboard.backend.installed.clear();
+        clipboard.backend.calls.clear();
+        assert_eq!(clipboard.copy("2"), Ok(Method::Osc52));
+        // xclip isn't tried a second time
+        assert_eq!(clipboard.backend.calls, vec!["xclip", "arboard", "xsel", "osc52"]);
+        assert_eq!(clipboard.working, Some(Method::Osc52));
+    }
+
+    #[test]
+    fn test_failure_lists_everything_tried() {
+        let mut clipboard = Clipboard::with_backend(Fake::default(), Environment::default(), false);
+        assert_eq!(clipboard.copy("42"), Err(
+            "Could not copy to clipboard; tried system clipboard (no display), xclip (not installed), \
+             xsel (not installed), OSC 52 (terminal) (not a terminal)".to_string()
+        ));
+        assert_eq!(clipboard.working, None);
+    }
+
+    #[test]
+    fn test_osc52_clipboard_sequence() {
+        assert_eq!(osc52_sequence("$1,234.57", false).unwrap(), "\x1b]52;c;JDEsMjM0LjU3\x07");
+        assert_eq!(osc52_sequence("42", true).unwrap(), "\x1bPtmux;\x1b\x1b]52;c;NDI=\x07\x1b\\");
+        // The limit is on the encoded text, which is a third longer
+        assert_eq!(
+            osc52_sequence(&"x".repeat(90_000), false),
+            Err("too large to copy through the terminal (120000 bytes encoded, at most 100000)".to_string())
+        );
+    }
+}
@@ -1,6 +1,8 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::time::{Duration, Instant};
+use chrono::{DateTime, Local};
 use once_cell::sync::Lazy;
 use reqwest::blocking::Client;
 use serde_json::Value;
@@ -9,97 +11,184 @@ use serde_json::Value;
 #[derive(Debug, Clone)]
 struct RateCache {
     rates: HashMap<String, HashMap<String, f64>>,
-    timestamp: Instant,
+    timestamp: Option<Instant>, // When rates were last fetched or tried, None before the first try
+    fetched_at: Option<DateTime<Local>>, // When the rates last came from the API, if ever
+    overrides: HashSet<(String, String)>, // Pairs set with setrate, both ways round
 }
 
 impl RateCache {
     fn new() -> Self {
         Self {
             rates: HashMap::new(),
-            timestamp: Instant::now(),
+            timestamp: None,
+            fetched_at: None,
+            overrides: HashSet::new(),
         }
     }
     
     fn is_expired(&self, ttl: Duration) -> bool {
-        self.timestamp.elapsed() > ttl
+        self.timestamp.is_none_or(|at| at.elapsed() > ttl)
+    }
+    
+    // Whether the fallback or fetched rates have a currency, rather than only setrate
+    fn lists(&self, code: &str) -> bool {
+        self.rates.get("USD").is_some_and(|usd| usd.contains_key(code))
+            && !self.overrides.contains(&("USD".to_string(), code.to_string()))
     }
 }
 
-// Global rate cache with mutex for thread safety
+// Global rate cache with mutex for thread safety. It starts out with the fallback rates; the
+// first lookup between two real currencies fetches the latest ones, so `setrate` and a sheet
+// without money never wait on the network.
 static RATE_CACHE: Lazy<Arc<Mutex<RateCache>>> = Lazy::new(|| {
-    // Initialize with fallback rates
     let mut cache = RateCache::new();
     initialize_fallback_rates(&mut cache.rates);
-    
-    // Try to update with latest rates from API - no UI messages
-    if let Ok(()) = fetch_latest_rates(&mut cache.rates) {
-        // Reset timestamp if successful
-        cache.timestamp = Instant::now();
-    }
-    
     Arc::new(Mutex::new(cache))
 });
 
 // Default TTL for cache entries (1 hour)
 const CACHE_TTL: Duration = Duration::from_secs(60 * 60);
 
-// Fetch latest rates from a free API
-fn fetch_latest_rates(rates: &mut HashMap<String, HashMap<String, f64>>) -> Result<(), Box<dyn std::error::Error>> {
+// Set while the terminal is out of focus: stale rates are used as they are until it's back
+static REFRESH_DEFERRED: AtomicBool = AtomicBool::new(false);
+
+pub fn defer_refresh(deferred: bool) {
+    REFRESH_DEFERRED.store(deferred, Ordering::Relaxed);
+}
+
+// Bumped whenever the cached rates change, by a fetch or by setrate
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+// Which rates are in the cache. Once they're due to be fetched again this changes on every
+// call, so results worked out with stale rates aren't reused until the fetch has happened.
+pub fn rates_generation() -> u64 {
+    let stale = Lazy::get(&RATE_CACHE).is_some_and(|cache| {
+        let cache = cache.lock().unwrap();
+        cache.timestamp.is_some() && cache.is_expired(CACHE_TTL) && !REFRESH_DEFERRED.load(Ordering::Relaxed)
+    });
+    if stale {
+        GENERATION.fetch_add(1, Ordering::Relaxed) + 1
+    } else {
+        GENERATION.load(Ordering::Relaxed)
+    }
+}
+
+// How the last attempt to fetch rates went
+#[derive(Debug, Clone, PartialEq)]
+pub enum FetchStatus {
+    NotTried,
+    Fetched,
+    Failed(String), // "Exchange rate update failed: <reason>; using <rates>"
+}
+
+static FETCH_STATUS: Mutex<FetchStatus> = Mutex::new(FetchStatus::NotTried);
+
+pub fn last_fetch_status() -> FetchStatus {
+    FETCH_STATUS.lock().unwrap().clone()
+}
+
+// Fetch the latest rates into the cache, and record how it went. A failed fetch isn't tried
+// again until the rates would have expired.
+fn refresh(cache: &mut RateCache) {
+    cache.timestamp = Some(Instant::now());
+    let status = match fetch_latest_rates() {
+        Ok(usd_rates) => {
+            store_rates(&mut cache.rates, &usd_rates);
+            cache.fetched_at = Some(Local::now());
+            GENERATION.fetch_add(1, Ordering::Relaxed);
+            log::info!("Fetched exchange rates for {} currencies", usd_rates.len());
+            FetchStatus::Fetched
+        }
+        Err(reason) => {
+            let using = match cache.fetched_at {
+                Some(at) => format!("rates from {}", at.format("%Y-%m-%d %H:%M")),
+                None => "built-in rates".to_string(),
+            };
+            let message = format!("Exchange rate update failed: {reason}; using {using}");
+            log::warn!("{message}");
+            FetchStatus::Failed(message)
+        }
+    };
+    *FETCH_STATUS.lock().unwrap() = status;
+}
+
+// Fetch latest rates from a free API, as how many of each currency one USD buys
+fn fetch_latest_rates() -> Result<HashMap<String, f64>, String> {
     let client = Client::new();
     
     // Use the ExchangeRate-API free endpoint
     let response = client.get("https://open.er-api.com/v6/latest/USD")
         .timeout(Duration::from_secs(5))
-        .send()?;
+        .send()
+        .map_err(|e| match e {
+            e if e.is_timeout() => "the rate service didn't answer in time".to_string(),
+            e if e.is_connect() => "can't reach the rate service".to_string(),
+            e => e.to_string(),
+        })?;
+    if !response.status().is_success() {
+        return Err(format!("the rate service answered {}", response.status()));
+    }
     
-    let json: Value = response.json()?;
+    // A captive portal answers with a web page
+    let json: Value = response.json().map_err(|_| "the answer isn't JSON".to_string())?;
+    parse_rates(&json)
+}
+
+// The USD rates in an API response, checked. Zero and negative rates would spoil every
+// conversion through them, so those currencies are left out rather than cached.
+fn parse_rates(json: &Value) -> Result<HashMap<String, f64>, String> {
+    match json.get("result") {
+        Some(Value::String(result)) if result == "success" => {}
+        Some(Value::String(result)) => {
+            return Err(match json.get("error-type").and_then(Value::as_str) {
+                Some(error) => format!("the rate service answered '{result}' ({error})"),
+                None => format!("the rate service answered '{result}'"),
+            });
+        }
+        Some(result) => return Err(format!("unexpected result {result} in the answer")),
+        None => return Err("the answer has no 'result'".to_string()),
+    }
     
-    // Check if the API call was successful
-    if json["result"] != "success" {
-        return Err("API call failed".into());
+    let rates = json.get("rates").ok_or("the answer has no 'rates'")?;
+    let rates = rates.as_object().ok_or("'rates' in the answer isn't a list of currencies")?;
+    let mut usd_rates = HashMap::new();
+    usd_rates.insert("USD".to_string(), 1.0); // USD to USD is always 1.0
+    for (currency, rate) in rates {
+        let rate = rate.as_f64().ok_or_else(|| format!("the rate for {currency} isn't a number"))?;
+        if rate > 0.0 && rate.is_finite() {
+            usd_rates.insert(currency.clone(), rate);
+        }
     }
+    if usd_rates.len() == 1 {
+        return Err("the answer has no usable rates".to_string());
+    }
+    Ok(usd_rates)
+}
+
+// Store USD rates, and the rates between every other pair worked out through USD
+fn store_rates(rates: &mut HashMap<String, HashMap<String, f64>>, usd_rates: &HashMap<String, f64>) {
+    rates.insert("USD".to_string(), usd_rates.clone());
     
-    // Extract rates from the response
-    if let Some(rates_obj) = json["rates"].as_object() {
-        // First build USD rates
-        let mut usd_rates = HashMap::new();
-        usd_rates.insert("USD".to_string(), 1.0); // USD to USD is always 1.0
-        
-        for (currency, rate_value) in rates_obj {
-            if let Some(rate) = rate_value.as_f64() {
-                usd_rates.insert(currency.clone(), rate);
-            }
+    for (currency, usd_rate) in usd_rates {
+        if currency == "USD" {
+            continue; // Already handled
         }
         
-        // Store USD rates
-        rates.insert("USD".to_string(), usd_rates.clone());
+        let mut currency_rates = HashMap::new();
+        currency_rates.insert(currency.clone(), 1.0); // Self rate is always 1.0
         
-        // Now build rates for each other currency
-        for (currency, usd_rate) in &usd_rates {
-            if currency == "USD" {
-                continue; // Already handled
-            }
-            
-            let mut currency_rates = HashMap::new();
-            currency_rates.insert(currency.clone(), 1.0); // Self rate is always 1.0
-            
-            for (target_currency, target_usd_rate) in &usd_rates {
-                if target_currency == currency {
-                    continue; // Skip self rate
-                }
-                
-                // Convert through USD: currency → USD → target_currency
-                let rate = target_usd_rate / usd_rate;
-                currency_rates.insert(target_currency.clone(), rate);
+        for (target_currency, target_usd_rate) in usd_rates {
+            if target_currency == currency {
+                continue; // Skip self rate
             }
             
-            rates.insert(currency.clone(), currency_rates);
+            // Convert through USD: currency → USD → target_currency
+            let rate = target_usd_rate / usd_rate;
+            currency_rates.insert(target_currency.clone(), rate);
         }
         
-        return Ok(());
+        rates.insert(currency.clone(), currency_rates);
     }
-    
-    Err("Could not parse rates from API response".into())
 }
 
 // Fallback rates for when API is unavailable
@@ -156,20 +245,20 @@ fn initialize_fallback_rates(rates: &mut HashMap<String, HashMap<String, f64>>)
 // Function to calculate a rate for any currency pair
 fn calculate_exchange_rate(from: &str, to: &str, rates: &HashMap<String, HashMap<String, f64>>) -> Option<f64> {
     // Direct conversion
-    if let Some(from_rates) = rates.get(from) {
-        if let Some(rate) = from_rates.get(to) {
-            return Some(*rate);
-        }
+    if let Some(from_rates) = rates.get(from)
+        && let Some(rate) = from_rates.get(to)
+    {
+        return Some(*rate);
     }
     
     // Try to calculate via USD as base
-    if from != "USD" && to != "USD" {
-        if let (Some(from_usd), Some(usd_to)) = (
+    if from != "USD" && to != "USD"
+        && let (Some(from_usd), Some(usd_to)) = (
             rates.get("USD").and_then(|r| r.get(from)).map(|r| 1.0 / r),
             rates.get("USD").and_then(|r| r.get(to))
-        ) {
-            return Some(from_usd * usd_to);
-        }
+        )
+    {
+        return Some(from_usd * usd_to);
     }
     
     None
@@ -182,14 +271,23 @@ pub fn get_exchange_rate(from: &str, to: &str) -> Option<f64> {
         return Some(1.0);
     }
     
+    // A code no rate service knows, like the FOO of `5 FOO`, only has a rate if setrate gave
+    // it one, and that set the cache up already; finding out never fetches anything
+    let (from_known, to_known) = (is_known_currency(from), is_known_currency(to));
+    if !from_known && !to_known {
+        let cache = Lazy::get(&RATE_CACHE)?.lock().unwrap();
+        return calculate_exchange_rate(from, to, &cache.rates);
+    }
+    
     let mut cache = RATE_CACHE.lock().unwrap();
     
-    // Check if we need to refresh the rates
-    if cache.is_expired(CACHE_TTL) {
+    // Check if we need to refresh the rates. A code the rate service has but the table
+    // doesn't, like GGP, is only known once they've been fetched, so next to a real currency
+    // it's worth fetching them the first time.
+    let worth_fetching = (from_known && to_known) || cache.timestamp.is_none();
+    if worth_fetching && cache.is_expired(CACHE_TTL) && !REFRESH_DEFERRED.load(Ordering::Relaxed) {
         // Try to update the rates from the API
-        if let Ok(()) = fetch_latest_rates(&mut cache.rates) {
-            cache.timestamp = Instant::now();
-        }
+        refresh(&mut cache);
     }
     
     calculate_exchange_rate(from, to, &cache.rates)
@@ -223,6 +321,124 @@ pub fn set_exchange_rate(from: &str, to: &str, rate: f64) -> bool {
     if let Some(to_rates) = cache.rates.get_mut(to) {
         to_rates.insert(from.to_string(), 1.0 / rate);
     }
+    cache.overrides.insert((from.to_string(), to.to_string()));
+    cache.overrides.insert((to.to_string(), from.to_string()));
+    GENERATION.fetch_add(1, Ordering::Relaxed);
     
     true
-} 
\ No newline at end of file
+}
+
+// Where the rate between two currencies comes from, for explaining a conversion
+pub fn rate_source(from: &str, to: &str) -> String {
+    let cache = RATE_CACHE.lock().unwrap();
+    if cache.overrides.contains(&(from.to_string(), to.to_string())) {
+        return "set with setrate".to_string();
+    }
+    match cache.fetched_at {
+        Some(at) => format!("rates from {}", at.format("%Y-%m-%d %H:%M")),
+        None => "built-in rates, the rate service couldn't be reached".to_string(),
+    }
+}
+
+// The ISO 4217 codes of currencies in circulation, the ones a rate service can know about
+const ISO_4217: &[&str] = &[
+    "AED", "AFN", "ALL", "AMD", "ANG", "AOA", "ARS", "AUD", "AWG", "AZN", "BAM", "BBD", "BDT", "BGN", "BHD", "BIF",
+    "BMD", "BND", "BOB", "BRL", "BSD", "BTN", "BWP", "BYN", "BZD", "CAD", "CDF", "CHF", "CLP", "CNY", "COP", "CRC",
+    "CUP", "CVE", "CZK", "DJF", "DKK", "DOP", "DZD", "EGP", "ERN", "ETB", "EUR", "FJD", "FKP", "GBP", "GEL", "GHS",
+    "GIP", "GMD", "GNF", "GTQ", "GYD", "HKD", "HNL", "HTG", "HUF", "IDR", "ILS", "INR", "IQD", "IRR", "ISK", "JMD",
+    "JOD", "JPY", "KES", "KGS", "KHR", "KMF", "KPW", "KRW", "KWD", "KYD", "KZT", "LAK", "LBP", "LKR", "LRD", "LSL",
+    "LYD", "MAD", "MDL", "MGA", "MKD", "MMK", "MNT", "MOP", "MRU", "MUR", "MVR", "MWK", "MXN", "MYR", "MZN", "NAD",
+    "NGN", "NIO", "NOK", "NPR", "NZD", "OMR", "PAB", "PEN", "PGK", "PHP", "PKR", "PLN", "PYG", "QAR", "RON", "RSD",
+    "RUB", "RWF", "SAR", "SBD", "SCR", "SDG", "SEK", "SGD", "SHP", "SLE", "SLL", "SOS", "SRD", "SSP", "STN", "SYP",
+    "SZL", "THB", "TJS", "TMT", "TND", "TOP", "TRY", "TTD", "TWD", "TZS", "UAH", "UGX", "USD", "UYU", "UZS", "VES",
+    "VND", "VUV", "WST", "XAF", "XCD", "XOF", "XPF", "YER", "ZAR", "ZMW", "ZWL",
+];
+
+// Whether a three-letter code is a real currency rather than one only setrate knows: one in
+// the table, or one the fallback or fetched rates have, as the rate service lists a few more
+pub fn is_known_currency(code: &str) -> bool {
+    ISO_4217.binary_search(&code).is_ok()
+        || Lazy::get(&RATE_CACHE).is_some_and(|cache| cache.lock().unwrap().lists(code))
+}
+
+// The code of a real currency in any case, as the table has it: eur -> EUR
+pub fn currency_code(code: &str) -> Option<&'static str> {
+    let upper = code.bytes().map(|b| b.to_ascii_uppercase());
+    ISO_4217.binary_search_by(|known| known.bytes().cmp(upper.clone())).ok().map(|i| ISO_4217[i])
+}
+
+// Digits after the decimal point in the smallest coin of a currency (ISO 4217): 2 for
+// cents, 0 for yen, 3 for fils
+pub fn minor_unit_digits(code: &str) -> u32 {
+    const NO_MINOR_UNIT: &[&str] = &[
+        "BIF", "CLP", "DJF", "GNF", "ISK", "JPY", "KMF", "KRW", "PYG", "RWF", "UGX", "VND", "VUV", "XAF", "XOF", "XPF",
+    ];
+    const THOUSANDTHS: &[&str] = &["BHD", "IQD", "JOD", "KWD", "LYD", "OMR", "TND"];
+    if NO_MINOR_UNIT.contains(&code) {
+        0
+    } else if THOUSANDTHS.contains(&code) {
+        3
+    } else {
+        2
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(json: &str) -> Result<HashMap<String, f64>, String> {
+        parse_rates(&serde_json::from_str(json).unwrap())
+    }
+
+    #[test]
+    fn test_parse_rates() {
+        let rates = parse(r#"{"result": "success", "rates": {"EUR": 0.92, "JPY": 151, "XXX": 0, "YYY": -1}}"#).unwrap();
+        assert_eq!(rates.get("EUR"), Some(&0.92));
+        assert_eq!(rates.get("JPY"), Some(&151.0));
+        assert_eq!(rates.get("USD"), Some(&1.0));
+        // Bogus rates are left out rather than cached
+        assert_eq!(rates.get("XXX"), None);
+        assert_eq!(rates.get("YYY"), None);
+
+        assert_eq!(parse(r#"{"rates": {"EUR": 0.92}}"#), Err("the answer has no 'result'".to_string()));
+        assert_eq!(parse(r#"{"result": true, "rates": {}}"#), Err("unexpected result true in the answer".to_string()));
+        assert_eq!(
+            parse(r#"{"result": "error", "error-type": "unsupported-code"}"#),
+            Err("the rate service answered 'error' (unsupported-code)".to_string())
+        );
+        assert_eq!(parse(r#"{"result": "error"}"#), Err("the rate service answered 'error'".to_string()));
+        assert_eq!(parse(r#"{"result": "success"}"#), Err("the answer has no 'rates'".to_string()));
+        assert_eq!(parse(r#"{"result": "success", "rates": [0.92]}"#), Err("'rates' in the answer isn't a list of currencies".to_string()));
+        assert_eq!(parse(r#"{"result": "success", "rates": {"EUR": "0.92"}}"#), Err("the rate for EUR isn't a number".to_string()));
+        assert_eq!(parse(r#"{"result": "success", "rates": {"EUR": 0}}"#), Err("the answer has no usable rates".to_string()));
+
+        let mut stored = HashMap::new();
+        store_rates(&mut stored, &rates);
+        assert!((stored["EUR"]["JPY"] - 151.0 / 0.92).abs() < 1e-9);
+        assert_eq!(stored["JPY"]["JPY"], 1.0);
+    }
+
+    #[test]
+    fn test_made_up_codes_need_no_rates() {
+        assert!(ISO_4217.windows(2).all(|pair| pair[0] < pair[1]), "ISO_4217 must stay sorted");
+        assert!(is_known_currency("EUR") && is_known_currency("JPY"));
+        assert!(!is_known_currency("FOO") && !is_known_currency("eur"));
+        assert_eq!(get_exchange_rate("FOO", "BAR"), None);
+        assert_eq!(get_exchange_rate("FOO", "FOO"), Some(1.0));
+    }
+
+    #[test]
+    fn test_codes_the_rate_service_lists_are_known() {
+        let mut cache = RateCache::new();
+        initialize_fallback_rates(&mut cache.rates);
+        assert!(cache.lists("EUR") && !cache.lists("GGP"));
+        store_rates(&mut cache.rates, &parse(r#"{"result": "success", "rates": {"EUR": 0.92, "GGP": 0.79}}"#).unwrap());
+        assert!(cache.lists("GGP"));
+        
+        // Not a code setrate gave a rate against USD
+        cache.rates.get_mut("USD").unwrap().insert("FOO".to_string(), 2.0);
+        cache.overrides.insert(("USD".to_string(), "FOO".to_string()));
+        assert!(!cache.lists("FOO"));
+    }
+}
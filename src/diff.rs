@@ -0,0 +1,137 @@
+// `cali --diff OLD NEW`: the results that changed between two versions of a sheet. Both
+// are evaluated as the notebook would, their lines are paired up, and only the pairs whose
+// values differ are reported, along with the lines one of them doesn't have.
+
+use std::io;
+use crate::app::App;
+use crate::evaluator::{self, Value};
+use crate::locale;
+use crate::parser::Op;
+
+// A line of an evaluated sheet that shows a result
+struct Line {
+    number: usize,
+    name: Option<String>,
+    text: String,
+    shown: String,
+    value: Option<Value>,
+}
+
+impl Line {
+    // How the report refers to the line: the variable it assigns, or else what it says
+    fn label(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.text)
+    }
+}
+
+// Evaluate both files and compare them. The report is empty when no result differs.
+pub fn run(old_path: &str, new_path: &str) -> io::Result<Vec<String>> {
+    let mut old = App::new();
+    crate::load_file_into_app(old_path, &mut old)?;
+    let mut new = App::new();
+    crate::load_file_into_app(new_path, &mut new)?;
+    Ok(compare(&mut old, &mut new))
+}
+
+// The report for two loaded sheets, one line per change
+pub fn compare(old: &mut App, new: &mut App) -> Vec<String> {
+    let old = result_lines(old);
+    let new = result_lines(new);
+    let pairs = pair(&old, &new);
+    let arrow = if locale::ascii() { "->" } else { "→" };
+
+    let mut report = Vec::new();
+    let mut removed = old.iter().enumerate().filter(|(i, _)| !pairs.contains(&Some(*i))).peekable();
+    for (line, partner) in new.iter().zip(&pairs) {
+        // Lines gone from the old sheet are reported where they used to be
+        if let Some(j) = partner {
+            while let Some((_, gone)) = removed.next_if(|(i, _)| i < j) {
+                report.push(format!("old line {} (removed): {}  {}", gone.number, gone.label(), gone.shown));
+            }
+        }
+        match partner.map(|j| &old[j]) {
+            None => report.push(format!("line {} (added): {}  {}", line.number, line.label(), line.shown)),
+            Some(before) if differs(before, line) => {
+                let mut entry = format!("line {}: {}  {} {} {}", line.number, line.label(), before.shown, arrow, line.shown);
+                if let Some(percent) = before.value.as_ref().zip(line.value.as_ref()).and_then(|(a, b)| percent_change(a, b)) {
+                    entry.push_str(&format!(" ({:+.1}%)", percent));
+                }
+                report.push(entry);
+            }
+            Some(_) => {}
+        }
+    }
+    for (_, gone) in removed {
+        report.push(format!("old line {} (removed): {}  {}", gone.number, gone.label(), gone.shown));
+    }
+    report
+}
+
+// Every line of a sheet that shows a result, once all of it is worked out
+fn result_lines(app: &mut App) -> Vec<Line> {
+    while app.catch_up(usize::MAX) {}
+    (0..app.lines.len())
+        .filter(|&i| app.debounced_results.get(i).is_some_and(|shown| !shown.is_empty()))
+        .map(|i| Line {
+            number: i + 1,
+            name: app.line_name(i).map(str::to_string),
+            text: app.lines[i].trim().to_string(),
+            shown: app.debounced_results[i].clone(),
+            value: app.line_value(i).cloned(),
+        })
+        .collect()
+}
+
+// For each line of the new sheet, the index of its old counterpart. An assignment pairs
+// with the old assignment of the same name wherever it is, so inserting a line doesn't
+// shift every comparison after it; other lines pair with the old line in the same place.
+fn pair(old: &[Line], new: &[Line]) -> Vec<Option<usize>> {
+    let mut taken = vec![false; old.len()];
+    let mut pairs: Vec<Option<usize>> = new.iter()
+        .map(|line| {
+            let name = line.name.as_deref()?;
+            let j = old.iter().enumerate().position(|(j, before)| !taken[j] && before.name.as_deref() == Some(name))?;
+            taken[j] = true;
+            Some(j)
+        })
+        .collect();
+    for (line, partner) in new.iter().zip(pairs.iter_mut()) {
+        if partner.is_some() {
+            continue;
+        }
+        let j = old.iter().position(|before| before.number == line.number);
+        if let Some(j) = j.filter(|&j| !taken[j] && (old[j].name.is_none() || line.name.is_none())) {
+            taken[j] = true;
+            *partner = Some(j);
+        }
+    }
+    pairs
+}
+
+// Whether two paired lines disagree. Values are compared rather than the text shown, so
+// a result only rounded the same way still counts as a change.
+fn differs(old: &Line, new: &Line) -> bool {
+    match (&old.value, &new.value) {
+        (Some(a), Some(b)) => a != b,
+        _ => old.shown != new.shown,
+    }
+}
+
+// The relative change from one value to the other, for plain numbers and for amounts in
+// the same or convertible units; anything else has no meaningful percentage
+fn percent_change(old: &Value, new: &Value) -> Option<f64> {
+    let (delta, before) = match (old, new) {
+        (Value::Number(a), Value::Number(b)) | (Value::Percentage(a), Value::Percentage(b)) => (b - a, *a),
+        (Value::Unit(..), Value::Unit(..)) => {
+            // new - old is in a unit both convert to; new - (new - old) is old in that unit
+            let delta = evaluator::apply_binary_op(new.clone(), &Op::Subtract, old.clone());
+            let before = evaluator::apply_binary_op(new.clone(), &Op::Subtract, delta.clone());
+            match (delta, before) {
+                (Value::Unit(delta, unit), Value::Unit(before, same)) if unit == same => (delta, before),
+                _ => return None,
+            }
+        }
+        _ => return None,
+    };
+    (before != 0.0).then(|| delta / before.abs() * 100.0)
+}
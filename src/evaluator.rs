@@ -1,9 +1,16 @@
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
-use chrono::{NaiveDate, Local, Datelike, Duration, Weekday};
-use crate::parser::{Expr, Op};
+use std::rc::Rc;
+use std::time::Instant;
+use chrono::{NaiveDate, Local, Datelike, Duration, Months};
+use once_cell::sync::Lazy;
+use crate::parser::{Aggregate, Day, Expr, MonthOf, Occurrence, Op, Rounding, Statistic};
+use crate::lexer::is_currency_code;
 
 // Value types that can be stored in variables
 #[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
 pub enum Value {
     Number(f64),
     Percentage(f64),
@@ -11,108 +18,686 @@ pub enum Value {
     Date(NaiveDate),
     Error(String),
     Assignment(String, Box<Value>),
+    Split(Box<Value>, Box<Value>), // Each share, and the remainder left when it isn't even
+    List(Vec<Value>),
+    Map(Vec<(String, Value)>), // {S: 36, M: 40}, in the order written
+    Rate(String, f64, String), // Set with setrate: 1 <from> = <rate> <to>
+}
+
+// Lists longer than this display their first items and a count
+const MAX_LIST_ITEMS_SHOWN: usize = 8;
+
+// An amount of money the way its currency is written: $12, €3.50, or 3,50 EUR in German.
+// Whole dollars leave the cents out unless `to_the_cent`.
+fn format_money(amount: f64, code: &str, to_the_cent: bool) -> String {
+    // Sub-cent amounts would round to 0.00; show them as under a cent instead, with
+    // the sign outside: -0.004 USD is -<$0.01
+    if amount != 0.0 && format!("{:.2}", amount.abs()) == "0.00" {
+        let sign = if amount < 0.0 { "-" } else { "" };
+        return format!("{sign}<{}", format_money(0.01, code, to_the_cent));
+    }
+    let digits = if code == "USD" && amount.fract() == 0.0 && !to_the_cent {
+        format!("{amount:.0}")
+    } else {
+        format!("{amount:.2}")
+    };
+    if crate::locale::current() == crate::locale::Locale::De {
+        // Amount first with the code after it, as in 3,50 EUR
+        return format!("{} {code}", crate::locale::localize(&digits));
+    }
+    match code {
+        "USD" => format!("${digits}"),
+        _ if crate::locale::ascii() => format!("{code} {digits}"),
+        "EUR" => format!("€{digits}"),
+        "GBP" => format!("£{digits}"),
+        // For other currencies, use the regular format but always with 2 decimal places
+        _ => format!("{digits} {code}"),
+    }
 }
 
 impl std::fmt::Display for Value {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Value::Number(n) => {
-                // Format integers without decimals, format decimals with up to 6 places
-                if n.fract() == 0.0 {
-                    write!(f, "{:.0}", n)
-                } else {
-                    // First try with 2 decimal places
-                    let s = format!("{:.2}", n);
-                    // If it rounds back to the original value, use that
-                    if let Ok(parsed) = s.parse::<f64>() {
-                        if (parsed - n).abs() < 1e-10 {
-                            return write!(f, "{}", s);
-                        }
-                    }
-                    // Otherwise use 6 decimal places
-                    write!(f, "{:.6}", n)
-                }
-            },
-            Value::Percentage(p) => write!(f, "{}%", p),
+            Value::Number(n) => write!(f, "{}", format_decimal(*n)),
+            Value::Percentage(p) => write!(f, "{}%", format_decimal(*p)),
             Value::Unit(v, u) => {
-                // Special handling for currencies (3-letter uppercase codes)
-                let is_currency = is_currency_code(u);
+                // A rate shows its amount per period, to the cent for money: $10.00/month
+                if let Some((amount, period)) = split_rate(u) {
+                    let shown = if is_currency_code(amount) {
+                        format_money(*v, amount, true)
+                    } else {
+                        Value::Unit(*v, amount.to_string()).to_string()
+                    };
+                    return write!(f, "{shown}/{period}");
+                }
                 
-                if is_currency {
-                    match u.as_str() {
-                        "USD" => {
-                            if v.fract() == 0.0 {
-                                write!(f, "${:.0}", v)
-                            } else {
-                                write!(f, "${:.2}", v)
-                            }
-                        },
-                        "EUR" => write!(f, "€{:.2}", v),
-                        "GBP" => write!(f, "£{:.2}", v),
-                        // For other currencies, use the regular format but always with 2 decimal places
-                        _ => write!(f, "{:.2} {}", v, u)
-                    }
-                } else if v.fract() == 0.0 {
-                    write!(f, "{:.0} {}", v, u)
+                // Special handling for currencies (3-letter uppercase codes)
+                if is_currency_code(u) {
+                    write!(f, "{}", format_money(*v, u, false))
                 } else {
-                    // First try with 2 decimal places
-                    let s = format!("{:.2}", v);
-                    // If it rounds back to the original value, use that
-                    if let Ok(parsed) = s.parse::<f64>() {
-                        if (parsed - v).abs() < 1e-10 {
-                            return write!(f, "{} {}", s, u);
-                        }
-                    }
-                    // Otherwise use 6 decimal places
-                    write!(f, "{:.6} {}", v, u)
+                    write!(f, "{} {}", format_decimal(*v), u)
                 }
             },
-            Value::Date(d) => write!(f, "{}", d),
+            Value::Date(d) => write!(f, "{}", d.format(crate::locale::date_style().format())),
             Value::Error(e) => write!(f, "Error: {}", e),
             Value::Assignment(_, value) => write!(f, "{}", value),
+            Value::Split(each, remainder) => {
+                let sign = if matches!(**remainder, Value::Unit(r, _) if r < 0.0) { "" } else { "+" };
+                write!(f, "{} each ({}{} remainder)", each, sign, remainder)
+            },
+            Value::List(items) => {
+                let shown: Vec<String> = items.iter().take(MAX_LIST_ITEMS_SHOWN).map(Value::to_string).collect();
+                if items.len() > MAX_LIST_ITEMS_SHOWN {
+                    write!(f, "[{}, …] ({} items)", shown.join(", "), items.len())
+                } else {
+                    write!(f, "[{}]", shown.join(", "))
+                }
+            },
+            Value::Map(entries) => {
+                let shown: Vec<String> = entries.iter().take(MAX_LIST_ITEMS_SHOWN).map(|(key, value)| format!("{key}: {value}")).collect();
+                if entries.len() > MAX_LIST_ITEMS_SHOWN {
+                    write!(f, "{{{}, …}} ({} entries)", shown.join(", "), entries.len())
+                } else {
+                    write!(f, "{{{}}}", shown.join(", "))
+                }
+            },
+            // The rate as it was typed, not rounded to 2 decimals like an amount
+            Value::Rate(from, rate, to) => write!(f, "1 {} = {} {} (rate set)", from, crate::locale::localize(&rate.to_string()), to),
+        }
+    }
+}
+
+// Format a plain number for display: integers without decimals, otherwise 2 decimal
+// places when that is exact, or up to 6 with trailing zeros trimmed. Float noise such as
+// 13.000000000000002 displays as 13, while values too small for 6 decimals switch to
+// scientific notation (3.33e-7) rather than showing as 0. The decimal separator follows
+// the locale.
+fn format_decimal(n: f64) -> String {
+    crate::locale::localize(&format_decimal_dot(n))
+}
+
+fn format_decimal_dot(n: f64) -> String {
+    if n.fract() == 0.0 {
+        return format!("{:.0}", n);
+    }
+    
+    // First try with 2 decimal places; if it rounds back to the original value, use that
+    let s = format!("{:.2}", n);
+    if let Ok(parsed) = s.parse::<f64>()
+        && (parsed - n).abs() < 1e-10
+    {
+        return match s.strip_suffix(".00") {
+            Some("-0") => "0".to_string(),
+            Some(integer) => integer.to_string(),
+            None => s,
+        };
+    }
+    
+    // Otherwise use up to 6 decimal places
+    let s = format!("{:.6}", n);
+    match s.trim_end_matches('0').trim_end_matches('.') {
+        // Anything this small is float noise, as in 0.1 + 0.2 - 0.3
+        "0" | "-0" if n.abs() < 1e-12 => "0".to_string(),
+        "0" | "-0" => format_scientific(n),
+        trimmed => trimmed.to_string(),
+    }
+}
+
+// 3 significant digits with trailing zeros trimmed: 3.33e-7, 1e-9
+fn format_scientific(n: f64) -> String {
+    let s = format!("{:.2e}", n);
+    let (mantissa, exponent) = s.split_once('e').expect("exponent in scientific format");
+    format!("{}e{}", mantissa.trim_end_matches('0').trim_end_matches('.'), exponent)
+}
+
+impl Value {
+    // A machine-friendly form for pasting elsewhere: the plain number at full precision,
+    // without currency symbols, units or % signs, and dates as ISO 8601. None for errors.
+    pub fn to_raw_string(&self) -> Option<String> {
+        match self {
+            Value::Number(n) | Value::Percentage(n) | Value::Unit(n, _) | Value::Rate(_, n, _) => Some(n.to_string()),
+            Value::Date(d) => Some(d.format("%Y-%m-%d").to_string()),
+            Value::Assignment(_, value) | Value::Split(value, _) => value.to_raw_string(),
+            Value::List(items) => {
+                let raw: Option<Vec<String>> = items.iter().map(Value::to_raw_string).collect();
+                raw.map(|raw| raw.join(", "))
+            },
+            Value::Map(entries) => {
+                let raw: Option<Vec<String>> = entries.iter().map(|(key, value)| Some(format!("{key}: {}", value.to_raw_string()?))).collect();
+                raw.map(|raw| raw.join(", "))
+            },
+            Value::Error(_) => None,
+        }
+    }
+
+    fn is_date(&self) -> bool {
+        matches!(self, Value::Date(_))
+    }
+
+    // What kind of value this is, for error messages: number, USD, date
+    fn type_name(&self) -> String {
+        match self {
+            Value::Number(_) => "number".to_string(),
+            Value::Percentage(_) => "percentage".to_string(),
+            Value::Unit(_, u) => u.clone(),
+            Value::Date(_) => "date".to_string(),
+            Value::Error(_) => "error".to_string(),
+            Value::Assignment(_, _) => "assignment".to_string(),
+            Value::Split(_, _) => "split".to_string(),
+            Value::List(_) => "list".to_string(),
+            Value::Map(_) => "map".to_string(),
+            Value::Rate(_, _, _) => "rate".to_string(),
+        }
+    }
+
+    // The value to compute with: a split is worth one share
+    fn settled(self) -> Value {
+        match self {
+            Value::Split(each, _) => *each,
+            other => other,
+        }
+    }
+}
+
+// How long one line may take to evaluate before it's given up on, so a pathological line
+// can't freeze the app. Time spent waiting for exchange rates doesn't count.
+const EVALUATION_BUDGET: std::time::Duration = std::time::Duration::from_millis(50);
+
+// Where evaluation gets today's date and exchange rates from. The app uses the clock and the
+// live rates; tests pin both so their results don't depend on the day or the network.
+#[derive(Clone)]
+pub struct Context {
+    pub today: Rc<dyn Fn() -> NaiveDate>,
+    pub rates: Rc<dyn RateProvider>,
+    pub budget: std::time::Duration,
+    pub round_currency: bool, // Round money to its smallest coin after every operation
+    pub base_currency: Option<String>, // What mixed currencies add up in; otherwise the left one's
+}
+
+impl Default for Context {
+    fn default() -> Self {
+        Context {
+            today: Rc::new(|| Local::now().date_naive()),
+            rates: Rc::new(LiveRates),
+            budget: EVALUATION_BUDGET,
+            round_currency: false,
+            base_currency: None,
         }
     }
 }
 
+impl Context {
+    // A context that always sees the same day and the same rates
+    pub fn fixed(today: NaiveDate, rates: FixedRates) -> Self {
+        Context { today: Rc::new(move || today), rates: Rc::new(rates), budget: EVALUATION_BUDGET, round_currency: false, base_currency: None }
+    }
+}
+
+// A source of exchange rates
+pub trait RateProvider {
+    // How many `to` one `from` buys
+    fn rate(&self, from: &str, to: &str) -> Option<f64>;
+    // Override a rate (setrate USD to EUR = 0.92); false if the rate can't be used
+    fn set_rate(&self, from: &str, to: &str, rate: f64) -> bool;
+    // Where a rate comes from, to explain a conversion: "rates from 2024-05-17 14:05"
+    fn source(&self, _from: &str, _to: &str) -> Option<String> {
+        None
+    }
+    // Why rates couldn't be brought up to date, if the last attempt failed
+    fn fetch_failure(&self) -> Option<String> {
+        None
+    }
+    // Changes whenever a rate may have, so results worked out with the old rates can be told
+    // from new ones
+    fn generation(&self) -> u64 {
+        0
+    }
+}
+
+// Rates from the currency API, cached, with built-in fallbacks when it can't be reached
+pub struct LiveRates;
+
+impl RateProvider for LiveRates {
+    fn rate(&self, from: &str, to: &str) -> Option<f64> {
+        crate::currency::get_exchange_rate(from, to)
+    }
+
+    fn set_rate(&self, from: &str, to: &str, rate: f64) -> bool {
+        crate::currency::set_exchange_rate(from, to, rate)
+    }
+
+    fn source(&self, from: &str, to: &str) -> Option<String> {
+        Some(crate::currency::rate_source(from, to))
+    }
+
+    fn fetch_failure(&self) -> Option<String> {
+        match crate::currency::last_fetch_status() {
+            crate::currency::FetchStatus::Failed(message) => Some(message),
+            _ => None,
+        }
+    }
+
+    fn generation(&self) -> u64 {
+        crate::currency::rates_generation()
+    }
+}
+
+// Live rates, with setrate overrides kept to itself instead of written to the shared cache,
+// so one Calculator's setrate doesn't change another's results
+#[derive(Default)]
+pub struct OwnRates(FixedRates);
+
+impl RateProvider for OwnRates {
+    fn rate(&self, from: &str, to: &str) -> Option<f64> {
+        self.0.direct(from, to).or_else(|| LiveRates.rate(from, to))
+    }
+
+    fn set_rate(&self, from: &str, to: &str, rate: f64) -> bool {
+        self.0.set_rate(from, to, rate)
+    }
+
+    fn source(&self, from: &str, to: &str) -> Option<String> {
+        match self.0.direct(from, to) {
+            Some(_) => Some("set with setrate".to_string()),
+            None => LiveRates.source(from, to),
+        }
+    }
+
+    fn fetch_failure(&self) -> Option<String> {
+        LiveRates.fetch_failure()
+    }
+
+    fn generation(&self) -> u64 {
+        LiveRates.generation() + self.0.generation()
+    }
+}
+
+// Rates given up front and never fetched. A pair that isn't listed is worked out from its
+// inverse, or through USD.
+#[derive(Default)]
+pub struct FixedRates(RefCell<HashMap<(String, String), f64>>, Cell<u64>);
+
+impl FixedRates {
+    pub fn with(self, from: &str, to: &str, rate: f64) -> Self {
+        self.set_rate(from, to, rate);
+        self
+    }
+
+    fn direct(&self, from: &str, to: &str) -> Option<f64> {
+        let rates = self.0.borrow();
+        rates.get(&(from.to_string(), to.to_string())).copied()
+            .or_else(|| rates.get(&(to.to_string(), from.to_string())).map(|rate| 1.0 / rate))
+    }
+}
+
+impl RateProvider for FixedRates {
+    fn rate(&self, from: &str, to: &str) -> Option<f64> {
+        if from == to {
+            return Some(1.0);
+        }
+        self.direct(from, to).or_else(|| Some(self.direct(from, "USD")? * self.direct("USD", to)?))
+    }
+
+    fn set_rate(&self, from: &str, to: &str, rate: f64) -> bool {
+        if rate <= 0.0 {
+            return false;
+        }
+        self.0.borrow_mut().insert((from.to_string(), to.to_string()), rate);
+        self.1.set(self.1.get() + 1);
+        true
+    }
+
+    fn generation(&self) -> u64 {
+        self.1.get()
+    }
+}
+
+// The context of the evaluation in progress, installed by evaluate(), and the time it has
+// to be finished by
+thread_local! {
+    static CONTEXT: RefCell<Context> = RefCell::new(Context::default());
+    static DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+    // The trace of the evaluation in progress, when one was asked for
+    static RECORDING: RefCell<Option<Trace>> = const { RefCell::new(None) };
+}
+
+// Run f with the given context installed, putting the previous one back afterwards
+pub fn in_context<T>(context: &Context, f: impl FnOnce() -> T) -> T {
+    let previous = CONTEXT.with(|current| current.replace(context.clone()));
+    let result = f();
+    CONTEXT.with(|current| *current.borrow_mut() = previous);
+    result
+}
+
+fn today() -> NaiveDate {
+    let today = CONTEXT.with(|current| current.borrow().today.clone());
+    today()
+}
+
+fn exchange_rate(from: &str, to: &str) -> Option<f64> {
+    let rates = CONTEXT.with(|current| current.borrow().rates.clone());
+    let started = Instant::now();
+    let rate = rates.rate(from, to);
+    // A fetch from the network isn't the line's fault
+    let waited = started.elapsed();
+    DEADLINE.with(|deadline| deadline.set(deadline.get().and_then(|deadline| deadline.checked_add(waited))));
+    rate
+}
+
+fn rate_source(from: &str, to: &str) -> Option<String> {
+    let rates = CONTEXT.with(|current| current.borrow().rates.clone());
+    rates.source(from, to)
+}
+
+fn out_of_time() -> bool {
+    DEADLINE.with(|deadline| deadline.get().is_some_and(|deadline| Instant::now() > deadline))
+}
+
+// Evaluate within the context's time budget, and turn results that overflowed into errors
+fn evaluate_guarded(expr: &Expr, variables: &mut HashMap<String, Value>, budget: std::time::Duration) -> Value {
+    let previous = DEADLINE.with(|deadline| deadline.replace(Instant::now().checked_add(budget)));
+    let value = evaluate_expr(expr, variables);
+    let late = out_of_time();
+    DEADLINE.with(|deadline| deadline.set(previous));
+    if late {
+        Value::Error("Evaluation took too long".to_string())
+    } else {
+        finite(value)
+    }
+}
+
+// The value, or an error if it overflowed to infinity or isn't a number at all
+fn finite(value: Value) -> Value {
+    match value {
+        Value::Number(n) | Value::Percentage(n) | Value::Unit(n, _) if n.is_infinite() => Value::Error("Result too large".to_string()),
+        Value::Number(n) | Value::Percentage(n) | Value::Unit(n, _) if n.is_nan() => Value::Error("Result is undefined".to_string()),
+        Value::Assignment(name, value) => Value::Assignment(name, Box::new(finite(*value))),
+        Value::Split(each, remainder) => match finite(*each) {
+            Value::Error(msg) => Value::Error(msg),
+            each => Value::Split(Box::new(each), remainder),
+        },
+        Value::List(items) => list_of(items.into_iter().map(finite)),
+        Value::Map(entries) => map_of(entries.into_iter().map(|(key, value)| (key, finite(value)))),
+        other => other,
+    }
+}
+
+// Extra detail about how a line was evaluated, shown in the status bar summary
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Trace {
+    pub variables: Vec<(String, Value)>,   // Variables read, in order of first use
+    pub rates: Vec<(String, f64, String)>, // Conversions applied: 1 <from> = <rate> <to>
+    pub rate_sources: Vec<Option<String>>, // Where each of those rates came from, for currencies
+    pub not_found: Option<String>,         // The first name that was neither a variable nor known otherwise
+}
+
+// Evaluate an expression and record which variables and conversion rates it used. Rates
+// are noted as the conversions happen, so they are the ones the result used.
+pub fn evaluate_with_trace(expr: &Expr, variables: &mut HashMap<String, Value>, context: &Context) -> (Value, Trace) {
+    in_context(context, || {
+        let previous = RECORDING.with(|recording| recording.replace(Some(Trace::default())));
+        let value = evaluate_guarded(expr, variables, context.budget);
+        let mut trace = RECORDING.with(|recording| recording.replace(previous)).unwrap_or_default();
+        collect_variables(expr, variables, &mut trace);
+        (value, trace)
+    })
+}
+
+// The error for a name nothing defines, noted for the trace being recorded
+fn not_found(name: &str) -> Value {
+    RECORDING.with(|recording| {
+        if let Some(trace) = recording.borrow_mut().as_mut() {
+            trace.not_found.get_or_insert_with(|| name.to_string());
+        }
+    });
+    Value::Error(format!("'{name}' not found"))
+}
+
+// Note the rate of a conversion for the trace being recorded, if there is one
+fn record_conversion(amount: f64, converted: f64, source_unit: &str, from: &str, to: &str, target_unit: &str) {
+    // Temperatures aren't a simple ratio, so there is no rate to show for them
+    let is_temperature = |unit: &str| matches!(unit, "C" | "F" | "K");
+    if !RECORDING.with(|recording| recording.borrow().is_some()) || is_temperature(from) || is_temperature(to) {
+        return;
+    }
+    let rate = if amount != 0.0 { Some(converted / amount) } else { convert_units(1.0, from, to) };
+    let Some(rate) = rate else { return };
+    let source = (is_currency_code(from) && is_currency_code(to)).then(|| rate_source(from, to)).flatten();
+    RECORDING.with(|recording| {
+        if let Some(trace) = recording.borrow_mut().as_mut() {
+            let conversion = (source_unit.to_string(), rate, target_unit.to_string());
+            // A conversion evaluated more than once, as when solving, is shown once
+            if !trace.rates.contains(&conversion) {
+                trace.rates.push(conversion);
+                trace.rate_sources.push(source);
+            }
+        }
+    });
+}
+
+fn collect_variables(expr: &Expr, variables: &HashMap<String, Value>, trace: &mut Trace) {
+    match expr {
+        Expr::Variable(name) => {
+            if !trace.variables.iter().any(|(seen, _)| seen == name)
+                && let Some(value) = variables.get(name)
+            {
+                trace.variables.push((name.clone(), value.clone()));
+            }
+        },
+        Expr::Assignment(_, inner) | Expr::ToPercentage(inner) | Expr::RoundToCents(_, inner) | Expr::Ingredient(inner, _) | Expr::Convert(inner, _) => collect_variables(inner, variables, trace),
+        Expr::BinaryOp(left, _, right)
+        | Expr::PercentOf(left, right)
+        | Expr::Off(left, right)
+        | Expr::Split(left, right)
+        | Expr::Index(left, right)
+        | Expr::DateDiff(left, right, _)
+        | Expr::Solve(_, left, right) => {
+            collect_variables(left, variables, trace);
+            collect_variables(right, variables, trace);
+        },
+        Expr::Map(entries) => {
+            for (_, value) in entries {
+                collect_variables(value, variables, trace);
+            }
+        },
+        Expr::Lookup(map, _) => collect_variables(map, variables, trace),
+        Expr::IfDefined(..) => collect_variables(expr.reading(variables), variables, trace),
+        Expr::Statistic(_, arguments) | Expr::List(arguments) | Expr::Random(arguments) | Expr::Clamp(arguments) | Expr::Round(_, arguments) => {
+            for argument in arguments {
+                collect_variables(argument, variables, trace);
+            }
+        },
+        _ => {},
+    }
+}
+
 // Evaluate an expression to a value
-pub fn evaluate(expr: &Expr, variables: &mut HashMap<String, Value>) -> Value {
+pub fn evaluate(expr: &Expr, variables: &mut HashMap<String, Value>, context: &Context) -> Value {
+    in_context(context, || evaluate_guarded(expr, variables, context.budget))
+}
+
+fn evaluate_expr(expr: &Expr, variables: &mut HashMap<String, Value>) -> Value {
+    // Unwind quickly once the line has run out of time
+    if out_of_time() {
+        return Value::Error("Evaluation took too long".to_string());
+    }
     match expr {
         Expr::Number(n) => Value::Number(*n),
         
         Expr::Percentage(p) => Value::Percentage(*p),
         
+        Expr::ToPercentage(expr) => match evaluate_operand(expr, variables) {
+            Value::Number(n) | Value::Percentage(n) => Value::Percentage(n),
+            Value::Error(msg) => Value::Error(msg),
+            _ => Value::Error("Invalid percentage".to_string()),
+        },
+        
         Expr::Variable(name) => {
             if let Some(value) = variables.get(name) {
-                value.clone()
+                value.clone().settled()
             } else {
-                Value::Error(format!("'{name}' not found"))
+                not_found(name)
             }
         },
         
+        Expr::NotFound(name) => not_found(name),
+        
         Expr::UnitValue(value, unit) => {
             Value::Unit(*value, unit.clone())
         },
         
+        Expr::IfDefined(..) => evaluate_expr(expr.reading(variables), variables),
+        
         Expr::Assignment(name, expr) => {
-            let value = evaluate(expr, variables);
+            let value = evaluate_expr(expr, variables);
             // Return a special value that indicates an assignment was made
             Value::Assignment(name.clone(), Box::new(value.clone()))
         },
         
-        Expr::BinaryOp(left, op, right) => {
-            evaluate_binary_op(left, op, right, variables)
+        Expr::BinaryOp(..) => {
+            // A chain like 1 + 2 + 3… nests to the left with every operator; it's worked
+            // through in a loop so a long one doesn't run out of stack
+            let mut chain = Vec::new();
+            let mut first = expr;
+            while let Expr::BinaryOp(left, op, right) = first {
+                chain.push((op, right));
+                first = left;
+            }
+            let mut value = evaluate_expr(first, variables);
+            for (op, right) in chain.into_iter().rev() {
+                let right = evaluate_operand(right, variables);
+                value = round_money(apply_binary_op(value.settled(), op, right));
+            }
+            value
         },
         
         Expr::PercentOf(percent, value) => {
-            evaluate_percent_of(percent, value, variables)
+            round_money(evaluate_percent_of(percent, value, variables))
+        },
+        
+        Expr::Off(amount, value) => {
+            // Subtracting a percentage takes that share of the value off
+            let amount = evaluate_operand(amount, variables);
+            let value = evaluate_operand(value, variables);
+            round_money(apply_binary_op(value, &Op::Subtract, amount))
+        },
+        
+        Expr::Split(total, ways) => {
+            let total = evaluate_operand(total, variables);
+            let ways = evaluate_operand(ways, variables);
+            split(total, ways)
+        },
+        
+        Expr::Statistic(statistic, arguments) => {
+            let values = arguments.iter().map(|argument| evaluate_operand(argument, variables)).collect();
+            round_money(compute_statistic(*statistic, values))
+        },
+        
+        Expr::List(items) => {
+            let items: Vec<Value> = items.iter().map(|item| evaluate_operand(item, variables)).collect();
+            list_of(items)
+        },
+        
+        Expr::Index(list, index) => {
+            let list = evaluate_operand(list, variables);
+            let index = evaluate_operand(index, variables);
+            index_list(list, index)
+        },
+        
+        Expr::Map(entries) => {
+            map_of(entries.iter().map(|(key, value)| (key.clone(), evaluate_operand(value, variables))))
+        },
+        
+        Expr::Lookup(map, key) => match evaluate_operand(map, variables) {
+            Value::Map(entries) => look_up(entries, key),
+            Value::Error(msg) => Value::Error(msg),
+            other => Value::Error(format!("'{key}' isn't a variable, and a {} has no keys", other.type_name())),
+        },
+        
+        Expr::Random(bounds) => {
+            let bounds: Vec<Value> = bounds.iter().map(|bound| evaluate_operand(bound, variables)).collect();
+            random_between(bounds)
+        },
+        
+        Expr::Clamp(arguments) => {
+            let arguments: Vec<Value> = arguments.iter().map(|argument| evaluate_operand(argument, variables)).collect();
+            match <[Value; 3]>::try_from(arguments) {
+                Ok([value, low, high]) => clamp(value, low, high),
+                Err(_) => Value::Error("Usage: clamp(value, low, high)".to_string()),
+            }
+        },
+        
+        Expr::Round(rounding, arguments) => {
+            let mut arguments = arguments.iter().map(|argument| evaluate_operand(argument, variables)).collect::<Vec<_>>().into_iter();
+            match (arguments.next(), arguments.next(), arguments.next()) {
+                (Some(value), step, None) => round_to_step(*rounding, value, step.unwrap_or(Value::Number(1.0))),
+                _ => Value::Error(format!("Usage: {0}(value) or {0}(value, step)", rounding.name())),
+            }
+        },
+        
+        Expr::RoundToCents(rounding, value) => match evaluate_operand(value, variables) {
+            Value::Unit(n, unit) if is_currency_code(&unit) => {
+                let step = 10f64.powi(-(crate::currency::minor_unit_digits(&unit) as i32));
+                Value::Unit(round_to(*rounding, n, step), unit)
+            },
+            Value::Error(msg) => Value::Error(msg),
+            other => Value::Error(format!("Only money rounds to cents, not a {}", other.type_name())),
+        },
+        
+        Expr::Dice(count, sides) => {
+            let total: u64 = crate::random::with(|rng| (0..*count).map(|_| rng.below(u64::from(*sides)) + 1).sum());
+            Value::Number(total as f64)
         },
         
         Expr::Convert(value_expr, target_unit) => {
-            convert_unit(value_expr, target_unit, variables)
+            round_money(convert_unit(value_expr, target_unit, variables))
+        },
+        
+        Expr::Ingredient(value, ingredient) => match grams_per_ml(ingredient) {
+            Some(_) => evaluate_operand(value, variables),
+            None => Value::Error(format!(
+                "Unknown ingredient '{ingredient}'; the known ones are {}",
+                INGREDIENTS.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(", ")
+            )),
+        },
+        
+        Expr::Day(day) => Value::Date(resolve_day(*day, today())),
+        Expr::WeekdayOfMonth(occurrence, weekday, month) => match weekday_of_month(*occurrence, *weekday, *month, today()) {
+            Ok(date) => Value::Date(date),
+            Err(msg) => Value::Error(msg),
+        },
+        
+        Expr::Date(date) => Value::Date(*date),
+        
+        Expr::DateDiff(from, to, unit) => {
+            match (evaluate_operand(from, variables), evaluate_operand(to, variables)) {
+                (Value::Date(from), Value::Date(to)) => date_difference(from, to, unit),
+                (Value::Error(msg), _) | (_, Value::Error(msg)) => Value::Error(msg),
+                (from, to) => Value::Error(format!("Expected a date, not {}", if from.is_date() { to } else { from })),
+            }
         },
         
-        Expr::DateOffset(day_name, amount, unit) => {
-            calculate_date_offset(day_name, *amount, unit)
+        Expr::LineRef(n) => match variables.get(&line_key(*n)) {
+            Some(value) => value.clone(),
+            None => Value::Error(format!("Line {n} has no result")),
+        },
+        
+        Expr::LineAggregate(aggregate, from, to) => aggregate_lines(*aggregate, *from, *to, variables),
+        
+        Expr::Solve(unknown, left, right) => solve_linear(unknown, left, right, variables),
+        
+        Expr::SetRate(from, to, rate) => {
+            let rates = CONTEXT.with(|current| current.borrow().rates.clone());
+            if rates.set_rate(from, to, *rate) {
+                Value::Rate(from.clone(), *rate, to.clone())
+            } else {
+                Value::Error(format!("Can't use a rate of {} for {from} to {to}", format_decimal(*rate)))
+            }
+        },
+        
+        Expr::DefineUnit(name, factor, unit) => match define_unit(name, *factor, unit) {
+            Ok(()) => Value::Unit(*factor, unit.clone()),
+            Err(msg) => Value::Error(msg),
         },
         
         Expr::Error(msg) => Value::Error(msg.clone()),
@@ -120,11 +705,310 @@ pub fn evaluate(expr: &Expr, variables: &mut HashMap<String, Value>) -> Value {
 }
 
 // Evaluate a binary operation (a + b, a * b, etc.)
-fn evaluate_binary_op(left: &Expr, op: &Op, right: &Expr, variables: &mut HashMap<String, Value>) -> Value {
-    let left_val = evaluate(left, variables);
-    let right_val = evaluate(right, variables);
+// Evaluate part of a larger expression
+fn evaluate_operand(expr: &Expr, variables: &mut HashMap<String, Value>) -> Value {
+    evaluate_expr(expr, variables).settled()
+}
+
+// Share an amount equally. Money is shared out in whole cents (or the currency's smallest
+// coin), and any cents left over are noted: $99.71 four ways is $24.92 each, $0.03 over.
+fn split(total: Value, ways: Value) -> Value {
+    let ways = match ways {
+        Value::Error(msg) => return Value::Error(msg),
+        Value::Number(n) if n >= 1.0 && n.fract() == 0.0 => n,
+        other => return Value::Error(format!("Can't split {other} ways")),
+    };
+    match total {
+        Value::Unit(amount, currency) if is_currency_code(&currency) => {
+            let coin = 10f64.powi(crate::currency::minor_unit_digits(&currency) as i32);
+            let coins = (amount * coin).round();
+            let each = (coins / ways).trunc();
+            let remainder = coins - each * ways;
+            let each = Value::Unit(each / coin, currency.clone());
+            if remainder == 0.0 {
+                each
+            } else {
+                Value::Split(Box::new(each), Box::new(Value::Unit(remainder / coin, currency)))
+            }
+        },
+        total => apply_binary_op(total, &Op::Divide, Value::Number(ways)),
+    }
+}
+
+// rand() is uniform in 0..1, rand(low, high) in low..high, which may have a unit
+fn random_between(bounds: Vec<Value>) -> Value {
+    let fraction = crate::random::with(|rng| rng.next_f64());
+    match <[Value; 2]>::try_from(bounds) {
+        Ok([low, high]) => {
+            let range = apply_binary_op(high, &Op::Subtract, low.clone());
+            apply_binary_op(low, &Op::Add, apply_binary_op(range, &Op::Multiply, Value::Number(fraction)))
+        },
+        Err(bounds) if bounds.is_empty() => Value::Number(fraction),
+        Err(_) => Value::Error("rand() takes no values, or a low and a high one".to_string()),
+    }
+}
+
+// The value kept within low..high, in the value's own unit. Bounds in another unit are
+// converted to it, and plain numbers are taken to be in it already.
+fn clamp(value: Value, low: Value, high: Value) -> Value {
+    let magnitude = |bound: &Value| match (&value, bound) {
+        (_, Value::Error(msg)) => Err(msg.clone()),
+        (Value::Number(_) | Value::Unit(..), Value::Number(n)) | (Value::Percentage(_), Value::Percentage(n)) => Ok(*n),
+        (Value::Unit(_, unit), Value::Unit(n, from)) => convert_units(*n, &normalize_unit(from), &normalize_unit(unit))
+            .ok_or_else(|| cannot_mix(unit, from)),
+        _ => Err(format!("clamp() can't mix {} and {}", value.type_name(), bound.type_name())),
+    };
+    let n = match &value {
+        Value::Error(msg) => return Value::Error(msg.clone()),
+        Value::Number(n) | Value::Percentage(n) | Value::Unit(n, _) => *n,
+        other => return Value::Error(format!("clamp() needs a number, not a {}", other.type_name())),
+    };
+    let (lo, hi) = match (magnitude(&low), magnitude(&high)) {
+        (Ok(lo), Ok(hi)) => (lo, hi),
+        (Err(msg), _) | (_, Err(msg)) => return Value::Error(msg),
+    };
+    if lo > hi {
+        return Value::Error(format!("clamp() bounds are the wrong way round: {low} is above {high}"));
+    }
+    let clamped = n.max(lo).min(hi);
+    match value {
+        Value::Percentage(_) => Value::Percentage(clamped),
+        Value::Unit(_, unit) => Value::Unit(clamped, unit),
+        _ => Value::Number(clamped),
+    }
+}
+
+// round(), floor() or ceil() to a multiple of the step, in the value's own unit. A step in
+// another unit is converted to it, and a plain number is taken to be in it already.
+fn round_to_step(rounding: Rounding, value: Value, step: Value) -> Value {
+    let name = rounding.name();
+    let step = match (&value, &step) {
+        (Value::Error(msg), _) | (_, Value::Error(msg)) => return Value::Error(msg.clone()),
+        (Value::Number(_) | Value::Unit(..), Value::Number(n)) | (Value::Percentage(_), Value::Percentage(n)) => *n,
+        (Value::Unit(_, unit), Value::Unit(n, from)) => match convert_units(*n, &normalize_unit(from), &normalize_unit(unit)) {
+            Some(n) => n,
+            None => return Value::Error(cannot_mix(unit, from)),
+        },
+        _ => return Value::Error(format!("{name}() can't mix {} and {}", value.type_name(), step.type_name())),
+    };
+    if step.is_nan() || step <= 0.0 {
+        return Value::Error(format!("{name}() needs a step above zero"));
+    }
+    match value {
+        Value::Number(n) => Value::Number(round_to(rounding, n, step)),
+        Value::Percentage(p) => Value::Percentage(round_to(rounding, p, step)),
+        Value::Unit(n, unit) => Value::Unit(round_to(rounding, n, step), unit),
+        other => Value::Error(format!("{name}() needs a number, not a {}", other.type_name())),
+    }
+}
+
+// Counting the steps to 15 significant digits first keeps the decimal the user wrote:
+// 2.675 / 0.01 is 267.49999999999997 in binary, but rounds as 267.5
+fn round_to(rounding: Rounding, n: f64, step: f64) -> f64 {
+    let steps = decimal(n / step);
+    let whole = match rounding {
+        Rounding::Round => steps.round(),
+        Rounding::Floor => steps.floor(),
+        Rounding::Ceil => steps.ceil(),
+    };
+    decimal(whole * step)
+}
+
+// The number its shortest decimal form stands for, so 1.005 / 0.01 is 100.5 and not 100.49999
+fn decimal(x: f64) -> f64 {
+    format!("{x:.14e}").parse().unwrap_or(x)
+}
+
+// With `set currency rounding on`, money is kept in whole cents (or the currency's smallest
+// coin) after every operation, rounding halves to even, so results add up the way they display
+fn round_money(value: Value) -> Value {
+    match value {
+        Value::Unit(n, unit) if is_currency_code(&unit) && CONTEXT.with(|current| current.borrow().round_currency) => {
+            let step = 10f64.powi(-(crate::currency::minor_unit_digits(&unit) as i32));
+            Value::Unit(decimal(decimal(n / step).round_ties_even() * step), unit)
+        },
+        other => other,
+    }
+}
+
+// A list of values, or the first error among them
+fn list_of(items: impl IntoIterator<Item = Value>) -> Value {
+    let mut list = Vec::new();
+    for item in items {
+        if let Value::Error(msg) = item {
+            return Value::Error(msg);
+        }
+        list.push(item);
+    }
+    Value::List(list)
+}
+
+fn map_of(entries: impl IntoIterator<Item = (String, Value)>) -> Value {
+    let mut map = Vec::new();
+    for (key, value) in entries {
+        if let Value::Error(msg) = value {
+            return Value::Error(msg);
+        }
+        map.push((key, value));
+    }
+    Value::Map(map)
+}
+
+// The value stored under a key. Keys are matched as written, so `size[m]` and `size[M]`
+// are different entries.
+fn look_up(entries: Vec<(String, Value)>, key: &str) -> Value {
+    let keys = entries.iter().map(|(k, _)| k.as_str()).collect::<Vec<_>>().join(", ");
+    match entries.into_iter().find(|(k, _)| k == key) {
+        Some((_, value)) => value,
+        None if keys.is_empty() => Value::Error(format!("No key '{key}': the map is empty")),
+        None => Value::Error(format!("No key '{key}'; the keys are {keys}")),
+    }
+}
+
+// The item at a 1-based position, the way lines are counted, or a map's entry under the
+// value's key (sizes[38])
+fn index_list(list: Value, index: Value) -> Value {
+    let items = match list {
+        Value::Map(entries) => return match index {
+            Value::Error(msg) => Value::Error(msg),
+            key => look_up(entries, &key.to_string()),
+        },
+        Value::List(items) => items,
+        Value::Error(msg) => return Value::Error(msg),
+        other => return Value::Error(format!("Can't index a {}", other.type_name())),
+    };
+    match index {
+        Value::Error(msg) => Value::Error(msg),
+        Value::Number(n) if n.fract() == 0.0 && n >= 1.0 && n <= items.len() as f64 => items[n as usize - 1].clone(),
+        Value::Number(n) if n.fract() == 0.0 => {
+            Value::Error(format!("Index {n} is out of range for a list of {} items (they count from 1)", items.len()))
+        },
+        other => Value::Error(format!("Can't index a list with {other}")),
+    }
+}
+
+// A statistic over a list of values, where lists count as their items. The values must be
+// all plain numbers, all percentages, or in units that convert to the first unit used
+// (currencies at the current rates), which the result is in. Plain numbers next to values
+// with a unit take that unit, as they do in `10 + 5 USD`.
+fn compute_statistic(statistic: Statistic, values: Vec<Value>) -> Value {
+    let name = statistic.name();
+    let mut values = values;
+    while values.iter().any(|value| matches!(value, Value::List(_))) {
+        values = values.into_iter().flat_map(|value| match value {
+            Value::List(items) => items,
+            other => vec![other],
+        }).collect();
+    }
+    if statistic == Statistic::Len {
+        return Value::Number(values.len() as f64);
+    }
+    let Some(first) = values.first() else {
+        return Value::Error(format!("{name}() needs at least one value"));
+    };
+    let kind = values.iter().find(|value| matches!(value, Value::Unit(..))).unwrap_or(first);
+    
+    let mut numbers = Vec::with_capacity(values.len());
+    for value in &values {
+        let number = match (kind, value) {
+            (_, Value::Error(msg)) => return Value::Error(msg.clone()),
+            (Value::Number(_) | Value::Unit(..), Value::Number(n)) | (Value::Percentage(_), Value::Percentage(n)) => *n,
+            (Value::Unit(_, unit), Value::Unit(n, from)) => {
+                match convert_units(*n, &normalize_unit(from), &normalize_unit(unit)) {
+                    Some(n) => n,
+                    None => return Value::Error(cannot_mix(unit, from)),
+                }
+            },
+            _ => return Value::Error(format!("{name}() can't mix {} and {}", kind.type_name(), value.type_name())),
+        };
+        numbers.push(number);
+    }
     
-    match (left_val, op, right_val) {
+    let count = numbers.len() as f64;
+    let mean = numbers.iter().sum::<f64>() / count;
+    let result = match statistic {
+        Statistic::Sum => numbers.iter().sum(),
+        Statistic::Mean => mean,
+        Statistic::Median => {
+            numbers.sort_by(f64::total_cmp);
+            let middle = numbers.len() / 2;
+            if numbers.len() % 2 == 0 {
+                (numbers[middle - 1] + numbers[middle]) / 2.0
+            } else {
+                numbers[middle]
+            }
+        },
+        // The sample standard deviation, dividing by n - 1
+        Statistic::Stdev => {
+            if numbers.len() < 2 {
+                return Value::Error("stdev() needs at least two values".to_string());
+            }
+            (numbers.iter().map(|n| (n - mean).powi(2)).sum::<f64>() / (count - 1.0)).sqrt()
+        },
+        Statistic::Min => numbers.iter().copied().fold(f64::INFINITY, f64::min),
+        Statistic::Max => numbers.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+        Statistic::Len => unreachable!("counted above"),
+    };
+    
+    match kind {
+        Value::Percentage(_) => Value::Percentage(result),
+        Value::Unit(_, unit) => Value::Unit(result, unit.clone()),
+        _ => Value::Number(result),
+    }
+}
+
+// Combine two already evaluated values, e.g. to total up results. A result that isn't a
+// finite number is an error here, on the line that produced it, rather than a NaN or an
+// infinity carried into variables and the lines that use them.
+pub fn apply_binary_op(left_val: Value, op: &Op, right_val: Value) -> Value {
+    let magnitude = |value: &Value| match value {
+        Value::Number(n) | Value::Percentage(n) | Value::Unit(n, _) => Some(*n),
+        _ => None,
+    };
+    let operands = (magnitude(&left_val), magnitude(&right_val));
+    let result = combine(left_val, op, right_val);
+    let n = match &result {
+        Value::Number(n) | Value::Percentage(n) | Value::Unit(n, _) => *n,
+        _ => return result,
+    };
+    if n.is_finite() {
+        return result;
+    }
+    Value::Error(match (op, operands) {
+        (Op::Power, (Some(a), Some(b))) if a == 0.0 && b < 0.0 => "Cannot divide by 0".to_string(),
+        _ if n.is_infinite() => "Result too large".to_string(),
+        (Op::Power, (Some(a), Some(b))) if a < 0.0 && b == 0.5 => "Square root of a negative number".to_string(),
+        (Op::Power, (Some(a), Some(_))) if a < 0.0 => "Can't raise a negative number to a fractional power".to_string(),
+        (_, (Some(a), Some(b))) => {
+            let symbol = match op {
+                Op::Add => "+",
+                Op::Subtract => "-",
+                Op::Multiply => "*",
+                Op::Divide => "/",
+                Op::Modulo => "%",
+                Op::Power => "^",
+            };
+            format!("Result is undefined ({} {symbol} {})", format_decimal(a), format_decimal(b))
+        }
+        _ => "Result is undefined".to_string(),
+    })
+}
+
+fn combine(left_val: Value, op: &Op, right_val: Value) -> Value {
+    match (left_val.settled(), op, right_val.settled()) {
+        // An error on either side is the result, rather than a "Can't add" message
+        (Value::Error(msg), _, _) | (_, _, Value::Error(msg)) => Value::Error(msg),
+        
+        // Lists work item by item: with a single value, or with a list of the same length
+        (Value::List(items), op, Value::List(others)) => {
+            if items.len() != others.len() {
+                return Value::Error(format!("Lists of {} and {} items can't be combined", items.len(), others.len()));
+            }
+            list_of(items.into_iter().zip(others).map(|(item, other)| apply_binary_op(item, op, other)))
+        },
+        (Value::List(items), op, other) => list_of(items.into_iter().map(|item| apply_binary_op(item, op, other.clone()))),
+        (other, op, Value::List(items)) => list_of(items.into_iter().map(|item| apply_binary_op(other.clone(), op, item))),
+        
         // Number operations
         (Value::Number(a), Op::Add, Value::Number(b)) => Value::Number(a + b),
         (Value::Number(a), Op::Subtract, Value::Number(b)) => Value::Number(a - b),
@@ -191,6 +1075,24 @@ fn evaluate_binary_op(left: &Expr, op: &Op, right: &Expr, variables: &mut HashMa
             }
         },
         
+        // Modulo keeps the unit of the left-hand side
+        (Value::Unit(a, unit), Op::Modulo, Value::Number(b)) => {
+            if b == 0.0 {
+                Value::Error("Cannot use modulo with 0".to_string())
+            } else {
+                Value::Unit(a % b, unit)
+            }
+        },
+        (Value::Unit(a, unit_a), Op::Modulo, Value::Unit(b, unit_b)) => {
+            // Bring the divisor into the left-hand unit first (90 min % 1 h = 30 min)
+            match convert_units(b, &normalize_unit(&unit_b), &normalize_unit(&unit_a)) {
+                Some(0.0) => Value::Error("Cannot use modulo with 0".to_string()),
+                Some(b) => Value::Unit(a % b, unit_a),
+                None => cannot(&Op::Modulo, &describe_unit(&unit_a), &describe_unit(&unit_b)),
+            }
+        },
+        (Value::Unit(a, unit), Op::Power, Value::Number(b)) => power_of_unit(a, &unit, b),
+        
         // Number with unit operations (new cases)
         (Value::Number(a), Op::Add, Value::Unit(b, unit)) => Value::Unit(a + b, unit),
         (Value::Number(a), Op::Subtract, Value::Unit(b, unit)) => Value::Unit(a - b, unit),
@@ -215,8 +1117,20 @@ fn evaluate_binary_op(left: &Expr, op: &Op, right: &Expr, variables: &mut HashMa
                 let is_unit_a_currency = is_currency_code(&normalized_unit_a);
                 let is_unit_b_currency = is_currency_code(&normalized_unit_b);
                 
-                if is_unit_a_currency && is_unit_b_currency {
-                    // For currencies, always convert to the first currency
+                let base = CONTEXT.with(|current| current.borrow().base_currency.clone());
+                if is_unit_a_currency && is_unit_b_currency && let Some(base) = base {
+                    // Both sides in the base currency, so the order doesn't change the answer
+                    match (convert_units(a, &normalized_unit_a, &base), convert_units(b, &normalized_unit_b, &base)) {
+                        (Some(a), Some(b)) => match op {
+                            Op::Add => Value::Unit(a + b, base),
+                            Op::Subtract => Value::Unit(a - b, base),
+                            _ => unreachable!(),
+                        },
+                        (None, _) => Value::Error(format!("No rate for {unit_a} to {base}")),
+                        (_, None) => Value::Error(format!("No rate for {unit_b} to {base}")),
+                    }
+                } else if is_unit_a_currency && is_unit_b_currency {
+                    // Without a base currency, the left-hand one wins
                     if let Some(converted_b) = convert_units(b, &normalized_unit_b, &normalized_unit_a) {
                         match op {
                             Op::Add => Value::Unit(a + converted_b, unit_a),
@@ -234,42 +1148,68 @@ fn evaluate_binary_op(left: &Expr, op: &Op, right: &Expr, variables: &mut HashMa
                         _ => unreachable!(),
                     }
                 } else {
-                    Value::Error(format!("Cannot mix {unit_a} and {unit_b}"))
+                    match cannot(op, &describe_unit(&unit_a), &describe_unit(&unit_b)) {
+                        Value::Error(msg) => Value::Error(msg + &minutes_hint(a, &unit_a, &unit_b) + &minutes_hint(b, &unit_b, &unit_a)),
+                        other => other,
+                    }
                 }
             }
         },
         
-        // Handle date operations
-        (Value::Date(date), Op::Add, Value::Number(days)) => 
-            Value::Date(date + Duration::days(days as i64)),
-        (Value::Date(date), Op::Subtract, Value::Number(days)) => 
-            Value::Date(date - Duration::days(days as i64)),
+        // Handle date operations. Plain numbers count days.
+        (Value::Date(date), Op::Add, Value::Number(days)) | (Value::Number(days), Op::Add, Value::Date(date)) =>
+            move_date(date, days, "day"),
+        (Value::Date(date), Op::Subtract, Value::Number(days)) =>
+            move_date(date, -days, "day"),
+        (Value::Date(date), Op::Add, Value::Unit(amount, unit)) | (Value::Unit(amount, unit), Op::Add, Value::Date(date)) =>
+            move_date(date, amount, &unit),
+        (Value::Date(date), Op::Subtract, Value::Unit(amount, unit)) =>
+            move_date(date, -amount, &unit),
+        (Value::Date(a), Op::Subtract, Value::Date(b)) =>
+            Value::Unit((a - b).num_days() as f64, "days".to_string()),
             
         // Error for incompatible types
-        (a, _op, b) => Value::Error(format!("Cannot mix {a_type} and {b_type}",
-            a_type = match a {
-                Value::Number(_) => "number".to_string(),
-                Value::Percentage(_) => "percentage".to_string(),
-                Value::Unit(_, u) => u.clone(),
-                Value::Date(_) => "date".to_string(),
-                Value::Error(_) => "error".to_string(),
-                Value::Assignment(_, _) => "assignment".to_string(),
-            },
-            b_type = match b {
-                Value::Number(_) => "number".to_string(),
-                Value::Percentage(_) => "percentage".to_string(),
-                Value::Unit(_, u) => u.clone(),
-                Value::Date(_) => "date".to_string(),
-                Value::Error(_) => "error".to_string(),
-                Value::Assignment(_, _) => "assignment".to_string(),
-            })),
+        (a, op, b) => cannot(op, &a.to_string(), &b.to_string()),
+    }
+}
+
+// Raise a unit value to a power. Squaring or cubing a length gives the matching area or
+// volume unit (m2, m3); any other unit can only be raised to the power of 0 or 1.
+fn power_of_unit(value: f64, unit: &str, exponent: f64) -> Value {
+    const LENGTHS: &[&str] = &["mm", "cm", "m", "km", "in", "ft", "yd", "mi"];
+    
+    if exponent == 0.0 {
+        return Value::Number(1.0);
+    }
+    if exponent == 1.0 {
+        return Value::Unit(value, unit.to_string());
+    }
+    
+    let normalized = normalize_unit(unit);
+    if (exponent == 2.0 || exponent == 3.0) && LENGTHS.contains(&normalized.as_ref()) {
+        Value::Unit(value.powf(exponent), format!("{normalized}{exponent}"))
+    } else {
+        cannot(&Op::Power, &describe_unit(unit), &exponent.to_string())
     }
 }
 
 // Evaluate percentage expression (X% of Y)
 fn evaluate_percent_of(percent_expr: &Expr, value_expr: &Expr, variables: &mut HashMap<String, Value>) -> Value {
-    let percent_val = evaluate(percent_expr, variables);
-    let value_val = evaluate(value_expr, variables);
+    let percent_val = evaluate_operand(percent_expr, variables);
+    let value_val = evaluate_operand(value_expr, variables);
+    
+    // `x of 200` reads as a percentage only when x holds one; 50 USD of 200 means nothing
+    if let Expr::Variable(name) = percent_expr
+        && !matches!(percent_val, Value::Percentage(_) | Value::Error(_))
+    {
+        let value = match value_expr {
+            Expr::Number(n) => Value::Number(*n).to_string(),
+            Expr::UnitValue(v, unit) => format!("{} {}", Value::Number(*v), unit),
+            Expr::Variable(name) => name.clone(),
+            _ => "…".to_string(),
+        };
+        return Value::Error(format!("'{name}' is {percent_val}, not a percentage; did you mean '{name} * {value}'?"));
+    }
     
     match (percent_val, value_val) {
         (Value::Number(p), Value::Number(v)) => {
@@ -288,20 +1228,157 @@ fn evaluate_percent_of(percent_expr: &Expr, value_expr: &Expr, variables: &mut H
     }
 }
 
+// The name under which the result of input line `n` (1-based) is passed in `variables`.
+// It can't clash with a variable, since variable names are made of words only.
+pub fn line_key(n: usize) -> String {
+    format!("line {n}")
+}
+
+// The line number of a name made by `line_key`
+pub fn line_key_number(name: &str) -> Option<usize> {
+    name.strip_prefix("line ")?.parse().ok()
+}
+
+// Add up (or average) the results of lines `from..=to`. Lines without a result, like
+// blank lines and comments, are skipped; an error on any line is the result.
+fn aggregate_lines(aggregate: Aggregate, from: usize, to: usize, variables: &HashMap<String, Value>) -> Value {
+    let mut total: Option<Value> = None;
+    let mut count = 0;
+    for n in from..=to {
+        let Some(value) = variables.get(&line_key(n)) else { continue };
+        let sum = match total {
+            None => value.clone().settled(),
+            Some(sum) => apply_binary_op(sum, &Op::Add, value.clone()),
+        };
+        if let Value::Error(_) = sum {
+            return sum;
+        }
+        total = Some(sum);
+        count += 1;
+    }
+    
+    match (total, aggregate) {
+        (None, _) => Value::Error(format!("Lines {from}..{to} have no results")),
+        (Some(sum), Aggregate::Sum) => sum,
+        (Some(sum), Aggregate::Average) => apply_binary_op(sum, &Op::Divide, Value::Number(count as f64)),
+    }
+}
+
+// Solve `left = right` for an unknown that appears linearly. Both sides are evaluated at a
+// few trial values; their difference has to grow in a straight line, and where that line
+// crosses zero is the answer. The unknown is tried as a plain number first, then with each
+// unit the equation produces, so `x * 1.13 = 150 USD` gives x in USD.
+fn solve_linear(unknown: &str, left: &Expr, right: &Expr, variables: &mut HashMap<String, Value>) -> Value {
+    const TRIALS: [f64; 3] = [1.0, 2.0, 3.0];
+    
+    // The unknown may shadow a variable (solve x: ...), which is put back afterwards
+    let shadowed = variables.remove(unknown);
+    let mut sides_at = |x: Value| {
+        variables.insert(unknown.to_string(), x);
+        let sides = (evaluate_operand(left, variables), evaluate_operand(right, variables));
+        variables.remove(unknown);
+        sides
+    };
+    
+    let mut kinds = vec![None];
+    let (left_value, right_value) = sides_at(Value::Number(1.0));
+    for side in [left_value, right_value] {
+        match side {
+            Value::Error(_) => return restore(variables, unknown, shadowed, side),
+            Value::Unit(_, unit) if !kinds.contains(&Some(unit.clone())) => kinds.push(Some(unit)),
+            _ => {}
+        }
+    }
+    
+    let mut result = Value::Error(format!("Can't solve for {unknown}: the two sides have different units"));
+    for kind in kinds {
+        let value_of = |x: f64| match &kind {
+            None => Value::Number(x),
+            Some(unit) => Value::Unit(x, unit.clone()),
+        };
+        
+        // Both sides have to come out as the same kind of value
+        let mut differences = Vec::new();
+        for x in TRIALS {
+            let difference = match sides_at(value_of(x)) {
+                (left @ Value::Number(_), right @ Value::Number(_)) | (left @ Value::Unit(_, _), right @ Value::Unit(_, _)) => {
+                    apply_binary_op(left, &Op::Subtract, right)
+                }
+                _ => break,
+            };
+            match difference {
+                Value::Number(d) | Value::Unit(d, _) if d.is_finite() => differences.push(d),
+                _ => break,
+            }
+        }
+        let [d1, d2, d3] = differences[..] else { continue };
+        
+        let slope = d2 - d1;
+        let curvature = d3 - 2.0 * d2 + d1;
+        let scale = d1.abs().max(d2.abs()).max(d3.abs()).max(1.0);
+        result = if curvature.abs() > 1e-9 * scale {
+            Value::Error(format!("Can't solve: {unknown} doesn't appear linearly"))
+        } else if slope.abs() <= 1e-12 * scale {
+            Value::Error(format!("Can't solve: {unknown} cancels out"))
+        } else {
+            value_of(TRIALS[0] - d1 / slope)
+        };
+        break;
+    }
+    restore(variables, unknown, shadowed, result)
+}
+
+// Put back a variable that solving shadowed, passing the result through
+fn restore(variables: &mut HashMap<String, Value>, name: &str, shadowed: Option<Value>, result: Value) -> Value {
+    if let Some(value) = shadowed {
+        variables.insert(name.to_string(), value);
+    }
+    result
+}
+
 // Convert a value from one unit to another
 fn convert_unit(value_expr: &Expr, target_unit: &str, variables: &mut HashMap<String, Value>) -> Value {
-    let value = evaluate(value_expr, variables);
+    let ingredient = match value_expr {
+        Expr::Ingredient(_, ingredient) => Some(ingredient.as_str()),
+        _ => None,
+    };
+    convert_value(evaluate_operand(value_expr, variables), target_unit, ingredient)
+}
+
+// A value already worked out, in another unit, as `<value> in <target>` would give it
+pub fn convert(value: Value, target_unit: &str, context: &Context) -> Value {
+    in_context(context, || finite(round_money(convert_value(value, target_unit, None))))
+}
+
+// Convert a value that's been evaluated. Between volume and weight too, when it's an amount
+// of a known ingredient.
+fn convert_value(value: Value, target_unit: &str, ingredient: Option<&str>) -> Value {
+    // `in per month` and `in monthly` keep the rate's amount unit and change its period
+    if let Some((amount, period)) = target_rate(target_unit) {
+        return match &value {
+            Value::Unit(_, unit) if let Some((source_amount, _)) = split_rate(unit) => {
+                let amount = amount.map_or_else(|| source_amount.to_string(), |amount| normalize_unit(amount).into_owned());
+                convert_value(value.clone(), &format!("{amount}/{period}"), ingredient)
+            }
+            Value::Unit(_, unit) => Value::Error(format!("Can't convert {} to an amount per {period}", describe_unit(unit))),
+            error @ Value::Error(_) => error.clone(),
+            other => Value::Error(format!("Can't convert {other} to an amount per {period}")),
+        };
+    }
     
     // Normalize the target unit
     let normalized_target_unit = normalize_unit(target_unit);
     
     // Prepare the display unit for output
-    let display_unit = if ["KB", "MB", "GB", "TB", "PB", "B"].contains(&normalized_target_unit.as_str()) {
-        normalized_target_unit.clone()
+    let display_unit = if ["KB", "MB", "GB", "TB", "PB", "B"].contains(&normalized_target_unit.as_ref()) || split_rate(target_unit).is_some() {
+        normalized_target_unit.to_string()
     } else if target_unit.chars().all(|c| c.is_uppercase()) {
         target_unit.to_string()
+    } else if !is_unit_name(target_unit) {
+        // An ad-hoc unit keeps its spelling (5 in apples)
+        target_unit.trim().to_lowercase()
     } else {
-        normalized_target_unit.clone()
+        normalized_target_unit.to_string()
     };
     
     match value {
@@ -314,64 +1391,186 @@ fn convert_unit(value_expr: &Expr, target_unit: &str, variables: &mut HashMap<St
                 return Value::Unit(v, display_unit);
             }
             
-            // Attempt conversion
+            // Attempt conversion, between volume and weight too when the ingredient is known
+            let by_density = |ingredient| convert_by_density(v, &normalized_source_unit, &normalized_target_unit, ingredient);
             match convert_units(v, &normalized_source_unit, &normalized_target_unit) {
-                Some(converted_value) => Value::Unit(converted_value, display_unit),
-                None => Value::Error(format!("Cannot convert to {target_unit}")),
+                Some(converted_value) => {
+                    record_conversion(v, converted_value, &source_unit, &normalized_source_unit, &normalized_target_unit, target_unit);
+                    Value::Unit(converted_value, display_unit)
+                }
+                None => match ingredient {
+                    Some(ingredient) if let Some(converted_value) = by_density(ingredient) => {
+                        Value::Unit(converted_value, display_unit)
+                    }
+                    _ => Value::Error(format!(
+                        "Can't convert {} to {}{}",
+                        describe_unit(&source_unit),
+                        describe_unit(target_unit),
+                        minutes_hint(v, &source_unit, target_unit),
+                    )),
+                },
             }
         },
         Value::Number(v) => {
             // For unitless numbers, just apply the target unit
             Value::Unit(v, display_unit)
         },
-        _ => Value::Error(format!("Cannot convert to {target_unit}")),
+        error @ Value::Error(_) => error,
+        other => Value::Error(format!("Can't convert {other} to {}", describe_unit(target_unit))),
     }
 }
 
-// Calculate date from expressions like "next friday + 2 weeks"
-fn calculate_date_offset(day_name: &str, amount: i64, unit: &str) -> Value {
-    // Start with today's date
-    let today = Local::now().date_naive();
-    
-    // Find the next occurrence of the specified day
-    let day_of_week = match day_name {
-        "monday" => Weekday::Mon,
-        "tuesday" => Weekday::Tue,
-        "wednesday" => Weekday::Wed,
-        "thursday" => Weekday::Thu,
-        "friday" => Weekday::Fri,
-        "saturday" => Weekday::Sat,
-        "sunday" => Weekday::Sun,
-        _ => return Value::Error(format!("Invalid day '{day_name}'")),
+// Grams in a US cup of each ingredient `2 cups flour in g` knows
+const INGREDIENTS: &[(&str, f64)] = &[
+    ("flour", 125.0),
+    ("sugar", 200.0),
+    ("butter", 227.0),
+    ("water", 236.588),
+    ("milk", 244.0),
+    ("rice", 185.0),
+];
+
+fn grams_per_ml(ingredient: &str) -> Option<f64> {
+    INGREDIENTS.iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(ingredient))
+        .map(|(_, grams_per_cup)| grams_per_cup / 236.588)
+}
+
+// Milliliters and grams of a value, for the volumes and weights the table can reach
+fn to_ml(value: f64, unit: &str) -> Option<f64> {
+    convert_units(value, unit, "ml").or_else(|| Some(convert_units(value, unit, "l")? * 1000.0))
+}
+
+fn to_grams(value: f64, unit: &str) -> Option<f64> {
+    convert_units(value, unit, "g").or_else(|| Some(convert_units(value, unit, "kg")? * 1000.0))
+}
+
+// Whether an amount in `unit` can be of an ingredient: a volume or a weight
+pub fn measures_ingredient(unit: &str) -> bool {
+    let unit = normalize_unit(unit);
+    to_ml(1.0, &unit).is_some() || to_grams(1.0, &unit).is_some()
+}
+
+// A volume of an ingredient as a weight, or a weight as a volume
+fn convert_by_density(value: f64, from_unit: &str, to_unit: &str, ingredient: &str) -> Option<f64> {
+    let density = grams_per_ml(ingredient)?;
+    if let Some(ml) = to_ml(value, from_unit) {
+        let grams = ml * density;
+        convert_units(grams, "g", to_unit).or_else(|| convert_units(grams / 1000.0, "kg", to_unit))
+    } else {
+        let ml = to_grams(value, from_unit)? / density;
+        convert_units(ml, "ml", to_unit).or_else(|| convert_units(ml / 1000.0, "l", to_unit))
+    }
+}
+
+// Move a date by a whole number of days, weeks, months or years
+fn move_date(date: NaiveDate, amount: f64, unit: &str) -> Value {
+    if amount.fract() != 0.0 {
+        return Value::Error(format!("Cannot move a date by {amount} {unit}"));
+    }
+    match shift_date(date, amount as i64, unit) {
+        Some(date) => Value::Date(date),
+        None => Value::Error(format!("Cannot move a date by {unit}")),
+    }
+}
+
+// Months and years follow the calendar, clamping to the end of shorter months
+// (Jan 31 + 1 month = Feb 28). Returns None for other units or out-of-range dates.
+fn shift_date(date: NaiveDate, amount: i64, unit: &str) -> Option<NaiveDate> {
+    let add_months = |months: i64| {
+        let step = Months::new(u32::try_from(months.unsigned_abs()).ok()?);
+        if months >= 0 { date.checked_add_months(step) } else { date.checked_sub_months(step) }
     };
     
-    // Calculate days until next occurrence
-    let today_weekday = today.weekday();
-    let days_until = (day_of_week.num_days_from_monday() + 7 - today_weekday.num_days_from_monday()) % 7;
-    
-    // If it's the same day and days_until is 0, we want the next week
-    let days_until = if days_until == 0 { 7 } else { days_until };
-    
-    // Calculate the next occurrence of the day
-    let next_day = today + Duration::days(days_until as i64);
-    
-    // Add the specified offset
-    let result_date = match unit {
-        "days" | "day" => next_day + Duration::days(amount),
-        "weeks" | "week" => next_day + Duration::days(amount * 7),
-        "months" | "month" => {
-            // Approximate month as 30 days
-            next_day + Duration::days(amount * 30)
+    match normalize_unit(unit).as_ref() {
+        "day" => date.checked_add_signed(Duration::try_days(amount)?),
+        "week" => date.checked_add_signed(Duration::try_days(amount.checked_mul(7)?)?),
+        "month" => add_months(amount),
+        "year" => add_months(amount.checked_mul(12)?),
+        _ => None,
+    }
+}
+
+// The time from one date to another in `unit`. Months and years are counted on the
+// calendar, so Jan 15 to Mar 15 is exactly 2 months, with any part month as a fraction of
+// the month it falls in. The result is negative when `to` comes first, and is in days like
+// `date - date`, or in the unit's usual name like a conversion.
+pub fn date_difference(from: NaiveDate, to: NaiveDate, unit: &str) -> Value {
+    let normalized = normalize_unit(unit);
+    let months_per_step = match normalized.as_ref() {
+        "day" => return Value::Unit((to - from).num_days() as f64, "days".to_string()),
+        "month" => 1,
+        "year" => 12,
+        _ => {
+            let days = (to - from).num_days() as f64;
+            return match convert_units(days, "day", &normalized) {
+                Some(amount) => Value::Unit(amount, normalized.into_owned()),
+                None => Value::Error(format!("Can't count {unit} between dates")),
+            };
         },
-        _ => return Value::Error(format!("Invalid unit '{unit}'")),
     };
+    if to < from {
+        return match date_difference(to, from, unit) {
+            Value::Unit(amount, unit) => Value::Unit(-amount, unit),
+            other => other,
+        };
+    }
     
-    Value::Date(result_date)
+    let step = |steps: i64| shift_date(from, steps * months_per_step, "month");
+    let mut whole = 0;
+    while step(whole + 1).is_some_and(|date| date <= to) {
+        whole += 1;
+    }
+    let (Some(start), Some(end)) = (step(whole), step(whole + 1)) else {
+        return Value::Error("Date out of range".to_string());
+    };
+    let part = (to - start).num_days() as f64 / (end - start).num_days() as f64;
+    Value::Unit(whole as f64 + part, normalized.into_owned())
+}
+
+// The date a day name stands for, counting from `today`
+pub fn resolve_day(day: Day, today: NaiveDate) -> NaiveDate {
+    let (weekday, at_least) = match day {
+        Day::Today => return today,
+        Day::This(weekday) => (weekday, 0),
+        Day::Next(weekday) => (weekday, 1),
+    };
+    let days_until = (weekday.num_days_from_monday() + 7 - today.weekday().num_days_from_monday()) % 7;
+    let days_until = if days_until < at_least { days_until + 7 } else { days_until };
+    today + Duration::days(days_until as i64)
+}
+
+// The date `first monday of march` stands for, where a month without a year is the coming
+// one, counting from `today`
+pub fn weekday_of_month(occurrence: Occurrence, weekday: chrono::Weekday, month: MonthOf, today: NaiveDate) -> Result<NaiveDate, String> {
+    let this_month = today.with_day(1).expect("every month has a first day");
+    let first = match month {
+        MonthOf::This => Some(this_month),
+        MonthOf::Next => this_month.checked_add_months(Months::new(1)),
+        MonthOf::Named(month, Some(year)) => NaiveDate::from_ymd_opt(year, month, 1),
+        MonthOf::Named(month, None) => {
+            let year = if month < today.month() { today.year() + 1 } else { today.year() };
+            NaiveDate::from_ymd_opt(year, month, 1)
+        }
+    }.ok_or("Date out of range")?;
+    let month_name = first.format("%B %Y");
+    match occurrence {
+        Occurrence::Nth(n) => NaiveDate::from_weekday_of_month_opt(first.year(), first.month(), weekday, n).ok_or_else(|| {
+            let weekday = NaiveDate::from_weekday_of_month_opt(first.year(), first.month(), weekday, 1)
+                .map_or(String::new(), |date| date.format("%A").to_string());
+            format!("{month_name} has no {} {weekday}", ordinal_word(n))
+        }),
+        Occurrence::Last => {
+            let next = first.checked_add_months(Months::new(1)).ok_or("Date out of range")?;
+            let last_day = next.pred_opt().ok_or("Date out of range")?;
+            let back = (last_day.weekday().num_days_from_monday() + 7 - weekday.num_days_from_monday()) % 7;
+            Ok(last_day - Duration::days(back as i64))
+        }
+    }
 }
 
-// Function to check if a string is a valid currency code
-fn is_currency_code(unit: &str) -> bool {
-    unit.len() == 3 && unit.chars().all(|c| c.is_ascii_uppercase())
+fn ordinal_word(n: u8) -> &'static str {
+    ["first", "second", "third", "fourth", "fifth"].get(n as usize - 1).copied().unwrap_or("nth")
 }
 
 // Convert between different units
@@ -390,20 +1589,66 @@ fn convert_units(value: f64, from_unit: &str, to_unit: &str) -> Option<f64> {
         return Some(value);
     }
     
+    // A rate converts its amount, then scales by how the periods compare: a year has twelve
+    // months, so 120 USD/year is 10 USD/month. Rates don't convert to or from anything else.
+    match (split_rate(&from_unit), split_rate(&to_unit)) {
+        (Some((from_amount, from_period)), Some((to_amount, to_period))) => {
+            let amount = convert_units(value, from_amount, to_amount)?;
+            return Some(amount * periods_per_year(from_period)? / periods_per_year(to_period)?);
+        }
+        (None, None) => {}
+        _ => return None,
+    }
+    
+    // Custom units convert through the unit they were defined with
+    if custom_unit(&from_unit).is_some() || custom_unit(&to_unit).is_some() {
+        let (from_factor, from_base) = resolve_custom_unit(&from_unit)?;
+        let (to_factor, to_base) = resolve_custom_unit(&to_unit)?;
+        let converted = convert_units(value * from_factor, &from_base, &to_base)?;
+        return Some(converted / to_factor);
+    }
+    
     // Check if both units are currencies (uppercase 3-letter codes like USD, EUR, etc.)
     let is_from_currency = is_currency_code(&from_unit);
     let is_to_currency = is_currency_code(&to_unit);
     
     if is_from_currency && is_to_currency {
         // Use currency API for currency conversions
-        if let Some(rate) = crate::currency::get_exchange_rate(&from_unit, &to_unit) {
+        if let Some(rate) = exchange_rate(&from_unit, &to_unit) {
             return Some(value * rate);
         }
         return None;
     }
     
-    // For non-currency conversions, use the lookup table
-    match (from_unit.as_str(), to_unit.as_str()) {
+    direct_conversion(value, &from_unit, &to_unit).or_else(|| convert_in_steps(value, &from_unit, &to_unit))
+}
+
+// Pairs without an entry of their own go through other units of the same kind, fewest
+// steps first: ft to cm is ft to m, then m to cm
+fn convert_in_steps(value: f64, from_unit: &str, to_unit: &str) -> Option<f64> {
+    let (_, units) = DIMENSIONS.iter()
+        .find(|(_, units)| units.iter().any(|&unit| unit == from_unit || direct_conversion(1.0, from_unit, unit).is_some()))?;
+    let mut reached = vec![(from_unit, value)];
+    let mut next = 0;
+    while let Some(&(unit, amount)) = reached.get(next) {
+        next += 1;
+        if let Some(converted) = direct_conversion(amount, unit, to_unit) {
+            return Some(converted);
+        }
+        for &step in *units {
+            if reached.iter().all(|(seen, _)| *seen != step)
+                && let Some(converted) = direct_conversion(amount, unit, step)
+            {
+                reached.push((step, converted));
+            }
+        }
+    }
+    None
+}
+
+// The lookup table of conversions between units that aren't currencies
+fn direct_conversion(value: f64, from_unit: &str, to_unit: &str) -> Option<f64> {
+    match (from_unit, to_unit) {
         // Data units conversions
         ("B", "bit") => Some(value * 8.0),
         ("bit", "B") => Some(value / 8.0),
@@ -465,6 +1710,42 @@ fn convert_units(value: f64, from_unit: &str, to_unit: &str) -> Option<f64> {
         ("ft", "yd") => Some(value / 3.0),
         ("yd", "m") => Some(value * 0.9144),
         ("m", "yd") => Some(value / 0.9144),
+        ("nmi", "km") => Some(value * 1.852),
+        ("km", "nmi") => Some(value / 1.852),
+        ("nmi", "m") => Some(value * 1852.0),
+        ("m", "nmi") => Some(value / 1852.0),
+        ("nmi", "mi") => Some(value * 1.852 / 1.60934),
+        ("mi", "nmi") => Some(value * 1.60934 / 1.852),
+        ("furlong", "m") => Some(value * 201.168),
+        ("m", "furlong") => Some(value / 201.168),
+        ("furlong", "mi") => Some(value / 8.0),
+        ("mi", "furlong") => Some(value * 8.0),
+        ("furlong", "yd") => Some(value * 220.0),
+        ("yd", "furlong") => Some(value / 220.0),
+        ("fathom", "m") => Some(value * 1.8288),
+        ("m", "fathom") => Some(value / 1.8288),
+        ("fathom", "ft") => Some(value * 6.0),
+        ("ft", "fathom") => Some(value / 6.0),
+        ("hand", "in") => Some(value * 4.0),
+        ("in", "hand") => Some(value / 4.0),
+        ("hand", "cm") => Some(value * 10.16),
+        ("cm", "hand") => Some(value / 10.16),
+        ("mil", "in") => Some(value / 1000.0),
+        ("in", "mil") => Some(value * 1000.0),
+        ("mil", "mm") => Some(value * 0.0254),
+        ("mm", "mil") => Some(value / 0.0254),
+        
+        // Astronomical lengths
+        ("au", "km") => Some(value * 149597870.7),
+        ("km", "au") => Some(value / 149597870.7),
+        ("au", "m") => Some(value * 149597870700.0),
+        ("m", "au") => Some(value / 149597870700.0),
+        ("ly", "km") => Some(value * 9460730472580.8),
+        ("km", "ly") => Some(value / 9460730472580.8),
+        ("ly", "m") => Some(value * 9460730472580800.0),
+        ("m", "ly") => Some(value / 9460730472580800.0),
+        ("ly", "au") => Some(value * 9460730472580.8 / 149597870.7),
+        ("au", "ly") => Some(value * 149597870.7 / 9460730472580.8),
         
         // Area conversions
         ("m2", "cm2") => Some(value * 10000.0),
@@ -501,6 +1782,18 @@ fn convert_units(value: f64, from_unit: &str, to_unit: &str) -> Option<f64> {
         ("ml", "floz") => Some(value / 29.5735),
         ("cup", "floz") => Some(value * 8.0),
         ("floz", "cup") => Some(value / 8.0),
+        ("dash", "ml") => Some(value * 0.625), // an eighth of a teaspoon
+        ("ml", "dash") => Some(value / 0.625),
+        ("dash", "tsp") => Some(value / 8.0),
+        ("tsp", "dash") => Some(value * 8.0),
+        ("pinch", "ml") => Some(value * 0.3125), // a sixteenth of a teaspoon
+        ("ml", "pinch") => Some(value / 0.3125),
+        ("pinch", "tsp") => Some(value / 16.0),
+        ("tsp", "pinch") => Some(value * 16.0),
+        ("fldr", "ml") => Some(value * 29.5735 / 8.0),
+        ("ml", "fldr") => Some(value * 8.0 / 29.5735),
+        ("floz", "fldr") => Some(value * 8.0),
+        ("fldr", "floz") => Some(value / 8.0),
         ("m3", "l") => Some(value * 1000.0),
         ("l", "m3") => Some(value / 1000.0),
         ("ft3", "m3") => Some(value * 0.0283168),
@@ -523,6 +1816,20 @@ fn convert_units(value: f64, from_unit: &str, to_unit: &str) -> Option<f64> {
         ("lb", "st") => Some(value / 14.0),
         ("st", "kg") => Some(value * 6.35029),
         ("kg", "st") => Some(value / 6.35029),
+        ("stick", "g") => Some(value * 113.4), // of butter
+        ("g", "stick") => Some(value / 113.4),
+        ("stick", "oz") => Some(value * 4.0),
+        ("oz", "stick") => Some(value / 4.0),
+        ("grain", "mg") => Some(value * 64.79891),
+        ("mg", "grain") => Some(value / 64.79891),
+        ("grain", "g") => Some(value * 0.06479891),
+        ("g", "grain") => Some(value / 0.06479891),
+        ("lb", "grain") => Some(value * 7000.0),
+        ("grain", "lb") => Some(value / 7000.0),
+        ("ct", "g") => Some(value * 0.2),
+        ("g", "ct") => Some(value / 0.2),
+        ("ct", "mg") => Some(value * 200.0),
+        ("mg", "ct") => Some(value / 200.0),
         
         // Temperature conversions
         ("C", "F") => Some(value * 9.0/5.0 + 32.0),
@@ -555,6 +1862,28 @@ fn convert_units(value: f64, from_unit: &str, to_unit: &str) -> Option<f64> {
         ("J", "kWh") => Some(value / 3600000.0),
         ("eV", "J") => Some(value * 1.602176634e-19),
         ("J", "eV") => Some(value / 1.602176634e-19),
+        ("Wh", "J") => Some(value * 3600.0),
+        ("J", "Wh") => Some(value / 3600.0),
+        ("Wh", "kWh") => Some(value / 1000.0),
+        ("kWh", "Wh") => Some(value * 1000.0),
+        ("MWh", "kWh") => Some(value * 1000.0),
+        ("kWh", "MWh") => Some(value / 1000.0),
+        ("MWh", "Wh") => Some(value * 1000000.0),
+        ("Wh", "MWh") => Some(value / 1000000.0),
+        ("BTU", "J") => Some(value * 1055.05585262),
+        ("J", "BTU") => Some(value / 1055.05585262),
+        ("BTU", "kJ") => Some(value * 1.05505585262),
+        ("kJ", "BTU") => Some(value / 1.05505585262),
+        ("BTU", "kWh") => Some(value * 1055.05585262 / 3600000.0),
+        ("kWh", "BTU") => Some(value * 3600000.0 / 1055.05585262),
+        ("BTU", "Wh") => Some(value * 1055.05585262 / 3600.0),
+        ("Wh", "BTU") => Some(value * 3600.0 / 1055.05585262),
+        ("therm", "BTU") => Some(value * 100000.0),
+        ("BTU", "therm") => Some(value / 100000.0),
+        ("therm", "kWh") => Some(value * 105505585.262 / 3600000.0),
+        ("kWh", "therm") => Some(value * 3600000.0 / 105505585.262),
+        ("therm", "J") => Some(value * 105505585.262),
+        ("J", "therm") => Some(value / 105505585.262),
         
         // Power conversions
         ("W", "kW") => Some(value / 1000.0),
@@ -575,6 +1904,21 @@ fn convert_units(value: f64, from_unit: &str, to_unit: &str) -> Option<f64> {
         ("kPa", "psi") => Some(value / 6.895),
         ("atm", "kPa") => Some(value * 101.325),
         ("kPa", "atm") => Some(value / 101.325),
+        // A millimeter of mercury taken as a torr, 1/760 of an atmosphere
+        ("mmHg", "atm") => Some(value / 760.0),
+        ("atm", "mmHg") => Some(value * 760.0),
+        ("mmHg", "kPa") => Some(value * 101.325 / 760.0),
+        ("kPa", "mmHg") => Some(value * 760.0 / 101.325),
+        ("mmHg", "Pa") => Some(value * 101325.0 / 760.0),
+        ("Pa", "mmHg") => Some(value * 760.0 / 101325.0),
+        ("inHg", "mmHg") => Some(value * 25.4),
+        ("mmHg", "inHg") => Some(value / 25.4),
+        ("inHg", "kPa") => Some(value * 25.4 * 101.325 / 760.0),
+        ("kPa", "inHg") => Some(value * 760.0 / (25.4 * 101.325)),
+        ("inHg", "atm") => Some(value * 25.4 / 760.0),
+        ("atm", "inHg") => Some(value * 760.0 / 25.4),
+        ("inHg", "Pa") => Some(value * 25.4 * 101325.0 / 760.0),
+        ("Pa", "inHg") => Some(value * 760.0 / (25.4 * 101325.0)),
         
         // Speed conversions
         ("mps", "kmph") => Some(value * 3.6),  // meters per second to km per hour
@@ -583,8 +1927,23 @@ fn convert_units(value: f64, from_unit: &str, to_unit: &str) -> Option<f64> {
         ("kmph", "mph") => Some(value / 1.60934),
         ("mph", "mps") => Some(value * 0.44704),
         ("mps", "mph") => Some(value / 0.44704),
+        // A knot is a nautical mile per hour, so both use the same 1.852
         ("knot", "kmph") => Some(value * 1.852),
         ("kmph", "knot") => Some(value / 1.852),
+        ("knot", "mps") => Some(value * 1.852 / 3.6),
+        ("mps", "knot") => Some(value * 3.6 / 1.852),
+        ("knot", "mph") => Some(value * 1.852 / 1.60934),
+        ("mph", "knot") => Some(value * 1.60934 / 1.852),
+        ("fps", "mps") => Some(value * 0.3048),
+        ("mps", "fps") => Some(value / 0.3048),
+        ("fps", "kmph") => Some(value * 1.09728),
+        ("kmph", "fps") => Some(value / 1.09728),
+        ("fps", "mph") => Some(value * 3600.0 / 5280.0),
+        ("mph", "fps") => Some(value * 5280.0 / 3600.0),
+        ("mpmin", "mps") => Some(value / 60.0),
+        ("mps", "mpmin") => Some(value * 60.0),
+        ("mpmin", "kmph") => Some(value * 0.06),
+        ("kmph", "mpmin") => Some(value / 0.06),
         
         // Same unit, no conversion needed
         (a, b) if a == b => Some(value),
@@ -595,196 +1954,529 @@ fn convert_units(value: f64, from_unit: &str, to_unit: &str) -> Option<f64> {
 }
 
 // Function to normalize unit strings - convert aliases to canonical forms
-fn normalize_unit(unit: &str) -> String {
-    use once_cell::sync::Lazy;
-    use std::collections::HashMap;
-
-    // Single, consolidated mapping of unit aliases to canonical forms
-    static UNIT_MAP: Lazy<HashMap<&'static str, &'static str>> = Lazy::new(|| {
-        let mut map = HashMap::new();
-        
-        // Special cases that need exact case preservation
-        map.insert("bit", "bit");
-        map.insert("s", "s");
-        map.insert("min", "min");
-        map.insert("h", "h");
-        map.insert("day", "day");
-        map.insert("week", "week");
-        map.insert("month", "month");
-        map.insert("year", "year");
-        map.insert("ms", "ms");
-        map.insert("us", "us");
-        map.insert("ns", "ns");
-        map.insert("b", "B");
-
-        // Data units that need uppercase
-        map.insert("kb", "KB");
-        map.insert("mb", "MB");
-        map.insert("gb", "GB");
-        map.insert("tb", "TB");
-        map.insert("pb", "PB");
-        
-        // Temperature units are uppercase
-        map.insert("c", "C");
-        map.insert("f", "F");
-        map.insert("k", "K");
-        
-        // Data units
-        map.insert("bytes", "B");
-        map.insert("kilobytes", "KB");
-        map.insert("megabytes", "MB");
-        map.insert("gigabytes", "GB");
-        map.insert("terabytes", "TB");
-        map.insert("petabytes", "PB");
-        map.insert("bits", "bit");
-        
-        // Currencies
-        map.insert("eur", "EUR");
-        map.insert("usd", "USD");
-        map.insert("gbp", "GBP");
-        map.insert("cad", "CAD");
-        map.insert("jpy", "JPY");
-        map.insert("aud", "AUD");
-        map.insert("cny", "CNY");
-        map.insert("inr", "INR");
-        
-        // Time units
-        map.insert("minute", "min");
-        map.insert("minutes", "min");
-        map.insert("mins", "min");
-        map.insert("m", "min");
-        map.insert("second", "s");
-        map.insert("seconds", "s");
-        map.insert("sec", "s");
-        map.insert("secs", "s");
-        map.insert("hour", "h");
-        map.insert("hours", "h");
-        map.insert("hr", "h");
-        map.insert("hrs", "h");
-        map.insert("millisecond", "ms");
-        map.insert("milliseconds", "ms");
-        map.insert("msec", "ms");
-        map.insert("msecs", "ms");
-        map.insert("microsecond", "us");
-        map.insert("microseconds", "us");
-        map.insert("usec", "us");
-        map.insert("usecs", "us");
-        map.insert("nanosecond", "ns");
-        map.insert("nanoseconds", "ns");
-        map.insert("nsec", "ns");
-        map.insert("nsecs", "ns");
-        map.insert("days", "day");
-        map.insert("weeks", "week");
-        map.insert("months", "month");
-        map.insert("years", "year");
-        
-        // Length units
-        map.insert("meters", "m");
-        map.insert("metre", "m");
-        map.insert("metres", "m");
-        map.insert("centimeters", "cm");
-        map.insert("centimetre", "cm");
-        map.insert("centimetres", "cm");
-        map.insert("millimeters", "mm");
-        map.insert("millimetre", "mm");
-        map.insert("millimetres", "mm");
-        map.insert("kilometers", "km");
-        map.insert("kilometre", "km");
-        map.insert("kilometres", "km");
-        map.insert("inches", "in");
-        map.insert("feet", "ft");
-        map.insert("foot", "ft");
-        map.insert("yards", "yd");
-        map.insert("miles", "mi");
-        
-        // Weight units
-        map.insert("grams", "g");
-        map.insert("kilograms", "kg");
-        map.insert("kgs", "kg");
-        map.insert("kilos", "kg");
-        map.insert("milligrams", "mg");
-        map.insert("pounds", "lb");
-        map.insert("lbs", "lb");
-        map.insert("ounces", "oz");
-        map.insert("tons", "ton");
-        map.insert("tonnes", "ton");
-        map.insert("stones", "st");
-        
-        // Volume units
-        map.insert("milliliters", "ml");
-        map.insert("millilitres", "ml");
-        map.insert("liters", "l");
-        map.insert("litres", "l");
-        map.insert("teaspoons", "tsp");
-        map.insert("tablespoons", "tbsp");
-        map.insert("cups", "cup");
-        map.insert("pints", "pt");
-        map.insert("quarts", "qt");
-        map.insert("gallons", "gal");
-        map.insert("fluid ounces", "floz");
-        map.insert("fluidounces", "floz");
-        
-        // Temperature units
-        map.insert("celsius", "C");
-        map.insert("centigrade", "C");
-        map.insert("fahrenheit", "F");
-        map.insert("kelvin", "K");
-        
-        // Energy units
-        map.insert("joules", "J");
-        map.insert("kilojoules", "kJ");
-        map.insert("calories", "cal");
-        map.insert("kilocalories", "kcal");
-        map.insert("kcals", "kcal");
-        map.insert("kilowatt hours", "kWh");
-        map.insert("kilowatt-hours", "kWh");
-        map.insert("electron volts", "eV");
-        
-        // Power units
-        map.insert("watts", "W");
-        map.insert("kilowatts", "kW");
-        map.insert("megawatts", "MW");
-        map.insert("horsepower", "hp");
-        
-        // Pressure units
-        map.insert("pascals", "Pa");
-        map.insert("kilopascals", "kPa");
-        map.insert("bars", "bar");
-        map.insert("pounds per square inch", "psi");
-        map.insert("atmospheres", "atm");
-        
-        // Speed units
-        map.insert("meters per second", "mps");
-        map.insert("metres per second", "mps");
-        map.insert("kilometers per hour", "kmph");
-        map.insert("kilometres per hour", "kmph");
-        map.insert("kph", "kmph");
-        map.insert("miles per hour", "mph");
-        map.insert("knots", "knot");
-        
-        map
-    });
+// Single, consolidated mapping of unit aliases to canonical forms
+static UNIT_MAP: Lazy<HashMap<&'static str, &'static str>> = Lazy::new(|| {
+    let mut map = HashMap::new();
+    
+    // Special cases that need exact case preservation
+    map.insert("bit", "bit");
+    map.insert("s", "s");
+    map.insert("min", "min");
+    map.insert("h", "h");
+    map.insert("day", "day");
+    map.insert("week", "week");
+    map.insert("month", "month");
+    map.insert("year", "year");
+    map.insert("ms", "ms");
+    map.insert("us", "us");
+    map.insert("ns", "ns");
+    map.insert("b", "B");
+
+    // Data units that need uppercase
+    map.insert("kb", "KB");
+    map.insert("mb", "MB");
+    map.insert("gb", "GB");
+    map.insert("tb", "TB");
+    map.insert("pb", "PB");
+    
+    // Temperature units are uppercase
+    map.insert("c", "C");
+    map.insert("f", "F");
+    map.insert("k", "K");
+    
+    // Data units
+    map.insert("bytes", "B");
+    map.insert("kilobytes", "KB");
+    map.insert("megabytes", "MB");
+    map.insert("gigabytes", "GB");
+    map.insert("terabytes", "TB");
+    map.insert("petabytes", "PB");
+    map.insert("bits", "bit");
+    
+    // Currencies
+    map.insert("eur", "EUR");
+    map.insert("usd", "USD");
+    map.insert("gbp", "GBP");
+    map.insert("cad", "CAD");
+    map.insert("jpy", "JPY");
+    map.insert("aud", "AUD");
+    map.insert("cny", "CNY");
+    map.insert("inr", "INR");
+    
+    // Time units
+    map.insert("minute", "min");
+    map.insert("minutes", "min");
+    map.insert("mins", "min");
+    map.insert("second", "s");
+    map.insert("seconds", "s");
+    map.insert("sec", "s");
+    map.insert("secs", "s");
+    map.insert("hour", "h");
+    map.insert("hours", "h");
+    map.insert("hr", "h");
+    map.insert("hrs", "h");
+    map.insert("millisecond", "ms");
+    map.insert("milliseconds", "ms");
+    map.insert("msec", "ms");
+    map.insert("msecs", "ms");
+    map.insert("microsecond", "us");
+    map.insert("microseconds", "us");
+    map.insert("usec", "us");
+    map.insert("usecs", "us");
+    map.insert("nanosecond", "ns");
+    map.insert("nanoseconds", "ns");
+    map.insert("nsec", "ns");
+    map.insert("nsecs", "ns");
+    map.insert("days", "day");
+    map.insert("weeks", "week");
+    map.insert("months", "month");
+    map.insert("years", "year");
+    
+    // Length units
+    map.insert("meters", "m");
+    map.insert("metre", "m");
+    map.insert("metres", "m");
+    map.insert("centimeters", "cm");
+    map.insert("centimetre", "cm");
+    map.insert("centimetres", "cm");
+    map.insert("millimeters", "mm");
+    map.insert("millimetre", "mm");
+    map.insert("millimetres", "mm");
+    map.insert("kilometers", "km");
+    map.insert("kilometre", "km");
+    map.insert("kilometres", "km");
+    map.insert("inches", "in");
+    map.insert("feet", "ft");
+    map.insert("foot", "ft");
+    map.insert("yards", "yd");
+    map.insert("miles", "mi");
+    map.insert("nmi", "nmi");
+    map.insert("nauticalmiles", "nmi");
+    map.insert("furlong", "furlong");
+    map.insert("furlongs", "furlong");
+    map.insert("fathom", "fathom");
+    map.insert("fathoms", "fathom");
+    map.insert("hand", "hand");
+    map.insert("hands", "hand");
+    map.insert("mil", "mil");
+    map.insert("mils", "mil");
+    map.insert("thou", "mil");
+    map.insert("au", "au");
+    map.insert("ly", "ly");
+    map.insert("lightyear", "ly");
+    map.insert("lightyears", "ly");
+    
+    // Weight units
+    map.insert("grams", "g");
+    map.insert("kilograms", "kg");
+    map.insert("kgs", "kg");
+    map.insert("kilos", "kg");
+    map.insert("milligrams", "mg");
+    map.insert("pounds", "lb");
+    map.insert("lbs", "lb");
+    map.insert("ounces", "oz");
+    map.insert("ton", "ton");
+    map.insert("tons", "ton");
+    map.insert("tonnes", "ton");
+    map.insert("stones", "st");
+    map.insert("stick", "stick");
+    map.insert("sticks", "stick");
+    map.insert("grain", "grain");
+    map.insert("grains", "grain");
+    map.insert("gr", "grain");
+    map.insert("ct", "ct");
+    map.insert("carat", "ct");
+    map.insert("carats", "ct");
+    
+    // Volume units
+    map.insert("milliliters", "ml");
+    map.insert("millilitres", "ml");
+    map.insert("liters", "l");
+    map.insert("litres", "l");
+    map.insert("tsp", "tsp"); // Three letters would otherwise read as a currency code
+    map.insert("gal", "gal");
+    map.insert("cup", "cup");
+    map.insert("teaspoons", "tsp");
+    map.insert("tablespoons", "tbsp");
+    map.insert("cups", "cup");
+    map.insert("pints", "pt");
+    map.insert("quarts", "qt");
+    map.insert("gallons", "gal");
+    map.insert("fluid ounces", "floz");
+    map.insert("fluidounces", "floz");
+    map.insert("dash", "dash");
+    map.insert("dashes", "dash");
+    map.insert("pinch", "pinch");
+    map.insert("pinches", "pinch");
+    map.insert("fldr", "fldr");
+    map.insert("fluiddrams", "fldr");
+    map.insert("drams", "fldr");
+    map.insert("dram", "fldr");
+    
+    // Temperature units
+    map.insert("celsius", "C");
+    map.insert("centigrade", "C");
+    map.insert("fahrenheit", "F");
+    map.insert("kelvin", "K");
+    
+    // Energy, power and pressure units. Aliases are looked up lowercased, so the usual
+    // spellings (kWh, kPa) need entries of their own, and `mw` can only be one of megawatt
+    // and milliwatt: it's megawatt.
+    map.insert("j", "J");
+    map.insert("kj", "kJ");
+    map.insert("cal", "cal");
+    map.insert("kcal", "kcal");
+    map.insert("ev", "eV");
+    map.insert("wh", "Wh");
+    map.insert("kwh", "kWh");
+    map.insert("mwh", "MWh");
+    map.insert("btu", "BTU");
+    map.insert("therm", "therm");
+    map.insert("w", "W");
+    map.insert("kw", "kW");
+    map.insert("mw", "MW");
+    map.insert("hp", "hp");
+    map.insert("pa", "Pa");
+    map.insert("kpa", "kPa");
+    map.insert("bar", "bar");
+    map.insert("psi", "psi");
+    map.insert("atm", "atm");
+    map.insert("mmhg", "mmHg");
+    map.insert("torr", "mmHg");
+    map.insert("inhg", "inHg");
+    
+    // Energy units
+    map.insert("joules", "J");
+    map.insert("kilojoules", "kJ");
+    map.insert("calories", "cal");
+    map.insert("kilocalories", "kcal");
+    map.insert("kcals", "kcal");
+    map.insert("kilowatt hours", "kWh");
+    map.insert("kilowatt-hours", "kWh");
+    map.insert("electron volts", "eV");
+    map.insert("watthours", "Wh");
+    map.insert("btus", "BTU");
+    map.insert("therms", "therm");
+    
+    // Power units
+    map.insert("watts", "W");
+    map.insert("kilowatts", "kW");
+    map.insert("megawatts", "MW");
+    map.insert("horsepower", "hp");
+    
+    // Pressure units
+    map.insert("pascals", "Pa");
+    map.insert("kilopascals", "kPa");
+    map.insert("bars", "bar");
+    map.insert("pounds per square inch", "psi");
+    map.insert("atmospheres", "atm");
+    
+    // Speed units. Their canonical names are listed too, or `mph` would read as a currency
+    // code and `mps` as the plural of `mp`.
+    map.insert("mps", "mps");
+    map.insert("kmph", "kmph");
+    map.insert("mph", "mph");
+    map.insert("knot", "knot");
+    map.insert("fps", "fps");
+    map.insert("mpmin", "mpmin");
+    map.insert("meters per second", "mps");
+    map.insert("metres per second", "mps");
+    map.insert("kilometers per hour", "kmph");
+    map.insert("kilometres per hour", "kmph");
+    map.insert("kph", "kmph");
+    map.insert("miles per hour", "mph");
+    map.insert("knots", "knot");
+    map.insert("kn", "knot");
+    map.insert("kts", "knot");
+    
+    map
+});
+
+// What each kind of unit measures, as error messages put it, with the units a conversion can
+// target, the most common first. The units are used for suggestions, so only ones that appear
+// in the conversion table are listed, and the specialist ones (nautical miles, light-years,
+// carats) are left out to keep the list short.
+const DIMENSIONS: &[(&str, &[&str])] = &[
+    ("a length", &["mi", "km", "m", "cm", "mm", "ft", "in", "yd"]),
+    ("an area", &["m2", "cm2", "km2", "ha", "acre", "mi2"]),
+    ("a volume", &["l", "ml", "gal", "cup", "floz", "pt", "qt", "tsp", "tbsp", "m3", "ft3"]),
+    ("a mass", &["kg", "g", "mg", "lb", "oz", "st", "ton"]),
+    ("a temperature", &["C", "F", "K"]),
+    ("a duration", &["h", "min", "s", "ms", "us", "ns", "day", "week", "month", "year", "decade", "century"]),
+    ("an amount of data", &["B", "bit", "KB", "MB", "GB", "TB", "PB"]),
+    ("an amount of energy", &["J", "kJ", "cal", "kcal", "kWh", "Wh", "MWh", "BTU", "therm", "eV"]),
+    ("a power", &["W", "kW", "MW", "hp"]),
+    ("a pressure", &["Pa", "kPa", "bar", "psi", "atm", "mmHg", "inHg"]),
+    ("a speed", &["mps", "kmph", "mph", "knot", "fps", "mpmin"]),
+];
+
+fn conversion_target_units() -> impl Iterator<Item = &'static str> {
+    DIMENSIONS.iter().flat_map(|(_, units)| units.iter().copied())
+}
+
+// What a unit measures ("a mass" for lb), if it's a currency or converts to one of the
+// units above. Custom units count as whatever they're defined in.
+fn dimension(unit: &str) -> Option<&'static str> {
+    let unit = normalize_unit(unit);
+    if is_currency_code(&unit) {
+        return Some("an amount of money");
+    }
+    DIMENSIONS.iter()
+        .find(|(_, units)| units.iter().any(|&known| known == unit || convert_units(1.0, &unit, known).is_some()))
+        .map(|&(name, _)| name)
+}
+
+// A bare `m` is meters; only inside a duration like 1h30m is it minutes. Meters mixed
+// with a duration were most likely meant as minutes, so the error says how to write them.
+fn minutes_hint(amount: f64, unit: &str, other: &str) -> String {
+    if unit == "m" && dimension(other) == Some("a duration") {
+        format!("; for minutes write {}min", format_decimal(amount))
+    } else {
+        String::new()
+    }
+}
+
+// A unit as error messages name it: "a mass (kg)", or just "apples" when it isn't known
+fn describe_unit(unit: &str) -> String {
+    if let Some((_, period)) = split_rate(unit) {
+        return format!("an amount per {period} ({unit})");
+    }
+    match dimension(unit) {
+        Some(name) => format!("{name} ({unit})"),
+        None => unit.to_string(),
+    }
+}
+
+// Why `a op b` can't be worked out, with the operation in words
+fn cannot(op: &Op, a: &str, b: &str) -> Value {
+    Value::Error(match op {
+        Op::Add => format!("Can't add {b} to {a}"),
+        Op::Subtract => format!("Can't subtract {b} from {a}"),
+        Op::Multiply => format!("Can't multiply {a} by {b}"),
+        Op::Divide => format!("Can't divide {a} by {b}"),
+        Op::Modulo => format!("Can't take the remainder of {a} divided by {b}"),
+        Op::Power => format!("Can't raise {a} to the power of {b}"),
+    })
+}
+
+// For functions given values in units that don't convert into each other
+fn cannot_mix(unit_a: &str, unit_b: &str) -> String {
+    format!("Can't mix {} and {}", describe_unit(unit_a), describe_unit(unit_b))
+}
+
+// Currencies suggested as conversion targets
+const COMMON_CURRENCIES: &[&str] = &["USD", "EUR", "GBP", "JPY", "CAD", "AUD", "CNY", "INR"];
+
+// The units a value in `unit` can be converted to whose name or one of its aliases starts
+// with `prefix` (case-insensitive), most common first
+pub fn conversion_targets(unit: &str, prefix: &str) -> Vec<&'static str> {
+    let source = normalize_unit(unit);
+    let prefix = prefix.to_lowercase();
+    let matches_prefix = |target: &str| {
+        target.to_lowercase().starts_with(&prefix)
+            || UNIT_MAP.iter().any(|(alias, &canonical)| canonical == target && alias.starts_with(&prefix))
+    };
+    
+    // Currency rates may have to be fetched, so currencies are never tried here
+    if is_currency_code(&source) {
+        return COMMON_CURRENCIES.iter()
+            .copied()
+            .filter(|&target| target != source && matches_prefix(target))
+            .collect();
+    }
+    conversion_target_units()
+        .filter(|&target| target != source && convert_units(1.0, &source, target).is_some() && matches_prefix(target))
+        .collect()
+}
+
+// Units defined in the sheet with `defunit`: name -> (factor, unit), meaning
+// 1 name = factor unit. Per thread like the locale, so tests don't share units.
+thread_local! {
+    static CUSTOM_UNITS: RefCell<HashMap<String, (f64, String)>> = RefCell::new(HashMap::new());
+    // Bumped on every change, so parses that depended on the old units can be told apart
+    static CUSTOM_UNITS_VERSION: Cell<u64> = const { Cell::new(0) };
+}
+
+// Longest chain of custom units defined in terms of each other (a = 2 b, b = 3 c, ...)
+const MAX_CUSTOM_UNIT_DEPTH: usize = 16;
+
+// Register or update a custom unit. Built-in units and currencies can't be redefined.
+pub fn define_unit(name: &str, factor: f64, unit: &str) -> Result<(), String> {
+    if is_builtin_unit_name(name) || conversion_target_units().any(|unit| unit == name) {
+        return Err(format!("'{name}' is a built-in unit"));
+    }
+    if is_currency_code(name) {
+        return Err(format!("'{name}' is a currency code"));
+    }
+    if name == unit {
+        return Err(format!("'{name}' can't be defined in terms of itself"));
+    }
+    if factor == 0.0 || !factor.is_finite() {
+        return Err(format!("1 {name} can't be {factor} {unit}"));
+    }
+    CUSTOM_UNITS.with(|units| units.borrow_mut().insert(name.to_string(), (factor, unit.to_string())));
+    CUSTOM_UNITS_VERSION.with(|version| version.set(version.get() + 1));
+    Ok(())
+}
+
+// Forget every custom unit, before they are registered again from the sheet
+pub fn clear_custom_units() {
+    CUSTOM_UNITS.with(|units| units.borrow_mut().clear());
+    CUSTOM_UNITS_VERSION.with(|version| version.set(version.get() + 1));
+}
+
+// Run f with the given custom units installed, putting the previous ones back afterwards.
+// Units f defines are kept in `units`, so a Calculator keeps its own.
+pub fn with_custom_units<T>(units: &mut HashMap<String, (f64, String)>, f: impl FnOnce() -> T) -> T {
+    let previous = CUSTOM_UNITS.with(|current| current.replace(std::mem::take(units)));
+    CUSTOM_UNITS_VERSION.with(|version| version.set(version.get() + 1));
+    let result = f();
+    *units = CUSTOM_UNITS.with(|current| current.replace(previous));
+    CUSTOM_UNITS_VERSION.with(|version| version.set(version.get() + 1));
+    result
+}
+
+pub fn custom_units_version() -> u64 {
+    CUSTOM_UNITS_VERSION.with(Cell::get)
+}
+
+fn custom_unit(name: &str) -> Option<(f64, String)> {
+    CUSTOM_UNITS.with(|units| units.borrow().get(name).cloned())
+}
+
+fn is_custom_unit(name: &str) -> bool {
+    CUSTOM_UNITS.with(|units| units.borrow().contains_key(name))
+}
+
+// Follow custom units down to a built-in (or unknown) unit: 3 sprint -> (2, week) per sprint.
+// None for a cycle of definitions.
+fn resolve_custom_unit(unit: &str) -> Option<(f64, String)> {
+    let mut factor = 1.0;
+    let mut unit = unit.to_string();
+    for _ in 0..MAX_CUSTOM_UNIT_DEPTH {
+        match custom_unit(&unit) {
+            Some((unit_factor, base)) => {
+                factor *= unit_factor;
+                unit = normalize_unit(&base).into_owned();
+            }
+            None => return Some((factor, unit)),
+        }
+    }
+    None
+}
+
+fn is_builtin_unit_name(name: &str) -> bool {
+    UNIT_MAP.contains_key(name.to_lowercase().as_str()) || UNIT_MAP.values().any(|&unit| unit == name)
+}
+
+// Whether a name is a unit alias or a canonical unit name, e.g. `m`, `s` or `miles`,
+// including units defined with `defunit`
+pub fn is_unit_name(name: &str) -> bool {
+    is_builtin_unit_name(name) || custom_unit(name).is_some()
+}
 
+// The canonical name of a unit: `Miles` -> `mi`, `usd` -> `USD`, `apples` -> `apple`. It's
+// looked up for every unit of every line, so built-in units and real currencies come back
+// borrowed from their tables rather than allocated each time.
+fn normalize_unit(unit: &str) -> Cow<'static, str> {
     let original = unit.trim();
-    let lowercase = original.to_lowercase();
+    if is_custom_unit(original) {
+        return Cow::Owned(original.to_string());
+    }
+    if let Some((amount, period)) = split_rate(original) {
+        return Cow::Owned(format!("{}/{}", normalize_unit(amount), period));
+    }
+    let lowercase = if original.chars().any(char::is_uppercase) {
+        Cow::Owned(original.to_lowercase())
+    } else {
+        Cow::Borrowed(original)
+    };
     
     // First try the map lookup which includes all special cases
-    if let Some(canonical) = UNIT_MAP.get(lowercase.as_str()) {
-        return (*canonical).to_string();
+    if let Some(canonical) = UNIT_MAP.get(lowercase.as_ref()) {
+        return Cow::Borrowed(canonical);
     }
     
     // Special case for currency detection (3-letter uppercase codes)
     if lowercase.len() == 3 && lowercase.chars().all(|c| c.is_ascii_alphabetic()) {
-        return lowercase.to_uppercase();
+        return match crate::currency::currency_code(&lowercase) {
+            Some(code) => Cow::Borrowed(code),
+            None => Cow::Owned(lowercase.to_uppercase()),
+        };
     }
     
-    // If no match, return the original lowercase
-    lowercase
+    // Unknown units are ad-hoc counting units, where `apples` and `apple` are the same thing
+    Cow::Owned(singular(&lowercase))
+}
+
+// How many of each period a rate can be per fit in a year. A month is exactly a twelfth of
+// a year, the way subscriptions are billed, rather than the 30.44 days of `1 month in days`.
+const RATE_PERIODS: &[(&str, f64)] = &[
+    ("year", 1.0),
+    ("month", 12.0),
+    ("week", 365.25 / 7.0),
+    ("day", 365.25),
+    ("h", 365.25 * 24.0),
+];
+
+fn periods_per_year(period: &str) -> Option<f64> {
+    RATE_PERIODS.iter().find(|(name, _)| *name == period).map(|(_, count)| *count)
+}
+
+// The period a time unit names, for the `month` of `per month`: months -> month, hour -> h
+pub fn rate_period(word: &str) -> Option<&'static str> {
+    let unit = UNIT_MAP.get(word.trim().to_lowercase().as_str()).copied()?;
+    RATE_PERIODS.iter().find(|(name, _)| *name == unit).map(|(name, _)| *name)
+}
+
+// The period of `monthly`, `yearly`, `weekly`, `daily` or `hourly`
+pub fn period_adverb(word: &str) -> Option<&'static str> {
+    match word.to_lowercase().as_str() {
+        "yearly" | "annually" => Some("year"),
+        "monthly" => Some("month"),
+        "weekly" => Some("week"),
+        "daily" => Some("day"),
+        "hourly" => Some("h"),
+        _ => None,
+    }
+}
+
+// The amount's unit and the period of a rate like `USD/month`
+fn split_rate(unit: &str) -> Option<(&str, &'static str)> {
+    let (amount, period) = unit.rsplit_once('/')?;
+    Some((amount.trim(), rate_period(period)?))
+}
+
+// The rate a conversion target asks for: `per month` or `monthly` name only the period,
+// `EUR per month` the amount's unit too
+fn target_rate(target: &str) -> Option<(Option<&str>, &'static str)> {
+    let target = target.trim();
+    if let Some(period) = period_adverb(target) {
+        return Some((None, period));
+    }
+    let words: Vec<&str> = target.split_whitespace().collect();
+    match words.as_slice() {
+        [per, period] if per.eq_ignore_ascii_case("per") => Some((None, rate_period(period)?)),
+        [amount, per, period] if per.eq_ignore_ascii_case("per") => Some((Some(amount), rate_period(period)?)),
+        _ => None,
+    }
+}
+
+// The singular of an unknown unit: apples -> apple, boxes -> box, glasses -> glass
+fn singular(unit: &str) -> String {
+    if let Some(stem) = unit.strip_suffix("es")
+        && ["x", "ch", "sh", "ss", "z"].iter().any(|ending| stem.ends_with(ending))
+    {
+        return stem.to_string();
+    }
+    match unit.strip_suffix('s') {
+        Some(stem) if !stem.is_empty() && !stem.ends_with('s') => stem.to_string(),
+        _ => unit.to_string(),
+    }
 }
 
 // Evaluate a list of expressions and return formatted results
-#[allow(dead_code)]
-pub fn evaluate_lines(lines: &[String], variables: &mut HashMap<String, Value>) -> Vec<String> {
+pub fn evaluate_lines(lines: &[String], variables: &mut HashMap<String, Value>, context: &Context) -> Vec<String> {
     lines.iter()
         .map(|line| {
             let trimmed = line.trim();
@@ -794,8 +2486,8 @@ pub fn evaluate_lines(lines: &[String], variables: &mut HashMap<String, Value>)
                 // Return an empty string for comment lines
                 String::new()
             } else {
-                let expr = crate::parser::parse_line(line, variables);
-                let result = evaluate(&expr, variables);
+                let expr = crate::parser::parse_line(line);
+                let result = evaluate(&expr, variables, context);
                 if let Value::Assignment(name, value) = &result {
                     // Store the variable for future use
                     variables.insert(name.clone(), (**value).clone());
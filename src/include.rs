@@ -0,0 +1,249 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use crate::evaluator::{evaluate, Context, Value};
+use crate::parser::Expr;
+
+// Includes inside included files, at most
+const MAX_DEPTH: usize = 8;
+
+// What an `include` line brought in: the definitions of the file, in order, and the first
+// line that failed, if any. The definitions that worked are kept either way.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Included {
+    pub definitions: Vec<(String, Value)>,
+    pub units: Vec<(String, f64, String)>, // With `collect_units`, the file's `defunit` lines
+    pub rates: usize,                      // How many lines set an exchange rate
+    pub error: Option<String>,
+}
+
+// How to load a file. An `include` line takes what it can from a file with a line that
+// fails; a `--preload` file stops there, and hands its units on for the app to define
+// again whenever it registers the sheet's.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LoadOptions {
+    pub stop_at_error: bool,
+    pub collect_units: bool,
+}
+
+// The path of an `include ~/cali/constants.cali` line
+pub fn directive(line: &str) -> Option<&str> {
+    let line = line.trim();
+    let (word, path) = line.split_once(char::is_whitespace)?;
+    let path = path.trim();
+    // `include = 5` assigns a variable
+    (word.eq_ignore_ascii_case("include") && !path.starts_with('=')).then_some(path)
+}
+
+// A path typed into a sheet, a prompt or the command line: `~` and `~user` are home
+// directories, and a relative path is relative to the sheet's own directory if given
+pub fn resolve(path: &str, base_dir: Option<&Path>) -> PathBuf {
+    let path = expand_home(path);
+    match base_dir {
+        Some(dir) if path.is_relative() => dir.join(path),
+        _ => path,
+    }
+}
+
+// A leading `~` or `~user` replaced by that home directory. A user we can't find leaves
+// the path as it is, as the shell does.
+fn expand_home(path: &str) -> PathBuf {
+    let Some(rest) = path.strip_prefix('~') else { return PathBuf::from(path) };
+    let (user, rest) = rest.split_once('/').unwrap_or((rest, ""));
+    let home = match user {
+        "" => std::env::var_os("HOME").map(PathBuf::from),
+        user => home_of(user),
+    };
+    match home {
+        Some(home) if rest.is_empty() => home,
+        Some(home) => home.join(rest),
+        None => PathBuf::from(path),
+    }
+}
+
+// The home directory of another user, from the password database
+#[cfg(unix)]
+fn home_of(user: &str) -> Option<PathBuf> {
+    use std::ffi::{CStr, CString, OsStr};
+    use std::os::unix::ffi::OsStrExt;
+
+    let name = CString::new(user).ok()?;
+    let mut buffer = vec![0 as libc::c_char; 16 * 1024];
+    let mut entry = std::mem::MaybeUninit::<libc::passwd>::uninit();
+    let mut found = std::ptr::null_mut();
+    // SAFETY: every pointer is valid for the call and the buffer's length is passed along;
+    // `entry` is only read once getpwnam_r says it filled it in
+    let status = unsafe { libc::getpwnam_r(name.as_ptr(), entry.as_mut_ptr(), buffer.as_mut_ptr(), buffer.len(), &mut found) };
+    if status != 0 || found.is_null() {
+        return None;
+    }
+    let dir = unsafe { entry.assume_init() }.pw_dir;
+    if dir.is_null() {
+        return None;
+    }
+    // SAFETY: pw_dir points into `buffer`, which is still alive, and ends with a NUL
+    let dir = unsafe { CStr::from_ptr(dir) };
+    Some(PathBuf::from(OsStr::from_bytes(dir.to_bytes())))
+}
+
+#[cfg(not(unix))]
+fn home_of(_user: &str) -> Option<PathBuf> {
+    None
+}
+
+// A file that was read, and when it had last been modified
+pub type Read = (PathBuf, Option<SystemTime>);
+
+// Included files, read again only once one of the files involved has been modified
+#[derive(Default)]
+pub struct IncludeCache {
+    files: HashMap<PathBuf, (Vec<Read>, Included)>,
+}
+
+impl IncludeCache {
+    pub fn load(&mut self, path: &Path, context: &Context) -> Included {
+        if let Some((read, included)) = self.files.get(path)
+            && read.iter().all(|(file, modified)| modified_time(file) == *modified)
+        {
+            return included.clone();
+        }
+        let mut read = Vec::new();
+        let included = load_file(path, HashMap::new(), LoadOptions::default(), context, &mut Vec::new(), &mut read);
+        self.files.insert(path.to_path_buf(), (read, included.clone()));
+        included
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+// Evaluate a file's lines in a scope of their own, which starts out as `scope`, and collect
+// what they define. `within` holds the files including this one, to catch cycles; `read`
+// gathers every file looked at.
+pub fn load_file(
+    path: &Path,
+    scope: HashMap<String, Value>,
+    options: LoadOptions,
+    context: &Context,
+    within: &mut Vec<PathBuf>,
+    read: &mut Vec<Read>,
+) -> Included {
+    let name = path.file_name().map_or_else(|| path.display().to_string(), |name| name.to_string_lossy().into_owned());
+    let failed = |error: String| Included { error: Some(error), ..Included::default() };
+    read.push((path.to_path_buf(), modified_time(path)));
+
+    let canonical = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    if within.contains(&canonical) {
+        return failed(format!("{name} includes itself"));
+    }
+    if within.len() == MAX_DEPTH {
+        return failed(format!("Includes are nested more than {MAX_DEPTH} deep"));
+    }
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return failed(format!("Can't find {}", path.display())),
+        Err(e) => return failed(format!("Can't read {name}: {e}")),
+    };
+
+    within.push(canonical);
+    let mut variables = scope;
+    let mut included = Included::default();
+    for (line_idx, line) in text.lines().enumerate() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        let error = if let Some(nested) = directive(line) {
+            let nested = load_file(&resolve(nested, path.parent()), HashMap::new(), options, context, within, read);
+            for (name, value) in nested.definitions {
+                variables.insert(name.clone(), value.clone());
+                included.definitions.push((name, value));
+            }
+            included.units.extend(nested.units);
+            included.rates += nested.rates;
+            nested.error
+        } else {
+            let expr = crate::parser::parse_line(line);
+            match evaluate(&expr, &mut variables, context) {
+                Value::Assignment(name, value) => match *value {
+                    Value::Error(msg) => Some(msg),
+                    value => {
+                        variables.insert(name.clone(), value.clone());
+                        included.definitions.push((name, value));
+                        None
+                    },
+                },
+                Value::Error(msg) => Some(msg),
+                Value::Rate(..) => {
+                    included.rates += 1;
+                    None
+                },
+                _ => {
+                    if let Expr::DefineUnit(unit, factor, base) = expr
+                        && options.collect_units
+                    {
+                        included.units.push((unit, factor, base));
+                    }
+                    None
+                },
+            }
+        };
+        if included.error.is_none()
+            && let Some(error) = error
+        {
+            included.error = Some(format!("{name} line {}: {error}", line_idx + 1));
+            if options.stop_at_error {
+                break;
+            }
+        }
+    }
+    within.pop();
+    included
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_include_paths_and_cycles() {
+        assert_eq!(directive("include constants.cali"), Some("constants.cali"));
+        assert_eq!(directive("  Include  ~/my constants.cali "), Some("~/my constants.cali"));
+        assert_eq!(directive("included = 5"), None);
+        assert_eq!(directive("include = 5"), None);
+        assert_eq!(directive("include"), None);
+
+        let base = Path::new("/sheets");
+        assert_eq!(resolve("rates.cali", Some(base)), PathBuf::from("/sheets/rates.cali"));
+        assert_eq!(resolve("/etc/rates.cali", Some(base)), PathBuf::from("/etc/rates.cali"));
+        if let Some(home) = std::env::var_os("HOME") {
+            assert_eq!(resolve("~/rates.cali", Some(base)), PathBuf::from(&home).join("rates.cali"));
+            assert_eq!(resolve("~", None), PathBuf::from(&home));
+        }
+        assert_eq!(resolve("~no-such-user-here/rates.cali", None), PathBuf::from("~no-such-user-here/rates.cali"));
+        assert_eq!(resolve("notes/~draft.cali", None), PathBuf::from("notes/~draft.cali"));
+
+        let dir = crate::temp_dir::TempDir::new("include");
+        fs::write(dir.join("a.cali"), "a = 1\ninclude b.cali\n").unwrap();
+        fs::write(dir.join("b.cali"), "b = 2\ninclude a.cali\n").unwrap();
+        let included = IncludeCache::default().load(&dir.join("a.cali"), &Context::default());
+        assert_eq!(included.definitions, vec![("a".to_string(), Value::Number(1.0)), ("b".to_string(), Value::Number(2.0))]);
+        assert_eq!(included.error.as_deref(), Some("a.cali line 2: b.cali line 2: a.cali includes itself"));
+
+        // Read again only when a file involved was modified
+        let mut cache = IncludeCache::default();
+        let path = dir.join("c.cali");
+        fs::write(&path, "c = 1\ninclude d.cali\n").unwrap();
+        fs::write(dir.join("d.cali"), "d = 1\n").unwrap();
+        let loaded = cache.load(&path, &Context::default());
+        let set_modified = |file: &str, time: SystemTime| fs::File::options().write(true).open(dir.join(file)).unwrap().set_modified(time).unwrap();
+        let then = modified_time(&dir.join("d.cali")).unwrap();
+        fs::write(dir.join("d.cali"), "d = 2\n").unwrap();
+        set_modified("d.cali", then);
+        assert_eq!(cache.load(&path, &Context::default()), loaded);
+        set_modified("d.cali", then + std::time::Duration::from_secs(1));
+        assert_eq!(cache.load(&path, &Context::default()).definitions[1], ("d".to_string(), Value::Number(2.0)));
+    }
+}
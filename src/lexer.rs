@@ -0,0 +1,184 @@
+// Shared tokenizer used by both the syntax highlighter and the parser, so the two
+// always agree on where tokens start and end
+
+// Words with a fixed meaning in expressions. `in` is also the inch unit, which callers
+// disambiguate from context (a word directly after a number is a unit).
+pub const KEYWORDS: &[&str] = &["to", "in", "of", "what", "is", "next", "mod"];
+
+// Words used by date expressions (next friday + 2 weeks, this monday, today + 1 month,
+// first monday of september)
+pub const DATE_WORDS: &[&str] = &[
+    "today", "this", "monday", "tuesday", "wednesday", "thursday", "friday", "saturday", "sunday",
+    "day", "days", "week", "weeks", "month", "months",
+    "first", "second", "third", "fourth", "fifth", "last",
+    "january", "february", "march", "april", "may", "june",
+    "july", "august", "september", "october", "november", "december",
+];
+
+// Filler words of natural-language phrases (how many oz in 2 kg, convert 5 kg to lb,
+// 25% off 80, 18% tip on 84.50, 99.71 split 4 ways). Unlike keywords they can still be
+// variable names.
+pub const PHRASE_WORDS: &[&str] = &[
+    "how", "many", "until", "since", "convert", "off", "discount", "tip", "split", "ways", "between",
+];
+
+// Units written in three capitals, which would otherwise read as currency codes
+const UPPERCASE_UNITS: &[&str] = &["BTU"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TokenKind {
+    Number,     // 42, 3.14
+    Word,       // identifiers, units, currencies and keywords
+    Operator,   // + - * / ^ = %
+    Bracket,    // ( ) [ ] { }
+    Comment,    // # to the end of the line
+    Whitespace,
+    Unknown,    // anything else, e.g. currency symbols in results
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Token<'a> {
+    pub kind: TokenKind,
+    pub text: &'a str,
+    pub start: usize, // Byte offset of the token in the line
+}
+
+pub fn is_keyword(word: &str) -> bool {
+    KEYWORDS.contains(&word)
+}
+
+pub fn is_phrase_word(word: &str) -> bool {
+    PHRASE_WORDS.contains(&word.to_lowercase().as_str())
+}
+
+pub fn is_date_word(word: &str) -> bool {
+    DATE_WORDS.contains(&word.to_lowercase().as_str())
+}
+
+// Three uppercase ASCII letters, e.g. USD or EUR
+pub fn is_currency_code(word: &str) -> bool {
+    word.len() == 3 && word.chars().all(|c| c.is_ascii_uppercase()) && !UPPERCASE_UNITS.contains(&word)
+}
+
+fn is_word_start(c: char) -> bool {
+    c.is_alphabetic() || c == '_'
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+// Split a line into tokens in a single left-to-right pass. Every byte of the input
+// belongs to exactly one token, so the tokens can be concatenated back into the line.
+pub fn tokenize(line: &str) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    let mut chars = line.char_indices().peekable();
+
+    while let Some(&(start, c)) = chars.peek() {
+        let kind = if c == '#' {
+            // A comment swallows the rest of the line
+            while chars.next().is_some() {}
+            TokenKind::Comment
+        } else if c.is_whitespace() {
+            while chars.next_if(|&(_, c)| c.is_whitespace()).is_some() {}
+            TokenKind::Whitespace
+        } else if c.is_ascii_digit() {
+            // Decimal and group separators depend on the locale (1,234.5 or 1.234,5)
+            let (len, _) = crate::locale::scan_number(&line[start..]);
+            while chars.next_if(|&(i, _)| i < start + len).is_some() {}
+            TokenKind::Number
+        } else if is_word_start(c) {
+            while chars.next_if(|&(_, c)| is_word_char(c)).is_some() {}
+            TokenKind::Word
+        } else {
+            chars.next();
+            match c {
+                // `**` is Python's way of writing `^`
+                '*' if chars.next_if(|&(_, c)| c == '*').is_some() => TokenKind::Operator,
+                '+' | '-' | '*' | '/' | '^' | '=' | '%' => TokenKind::Operator,
+                '(' | ')' | '[' | ']' | '{' | '}' => TokenKind::Bracket,
+                _ => TokenKind::Unknown,
+            }
+        };
+
+        let end = chars.peek().map_or(line.len(), |&(i, _)| i);
+        tokens.push(Token { kind, text: &line[start..end], start });
+    }
+
+    tokens
+}
+
+// Find the longest variable name starting at `tokens[start]`. Names can span several
+// words ("monthly rent"), which are matched joined by single spaces, so any amount of
+// whitespace may separate them. Returns the name and the index just past its last word.
+pub fn longest_name(tokens: &[Token], start: usize, is_name: impl Fn(&str) -> bool) -> Option<(String, usize)> {
+    let mut name = String::new();
+    let mut candidates = Vec::new();
+    let mut i = start;
+
+    while let Some(token) = tokens.get(i) {
+        if token.kind != TokenKind::Word || is_keyword(token.text) {
+            break;
+        }
+        if !name.is_empty() {
+            name.push(' ');
+        }
+        name.push_str(token.text);
+        i += 1;
+        candidates.push((name.clone(), i));
+        while tokens.get(i).is_some_and(|t| t.kind == TokenKind::Whitespace) {
+            i += 1;
+        }
+    }
+
+    // Longest first, asking about no more names than it has to
+    candidates.into_iter().rev().find(|(name, _)| is_name(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(line: &str) -> Vec<(TokenKind, &str)> {
+        tokenize(line).into_iter().map(|t| (t.kind, t.text)).collect()
+    }
+
+    #[test]
+    fn test_tokenize_expression() {
+        assert_eq!(kinds("10.5 USD*2 # tip"), vec![
+            (TokenKind::Number, "10.5"),
+            (TokenKind::Whitespace, " "),
+            (TokenKind::Word, "USD"),
+            (TokenKind::Operator, "*"),
+            (TokenKind::Number, "2"),
+            (TokenKind::Whitespace, " "),
+            (TokenKind::Comment, "# tip"),
+        ]);
+        // `**` is one operator, the power
+        assert_eq!(kinds("2**3 * 4"), vec![
+            (TokenKind::Number, "2"),
+            (TokenKind::Operator, "**"),
+            (TokenKind::Number, "3"),
+            (TokenKind::Whitespace, " "),
+            (TokenKind::Operator, "*"),
+            (TokenKind::Whitespace, " "),
+            (TokenKind::Number, "4"),
+        ]);
+    }
+
+    #[test]
+    fn test_tokenize_unicode_round_trips() {
+        for line in ["café = 3 €", "€8.50", "größe * 2 # ünïcödé", "5 km → mi"] {
+            let tokens = tokenize(line);
+            let rebuilt: String = tokens.iter().map(|t| t.text).collect();
+            assert_eq!(rebuilt, line);
+        }
+        assert_eq!(kinds("café")[0], (TokenKind::Word, "café"));
+        assert_eq!(kinds("€8.50")[0], (TokenKind::Unknown, "€"));
+    }
+
+    #[test]
+    fn test_tokenize_trailing_dot_is_not_decimal() {
+        assert_eq!(kinds("3."), vec![(TokenKind::Number, "3"), (TokenKind::Unknown, ".")]);
+    }
+}
@@ -0,0 +1,163 @@
+//! cali's expression engine, for tools that want its arithmetic, units, dates and currencies
+//! without the notebook around it.
+//!
+//! ```
+//! use cali::{Calculator, FixedRates};
+//!
+//! let mut calc = Calculator::new().with_rates(FixedRates::default().with("USD", "EUR", 0.85));
+//! calc.eval("rent = 1200 USD");
+//! assert_eq!(calc.eval("rent * 12 in EUR").to_string(), "€12240.00");
+//! ```
+
+use std::collections::HashMap;
+use std::rc::Rc;
+use evaluator::{evaluate, line_key, with_custom_units, Context, OwnRates};
+
+// The engine's modules. They're public for the cali binary, which builds the notebook on
+// them; embedders should stick to the items re-exported below.
+#[doc(hidden)]
+pub mod parser;
+#[doc(hidden)]
+pub mod evaluator;
+#[doc(hidden)]
+pub mod currency;
+#[doc(hidden)]
+pub mod lexer;
+#[doc(hidden)]
+pub mod locale;
+#[doc(hidden)]
+pub mod random;
+#[doc(hidden)]
+pub mod include;
+#[cfg(test)]
+mod temp_dir;
+
+pub use chrono::NaiveDate;
+pub use evaluator::{FixedRates, RateProvider, Value};
+
+/// Evaluates lines one at a time, keeping the variables they define for the lines after them.
+///
+/// Exchange rates come from the rate service, cached for an hour, and "today" is the local date,
+/// unless other ones are given with [`Calculator::with_rates`] and [`Calculator::with_today`].
+/// Units defined with `defunit` and rates set with `setrate` only change the calculator that
+/// evaluated them.
+///
+/// ```
+/// use cali::{Calculator, Value};
+///
+/// let mut calc = Calculator::new();
+/// calc.eval("price = 40");
+/// assert_eq!(calc.eval("price + 15%"), Value::Number(46.0));
+/// assert_eq!(calc.eval("5 km in m").to_string(), "5000 m");
+/// assert!(matches!(calc.eval("nothing * 2"), Value::Error(_)));
+///
+/// let mut other = Calculator::new();
+/// calc.eval("defunit sprint = 2 week");
+/// calc.eval("setrate FOO to QUX = 2");
+/// assert_eq!(calc.eval("3 sprint in day").to_string(), "42 day");
+/// assert_eq!(calc.eval("5 FOO in QUX").to_string(), "10.00 QUX");
+/// assert!(matches!(other.eval("3 sprint in day"), Value::Error(_)));
+/// assert!(matches!(other.eval("5 FOO in QUX"), Value::Error(_)));
+/// ```
+pub struct Calculator {
+    variables: HashMap<String, Value>,
+    units: HashMap<String, (f64, String)>, // Defined with defunit, for this calculator only
+    context: Context,
+}
+
+impl Default for Calculator {
+    fn default() -> Self {
+        Self {
+            variables: HashMap::new(),
+            units: HashMap::new(),
+            // setrate changes this calculator's rates, not the ones every other sees
+            context: Context { rates: Rc::new(OwnRates::default()), ..Context::default() },
+        }
+    }
+}
+
+impl Calculator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Use these exchange rates instead of the live ones.
+    ///
+    /// ```
+    /// use cali::{Calculator, FixedRates};
+    ///
+    /// let mut calc = Calculator::new().with_rates(FixedRates::default().with("EUR", "GBP", 0.5));
+    /// assert_eq!(calc.eval("10 EUR in GBP").to_string(), "£5.00");
+    /// ```
+    pub fn with_rates(mut self, rates: impl RateProvider + 'static) -> Self {
+        self.context.rates = Rc::new(rates);
+        self
+    }
+
+    /// Take `today` as the current date, for `today`, `now` and date arithmetic.
+    ///
+    /// ```
+    /// use cali::Calculator;
+    /// use cali::NaiveDate;
+    ///
+    /// let mut calc = Calculator::new().with_today(NaiveDate::from_ymd_opt(2024, 5, 17).unwrap());
+    /// assert_eq!(calc.eval("today + 2 weeks").to_string(), "Fri, 2024-05-31");
+    /// ```
+    pub fn with_today(mut self, today: NaiveDate) -> Self {
+        self.context.today = Rc::new(move || today);
+        self
+    }
+
+    /// The result of a line. An assignment defines its variable for later lines and
+    /// returns [`Value::Assignment`], which displays as the value assigned.
+    pub fn eval(&mut self, line: &str) -> Value {
+        let result = with_custom_units(&mut self.units, || {
+            evaluate(&parser::parse_line(line.trim()), &mut self.variables, &self.context)
+        });
+        if let Value::Assignment(name, value) = &result {
+            self.variables.insert(name.clone(), (**value).clone());
+        }
+        result
+    }
+
+    /// The value of a variable defined so far
+    pub fn variable(&self, name: &str) -> Option<&Value> {
+        self.variables.get(name)
+    }
+
+    /// The results of a whole document, line by line, as the notebook shows them: `None`
+    /// for blank lines and comments. Later lines can refer to earlier ones as `line 2`.
+    pub fn eval_document(&mut self, lines: &[String]) -> Vec<Option<Value>> {
+        lines.iter()
+            .enumerate()
+            .map(|(idx, line)| {
+                let trimmed = line.trim();
+                if trimmed.is_empty() || trimmed.starts_with('#') {
+                    return None;
+                }
+                let result = self.eval(trimmed);
+                let value = match &result {
+                    Value::Assignment(_, value) => (**value).clone(),
+                    value => value.clone(),
+                };
+                self.variables.insert(line_key(idx + 1), value);
+                Some(result)
+            })
+            .collect()
+    }
+}
+
+/// The results of a document evaluated from scratch, with live rates and today's date. See
+/// [`Calculator::eval_document`] to choose them.
+///
+/// ```
+/// let lines = ["a = 3", "", "# the square", "a * a", "line 4 + 1"].map(String::from);
+/// let results: Vec<String> = cali::evaluate_document(&lines)
+///     .iter()
+///     .map(|value| value.as_ref().map_or(String::new(), ToString::to_string))
+///     .collect();
+/// assert_eq!(results, ["3", "", "", "9", "10"]);
+/// ```
+pub fn evaluate_document(lines: &[String]) -> Vec<Option<Value>> {
+    Calculator::new().eval_document(lines)
+}
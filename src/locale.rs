@@ -0,0 +1,203 @@
+use std::cell::Cell;
+
+// Number conventions for reading and displaying values. Only display and parsing change;
+// machine-friendly copies always use a dot as the decimal point.
+//
+// The group separator is only recognized when exactly three digits follow it, so in `de`
+// `1.234` is 1234 while `1.5` is still read as 1.5, and in `en` `1,234` is 1234 while
+// `1,5` is the number 1 followed by a stray comma.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Locale {
+    #[default]
+    En, // 1234.56, also accepts 1,234.56
+    De, // 1234,56, also accepts 1.234,56
+}
+
+impl Locale {
+    // Accepts plain language codes and full tags: en, en-US, de_DE.UTF-8
+    pub fn from_name(name: &str) -> Option<Self> {
+        let language = name.split(['-', '_', '.']).next()?.to_lowercase();
+        match language.as_str() {
+            "en" | "c" | "posix" => Some(Locale::En),
+            "de" => Some(Locale::De),
+            _ => None,
+        }
+    }
+
+    // The locale set in CALI_LOCALE, or en
+    pub fn from_env() -> Self {
+        std::env::var("CALI_LOCALE").ok()
+            .and_then(|name| Self::from_name(&name))
+            .unwrap_or_default()
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Locale::En => "en",
+            Locale::De => "de",
+        }
+    }
+
+    pub fn decimal_separator(self) -> char {
+        match self {
+            Locale::En => '.',
+            Locale::De => ',',
+        }
+    }
+
+    pub fn group_separator(self) -> char {
+        match self {
+            Locale::En => ',',
+            Locale::De => '.',
+        }
+    }
+}
+
+// The locale is per thread: the UI runs on one thread, and tests can each use their own
+thread_local! {
+    static CURRENT: Cell<Locale> = const { Cell::new(Locale::En) };
+}
+
+pub fn current() -> Locale {
+    CURRENT.with(Cell::get)
+}
+
+pub fn set(locale: Locale) {
+    CURRENT.with(|current| current.set(locale));
+}
+
+// With NO_COLOR or --no-color, values only use ASCII: `EUR 10.00` rather than `€10.00`
+thread_local! {
+    static ASCII: Cell<bool> = const { Cell::new(false) };
+}
+
+pub fn ascii() -> bool {
+    ASCII.with(Cell::get)
+}
+
+pub fn set_ascii(ascii: bool) {
+    ASCII.with(|current| current.set(ascii));
+}
+
+// How dates display. Copies always use the ISO form.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DateStyle {
+    Iso,     // 2025-09-12
+    #[default]
+    Weekday, // Fri, 2025-09-12
+    Long,    // September 12, 2025
+}
+
+impl DateStyle {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "iso" => Some(DateStyle::Iso),
+            "weekday" => Some(DateStyle::Weekday),
+            "long" => Some(DateStyle::Long),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            DateStyle::Iso => "iso",
+            DateStyle::Weekday => "weekday",
+            DateStyle::Long => "long",
+        }
+    }
+
+    // A chrono format string for dates in this style
+    pub fn format(self) -> &'static str {
+        match self {
+            DateStyle::Iso => "%Y-%m-%d",
+            DateStyle::Weekday => "%a, %Y-%m-%d",
+            DateStyle::Long => "%B %-d, %Y",
+        }
+    }
+}
+
+thread_local! {
+    static DATE_STYLE: Cell<DateStyle> = const { Cell::new(DateStyle::Weekday) };
+}
+
+pub fn date_style() -> DateStyle {
+    DATE_STYLE.with(Cell::get)
+}
+
+pub fn set_date_style(style: DateStyle) {
+    DATE_STYLE.with(|current| current.set(style));
+}
+
+// Scan the number at the start of `text` in the current locale. Returns its length in
+// bytes and its value written with a plain dot decimal, ready for `str::parse`.
+pub fn scan_number(text: &str) -> (usize, String) {
+    let locale = current();
+    let bytes = text.as_bytes();
+    let digits_from = |i: usize| bytes[i.min(bytes.len())..].iter().take_while(|b| b.is_ascii_digit()).count();
+    let is_separator = |i: usize, c: char| bytes.get(i) == Some(&(c as u8));
+
+    let mut end = digits_from(0);
+    let mut normalized = text[..end].to_string();
+
+    // Groups of exactly three digits
+    while is_separator(end, locale.group_separator()) && digits_from(end + 1) == 3 {
+        normalized.push_str(&text[end + 1..end + 4]);
+        end += 4;
+    }
+
+    // The decimal part. A dot that didn't group three digits is a decimal point in any locale.
+    if (is_separator(end, locale.decimal_separator()) || is_separator(end, '.')) && digits_from(end + 1) > 0 {
+        let fraction = digits_from(end + 1);
+        normalized.push('.');
+        normalized.push_str(&text[end + 1..end + 1 + fraction]);
+        end += 1 + fraction;
+    }
+
+    (end, normalized)
+}
+
+// Parse a number token in the current locale
+pub fn parse_number(text: &str) -> Option<f64> {
+    let (len, normalized) = scan_number(text);
+    if len != text.len() {
+        return None;
+    }
+    normalized.parse().ok()
+}
+
+// Rewrite a number formatted with a dot decimal (as `format!` does) for the current locale
+pub fn localize(formatted: &str) -> String {
+    match current() {
+        Locale::En => formatted.to_string(),
+        Locale::De => formatted.replace('.', ","),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scan_number_per_locale() {
+        set(Locale::En);
+        assert_eq!(scan_number("1,234.56 USD"), (8, "1234.56".to_string()));
+        assert_eq!(scan_number("1.234"), (5, "1.234".to_string()));
+        assert_eq!(scan_number("1,5"), (1, "1".to_string()));
+        assert_eq!(parse_number("12,345,678"), Some(12345678.0));
+
+        set(Locale::De);
+        assert_eq!(scan_number("1.234,56 EUR"), (8, "1234.56".to_string()));
+        assert_eq!(scan_number("1.234"), (5, "1234".to_string()));
+        assert_eq!(scan_number("3,5"), (3, "3.5".to_string()));
+        assert_eq!(scan_number("1.5"), (3, "1.5".to_string()));
+        assert_eq!(parse_number("1.234.567,8"), Some(1234567.8));
+        assert_eq!(localize("3.50"), "3,50");
+    }
+
+    #[test]
+    fn test_locale_names() {
+        assert_eq!(Locale::from_name("de_DE.UTF-8"), Some(Locale::De));
+        assert_eq!(Locale::from_name("en-US"), Some(Locale::En));
+        assert_eq!(Locale::from_name("fr"), None);
+    }
+}
@@ -0,0 +1,122 @@
+// What cali and its dependencies logged while the notebook was up. Nothing may be printed
+// over the alternate screen, so log records are kept here instead and shown with F12.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use chrono::{DateTime, Local};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+// The most records kept; older ones are dropped first
+const CAPACITY: usize = 500;
+
+pub struct Entry {
+    pub at: DateTime<Local>,
+    pub level: Level,
+    pub target: String,
+    pub message: String,
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {:<5} {}: {}", self.at.format("%H:%M:%S"), self.level, self.target, self.message)
+    }
+}
+
+#[derive(Default)]
+pub struct Logbook {
+    entries: Mutex<VecDeque<Entry>>,
+    logged: AtomicUsize, // Records kept since the start, including ones since dropped
+}
+
+impl Logbook {
+    pub const fn new() -> Self {
+        Self { entries: Mutex::new(VecDeque::new()), logged: AtomicUsize::new(0) }
+    }
+
+    // The records kept, oldest first, one line each
+    pub fn lines(&self) -> Vec<String> {
+        self.entries.lock().unwrap().iter().map(Entry::to_string).collect()
+    }
+}
+
+impl Log for Logbook {
+    // Our own information is worth keeping; from dependencies, only warnings and errors
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        let ours = metadata.target() == "cali" || metadata.target().starts_with("cali::");
+        metadata.level() <= if ours { Level::Info } else { Level::Warn }
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() == CAPACITY {
+            entries.pop_front();
+        }
+        entries.push_back(Entry {
+            at: Local::now(),
+            level: record.level(),
+            target: record.target().to_string(),
+            message: record.args().to_string(),
+        });
+        self.logged.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGBOOK: Logbook = Logbook::new();
+
+// Send log records to the logbook from now on. Only the first call does anything.
+pub fn install() {
+    if log::set_logger(&LOGBOOK).is_ok() {
+        log::set_max_level(LevelFilter::Info);
+    }
+}
+
+// What the installed logbook holds
+pub fn lines() -> Vec<String> {
+    LOGBOOK.lines()
+}
+
+// How many records the installed logbook has taken, which changes whenever its lines do
+pub fn logged() -> usize {
+    LOGBOOK.logged.load(Ordering::Relaxed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(logbook: &Logbook, level: Level, target: &str, message: &str) {
+        logbook.log(&Record::builder().level(level).target(target).args(format_args!("{}", message)).build());
+    }
+
+    #[test]
+    fn test_records_are_kept_not_printed() {
+        let logbook = Logbook::new();
+        record(&logbook, Level::Info, "cali::currency", "Fetched exchange rates for 160 currencies");
+        record(&logbook, Level::Info, "reqwest::connect", "starting new connection");
+        record(&logbook, Level::Warn, "native_tls", "certificate expires soon");
+        record(&logbook, Level::Debug, "cali::currency", "too chatty");
+
+        let lines = logbook.lines();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with(" INFO  cali::currency: Fetched exchange rates for 160 currencies"));
+        assert!(lines[1].ends_with(" WARN  native_tls: certificate expires soon"));
+    }
+
+    #[test]
+    fn test_oldest_records_make_room() {
+        let logbook = Logbook::new();
+        for i in 0..CAPACITY + 3 {
+            record(&logbook, Level::Warn, "cali", &i.to_string());
+        }
+        let lines = logbook.lines();
+        assert_eq!(lines.len(), CAPACITY);
+        assert!(lines[0].ends_with("cali: 3"));
+    }
+}
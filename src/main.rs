@@ -1,185 +1,396 @@
 mod app;
 mod ui;
-mod parser;
-mod evaluator;
-mod currency;
+mod term;
+mod clipboard;
+mod cli;
+mod repl;
+mod tutorial;
+mod diff;
+mod logbook;
 #[cfg(test)]
 mod tests;
+#[cfg(test)]
+mod temp_dir;
+
+// The engine comes from the library; the notebook, its terminal and its prompt are the binary's
+use cali::{currency, evaluator, include, lexer, locale, parser, random};
 
 use std::io;
 use std::env;
 use std::fs;
 use std::path::Path;
-use crossterm::{
-    event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyEventKind, KeyModifiers},
-    execute,
-    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
-};
+use crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
 use ratatui::{Terminal, backend::CrosstermBackend};
 use app::App;
+use ui::Theme;
 
 fn main() -> Result<(), io::Error> {
     // Parse command line args
-    let args: Vec<String> = env::args().collect();
-    
-    // Check for version flags
-    if args.len() > 1 && (args[1] == "-v" || args[1] == "--version") {
+    let options = match cli::parse_args(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            eprintln!("Run 'cali --help' for usage.");
+            std::process::exit(2);
+        }
+    };
+
+    if options.help {
+        print_help();
+        return Ok(());
+    }
+    if options.version {
         println!("Cali version {}", env!("CARGO_PKG_VERSION"));
         return Ok(());
     }
 
-    // Check for help flag
-    if args.len() > 1 && (args[1] == "-h" || args[1] == "--help") {
-        print_help();
-        return Ok(());
+    // NO_COLOR (https://no-color.org) counts when set to anything but an empty string
+    let no_color = options.no_color || env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+    
+    // Compare two sheets and exit: 0 when every result matches, 1 when some differ
+    if let (Some(old), Some(new)) = (&options.diff, &options.file) {
+        locale::set_ascii(no_color);
+        match diff::run(old, new) {
+            Ok(report) if report.is_empty() => std::process::exit(0),
+            Ok(report) => {
+                for line in report {
+                    println!("{}", line);
+                }
+                std::process::exit(1);
+            }
+            Err(e) => {
+                eprintln!("Error comparing '{}' and '{}': {}", old, new, e);
+                std::process::exit(2);
+            }
+        }
+    }
+    
+    if options.repl {
+        term::install_panic_hook();
+        if no_color {
+            locale::set_ascii(true);
+        }
+        return repl::run();
     }
     
     // Create app state
     let mut app = App::new();
+    if no_color {
+        app.disable_color();
+    } else if options.high_contrast {
+        app.theme = Theme::high_contrast();
+    }
+    
+    // Preloaded files go first, in order, so the sheet's lines can use their definitions
+    let mut preloaded = Vec::new();
+    for path in &options.preload {
+        match app.preload(&include::resolve(path, None)) {
+            Ok(count) => preloaded.push((count, path.as_str())),
+            Err(e) => {
+                eprintln!("Error preloading '{}': {}", path, e);
+                std::process::exit(1);
+            }
+        }
+    }
+    if !preloaded.is_empty() {
+        let count: usize = preloaded.iter().map(|(count, _)| count).sum();
+        let files: Vec<&str> = preloaded.iter().map(|(_, path)| *path).collect();
+        app.set_status_message(format!("Preloaded {count} definition{} from {}", if count == 1 { "" } else { "s" }, files.join(", ")));
+    }
     
     // Track the current file path
     let mut current_file_path: Option<String> = None;
     
     // If a file path is provided, load it
-    if args.len() > 1 {
-        let file_path = &args[1];
-        if !file_path.starts_with("-") {  // Ensure it's not a flag
-            current_file_path = Some(file_path.clone());
-            if let Err(e) = load_file_into_app(file_path, &mut app) {
-                eprintln!("Error loading file '{}': {}", file_path, e);
-                return Ok(());
-            }
+    if let Some(file_path) = options.file {
+        if let Err(e) = load_file_into_app(&file_path, &mut app) {
+            eprintln!("Error loading file '{}': {}", file_path, e);
+            return Ok(());
         }
+        current_file_path = Some(file_path);
+    } else if options.tutorial || tutorial::first_run() {
+        // Two empty panels don't say much; the first time round, show what cali can do
+        app.open_tutorial();
+        tutorial::mark_shown();
+    }
+    if options.read_only {
+        app.read_only = true;
     }
 
-    // Set up terminal
-    enable_raw_mode()?;
-    let mut stdout = io::stdout();
-    execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
-    let backend = CrosstermBackend::new(stdout);
+    // Set up terminal. The panic hook and the guards make sure the terminal and stderr are
+    // restored however we exit, including `?` errors from the loop below. Log records and
+    // stderr are kept off the screen while it's ours; F12 shows them.
+    term::install_panic_hook();
+    let signals = term::Signals::register()?;
+    logbook::install();
+    let _stderr = term::StderrGuard::default_path().and_then(|path| {
+        let guard = term::StderrGuard::new(&path).ok()?;
+        app.stderr_log = Some(path);
+        Some(guard)
+    });
+    let _guard = term::TerminalGuard::new()?;
+    let backend = CrosstermBackend::new(io::stdout());
     let mut terminal = Terminal::new(backend)?;
     
-    // Tick rate for UI updates (for debouncing errors)
-    let tick_rate = std::time::Duration::from_millis(100);
-    
     // Main loop
     loop {
-        // Draw UI
-        terminal.draw(|f| ui::draw(f, &mut app))?;
+        // SIGTERM/SIGHUP quit the same way Ctrl+Q does
+        if signals.should_terminate() {
+            break;
+        }
+
+        // An external SIGTSTP stops us just like Ctrl+Z
+        if signals.take_suspend() {
+            term::suspend()?;
+        }
+
+        // Back from a stop: set the terminal up again and repaint everything
+        if signals.take_resumed() {
+            term::enter()?;
+            terminal.clear()?;
+            app.needs_redraw = true;
+        }
+
+        // Draw UI, but only when something visible changed
+        if app.needs_redraw {
+            terminal.draw(|f| ui::draw(f, &mut app))?;
+            app.needs_redraw = false;
+        }
 
         // Handle input with timeout to allow periodic ticks
-        if crossterm::event::poll(tick_rate)? {
+        if crossterm::event::poll(app.poll_timeout())? {
             match event::read()? {
-                Event::Key(key) => {
-                    if key.kind == KeyEventKind::Press {
-                        match app.input_mode {
-                            app::InputMode::Normal => {
-                                // Handle keys in normal mode
-                                match key.code {
-                                    KeyCode::Char('q') if key.modifiers.contains(KeyModifiers::CONTROL) => {
-                                        break;
+                Event::Key(key) if key.kind == KeyEventKind::Press => {
+                    app.needs_redraw = true;
+                    match app.input_mode {
+                        app::InputMode::Normal => {
+                            // Handle keys in normal mode
+                            match key.code {
+                                KeyCode::Char('q') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                                    break;
+                                }
+                                KeyCode::Char('z') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                                    // Suspend to the shell; the terminal is set up again on SIGCONT
+                                    term::suspend()?;
+                                }
+                                _ if app.full_result.is_some() => {
+                                    // A whole result is showing until Esc
+                                    app.handle_full_result_key(key);
+                                }
+                                _ if app.show_log => {
+                                    app.handle_log_key(key);
+                                }
+                                KeyCode::F(12) => {
+                                    app.open_log();
+                                }
+                                // Ctrl+Shift+L arrives with Shift set or as an uppercase L, depending on the terminal
+                                KeyCode::Char(c @ ('l' | 'L'))
+                                    if key.modifiers.contains(KeyModifiers::CONTROL) && (c == 'L' || key.modifiers.contains(KeyModifiers::SHIFT)) =>
+                                {
+                                    app.toggle_read_only();
+                                }
+                                KeyCode::Char('n') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                                    // A blank sheet is saved to a new file
+                                    if app.new_sheet() {
+                                        current_file_path = None;
                                     }
-                                    KeyCode::Char('s') if key.modifiers.contains(KeyModifiers::CONTROL) => {
-                                        // Check if we already have a file path
-                                        if let Some(path) = &current_file_path {
-                                            // Save to the existing path
-                                            match save_file_from_app(path, &app) {
-                                                Ok(_) => {
-                                                    // Show success message in status bar
-                                                    app.set_status_message(format!("File saved successfully to '{}'", path));
-                                                }
-                                                Err(e) => {
-                                                    // Show error message in status bar
-                                                    app.set_status_message(format!("Error saving file: {}", e));
-                                                }
+                                }
+                                KeyCode::Char('s') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                                    // Nothing to save in read-only mode
+                                    if !app.can_edit() {
+                                        continue;
+                                    }
+                                    // Check if we already have a file path
+                                    if let Some(path) = &current_file_path {
+                                        // Save to the existing path
+                                        match save_file_from_app(path, &app) {
+                                            Ok(_) => {
+                                                app.modified = false;
+                                                // Show success message in status bar
+                                                app.set_status_message(format!("File saved successfully to '{}'", path));
+                                            }
+                                            Err(e) => {
+                                                // Show error message in status bar
+                                                app.set_status_error(format!("Can't save the file: {}", e));
                                             }
-                                        } else {
-                                            // Need to get a file path from the user
-                                            // Switch to file path input mode
-                                            app.set_input_mode(app::InputMode::FilePath);
                                         }
+                                    } else {
+                                        // Need to get a file path from the user
+                                        // Switch to file path input mode
+                                        app.set_input_mode(app::InputMode::FilePath);
                                     }
-                                    KeyCode::Tab => {
-                                        // Regular TAB goes forward
-                                        app.toggle_panel_focus(true);
-                                    }
-                                    KeyCode::BackTab => {
-                                        // SHIFT+TAB goes backward
-                                        app.toggle_panel_focus(false);
-                                    }
-                                    _ => {
-                                        match app.panel_focus {
-                                            app::PanelFocus::Input => {
-                                                // Process input normally
-                                                app.handle_key(key);
+                                }
+                                KeyCode::F(4) => {
+                                    // Shift+F4 goes back
+                                    app.jump_to_error(!key.modifiers.contains(KeyModifiers::SHIFT));
+                                }
+                                KeyCode::Tab => {
+                                    // Regular TAB goes forward
+                                    app.toggle_panel_focus(true);
+                                }
+                                KeyCode::BackTab => {
+                                    // SHIFT+TAB goes backward
+                                    app.toggle_panel_focus(false);
+                                }
+                                _ => {
+                                    match app.panel_focus {
+                                        app::PanelFocus::Input => {
+                                            // Process input normally
+                                            app.handle_key(key);
+                                        }
+                                        app::PanelFocus::Output => {
+                                            // Right after an explanation, `y` copies it
+                                            if app.take_copy_offer(&key) {
+                                                continue;
                                             }
-                                            app::PanelFocus::Output => {
-                                                // Handle navigation in output panel
-                                                match key.code {
-                                                    KeyCode::Up | KeyCode::Down | 
-                                                    KeyCode::Char('j') | KeyCode::Char('k') |
-                                                    KeyCode::Home | KeyCode::End |
-                                                    KeyCode::Char('g') | KeyCode::Char('G') => {
-                                                        app.navigate_output_panel(key.code);
+                                            // Handle navigation in output panel
+                                            match key.code {
+                                                KeyCode::Up | KeyCode::Down | 
+                                                KeyCode::Char('j') | KeyCode::Char('k') |
+                                                KeyCode::Home | KeyCode::End |
+                                                KeyCode::PageUp | KeyCode::PageDown |
+                                                KeyCode::Char('g') | KeyCode::Char('G') => {
+                                                    app.navigate_output_panel(key);
+                                                }
+                                                KeyCode::Char('V') => {
+                                                    // Select several results to see their sum
+                                                    app.toggle_output_visual();
+                                                }
+                                                KeyCode::Esc => {
+                                                    app.collapse_output_selection();
+                                                }
+                                                KeyCode::Char('u' | 'd') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                                                    // Half a page up or down, as in vim
+                                                    app.navigate_output_panel(key);
+                                                }
+                                                KeyCode::Char(c @ ('y' | 'Y')) if c == 'Y' || key.modifiers.contains(KeyModifiers::CONTROL) => {
+                                                    // Copy the plain value, e.g. 1234.5678 rather than $1,234.57
+                                                    match app.copy_selected_raw_value_to_clipboard() {
+                                                        Ok((raw, method)) => {
+                                                            app.set_status_message(format!("Copied raw value '{}' via {}", raw, method));
+                                                        }
+                                                        Err(e) => {
+                                                            app.set_status_error(e);
+                                                        }
                                                     }
-                                                    KeyCode::Enter | KeyCode::Char('y') => {
-                                                        // Copy selected line to clipboard (y for "yank" in vim)
-                                                        match app.copy_selected_output_to_clipboard() {
-                                                            Ok(_) => {
-                                                                app.set_status_message("Copied to clipboard".to_string());
-                                                            }
-                                                            Err(e) => {
-                                                                app.set_status_message(format!("Error: {}", e));
-                                                            }
+                                                }
+                                                KeyCode::Char('p') => {
+                                                    // Pin the result to compare against later edits
+                                                    match app.toggle_pin_selected() {
+                                                        Ok(Some(pinned)) => {
+                                                            app.set_status_message(format!("Pinned '{}'", pinned));
+                                                        }
+                                                        Ok(None) => {
+                                                            app.set_status_message("Unpinned result".to_string());
+                                                        }
+                                                        Err(e) => {
+                                                            app.set_status_error(e);
                                                         }
                                                     }
-                                                    _ => {}
                                                 }
+                                                KeyCode::Char('c') => {
+                                                    // The result in another unit, leaving the sheet alone
+                                                    app.start_convert();
+                                                }
+                                                KeyCode::Char('e') => {
+                                                    // How the result came about: variables read and rates used
+                                                    app.explain_selected();
+                                                }
+                                                KeyCode::Char('P') => {
+                                                    let count = app.clear_pins();
+                                                    app.set_status_message(format!("Cleared {} pinned result(s)", count));
+                                                }
+                                                KeyCode::Enter | KeyCode::Char('o') if app.open_full_result() => {
+                                                    // A result the panel cuts short is shown whole
+                                                }
+                                                KeyCode::Enter => {
+                                                    // Edit the line the result came from
+                                                    app.edit_selected_output_line();
+                                                }
+                                                KeyCode::Char('o') => {
+                                                    app.set_status_message("The whole result is showing already".to_string());
+                                                }
+                                                KeyCode::Char('y') => {
+                                                    // Copy selected line to clipboard (y for "yank" in vim)
+                                                    match app.copy_selected_output_to_clipboard() {
+                                                        Ok((output, method)) if output.contains('\n') => {
+                                                            app.set_status_message(format!("Copied {} results via {}", output.lines().count(), method));
+                                                        }
+                                                        Ok((output, method)) => {
+                                                            app.set_status_message(format!("Copied '{}' as displayed via {}", output, method));
+                                                        }
+                                                        Err(e) => {
+                                                            app.set_status_error(e);
+                                                        }
+                                                    }
+                                                }
+                                                _ => {}
                                             }
                                         }
                                     }
                                 }
-                            },
-                            app::InputMode::FilePath => {
-                                // Handle file path input
-                                if let Some(path) = app.handle_status_input(key) {
-                                    if !path.is_empty() {
-                                        // Save file
-                                        match save_file_from_app(&path, &app) {
-                                            Ok(_) => {
-                                                current_file_path = Some(path.clone());
-                                                app.set_status_message(format!("File saved successfully to '{}'", path));
-                                            }
-                                            Err(e) => {
-                                                app.set_status_message(format!("Error saving file: {}", e));
-                                            }
+                            }
+                        },
+                        app::InputMode::Convert => {
+                            if let Some(target) = app.handle_status_input(key) {
+                                app.finish_convert(&target);
+                            } else if app.input_mode == app::InputMode::Normal {
+                                app.convert_from = None;
+                            }
+                        }
+                        app::InputMode::Rename => {
+                            if let Some(new_name) = app.handle_status_input(key) {
+                                app.finish_rename(&new_name);
+                            } else if app.input_mode == app::InputMode::Normal {
+                                app.rename_from = None;
+                            }
+                        }
+                        app::InputMode::FilePath => {
+                            // Handle file path input
+                            if let Some(path) = app.handle_status_input(key) {
+                                if !path.is_empty() {
+                                    // Save file
+                                    match save_file_from_app(&path, &app) {
+                                        Ok(created) => {
+                                            app.modified = false;
+                                            app.base_dir = include::resolve(&path, None).parent().map(Path::to_path_buf);
+                                            let created = created.map(|dir| format!(" (created {})", dir.display())).unwrap_or_default();
+                                            app.set_status_message(format!("File saved successfully to '{}'{}", path, created));
+                                            current_file_path = Some(path);
+                                        }
+                                        Err(e) => {
+                                            app.set_status_error(format!("Can't save the file: {}", e));
                                         }
-                                    } else {
-                                        app.set_status_message("Save cancelled - no file path provided.".to_string());
                                     }
+                                } else {
+                                    app.set_status_message("Save cancelled - no file path provided.".to_string());
                                 }
                             }
                         }
                     }
                 },
                 Event::Mouse(mouse_event) => {
-                    match mouse_event.kind {
-                        event::MouseEventKind::Down(event::MouseButton::Left) => {
-                            // Try to handle click in input panel
-                            if let Some(area) = app.input_panel_area {
-                                if app.handle_mouse_click(mouse_event.column, mouse_event.row, area) {
-                                    continue;
-                                }
-                            }
-                            
-                            // If not handled by input panel, try output panel
-                            if let Some(area) = app.output_panel_area {
-                                app.handle_output_mouse_click(mouse_event.column, mouse_event.row, area);
-                            }
-                        },
-                        _ => {}
+                    if let event::MouseEventKind::Down(event::MouseButton::Left) = mouse_event.kind {
+                        app.needs_redraw = true;
+
+                        // Try to handle click in input panel
+                        if let Some(area) = app.input_panel_area
+                            && app.handle_mouse_click(mouse_event.column, mouse_event.row, area)
+                        {
+                            continue;
+                        }
+
+                        // If not handled by input panel, try output panel
+                        if let Some(area) = app.output_panel_area {
+                            app.handle_output_mouse_click(mouse_event.column, mouse_event.row, area);
+                        }
                     }
                 },
+                Event::Resize(_, _) => {
+                    app.needs_redraw = true;
+                }
+                Event::FocusGained => app.set_focused(true),
+                Event::FocusLost => app.set_focused(false),
                 _ => {}
             }
         } else {
@@ -188,22 +399,14 @@ fn main() -> Result<(), io::Error> {
         }
     }
 
-    // Restore terminal
-    disable_raw_mode()?;
-    execute!(
-        terminal.backend_mut(),
-        LeaveAlternateScreen,
-        DisableMouseCapture
-    )?;
-    terminal.show_cursor()?;
-
+    // The terminal is restored when the guard is dropped
     Ok(())
 }
 
 // Load calculations from a file into the app
 fn load_file_into_app(file_path: &str, app: &mut App) -> io::Result<()> {
     // Check if file exists
-    let path = Path::new(file_path);
+    let path = &include::resolve(file_path, None);
     if !path.exists() {
         return Err(io::Error::new(
             io::ErrorKind::NotFound,
@@ -211,31 +414,47 @@ fn load_file_into_app(file_path: &str, app: &mut App) -> io::Result<()> {
         ));
     }
     
-    // Read file contents
-    let content = fs::read_to_string(path)?;
+    // Read file contents. A byte order mark isn't part of the first line, and lines may
+    // end in \r\n; both are remembered so the file is saved the same way.
+    let (content, warning) = decode_text(&fs::read(path)?)?;
+    let text = content.strip_prefix('\u{feff}').unwrap_or(&content);
     
     // Clear existing content
-    app.lines.clear();
-    app.results.clear();
-    app.debounced_results.clear();
-    app.variables.clear();
-    app.cursor_pos = (0, 0);
+    app.clear();
+    app.file_format = app::FileFormat {
+        crlf: text.contains("\r\n"),
+        bom: text.len() < content.len(),
+    };
+    app.base_dir = path.parent().map(Path::to_path_buf);
     
-    // Split content by lines and add each line to the app
-    for line in content.lines() {
-        let trimmed = line.trim();
-        if !trimmed.is_empty() {
-            app.add_line(trimmed.to_string());
-        }
+    // Add the lines as written, blank and indented ones included, so `line N` references
+    // and sections survive a save. `lines` splits on both \n and \r\n; a stray \r is
+    // dropped.
+    for line in text.lines() {
+        app.add_saved_line(line.strip_suffix('\r').unwrap_or(line));
     }
     
-    // If file was empty or only had empty lines, add at least one empty line
+    // If file was empty, add at least one empty line
     if app.lines.is_empty() {
         app.add_line(String::new());
     }
     
-    // Evaluate all lines
-    app.evaluate_expressions();
+    if let Some(warning) = warning {
+        app.set_status_message(warning);
+    }
+    
+    // A file we couldn't save over is only shown
+    if fs::metadata(path)?.permissions().readonly() {
+        app.read_only = true;
+        app.set_status_message(format!("{} is read-only; opened in read-only mode", file_path));
+    }
+    
+    // Evaluate the first screenful now and the rest from the main loop, so a huge file
+    // doesn't hold up the first draw
+    app.defer_evaluation();
+    app.report_errors_when_loaded();
+    app.catch_up(app::CATCH_UP_CHUNK);
+    app.modified = false;
     
     // Position cursor at the end of the loaded content
     let last_line_idx = app.lines.len() - 1;
@@ -245,16 +464,86 @@ fn load_file_into_app(file_path: &str, app: &mut App) -> io::Result<()> {
     Ok(())
 }
 
-// Save calculations from the app to a file
-fn save_file_from_app(file_path: &str, app: &App) -> io::Result<()> {
+// Longest line we load; the editor and highlighter work per character, so a minified
+// file with one huge line would make every keystroke slow
+const MAX_LINE_CHARS: usize = 10_000;
+
+// Most bytes that may be invalid UTF-8 (per thousand) before a file counts as binary
+const MAX_INVALID_PER_MILLE: usize = 10;
+
+// Turn the bytes of a file into text, refusing binary files and huge lines. A few bytes
+// that aren't UTF-8, like stray Latin-1 accents, are read as Latin-1 with a warning.
+fn decode_text(bytes: &[u8]) -> io::Result<(String, Option<String>)> {
+    let invalid_data = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+    if bytes.contains(&0) {
+        return Err(invalid_data("not a text file".to_string()));
+    }
+    
+    let mut text = String::with_capacity(bytes.len());
+    let mut invalid = 0;
+    for chunk in bytes.utf8_chunks() {
+        text.push_str(chunk.valid());
+        invalid += chunk.invalid().len();
+        text.extend(chunk.invalid().iter().map(|&byte| char::from(byte)));
+    }
+    if invalid * 1000 > bytes.len() * MAX_INVALID_PER_MILLE {
+        return Err(invalid_data("not a text file".to_string()));
+    }
+    
+    if let Some((idx, line)) = text.lines().enumerate().find(|(_, line)| line.chars().count() > MAX_LINE_CHARS) {
+        return Err(invalid_data(format!(
+            "line {} is too long ({} characters; the limit is {})", idx + 1, line.chars().count(), MAX_LINE_CHARS
+        )));
+    }
+    
+    let warning = (invalid > 0).then(|| format!("Read {} byte(s) that weren't UTF-8 as Latin-1", invalid));
+    Ok((text, warning))
+}
+
+// Save the sheet to a path as typed, `~` and all, creating the directories missing on the
+// way. Returns the topmost directory that had to be created, if any, and on failure a
+// reason that says which part of the path is the problem.
+fn save_file_from_app(file_path: &str, app: &App) -> Result<Option<std::path::PathBuf>, String> {
+    let path = include::resolve(file_path, None);
+    if path.file_name().is_none() || file_path.ends_with(std::path::is_separator) {
+        return Err(format!("'{}' doesn't name a file", file_path));
+    }
+    let mut created = None;
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty() && !dir.is_dir()) {
+        created = dir.ancestors().take_while(|dir| !dir.as_os_str().is_empty() && !dir.exists()).last().map(Path::to_path_buf);
+        fs::create_dir_all(dir).map_err(|e| save_error(&path, &e))?;
+    }
+    write_sheet(&path, app).map_err(|e| save_error(&path, &e))?;
+    Ok(created)
+}
+
+// Why saving to `path` failed, in terms of the path rather than the system call
+fn save_error(path: &Path, e: &io::Error) -> String {
+    // A file where a directory should be, anywhere along the path
+    if let Some(file) = path.ancestors().find(|ancestor| ancestor.is_file() && *ancestor != path) {
+        return format!("'{}' is a file, not a directory", file.display());
+    }
+    match e.kind() {
+        io::ErrorKind::PermissionDenied | io::ErrorKind::ReadOnlyFilesystem => format!("no permission to write to '{}'", path.display()),
+        io::ErrorKind::IsADirectory => format!("'{}' is a directory", path.display()),
+        io::ErrorKind::NotFound => format!("'{}' can't be reached", path.display()),
+        io::ErrorKind::InvalidFilename | io::ErrorKind::InvalidInput => format!("'{}' isn't a valid file name", path.display()),
+        _ => format!("{} ('{}')", e, path.display()),
+    }
+}
+
+fn write_sheet(path: &Path, app: &App) -> io::Result<()> {
     use std::fs::File;
     use std::io::Write;
     
-    let mut file = File::create(Path::new(file_path))?;
+    let mut file = File::create(path)?;
+    if app.file_format.bom {
+        write!(file, "\u{feff}")?;
+    }
     
-    // Write each line to the file
-    for line in &app.lines {
-        writeln!(file, "{}", line)?;
+    // Write each line to the file, pins included
+    for line_idx in 0..app.lines.len() {
+        write!(file, "{}{}", app.line_to_save(line_idx), app.file_format.line_ending())?;
     }
     
     Ok(())
@@ -265,25 +554,64 @@ fn print_help() {
     println!("Cali v{} - A terminal calculator with unit conversions and natural language expressions", env!("CARGO_PKG_VERSION"));
     println!();
     println!("USAGE:");
-    println!("  cali                    Start interactive calculator");
-    println!("  cali [FILE]             Load and execute calculations from FILE");
-    println!("  cali -v, --version      Display version information");
-    println!("  cali -h, --help         Display this help message");
+    println!("  cali [OPTIONS] [FILE]   Start the calculator, loading calculations from FILE if given");
+    println!("  cali --diff OLD NEW     Print the results that changed from OLD to NEW (exit code 1 if any did)");
+    println!();
+    println!("OPTIONS:");
+    print!("{}", cli::options_help());
     println!();
     println!("KEYBOARD SHORTCUTS:");
     println!("  Ctrl+Q                  Quit the application");
     println!("  Ctrl+S                  Save the current work to a file");
-    println!("  Tab                     Switch focus between input and output panels");
+    println!("  Ctrl+N                  Start a blank sheet (press twice to discard unsaved changes)");
+    println!("  Ctrl+Z                  Suspend to the shell (resume with 'fg')");
+    println!("  Ctrl+Shift+L            Read-only mode: keys that would change the sheet are ignored");
+    println!("  Tab, Shift+Tab          Move focus to the next or previous panel");
+    println!("  Ctrl+/                  Comment out the current line, or uncomment it");
+    println!("  Ctrl+.                  Mark the current line as a draft (~) that nothing else sees, or unmark it");
+    println!("  Ctrl+]                  Go to the definition of the variable under the cursor");
+    println!("  Ctrl+O, Ctrl+Shift+O    Jump back, or forward again");
+    println!("  Ctrl+R                  Rename the variable under the cursor on every line");
+    println!("  Ctrl+E                  Evaluate just the selected text (then y copies the result)");
+    println!("  F4, Shift+F4            Jump to the next or previous line with an error");
+    println!("  F12                     Show what was logged or printed to stderr while cali ran");
     println!();
     println!("  When output panel is focused:");
     println!("  Up/k                    Move selection up");
     println!("  Down/j                  Move selection down");
     println!("  g/Home                  Jump to first line");
     println!("  G/End                   Jump to last line");
-    println!("  Enter/y                 Copy selected output to clipboard (y for 'yank')");
+    println!("  PageUp/PageDown         Move selection a page up or down");
+    println!("  Ctrl+U/Ctrl+D           Move selection half a page up or down");
+    println!("  Shift+Up/Down, V        Select several results to see their sum, average and count (Esc ends it)");
+    println!("  Enter                   Edit the line the selected result came from");
+    println!("  o                       Show the whole of a result too wide for the panel (Esc closes, y copies)");
+    println!("  y                       Copy selected output to clipboard (y for 'yank')");
+    println!("  Y/Ctrl+Y                Copy the raw value (plain number or ISO date)");
+    println!("  c                       Show the selected result in another unit (then y copies it)");
+    println!("  p                       Pin the selected result to compare against later edits");
+    println!("  P                       Clear all pinned results");
+    println!("  e                       Explain the result: variables read and rates used (then y copies it)");
+    println!();
+    println!("OPERATORS:");
+    println!("  ^ (or **)               Power, binding tightest and grouping from the right:");
+    println!("                          2 * 3 ^ 2 = 18, 2 ^ 3 ^ 2 = 512, -2 ^ 2 = -4");
+    println!("  * / % mod               Multiply, divide and remainder, before + and -");
+    println!();
+    println!("VARIABLE NAMES:");
+    println!("  Keywords (in, to, of, what, is, next, mod) and currency codes (USD) can't be variables.");
+    println!("  A variable named like a unit (m, s) means the unit directly after a number,");
+    println!("  e.g. with m = 5, '10 m in ft' is meters and 'm * 2' is 10.");
+    println!();
+    println!("ENVIRONMENT:");
+    println!("  CALI_CLIPBOARD=osc52    Copy through the terminal (OSC 52), e.g. over SSH");
+    println!("  CALI_LOCALE=de          Read and show numbers as 1.234,56 (also: 'set locale de' in a sheet)");
+    println!("  CALI_BASE_CURRENCY=EUR  Add up mixed currencies in EUR (also: 'set base currency EUR' in a sheet)");
+    println!("  NO_COLOR=1              Turn off colors, like --no-color");
     println!();
     println!("EXAMPLES:");
     println!("  cali                    Start interactive calculator");
     println!("  cali calculations.txt   Load calculations from file");
+    println!("  cali --tutorial         Open the tutorial again");
     println!();
 }
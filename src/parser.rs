@@ -1,23 +1,11 @@
+use std::cell::RefCell;
 use std::collections::HashMap;
-use regex::Regex;
-use crate::evaluator::Value;
-use once_cell::sync::Lazy;
-
-// Pre-compiled regular expressions for better performance
-static SET_RATE_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?i)setrate\s+([A-Z]{3})\s+(?:to|in)\s+([A-Z]{3})\s*=\s*(\d+(?:\.\d+)?)").unwrap());
-static CONVERSION_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"(.+)\s+(?:in|to)\s+(.+)").unwrap());
-static PERCENT_OF_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"(.+)%\s+of\s+(.+)").unwrap());
-static VAR_OF_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"(\w+)\s+of\s+(.+)").unwrap());
-static PERCENT_OF_WHAT_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"(.+)\s+of\s+what\s+is\s+(.+)").unwrap());
-static DATE_EXPR_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?i)next\s+(\w+)(?:\s*\+\s*(\d+)\s+(\w+))?").unwrap());
-static PARENTHESIS_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"^\s*\((.+)\)\s*$").unwrap());
-static ADD_SUB_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"(.+?)([+\-])(.+)").unwrap());
-static MUL_DIV_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"(.+?)([*/^%])(.+)").unwrap());
-static NUMBER_UNIT_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"(-?\d+(?:\.\d+)?)\s*([a-zA-Z][a-zA-Z0-9]*)").unwrap());
-static VAR_UNIT_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"([a-zA-Z][a-zA-Z0-9]*)\s+([A-Z]{3})").unwrap());
+use chrono::{NaiveDate, Weekday};
+use crate::evaluator::{self, Value};
+use crate::lexer::{self, Token, TokenKind};
 
 // Expression type enum
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Expr {
     Assignment(String, Box<Expr>),
     BinaryOp(Box<Expr>, Op, Box<Expr>),
@@ -25,14 +13,128 @@ pub enum Expr {
     Variable(String),
     UnitValue(f64, String),
     PercentOf(Box<Expr>, Box<Expr>),
+    Off(Box<Expr>, Box<Expr>), // 25% off 80, 10 EUR off 100 USD
+    Split(Box<Expr>, Box<Expr>), // 99.71 split 4 ways
+    Statistic(Statistic, Vec<Expr>), // mean(4, 8, 15)
+    List(Vec<Expr>),                 // [12.50, 8.99, 30 USD]
+    Index(Box<Expr>, Box<Expr>),     // prices[2], counting from 1
+    Map(Vec<(String, Expr)>),        // {S: 36, M: 40}
+    Lookup(Box<Expr>, String),       // sizes[M], a key written as a word
+    Random(Vec<Expr>),               // rand(), rand(10, 20)
+    Clamp(Vec<Expr>),                // clamp(x, 0, 100): the value, then its low and high bounds
+    Round(Rounding, Vec<Expr>),      // round(x), round(12.3456 USD, 0.01): the value, then the step
+    RoundToCents(Rounding, Box<Expr>), // round 12.3456 USD to cents, in the currency's smallest coin
+    Dice(u32, u32),                  // 3d6: how many dice, and their sides
     Convert(Box<Expr>, String),
-    DateOffset(String, i64, String),
+    Ingredient(Box<Expr>, String),   // 2 cups flour: an amount of something with a known density
+    Day(Day),
+    WeekdayOfMonth(Occurrence, Weekday, MonthOf), // first monday of march
+    Date(NaiveDate),                 // 2025-12-25
+    DateDiff(Box<Expr>, Box<Expr>, String), // days until 2025-12-25: from, to, and the unit to count in
     Error(String),
     Percentage(f64),
+    DefineUnit(String, f64, String), // defunit sprint = 2 week
+    SetRate(String, String, f64),    // setrate USD to EUR = 0.92
+    ToPercentage(Box<Expr>), // x% for a variable or parenthesized expression
+    LineRef(usize),          // line 3, the result of input line 3
+    LineAggregate(Aggregate, usize, usize), // sum of lines 2..5
+    Solve(String, Box<Expr>, Box<Expr>),    // 2x + 6 = 20, solved for x
+    IfDefined(String, Box<Expr>, Box<Expr>), // How the line reads when a variable has the name, and when none does
+    NotFound(String),        // A word that is neither a variable nor anything else the parser knows
 }
 
+impl Expr {
+    // What the line is with these variables: whether `2 r` multiplies by r or is 2 of a unit
+    // called r, for example. Readings joined further in are left for the evaluator.
+    pub fn reading(&self, variables: &HashMap<String, Value>) -> &Expr {
+        match self {
+            Expr::IfDefined(name, defined, otherwise) => {
+                if variables.contains_key(name) { defined } else { otherwise }.reading(variables)
+            }
+            expr => expr,
+        }
+    }
+}
+
+// A date named relative to today
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Day {
+    Today,
+    This(Weekday), // this friday, or just friday: the coming one, today if it is Friday
+    Next(Weekday), // next friday: strictly after today
+}
+
+// Which of a month's mondays `first monday of march` means
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Occurrence {
+    Nth(u8), // first to fifth
+    Last,
+}
+
+// The month after `of` in `first monday of ...`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MonthOf {
+    This,                    // of this month
+    Next,                    // of next month
+    Named(u32, Option<i32>), // of march: the coming one, this year's while it lasts; of september 2025, of 2025-09
+}
+
+// Functions over a list of values
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Statistic {
+    Mean,
+    Median,
+    Stdev, // Sample standard deviation
+    Sum,
+    Min,
+    Max,
+    Len, // How many values there are
+}
+
+impl Statistic {
+    pub fn name(self) -> &'static str {
+        match self {
+            Statistic::Mean => "mean",
+            Statistic::Median => "median",
+            Statistic::Stdev => "stdev",
+            Statistic::Sum => "sum",
+            Statistic::Min => "min",
+            Statistic::Max => "max",
+            Statistic::Len => "len",
+        }
+    }
+}
+
+// Which way round(), floor() and ceil() go
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rounding {
+    Round, // To the nearest step, halves away from zero
+    Floor,
+    Ceil,
+}
+
+impl Rounding {
+    pub fn name(self) -> &'static str {
+        match self {
+            Rounding::Round => "round",
+            Rounding::Floor => "floor",
+            Rounding::Ceil => "ceil",
+        }
+    }
+}
+
+// What to do with the results of a range of lines
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Aggregate {
+    Sum,
+    Average,
+}
+
+// Longest range of lines an aggregate may cover
+pub const MAX_LINE_RANGE: usize = 10_000;
+
 // Operation enum
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Op {
     Add,
     Subtract,
@@ -42,358 +144,1471 @@ pub enum Op {
     Power,
 }
 
-// Parse a line of input into an expression
-pub fn parse_line(line: &str, variables: &HashMap<String, Value>) -> Expr {
-    // Remove any inline comments (anything after #)
-    let line = if let Some(pos) = line.find('#') {
-        line[..pos].trim()
-    } else {
-        line.trim()
-    };
-    
+// Operator precedence, from loosest to tightest binding:
+//
+//   level | operators          | associativity | example
+//   ------+--------------------+---------------+------------------------------------
+//     1   | in, to             | left          | 10 USD in EUR in GBP
+//     2   | + -                | left          | 10 - 2 - 3 = (10 - 2) - 3
+//     3   | * / % mod          | left          | 10 % 3 * 2 = (10 % 3) * 2
+//         | implicit *         | left          | 2(3 + 4), 2 pi r = (2 * pi) * r
+//     4   | of                 | right         | 2 * 20% of 50 = 2 * (20% of 50)
+//     5   | unary -            | prefix        | -2 ^ 2 = -(2 ^ 2)
+//     6   | ^ **               | right         | 2 ^ 3 ^ 2 = 2 ^ (3 ^ 2)
+//     7   | postfix %, units   | postfix       | 20%, 10 km, price USD
+//
+// Implicit multiplication happens between a value (a number, name or `)`) and a following
+// `(`, variable or constant (`2(x + 1)`, `2 pi r`, `x(2)`), and between `)` and a number.
+// A unit after a number stays a unit even when a variable has its name (`3 m` is meters).
+//
+// `%` is a percentage when it is glued to the value before it (`20%`, `x%`, `(a + b)%`)
+// or when nothing follows it to take the modulo of (`20 %`, `20 % of 50`). It is modulo
+// when spaces separate it from the value before it and an operand follows (`10 % 3`,
+// `10 %3`). A glued `%` followed by an operand (`10%3`) is rejected as ambiguous;
+// `mod` is always modulo.
+const PREC_CONVERT: u8 = 1;
+const PREC_ADD: u8 = 2;
+const PREC_MUL: u8 = 3;
+const PREC_OF: u8 = 4;
+const PREC_UNARY: u8 = 5;
+const PREC_POWER: u8 = 6;
+
+// How deeply expressions may nest: (((1))) is three levels, as is - - -1. Only brackets,
+// signs and right-associative operators nest; a chain like 1 + 2 + 3… doesn't. Far more than
+// a line needs, and inside the main thread's stack even in debug builds.
+const MAX_DEPTH: usize = 256;
+
+// Most tokens in a line. Every one can add a level to the expression, which is taken apart
+// and copied recursively, so this keeps even a flat chain of 1 + 1 + … inside the stack.
+const MAX_TOKENS: usize = 2_000;
+
+// Most readings of one line. A long run of words has many, since any of them could start a
+// multi-word name; lines that go past this say so instead of taking long to parse.
+const MAX_READINGS: usize = 1024;
+
+// Parse a line of input into an expression. Variables aren't known yet: wherever a defined
+// name would change how the line reads (multi-word names, phrase words, `2 r` as 2 times r or
+// 2 of a unit), both readings are kept in an `Expr::IfDefined` for the evaluator to pick
+// from. So a parse stays good however the variables change.
+pub fn parse_line(line: &str) -> Expr {
+    // Whitespace and inline comments carry no meaning for the parser
+    let tokens: Vec<Token> = lexer::tokenize(line)
+        .into_iter()
+        .filter(|t| !matches!(t.kind, TokenKind::Whitespace | TokenKind::Comment))
+        .collect();
+
     // Handle empty lines
-    if line.is_empty() {
+    if tokens.is_empty() {
         return Expr::Error("Empty input".to_string());
     }
-    
-    // Try to parse as a setrate command
-    if let Some(rate_expr) = parse_set_rate(line) {
-        return rate_expr;
+    if tokens.len() > MAX_TOKENS {
+        return Expr::Error("Expression too long".to_string());
     }
-    
-    // Try to parse as an assignment
-    if let Some(assignment) = parse_assignment(line, variables) {
-        return assignment;
+
+    let mut parser = Parser {
+        tokens,
+        pos: 0,
+        assumed: Vec::new(),
+        undecided: RefCell::new(None),
+        readings: 0,
+        unknown: None,
+        not_found: None,
+        depth: 0,
+    };
+    parser.parse_statement().unwrap_or_else(|msg| failed(msg, parser.not_found))
+}
+
+// The expression for a reading that failed. An unknown word is kept as a name, so the app
+// can point at the line that defines it if that's further down.
+fn failed(msg: String, not_found: Option<String>) -> Expr {
+    match not_found {
+        Some(name) => Expr::NotFound(name),
+        None => Expr::Error(msg),
     }
-    
-    // Try to parse as a unit conversion
-    if let Some(conversion) = parse_conversion(line, variables) {
-        return conversion;
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token<'a>>,
+    pos: usize,
+    assumed: Vec<(String, bool)>,      // Names taken as defined or not in the reading being parsed
+    undecided: RefCell<Option<String>>, // The first name the reading needed that isn't in `assumed`
+    readings: usize,                   // Readings parsed so far
+    unknown: Option<String>, // The name being solved for, while parsing an equation
+    not_found: Option<String>, // The word the reading stopped at for not knowing it
+    depth: usize,            // Expressions being parsed inside one another
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&Token<'a>> {
+        self.tokens.get(self.pos)
     }
-    
-    // Try to parse as a percentage calculation
-    if let Some(percentage) = parse_percentage(line, variables) {
-        return percentage;
+
+    fn peek_at(&self, offset: usize) -> Option<&Token<'a>> {
+        self.tokens.get(self.pos + offset)
     }
-    
-    // Try to parse as a date expression
-    if let Some(date_expr) = parse_date_expression(line) {
-        return date_expr;
+
+    fn advance(&mut self) -> Option<Token<'a>> {
+        let token = self.tokens.get(self.pos).copied();
+        self.pos += 1;
+        token
     }
-    
-    // Try to parse as an expression within parentheses
-    if let Some(paren_expr) = parse_parentheses(line, variables) {
-        return paren_expr;
+
+    // Consume the next token if it has the given text
+    fn eat(&mut self, text: &str) -> bool {
+        if self.peek().is_some_and(|t| t.text == text) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
     }
-    
-    // Try to parse as a binary operation
-    if let Some(binary_op) = parse_binary_op(line, variables) {
-        return binary_op;
+
+    // Whether a variable has this name in the reading being parsed. A name it doesn't decide
+    // yet reads as undefined for now and is noted, so `read_both_ways` parses again each way.
+    fn is_defined(&self, name: &str) -> bool {
+        if let Some(&(_, defined)) = self.assumed.iter().find(|(assumed, _)| assumed == name) {
+            return defined;
+        }
+        self.undecided.borrow_mut().get_or_insert_with(|| name.to_string());
+        false
     }
-    
-    // Try to parse as a simple value (number, variable, or unit value)
-    parse_simple_value(line, variables)
-}
 
-// Parse a setrate command (setrate USD to EUR = 0.92)
-fn parse_set_rate(line: &str) -> Option<Expr> {
-    if let Some(caps) = SET_RATE_RE.captures(line) {
-        let from_currency = caps[1].to_uppercase();
-        let to_currency = caps[2].to_uppercase();
-        if let Ok(rate) = caps[3].parse::<f64>() {
-            // Call the currency module to set the rate
-            if crate::currency::set_exchange_rate(&from_currency, &to_currency, rate) {
-                return Some(Expr::UnitValue(rate, to_currency));
+    // Parse the rest of the line with `parse`, once for every way the variables it runs into
+    // could be defined, and join the readings that differ with `Expr::IfDefined`
+    fn read_both_ways(&mut self, parse: &impl Fn(&mut Self) -> Result<Expr, String>) -> Result<Expr, String> {
+        let (start, unknown) = (self.pos, self.unknown.clone());
+        self.not_found = None;
+        let read = parse(self);
+        let Some(name) = self.undecided.take() else {
+            return read;
+        };
+        self.count_readings()?;
+        let mut read_as = |defined: bool| {
+            (self.pos, self.unknown) = (start, unknown.clone());
+            self.assumed.push((name.clone(), defined));
+            let read = self.read_both_ways(parse);
+            self.assumed.pop();
+            (read, self.not_found.take())
+        };
+        // Undefined first: if a line reads too many ways, it's the readings with more
+        // variables that go without
+        let otherwise = read_as(false);
+        let when_defined = read_as(true);
+        if when_defined == otherwise {
+            self.not_found = otherwise.1;
+            return otherwise.0;
+        }
+        let leaf = |(read, not_found): (Result<Expr, String>, _)| Box::new(read.unwrap_or_else(|msg| failed(msg, not_found)));
+        Ok(Expr::IfDefined(name, leaf(when_defined), leaf(otherwise)))
+    }
+
+    // Like `read_both_ways`, for an operand inside the line. When both readings of the operand
+    // end at the same token they are joined right there, so the rest of the line is parsed
+    // once rather than once for each, as in `2 a + 3 b + 4 c`. When they don't, the name is
+    // left for `read_both_ways` to read the whole line again.
+    fn read_locally(&mut self, parse: &impl Fn(&mut Self) -> Result<Expr, String>) -> Result<Expr, String> {
+        // This reading is only parsed to the end to be thrown away
+        if self.undecided.borrow().is_some() {
+            return parse(self);
+        }
+        let (start, unknown) = (self.pos, self.unknown.clone());
+        let read = parse(self);
+        let Some(name) = self.undecided.take() else {
+            return read;
+        };
+        self.count_readings()?;
+        let mut read_as = |defined: bool| {
+            (self.pos, self.unknown) = (start, unknown.clone());
+            self.assumed.push((name.clone(), defined));
+            let read = self.read_locally(parse);
+            self.assumed.pop();
+            (read, self.pos, self.undecided.take())
+        };
+        let otherwise = read_as(false);
+        match (read_as(true), otherwise) {
+            ((Ok(when_defined), end, None), (Ok(otherwise), other_end, None)) if end == other_end => {
+                self.pos = end;
+                if when_defined == otherwise {
+                    return Ok(otherwise);
+                }
+                Ok(Expr::IfDefined(name, Box::new(when_defined), Box::new(otherwise)))
+            }
+            _ => {
+                *self.undecided.borrow_mut() = Some(name);
+                read
             }
         }
     }
-    None
-}
 
-// Parse an assignment expression (var = expr)
-fn parse_assignment(line: &str, variables: &HashMap<String, Value>) -> Option<Expr> {
-    let parts: Vec<&str> = line.splitn(2, '=').collect();
-    if parts.len() == 2 {
-        let var_name = parts[0].trim().to_string();
-        let expr_str = parts[1].trim();
-        
-        // Special case for percentage values
-        if expr_str.ends_with("%") {
-            if let Ok(num) = expr_str[..expr_str.len()-1].trim().parse::<f64>() {
-                return Some(Expr::Assignment(var_name, Box::new(Expr::Percentage(num))));
+    // Two more readings to parse, unless the line already has too many
+    fn count_readings(&mut self) -> Result<(), String> {
+        self.readings += 2;
+        if self.readings > MAX_READINGS {
+            return Err("Too many ways to read this line; put * between the names".to_string());
+        }
+        Ok(())
+    }
+
+    fn at_word(&self, offset: usize, word: &str) -> bool {
+        self.peek_at(offset).is_some_and(|t| t.kind == TokenKind::Word && t.text == word)
+    }
+
+    // A filler word of a natural-language phrase, unless a variable has taken the name
+    fn at_phrase_word(&self, offset: usize, word: &str) -> bool {
+        self.peek_at(offset).is_some_and(|t| {
+            t.kind == TokenKind::Word && t.text.eq_ignore_ascii_case(word) && !self.is_defined(t.text)
+        })
+    }
+
+    // A word of a phrase like "25% off 80" or "99.71 split 4 ways" that follows a value,
+    // so it is neither a unit nor an operand
+    fn at_word_after_value(&self, offset: usize) -> bool {
+        ["off", "on", "tip", "split", "ways", "between"].iter().any(|word| self.at_phrase_word(offset, word))
+    }
+
+    // "to cents" at the end of a rounding
+    fn eat_to_cents(&mut self) -> bool {
+        let at_cents = self.at_word(0, "to") && ["cents", "cent"].iter().any(|word| self.at_phrase_word(1, word));
+        if at_cents {
+            self.pos += 2;
+        }
+        at_cents
+    }
+
+    fn at_end(&self) -> bool {
+        self.pos >= self.tokens.len()
+    }
+
+    // A whole line: setrate command, assignment or plain expression
+    fn parse_statement(&mut self) -> Result<Expr, String> {
+        if self.peek().is_some_and(|t| t.text.eq_ignore_ascii_case("setrate")) {
+            return self.parse_set_rate();
+        }
+        if self.peek().is_some_and(|t| t.text.eq_ignore_ascii_case("defunit")) {
+            return self.parse_define_unit();
+        }
+
+        // The name may be several words (monthly rent = 1800)
+        let name_len = self.tokens.iter().take_while(|t| t.kind == TokenKind::Word).count();
+        if name_len > 0 && self.peek_at(name_len).is_some_and(|t| t.text == "=") {
+            let words: Vec<&str> = self.tokens[..name_len].iter().map(|t| t.text).collect();
+            let name = words.join(" ");
+            for word in &words {
+                check_variable_name(word)?;
+            }
+            check_variable_name(&name)?;
+            self.pos = name_len + 1;
+            // The variable still gets defined when the right-hand side is invalid, so lines
+            // using it report the error instead of "not found"
+            let invalid = |message: String| Ok(Expr::Assignment(name.clone(), Box::new(Expr::Error(message))));
+            if self.at_end() || self.peek().is_some_and(|t| t.text == "=") {
+                return invalid(format!("Incomplete assignment: {name} = what?"));
             }
+            // a = b = 5 would leave it unclear which lines define b
+            let chained = self.tokens[self.pos..].iter().take_while(|t| t.kind == TokenKind::Word).count();
+            if chained > 0 && self.peek_at(chained).is_some_and(|t| t.text == "=") {
+                let other = self.tokens[self.pos..self.pos + chained].iter().map(|t| t.text).collect::<Vec<_>>().join(" ");
+                return invalid(format!("One assignment per line: define {other} on a line of its own, then {name} = {other}"));
+            }
+            let expr = self.read_both_ways(&Self::parse_expression).unwrap_or_else(|msg| failed(msg, self.not_found.take()));
+            return Ok(Expr::Assignment(name, Box::new(expr)));
         }
-        
-        let expr = parse_line(expr_str, variables);
-        Some(Expr::Assignment(var_name, Box::new(expr)))
-    } else {
-        None
+
+        // solve x: 2x + 6 = 20 defines x
+        if self.peek().is_some_and(|t| t.text.eq_ignore_ascii_case("solve"))
+            && self.peek_at(1).is_some_and(|t| t.kind == TokenKind::Word)
+            && self.peek_at(2).is_some_and(|t| t.text == ":")
+        {
+            let name = self.tokens[1].text.to_string();
+            check_variable_name(&name)?;
+            self.pos = 3;
+            let equation = self.read_both_ways(&|parser: &mut Self| parser.parse_equation(name.clone()))?;
+            return Ok(Expr::Assignment(name, Box::new(equation)));
+        }
+        if self.tokens.iter().any(|t| t.text == "=") {
+            return self.read_both_ways(&|parser: &mut Self| {
+                let unknown = parser.find_unknown()?;
+                parser.parse_equation(unknown)
+            });
+        }
+
+        self.read_both_ways(&Self::parse_expression)
     }
-}
 
-// Parse a unit conversion expression (expr in unit)
-fn parse_conversion(line: &str, variables: &HashMap<String, Value>) -> Option<Expr> {
-    // Match pattern like "X in Y" or "X to Y"
-    if let Some(caps) = CONVERSION_RE.captures(line) {
-        let value_expr = parse_line(&caps[1], variables);
-        let target_unit = caps[2].trim().to_string();
-        Some(Expr::Convert(Box::new(value_expr), target_unit))
-    } else {
-        None
+    // Parse `<expr> = <expr>` to be solved for `unknown`
+    fn parse_equation(&mut self, unknown: String) -> Result<Expr, String> {
+        self.unknown = Some(unknown.clone());
+        let left = self.parse_expr(0)?;
+        if !self.eat("=") {
+            return Err("Expected '=' in the equation".to_string());
+        }
+        let right = self.parse_expr(0)?;
+        let right = self.expect_end(right)?;
+        Ok(Expr::Solve(unknown, Box::new(left), Box::new(right)))
     }
-}
 
-// Parse a percentage expression (X% of Y)
-fn parse_percentage(line: &str, variables: &HashMap<String, Value>) -> Option<Expr> {
-    // Handle X% of Y
-    if let Some(caps) = PERCENT_OF_RE.captures(line) {
-        let percent_expr = parse_simple_value(&caps[1], variables);
-        let value_expr = parse_line(&caps[2], variables);
-        Some(Expr::PercentOf(Box::new(percent_expr), Box::new(value_expr)))
-    } else {
-        // Handle "X of Y" where X is a variable that might be a percentage
-        if let Some(caps) = VAR_OF_RE.captures(line) {
-            let var_name = caps[1].trim();
-            if variables.contains_key(var_name) {
-                let percent_expr = Expr::Variable(var_name.to_string());
-                let value_expr = parse_line(&caps[2], variables);
-                return Some(Expr::PercentOf(Box::new(percent_expr), Box::new(value_expr)));
+    // The one name in an equation that isn't a variable, keyword or unit
+    fn find_unknown(&self) -> Result<String, String> {
+        let mut unknowns: Vec<&str> = Vec::new();
+        let mut i = 0;
+        while let Some(token) = self.tokens.get(i) {
+            i += 1;
+            if token.kind != TokenKind::Word {
+                continue;
+            }
+            // Skip over variables, including multi-word ones
+            if let Some((_, end)) = lexer::longest_name(&self.tokens, i - 1, |name| self.is_defined(name)) {
+                i = end;
+                continue;
             }
+            let word = token.text;
+            let is_known = lexer::is_keyword(word)
+                || lexer::is_phrase_word(word)
+                || lexer::is_date_word(word)
+                || lexer::is_currency_code(word)
+                || crate::evaluator::is_unit_name(word)
+                || aggregate_word(word).is_some()
+                || statistic(word).is_some()
+                || word.eq_ignore_ascii_case("rand")
+                || word.eq_ignore_ascii_case("clamp")
+                || rounding(word).is_some()
+                || dice_sides(word).is_some()
+                || is_number_word(&word.to_lowercase())
+                || constant(word).is_some()
+                || ["line", "lines"].contains(&word.to_lowercase().as_str());
+            if !is_known && !unknowns.contains(&word) {
+                unknowns.push(word);
+            }
+        }
+        match unknowns.as_slice() {
+            [unknown] => Ok(unknown.to_string()),
+            [] => Err("Can't solve: the equation has no unknown".to_string()),
+            _ => Err(format!("Can't solve for more than one unknown ({})", unknowns.join(", "))),
+        }
+    }
+
+    // Parse a full expression and make sure nothing is left over
+    fn parse_expression(&mut self) -> Result<Expr, String> {
+        // Search-engine phrasings: how many oz in 2 kg, convert 5 kg to lb
+        if self.at_phrase_word(0, "how") && self.at_phrase_word(1, "many") {
+            self.pos += 2;
+            return self.parse_how_many();
+        }
+        if self.at_phrase_word(0, "convert") && self.at_operand(1) {
+            self.pos += 1;
+        }
+        // days until 2025-12-25, weeks since 2024-01-01
+        if let Some(unit) = self.peek().filter(|t| t.kind == TokenKind::Word && !self.is_defined(t.text))
+            && (self.at_phrase_word(1, "until") || self.at_phrase_word(1, "since"))
+        {
+            let unit = unit.text.to_string();
+            self.pos += 1;
+            return self.parse_date_difference(unit);
         }
         
-        // Alternative pattern: "X of what is Y"
-        if let Some(caps) = PERCENT_OF_WHAT_RE.captures(line) {
-            let percent_expr = parse_simple_value(&caps[1], variables);
-            let result_expr = parse_line(&caps[2], variables);
-            // If X% of Y = Z, then Y = Z / (X/100)
-            Some(Expr::PercentOf(Box::new(percent_expr), Box::new(result_expr)))
-        } else {
-            None
+        let expr = self.parse_expr(0)?;
+        self.expect_end(expr)
+    }
+
+    // The rest of `how many <unit> in <expr>` or `how many <unit> until <date>`
+    fn parse_how_many(&mut self) -> Result<Expr, String> {
+        const USAGE: &str = "Expected 'how many <unit> in <value>' or 'how many days until <date>'";
+        
+        let mut words = Vec::new();
+        while let Some(t) = self.peek().filter(|t| {
+            t.kind == TokenKind::Word
+                && !lexer::is_keyword(t.text)
+                && !t.text.eq_ignore_ascii_case("until")
+                && !t.text.eq_ignore_ascii_case("since")
+        }) {
+            words.push(t.text);
+            self.pos += 1;
+        }
+        if words.is_empty() {
+            return Err(USAGE.to_string());
+        }
+        let unit = words.join(" ");
+        
+        // The value stops before any `in`/`to` of its own, which is then left over
+        if self.eat("in") {
+            let value = self.parse_operand(PREC_CONVERT + 1)?;
+            return self.expect_end(Expr::Convert(Box::new(value), unit));
         }
+        if self.at_phrase_word(0, "until") || self.at_phrase_word(0, "since") {
+            return self.parse_date_difference(unit);
+        }
+        Err(USAGE.to_string())
     }
-}
 
-// Parse a date expression (next friday + 2 weeks)
-fn parse_date_expression(line: &str) -> Option<Expr> {
-    // Simple pattern for "next X + Y Z" where X is a day, Y is a number, Z is a unit
-    if let Some(caps) = DATE_EXPR_RE.captures(line) {
-        let day = caps[1].to_lowercase();
-        let amount = caps.get(2).map_or(0, |m| m.as_str().parse::<i64>().unwrap_or(0));
-        // Store the lowercase unit in a new variable to avoid the temporary value issue
-        let unit = if let Some(m) = caps.get(3) {
-            m.as_str().to_lowercase()
-        } else {
-            "days".to_string()
+    // The rest of `<unit> until <date>` or `<unit> since <date>`, counted from today
+    fn parse_date_difference(&mut self, unit: String) -> Result<Expr, String> {
+        let since = self.at_phrase_word(0, "since");
+        self.pos += 1;
+        let date = self.parse_operand(PREC_CONVERT + 1)?;
+        let today = Expr::Day(Day::Today);
+        let (from, to) = if since { (date, today) } else { (today, date) };
+        self.expect_end(Expr::DateDiff(Box::new(from), Box::new(to), unit.to_lowercase()))
+    }
+
+    // A whole expression has been parsed; anything after it is an error
+    fn expect_end(&self, expr: Expr) -> Result<Expr, String> {
+        match self.peek() {
+            None => Ok(expr),
+            Some(t) if t.text == ")" => Err("Unmatched closing parenthesis".to_string()),
+            Some(_) => Err("Invalid expression".to_string()),
+        }
+    }
+
+    // Parse a setrate command (setrate USD to EUR = 0.92)
+    fn parse_set_rate(&mut self) -> Result<Expr, String> {
+        const USAGE: &str = "Usage: setrate USD to EUR = 0.92";
+        self.advance();
+
+        let from = self.advance().filter(|t| is_currency_like(t)).ok_or(USAGE)?;
+        if !(self.eat("to") || self.eat("in")) {
+            return Err(USAGE.to_string());
+        }
+        let to = self.advance().filter(|t| is_currency_like(t)).ok_or(USAGE)?;
+        if !self.eat("=") {
+            return Err(USAGE.to_string());
+        }
+        let negative = self.eat("-");
+        let rate = self.advance()
+            .filter(|t| t.kind == TokenKind::Number)
+            .and_then(|t| crate::locale::parse_number(t.text))
+            .ok_or(USAGE)?;
+        if !self.at_end() {
+            return Err(USAGE.to_string());
+        }
+
+        let (from, to) = (from.text.to_uppercase(), to.text.to_uppercase());
+        if from == to {
+            return Err(format!("1 {from} is always 1 {to}; set the rate between two different currencies"));
+        }
+        if negative || rate == 0.0 {
+            return Err("An exchange rate has to be more than 0".to_string());
+        }
+        // The rate is set when the line is evaluated
+        Ok(Expr::SetRate(from, to, rate))
+    }
+
+    // Parse a unit definition (defunit sprint = 2 week). The unit is registered when the
+    // line is evaluated.
+    fn parse_define_unit(&mut self) -> Result<Expr, String> {
+        const USAGE: &str = "Usage: defunit sprint = 2 week";
+        self.advance();
+
+        let name = self.advance()
+            .filter(|t| t.kind == TokenKind::Word && !lexer::is_keyword(t.text))
+            .ok_or(USAGE)?
+            .text
+            .to_string();
+        if !self.eat("=") {
+            return Err(USAGE.to_string());
+        }
+        let factor = self.advance()
+            .filter(|t| t.kind == TokenKind::Number)
+            .and_then(|t| crate::locale::parse_number(t.text))
+            .ok_or(USAGE)?;
+        let unit = self.parse_unit_name().map_err(|_| USAGE.to_string())?;
+        if !self.at_end() {
+            return Err(USAGE.to_string());
+        }
+        Ok(Expr::DefineUnit(name, factor, unit))
+    }
+
+    // Precedence climbing: parse operators that bind at least as tightly as `min_prec`
+    fn parse_expr(&mut self, min_prec: u8) -> Result<Expr, String> {
+        // Each level of nesting recurses, so a line like ((((… or - - - -… could otherwise
+        // run out of stack and take the app down with it. An operator's right-hand side
+        // returns before the next operator is read, so a chain doesn't add up.
+        if self.depth == MAX_DEPTH {
+            return Err("Expression too deeply nested".to_string());
+        }
+        self.depth += 1;
+        let expr = self.parse_binary_ops(min_prec);
+        self.depth -= 1;
+        expr
+    }
+
+    fn parse_binary_ops(&mut self, min_prec: u8) -> Result<Expr, String> {
+        let mut left = self.parse_prefix()?;
+
+        while let Some(token) = self.peek().copied() {
+            let (op, prec) = match (token.kind, token.text) {
+                (TokenKind::Word, "in" | "to") => (None, PREC_CONVERT),
+                (TokenKind::Operator, "+") => (Some(Op::Add), PREC_ADD),
+                (TokenKind::Operator, "-") => (Some(Op::Subtract), PREC_ADD),
+                (TokenKind::Operator, "*") => (Some(Op::Multiply), PREC_MUL),
+                (TokenKind::Operator, "/") => (Some(Op::Divide), PREC_MUL),
+                (TokenKind::Operator, "%") | (TokenKind::Word, "mod") => (Some(Op::Modulo), PREC_MUL),
+                (TokenKind::Word, "of") => (None, PREC_OF),
+                (TokenKind::Word, _) if ["off", "split", "between"].iter().any(|word| self.at_phrase_word(0, word)) => (None, PREC_OF),
+                (TokenKind::Operator, "^" | "**") => (Some(Op::Power), PREC_POWER),
+                _ if self.at_implicit_multiplication() => (Some(Op::Multiply), PREC_MUL),
+                _ => break,
+            };
+            if prec < min_prec {
+                break;
+            }
+            // An implicit multiplication has no token of its own
+            if !self.at_implicit_multiplication() {
+                self.advance();
+            }
+
+            left = match (op, token.text) {
+                (None, "of") => {
+                    // "X of what is Y" reads the same as "X of Y"
+                    if self.at_word(0, "what") && self.at_word(1, "is") {
+                        self.pos += 2;
+                    }
+                    // Right associative
+                    let right = self.parse_operand(prec)?;
+                    let percent = match left {
+                        Expr::Percentage(p) => Expr::Number(p),
+                        other => other,
+                    };
+                    Expr::PercentOf(Box::new(percent), Box::new(right))
+                }
+                (None, text) if text.eq_ignore_ascii_case("off") => {
+                    let right = self.parse_operand(prec)?;
+                    Expr::Off(Box::new(left), Box::new(right))
+                }
+                // A range check answers yes or no, and there are no true/false values yet
+                (None, text) if text.eq_ignore_ascii_case("between") => {
+                    return Err("'between' needs true/false values, which aren't supported yet; clamp(value, low, high) keeps a value in range".to_string());
+                }
+                (None, text) if text.eq_ignore_ascii_case("split") => {
+                    // "split between 4" reads the same as "split 4 ways"
+                    if self.at_phrase_word(0, "between") {
+                        self.advance();
+                    }
+                    let ways = self.parse_ways()?;
+                    Expr::Split(Box::new(left), Box::new(ways))
+                }
+                (None, _) => {
+                    let target = self.parse_unit_name()?;
+                    Expr::Convert(Box::new(left), target)
+                }
+                (Some(op), _) => {
+                    // Power is right associative, everything else is left associative
+                    let next_prec = if matches!(op, Op::Power) { prec } else { prec + 1 };
+                    let right = self.parse_operand(next_prec)?;
+                    Expr::BinaryOp(Box::new(left), op, Box::new(right))
+                }
+            };
+        }
+
+        Ok(left)
+    }
+
+    // Comma-separated values up to `close`, as in `max(...)` or `[...]`. With `en` number
+    // formatting, 100,200 is a single number, so a list must have a space after each comma.
+    fn parse_list(&mut self, close: &str, name: &str) -> Result<Vec<Expr>, String> {
+        let mut arguments = Vec::new();
+        if self.eat(close) {
+            return Ok(arguments);
+        }
+        loop {
+            if let Some(number) = self.peek().filter(|t| t.kind == TokenKind::Number)
+                && crate::locale::current().group_separator() == ','
+                && number.text.contains(',')
+            {
+                return Err(format!("Ambiguous '{}': put a space after each comma in {name}", number.text));
+            }
+            arguments.push(self.parse_operand(0)?);
+            if self.eat(close) {
+                return Ok(arguments);
+            }
+            if !self.eat(",") {
+                return Err(format!("Expected ',' or '{close}' in {name}"));
+            }
+        }
+    }
+
+    // The number of ways to split an amount, with an optional "ways" after it
+    fn parse_ways(&mut self) -> Result<Expr, String> {
+        let ways = self.parse_operand(PREC_OF + 1)?;
+        if self.at_phrase_word(0, "ways") {
+            self.advance();
+        }
+        Ok(ways)
+    }
+
+    // The right-hand side of a binary operator, which must exist
+    fn parse_operand(&mut self, min_prec: u8) -> Result<Expr, String> {
+        if self.at_end() {
+            return Err("Expression ends with an operator".to_string());
+        }
+        self.read_locally(&|parser: &mut Self| parser.parse_expr(min_prec))
+    }
+
+    // Whether the token at `offset` can start an operand
+    fn at_operand(&self, offset: usize) -> bool {
+        match self.peek_at(offset) {
+            Some(t) => match t.kind {
+                TokenKind::Number => true,
+                TokenKind::Word => {
+                    (!lexer::is_keyword(t.text) || t.text == "next") && !self.at_word_after_value(offset)
+                }
+                TokenKind::Bracket => t.text == "(" || t.text == "[",
+                _ => false,
+            },
+            None => false,
+        }
+    }
+
+    // Whether the next token multiplies the operand just parsed without a `*` in between
+    fn at_implicit_multiplication(&self) -> bool {
+        let (Some(previous), Some(next)) = (self.pos.checked_sub(1).and_then(|i| self.tokens.get(i)), self.peek()) else {
+            return false;
         };
-        
-        Some(Expr::DateOffset(day, amount, unit))
-    } else {
-        None
+        let after_value = previous.kind == TokenKind::Number
+            || previous.text == ")"
+            || (previous.kind == TokenKind::Word && !lexer::is_keyword(previous.text));
+        match next.kind {
+            TokenKind::Bracket => next.text == "(" && after_value,
+            TokenKind::Number => previous.text == ")",
+            TokenKind::Word => after_value && self.at_value_name(),
+            _ => false,
+        }
     }
-}
 
-// Parse an expression enclosed in parentheses
-fn parse_parentheses(line: &str, variables: &HashMap<String, Value>) -> Option<Expr> {
-    // Check if the entire expression is wrapped in parentheses
-    if let Some(caps) = PARENTHESIS_RE.captures(line) {
-        let inner_expr = &caps[1];
-        let parsed_inner = parse_line(inner_expr, variables);
-        return Some(parsed_inner);
+    // Whether the next word is a variable, constant or the unknown being solved for, and not
+    // a unit or currency
+    fn at_value_name(&self) -> bool {
+        let Some(token) = self.peek().filter(|t| t.kind == TokenKind::Word) else {
+            return false;
+        };
+        let word = token.text;
+        if lexer::is_keyword(word) || lexer::is_currency_code(word) || crate::evaluator::is_unit_name(word) {
+            return false;
+        }
+        self.unknown.as_deref() == Some(word)
+            || constant(word).is_some()
+            || lexer::longest_name(&self.tokens, self.pos, |name| self.is_defined(name)).is_some()
     }
-    
-    // If there are parentheses but they don't enclose the entire expression,
-    // we'll handle them in the binary operation parsing
-    None
-}
 
-// Parse a binary operation (expr op expr)
-fn parse_binary_op(line: &str, variables: &HashMap<String, Value>) -> Option<Expr> {
-    // Find the outermost +/- operator by tracking parentheses balance
-    let mut paren_balance = 0;
-    let mut last_add_sub_pos = None;
-    
-    for (i, c) in line.char_indices() {
-        match c {
-            '(' => paren_balance += 1,
-            ')' => paren_balance -= 1,
-            '+' | '-' => {
-                if paren_balance == 0 {
-                    last_add_sub_pos = Some(i);
+    // Unary operators, then a primary value
+    fn parse_prefix(&mut self) -> Result<Expr, String> {
+        if self.eat("-") {
+            let operand = self.parse_operand(PREC_UNARY)?;
+            return Ok(match operand {
+                Expr::Number(n) => Expr::Number(-n),
+                Expr::UnitValue(v, u) => Expr::UnitValue(-v, u),
+                Expr::Percentage(p) => Expr::Percentage(-p),
+                other => Expr::BinaryOp(Box::new(Expr::Number(-1.0)), Op::Multiply, Box::new(other)),
+            });
+        }
+        if self.eat("+") {
+            return self.parse_operand(PREC_UNARY);
+        }
+        self.parse_primary()
+    }
+
+    // A primary value with an optional postfix %
+    fn parse_primary(&mut self) -> Result<Expr, String> {
+        let mut value = self.parse_atom()?;
+
+        // prices[2], or sizes[M] with a word that isn't a variable as the key
+        while self.eat("[") {
+            if let (Some(key), Some(close)) = (self.peek().copied(), self.peek_at(1))
+                && key.kind == TokenKind::Word
+                && close.text == "]"
+                && !self.is_defined(key.text)
+            {
+                self.pos += 2;
+                value = Expr::Lookup(Box::new(value), key.text.to_string());
+                continue;
+            }
+            let index = self.parse_operand(0)?;
+            if !self.eat("]") {
+                return Err("Missing closing bracket".to_string());
+            }
+            value = Expr::Index(Box::new(value), Box::new(index));
+        }
+
+        if !self.peek().is_some_and(|t| t.kind == TokenKind::Operator && t.text == "%") {
+            return Ok(value);
+        }
+        let previous = &self.tokens[self.pos - 1];
+        let glued = previous.start + previous.text.len() == self.tokens[self.pos].start;
+        let operand_follows = self.at_operand(1);
+        if glued && operand_follows {
+            return Err("Ambiguous '%': write '10 % 3' or '10 mod 3' for modulo".to_string());
+        }
+        if !glued && operand_follows {
+            // Modulo, handled by the caller as a binary operator
+            return Ok(value);
+        }
+
+        self.pos += 1;
+        let percent = match value {
+            Expr::Number(n) => Expr::Percentage(n),
+            other => Expr::ToPercentage(Box::new(other)),
+        };
+
+        // A tip is a percentage like any other: "84.50 + 18% tip", "18% tip on 84.50"
+        if self.at_phrase_word(0, "tip") {
+            self.advance();
+            if self.at_phrase_word(0, "on") {
+                self.advance();
+                let value = self.parse_operand(PREC_OF)?;
+                let percent = match percent {
+                    Expr::Percentage(p) => Expr::Number(p),
+                    other => other,
+                };
+                return Ok(Expr::PercentOf(Box::new(percent), Box::new(value)));
+            }
+        }
+        Ok(percent)
+    }
+
+    // Numbers (with an optional unit), variables, dates and parenthesized expressions
+    fn parse_atom(&mut self) -> Result<Expr, String> {
+        let Some(token) = self.advance() else {
+            // Out of tokens where an operand should be: most often `5 +` while typing
+            return Err(match self.tokens.last() {
+                Some(last) if last.kind == TokenKind::Operator => "Expression ends with an operator",
+                _ => "Invalid expression",
+            }.to_string());
+        };
+
+        match token.kind {
+            TokenKind::Number => self.parse_number(token),
+            TokenKind::Word if token.text.eq_ignore_ascii_case("next") => self.parse_date(),
+            TokenKind::Word if lexer::is_keyword(token.text) => Err("Invalid expression".to_string()),
+            TokenKind::Word if self.unknown.as_deref() == Some(token.text) => Ok(Expr::Variable(token.text.to_string())),
+            TokenKind::Word => self.parse_word(token),
+            TokenKind::Bracket if token.text == "(" => {
+                let inner = self.parse_operand(0)?;
+                if !self.eat(")") {
+                    return Err("Missing closing parenthesis".to_string());
+                }
+                // "(2 * 5) days" gives the result a unit, as for a variable
+                if !self.at_value_name()
+                    && let Some(unit) = self.parse_unit_after_value()
+                {
+                    return Ok(Expr::BinaryOp(Box::new(inner), Op::Multiply, Box::new(Expr::UnitValue(1.0, unit))));
                 }
+                Ok(inner)
             }
-            _ => {}
+            TokenKind::Bracket if token.text == "[" => Ok(Expr::List(self.parse_list("]", "[...]")?)),
+            TokenKind::Bracket if token.text == "{" => self.parse_map(),
+            TokenKind::Bracket if token.text == ")" => Err("Unmatched closing parenthesis".to_string()),
+            TokenKind::Operator if token.text == "%" => Err("Invalid percentage".to_string()),
+            TokenKind::Operator | TokenKind::Bracket => Err("Invalid expression".to_string()),
+            _ => Err("Invalid input".to_string()),
         }
     }
-    
-    // If we found a balanced +/- operator outside parentheses
-    if let Some(pos) = last_add_sub_pos {
-        let left = &line[..pos].trim();
-        let op_char = line.chars().nth(pos).unwrap();
-        let right = &line[pos+1..].trim();
-        
-        let left_expr = parse_line(left, variables);
-        let right_expr = parse_line(right, variables);
-        
-        let op = match op_char {
-            '+' => Op::Add,
-            '-' => Op::Subtract,
-            _ => unreachable!(),
+
+    // A number, with whatever follows it: a date, dice, a duration, a scale or a unit
+    fn parse_number(&mut self, token: Token<'a>) -> Result<Expr, String> {
+        let value = crate::locale::parse_number(token.text).ok_or("Invalid number")?;
+
+        if let Some(date) = self.parse_date_literal(token)? {
+            return Ok(Expr::Date(date));
+        }
+        // 3d6 rolls three six-sided dice
+        if let Some(sides) = self.peek()
+            .filter(|t| t.start == token.start + token.text.len())
+            .and_then(|t| dice_sides(t.text))
+        {
+            self.advance();
+            if value.fract() != 0.0 || !(1.0..=1000.0).contains(&value) {
+                return Err("Roll between 1 and 1000 dice".to_string());
+            }
+            if sides == 0 {
+                return Err("Dice need at least one side".to_string());
+            }
+            return Ok(Expr::Dice(value as u32, sides));
+        }
+
+        if let Some(duration) = self.parse_compound_duration(value)? {
+            return Ok(duration);
+        }
+        // "2 million", "3 dozen"
+        let value = value * self.parse_scales();
+        self.parse_after_value(value)
+    }
+
+    // A name, or one of the words that read like a value or a function. Kept out of
+    // parse_atom so that the bracket case, which recurses, keeps a small stack frame.
+    fn parse_word(&mut self, token: Token<'a>) -> Result<Expr, String> {
+        // Prefer the longest defined name, so "monthly rent" wins over "monthly"
+        let Some((name, end)) = lexer::longest_name(&self.tokens, self.pos - 1, |name| self.is_defined(name)) else {
+            // "first monday of march"; without a weekday and `of` after it, `second` is
+            // still the unit
+            if let Some(occurrence) = ordinal(token.text)
+                && let Some(day) = self.peek().and_then(|t| weekday(t.text))
+                && self.at_word(1, "of")
+            {
+                self.pos += 2;
+                return Ok(Expr::WeekdayOfMonth(occurrence, day, self.parse_month_of()?));
+            }
+            // "two dozen", "half a million"
+            if let Some(value) = self.parse_number_words() {
+                return self.parse_after_value(value);
+            }
+            // A variable called "today" takes precedence over the date
+            if token.text.eq_ignore_ascii_case("today") {
+                return Ok(Expr::Day(Day::Today));
+            }
+            // "this friday", or "friday" alone
+            if token.text.eq_ignore_ascii_case("this")
+                && let Some(day) = self.peek().and_then(|t| weekday(t.text))
+            {
+                self.advance();
+                return Ok(Expr::Day(Day::This(day)));
+            }
+            if let Some(day) = weekday(token.text) {
+                return Ok(Expr::Day(Day::This(day)));
+            }
+            // Likewise for references to other lines
+            if token.text.eq_ignore_ascii_case("line") && self.peek().is_some_and(|t| t.kind == TokenKind::Number) {
+                return Ok(Expr::LineRef(self.parse_line_number()?));
+            }
+            if let Some(aggregate) = aggregate_word(token.text)
+                && self.at_word(0, "of")
+                && self.peek_at(1).is_some_and(|t| t.text.eq_ignore_ascii_case("lines"))
+            {
+                self.pos += 2;
+                return self.parse_line_range(aggregate);
+            }
+            // "half of 200", "a quarter of" without the article
+            if let Some(parts) = fraction_word(token.text)
+                && self.at_word(0, "of")
+            {
+                self.advance();
+                let value = self.parse_operand(PREC_OF)?;
+                return Ok(Expr::BinaryOp(Box::new(value), Op::Divide, Box::new(Expr::Number(parts))));
+            }
+            if let Some(statistic) = statistic(token.text)
+                && self.eat("(")
+            {
+                let arguments = self.parse_list(")", &format!("{}(...)", statistic.name()))?;
+                return Ok(Expr::Statistic(statistic, arguments));
+            }
+            if token.text.eq_ignore_ascii_case("rand") && self.eat("(") {
+                return Ok(Expr::Random(self.parse_list(")", "rand(...)")?));
+            }
+            if token.text.eq_ignore_ascii_case("clamp") && self.eat("(") {
+                return Ok(Expr::Clamp(self.parse_list(")", "clamp(...)")?));
+            }
+            if let Some(rounding) = rounding(token.text) {
+                // "round(x, 0.05)", or "round(x) to cents"
+                if self.eat("(") {
+                    let mut arguments = self.parse_list(")", &format!("{}(...)", rounding.name()))?;
+                    if arguments.len() == 1 && self.eat_to_cents() {
+                        return Ok(Expr::RoundToCents(rounding, Box::new(arguments.remove(0))));
+                    }
+                    return Ok(Expr::Round(rounding, arguments));
+                }
+                // "round 12.3456 USD to cents"
+                let value = self.parse_operand(PREC_OF + 1)?;
+                if !self.eat_to_cents() {
+                    return Err(format!("Expected 'to cents' after the amount to {}", rounding.name()));
+                }
+                return Ok(Expr::RoundToCents(rounding, Box::new(value)));
+            }
+            // "split 99.71 between 4"
+            if token.text.eq_ignore_ascii_case("split") {
+                let total = self.parse_operand(PREC_OF + 1)?;
+                if !self.at_phrase_word(0, "between") {
+                    return Err("Expected 'between' after the amount to split".to_string());
+                }
+                self.advance();
+                let ways = self.parse_ways()?;
+                return Ok(Expr::Split(Box::new(total), Box::new(ways)));
+            }
+            // "discount of 25% on 80" is the amount taken off, 20
+            if token.text.eq_ignore_ascii_case("discount") && self.at_word(0, "of") {
+                self.advance();
+                let percent = match self.parse_operand(PREC_OF + 1)? {
+                    Expr::Percentage(p) => Expr::Number(p),
+                    other => other,
+                };
+                if !self.at_phrase_word(0, "on") {
+                    return Err("Expected 'on' after the discount".to_string());
+                }
+                self.advance();
+                let value = self.parse_operand(PREC_OF)?;
+                return Ok(Expr::PercentOf(Box::new(percent), Box::new(value)));
+            }
+            if let Some(value) = constant(token.text) {
+                return Ok(Expr::Number(value));
+            }
+            self.not_found = Some(token.text.to_string());
+            return Err(format!("'{}' not found", token.text));
         };
-        
-        return Some(Expr::BinaryOp(Box::new(left_expr), op, Box::new(right_expr)));
+        self.pos = end;
+        let variable = Expr::Variable(name);
+
+        // "price USD" gives a plain variable a unit
+        match self.parse_unit_after_value() {
+            Some(unit) => Ok(Expr::BinaryOp(
+                Box::new(variable),
+                Op::Multiply,
+                Box::new(Expr::UnitValue(1.0, unit)),
+            )),
+            None => Ok(variable),
+        }
     }
-    
-    // If no +/- found, look for outermost */^% operators
-    let mut paren_balance = 0;
-    let mut last_mul_div_pos = None;
-    
-    for (i, c) in line.char_indices() {
-        match c {
-            '(' => paren_balance += 1,
-            ')' => paren_balance -= 1,
-            '*' | '/' | '^' | '%' => {
-                if paren_balance == 0 {
-                    last_mul_div_pos = Some(i);
+
+    // The rest of a duration with several parts, glued (1h30m) or spaced (2h 15m, 2 h 15 min),
+    // as a single value in its smallest unit. None when the number has at most one duration
+    // unit, which is parsed as an ordinary unit value.
+    fn parse_compound_duration(&mut self, first: f64) -> Result<Option<Expr>, String> {
+        let start = self.pos;
+        let mut parts = Vec::new();
+        let mut amount = first;
+        while let Some(word) = self.peek().filter(|t| t.kind == TokenKind::Word).map(|t| t.text) {
+            let Some(components) = split_duration(amount, word) else {
+                // 1h30x starts like a duration but isn't one; m2 is an area unit
+                let starts_like_duration = word.find(|c: char| c.is_ascii_digit())
+                    .is_some_and(|digit| duration_unit(&word[..digit]).is_some())
+                    && word.ends_with(char::is_alphabetic);
+                if starts_like_duration {
+                    return Err(format!("Invalid duration '{word}'"));
                 }
+                break;
+            };
+            parts.extend(components);
+            self.pos += 1;
+
+            // Another number and duration unit may follow after a space
+            match (self.peek(), self.peek_at(1)) {
+                (Some(number), Some(unit))
+                    if number.kind == TokenKind::Number
+                        && unit.kind == TokenKind::Word
+                        && split_duration(0.0, unit.text).is_some() =>
+                {
+                    amount = crate::locale::parse_number(number.text).ok_or("Invalid number")?;
+                    self.pos += 1;
+                }
+                _ => break,
             }
-            _ => {}
         }
+
+        if parts.len() < 2 {
+            self.pos = start;
+            return Ok(None);
+        }
+        if !parts.windows(2).all(|pair| pair[0].1 > pair[1].1) {
+            return Err("Invalid duration; put larger units first, like 1h30m".to_string());
+        }
+        let (_, smallest, unit) = parts[parts.len() - 1];
+        let seconds: f64 = parts.iter().map(|(amount, seconds, _)| amount * seconds).sum();
+        Ok(Some(Expr::UnitValue(seconds / smallest, unit.to_string())))
     }
-    
-    // If we found a balanced */^% operator outside parentheses
-    if let Some(pos) = last_mul_div_pos {
-        let left = &line[..pos].trim();
-        let op_char = line.chars().nth(pos).unwrap();
-        let right = &line[pos+1..].trim();
-        
-        let left_expr = parse_line(left, variables);
-        let right_expr = parse_line(right, variables);
-        
-        let op = match op_char {
-            '*' => Op::Multiply,
-            '/' => Op::Divide,
-            '^' => Op::Power,
-            '%' => Op::Modulo,
-            _ => unreachable!(),
+
+    // A 1-based line number as shown in the editor
+    fn parse_line_number(&mut self) -> Result<usize, String> {
+        let token = self.advance().filter(|t| t.kind == TokenKind::Number).ok_or("Expected a line number")?;
+        token.text.parse::<usize>()
+            .ok()
+            .filter(|&n| n > 0)
+            .ok_or_else(|| format!("Invalid line number '{}'", token.text))
+    }
+
+    // The `2..5` of `sum of lines 2..5`, both ends included
+    fn parse_line_range(&mut self, aggregate: Aggregate) -> Result<Expr, String> {
+        const USAGE: &str = "Expected a range of lines like 'lines 2..5'";
+        let from = self.parse_line_number().map_err(|_| USAGE.to_string())?;
+        if !(self.eat(".") && self.eat(".")) {
+            return Err(USAGE.to_string());
+        }
+        let to = self.parse_line_number().map_err(|_| USAGE.to_string())?;
+        if to < from {
+            return Err(format!("Line range {from}..{to} is backwards"));
+        }
+        if to - from >= MAX_LINE_RANGE {
+            return Err(format!("Line range {from}..{to} is too long"));
+        }
+        Ok(Expr::LineAggregate(aggregate, from, to))
+    }
+
+    // A number's unit, rate or ingredient, if it has one: the `km` of `10 km`
+    fn parse_after_value(&mut self, value: f64) -> Result<Expr, String> {
+        // In `2x` the x is multiplied rather than being a unit
+        if self.at_value_name() {
+            return Ok(Expr::Number(value));
+        }
+        match self.parse_unit_after_value() {
+            Some(unit) => {
+                let unit = self.parse_rate(unit)?;
+                match self.parse_ingredient(&unit) {
+                    Some(ingredient) => Ok(Expr::Ingredient(Box::new(Expr::UnitValue(value, unit)), ingredient)),
+                    None => Ok(Expr::UnitValue(value, unit)),
+                }
+            }
+            None => Ok(Expr::Number(value)),
+        }
+    }
+
+    // The product of the scale words after a number, as in `2 million` or `3 dozen`, 1 if
+    // there are none. A variable or unit of the same name wins.
+    fn parse_scales(&mut self) -> f64 {
+        let mut product = 1.0;
+        while let Some(scale) = self.peek()
+            .filter(|t| t.kind == TokenKind::Word && !self.is_defined(t.text) && !crate::evaluator::is_unit_name(t.text))
+            .and_then(|t| scale_word(t.text))
+        {
+            self.pos += 1;
+            product *= scale;
+        }
+        product
+    }
+
+    // A number written in words, its first word just consumed: `seven`, `twenty-one`, `two
+    // dozen`, `three hundred and five`, `half a million`. None, with nothing more consumed,
+    // when the words don't make a number.
+    fn parse_number_words(&mut self) -> Option<f64> {
+        let mut words = Vec::new();
+        let mut end = self.pos - 1;
+        while let Some(&token) = self.tokens.get(end).filter(|t| t.kind == TokenKind::Word) {
+            let word = token.text.to_lowercase();
+            if !is_number_word(&word) {
+                break;
+            }
+            words.push(word);
+            end += 1;
+            // twenty-one
+            if let [dash, ones] = &self.tokens[end..(end + 2).min(self.tokens.len())]
+                && dash.text == "-"
+                && dash.start == token.start + token.text.len()
+                && ones.start == dash.start + 1
+            {
+                words.push(format!("-{}", ones.text.to_lowercase()));
+                end += 2;
+            }
+        }
+        // The longest run of words that makes a number: in `two and three`, just `two`
+        while !words.is_empty() {
+            if let Some(value) = words_value(&words) {
+                self.pos = end;
+                return Some(value);
+            }
+            let last = words.pop()?;
+            end -= if last.starts_with('-') { 2 } else { 1 };
+        }
+        None
+    }
+
+    // The month after `of` in `first monday of ...`
+    fn parse_month_of(&mut self) -> Result<MonthOf, String> {
+        const EXPECTED: &str = "Expected a month after 'of': march, september 2025, 2025-09 or next month";
+        let token = self.advance().ok_or(EXPECTED)?;
+        // 2025-09
+        if token.kind == TokenKind::Number {
+            let parts: Vec<Token> = self.tokens[self.pos..].iter().take(2).copied().collect();
+            let glued = matches!(parts.as_slice(), [dash, month]
+                if dash.text == "-" && dash.start == token.start + token.text.len() && month.start == dash.start + 1);
+            let month = parts.get(1).and_then(|t| t.text.parse::<u32>().ok()).filter(|m| (1..=12).contains(m));
+            return match (glued, token.text.len() == 4, token.text.parse::<i32>().ok(), month) {
+                (true, true, Some(year), Some(month)) => {
+                    self.pos += 2;
+                    Ok(MonthOf::Named(month, Some(year)))
+                }
+                _ => Err(EXPECTED.to_string()),
+            };
+        }
+        if let Some(month) = month_name(token.text) {
+            let year = self.peek()
+                .filter(|t| t.kind == TokenKind::Number && t.text.len() == 4)
+                .and_then(|t| t.text.parse::<i32>().ok());
+            if year.is_some() {
+                self.pos += 1;
+            }
+            return Ok(MonthOf::Named(month, year));
+        }
+        let relative = match token.text.to_lowercase().as_str() {
+            "this" => MonthOf::This,
+            "next" => MonthOf::Next,
+            _ => return Err(EXPECTED.to_string()),
         };
-        
-        return Some(Expr::BinaryOp(Box::new(left_expr), op, Box::new(right_expr)));
+        if !self.peek().is_some_and(|t| t.text.eq_ignore_ascii_case("month")) {
+            return Err(EXPECTED.to_string());
+        }
+        self.pos += 1;
+        Ok(relative)
     }
-    
-    // Fallback to regex-based parsing for simpler cases
-    if let Some(caps) = ADD_SUB_RE.captures(line) {
-        let left = parse_line(&caps[1], variables);
-        let right = parse_line(&caps[3], variables);
-        
-        let op = match &caps[2] {
-            "+" => Op::Add,
-            "-" => Op::Subtract,
-            _ => return None,
+
+    // The unit attached to a value, e.g. the `km` in `10 km`. `in` and `to` are only
+    // units when they can't be a conversion, i.e. nothing but another operator follows.
+    fn parse_unit_after_value(&mut self) -> Option<String> {
+        let token = self.peek().filter(|t| t.kind == TokenKind::Word)?;
+        let is_unit = match token.text {
+            "in" | "to" => match self.peek_at(1) {
+                None => true,
+                Some(next) => match next.kind {
+                    TokenKind::Operator => true,
+                    TokenKind::Bracket => next.text == ")",
+                    TokenKind::Word => next.text == "in" || next.text == "to",
+                    _ => false,
+                },
+            },
+            // "5 off 20" takes 5 off rather than being 5 of a unit called "off"
+            word => !lexer::is_keyword(word) && !self.at_word_after_value(0),
         };
-        
-        return Some(Expr::BinaryOp(Box::new(left), op, Box::new(right)));
+        if !is_unit {
+            return None;
+        }
+        let unit = token.text.to_string();
+        self.pos += 1;
+        Some(unit)
     }
-    
-    // If no addition/subtraction, check for multiplication, division, etc.
-    if let Some(caps) = MUL_DIV_RE.captures(line) {
-        let left = parse_line(&caps[1], variables);
-        let right = parse_line(&caps[3], variables);
-        
-        let op = match &caps[2] {
-            "*" => Op::Multiply,
-            "/" => Op::Divide,
-            "^" => Op::Power,
-            "%" => Op::Modulo,
-            _ => return None,
+
+    // `per month`, or `monthly`, after a value's unit makes it a rate: 120 USD per year is
+    // the unit USD/year
+    fn parse_rate(&mut self, unit: String) -> Result<String, String> {
+        if self.at_word(0, "per") {
+            self.pos += 1;
+            let period = self.advance()
+                .filter(|t| t.kind == TokenKind::Word)
+                .and_then(|t| evaluator::rate_period(t.text))
+                .ok_or("'per' takes a time: per hour, day, week, month or year")?;
+            return Ok(format!("{unit}/{period}"));
+        }
+        match self.peek().filter(|t| t.kind == TokenKind::Word).and_then(|t| evaluator::period_adverb(t.text)) {
+            Some(period) => {
+                self.pos += 1;
+                Ok(format!("{unit}/{period}"))
+            }
+            None => Ok(unit),
+        }
+    }
+
+    // The entries of a `{S: 36, M: 40}` map after its `{`. Keys are single words or numbers.
+    fn parse_map(&mut self) -> Result<Expr, String> {
+        let mut entries: Vec<(String, Expr)> = Vec::new();
+        if self.eat("}") {
+            return Ok(Expr::Map(entries));
+        }
+        loop {
+            let key = self.advance()
+                .filter(|t| matches!(t.kind, TokenKind::Word | TokenKind::Number))
+                .ok_or("Expected a key in {...}")?
+                .text
+                .to_string();
+            if !self.eat(":") {
+                return Err(format!("Expected ':' after '{key}' in {{...}}"));
+            }
+            if entries.iter().any(|(k, _)| *k == key) {
+                return Err(format!("'{key}' appears twice in {{...}}"));
+            }
+            entries.push((key, self.parse_operand(0)?));
+            if self.eat("}") {
+                return Ok(Expr::Map(entries));
+            }
+            if !self.eat(",") {
+                return Err("Expected ',' or '}' in {...}".to_string());
+            }
+        }
+    }
+
+    // The word naming what a volume or weight measures, as in `2 cups flour`. Any word that
+    // isn't a variable, unit or keyword counts, so an unknown ingredient can be named in the
+    // error.
+    fn parse_ingredient(&mut self, unit: &str) -> Option<String> {
+        let token = self.peek().filter(|t| t.kind == TokenKind::Word)?;
+        let word = token.text;
+        if !crate::evaluator::measures_ingredient(unit)
+            || lexer::is_keyword(word)
+            || lexer::is_phrase_word(word)
+            || lexer::is_date_word(word)
+            || lexer::is_currency_code(word)
+            || crate::evaluator::is_unit_name(word)
+            || self.at_value_name()
+        {
+            return None;
+        }
+        self.pos += 1;
+        Some(word.to_string())
+    }
+
+    // The target of a conversion: one word (which may be `in`, the inch), optionally
+    // followed by more non-keyword words for names like "fluid ounces"
+    fn parse_unit_name(&mut self) -> Result<String, String> {
+        let first = self.advance()
+            .filter(|t| t.kind == TokenKind::Word)
+            .ok_or("Missing unit to convert to")?;
+        let mut unit = first.text.to_string();
+        while let Some(t) = self.peek().filter(|t| t.kind == TokenKind::Word && !lexer::is_keyword(t.text)) {
+            unit.push(' ');
+            unit.push_str(t.text);
+            self.pos += 1;
+        }
+        Ok(unit)
+    }
+
+    // A date expression after `next`: next friday, next monday + 2 weeks
+    // An ISO date such as 2025-12-25, written without spaces. `year` is the token already read.
+    fn parse_date_literal(&mut self, year: Token) -> Result<Option<NaiveDate>, String> {
+        let parts: Vec<Token> = self.tokens[self.pos..].iter().take(4).copied().collect();
+        let mut end = year.start + year.text.len();
+        for (i, part) in parts.iter().enumerate() {
+            let expected = if i % 2 == 0 { part.text == "-" } else { part.kind == TokenKind::Number && part.text.len() <= 2 };
+            if part.start != end || !expected {
+                return Ok(None);
+            }
+            end += part.text.len();
+        }
+        if year.text.len() != 4 || parts.len() < 4 {
+            return Ok(None);
+        }
+        self.pos += 4;
+        let number = |token: &Token| token.text.parse::<u32>().ok();
+        let (y, m, d) = (year.text.parse().ok(), number(&parts[1]), number(&parts[3]));
+        let date = match (y, m, d) {
+            (Some(y), Some(m), Some(d)) => NaiveDate::from_ymd_opt(y, m, d),
+            _ => None,
         };
-        
-        return Some(Expr::BinaryOp(Box::new(left), op, Box::new(right)));
+        let text = format!("{}-{}-{}", year.text, parts[1].text, parts[3].text);
+        date.map(Some).ok_or(format!("Invalid date {text}"))
+    }
+
+    // The weekday after `next`. An offset such as `+ 2 weeks` is ordinary date arithmetic.
+    fn parse_date(&mut self) -> Result<Expr, String> {
+        let day = self.advance()
+            .and_then(|t| weekday(t.text))
+            .ok_or("Expected a day of the week after 'next'")?;
+        Ok(Expr::Day(Day::Next(day)))
+    }
+}
+
+// Keywords and currency codes can't be variable names: `in = 2` would break every
+// conversion below it and `USD = 5` would make `10 USD` ambiguous. Unit names like `m`
+// are allowed, because position decides: directly after a value it is the unit,
+// anywhere else it is the variable.
+fn check_variable_name(name: &str) -> Result<(), String> {
+    if lexer::is_keyword(name) {
+        Err(format!("'{name}' is a keyword; choose another variable name"))
+    } else if lexer::is_currency_code(name) {
+        Err(format!("'{name}' is a currency code; choose another variable name"))
+    } else {
+        Ok(())
     }
-    
-    None
 }
 
-// Parse a value with a unit (10 USD, 5 kg, etc.)
-fn parse_unit_value(text: &str) -> Option<(f64, String)> {
-    // Pattern for numbers with units: "10 USD", "5.2 kg", "3 m2", etc.
-    // This handles both pure alphabetic units (USD, kg) and units with numbers (m2, km2)
-    if let Some(caps) = NUMBER_UNIT_RE.captures(text) {
-        let value = caps[1].parse::<f64>().ok()?;
-        let unit = caps[2].trim().to_string();
-        return Some((value, unit));
+// Seconds in a unit of a compound duration, and its canonical name. `m` is minutes here,
+// though on its own `90m` is meters.
+fn duration_unit(word: &str) -> Option<(f64, &'static str)> {
+    match word.to_lowercase().as_str() {
+        "d" | "day" | "days" => Some((86400.0, "day")),
+        "h" | "hr" | "hrs" | "hour" | "hours" => Some((3600.0, "h")),
+        "m" | "min" | "mins" | "minute" | "minutes" => Some((60.0, "min")),
+        "s" | "sec" | "secs" | "second" | "seconds" => Some((1.0, "s")),
+        _ => None,
     }
-    
-    // We didn't find a number with a unit directly, let's return None
-    None
 }
 
-// Parse a simple value (number, variable, or unit value)
-fn parse_simple_value(line: &str, variables: &HashMap<String, Value>) -> Expr {
-    let line = line.trim();
-    
-    // Try to parse as a percentage (e.g., "8%") - this must come before parentheses check
-    if line.ends_with("%") {
-        if let Ok(num) = line[..line.len()-1].trim().parse::<f64>() {
-            return Expr::Percentage(num);
+// Split the word after a duration's first number into (amount, seconds per unit, unit)
+// parts: `first` with "h30m" is 1 h and 30 min. None unless every number has a duration unit.
+fn split_duration(first: f64, word: &str) -> Option<Vec<(f64, f64, &'static str)>> {
+    let mut parts = Vec::new();
+    let mut amount = first;
+    let mut rest = word;
+    loop {
+        let letters = rest.find(|c: char| !c.is_alphabetic()).unwrap_or(rest.len());
+        let (seconds, unit) = duration_unit(&rest[..letters])?;
+        parts.push((amount, seconds, unit));
+        rest = &rest[letters..];
+        if rest.is_empty() {
+            return Some(parts);
         }
+        let digits = rest.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(rest.len());
+        amount = rest[..digits].parse().ok()?;
+        rest = &rest[digits..];
     }
-    
-    // Check for parentheses
-    if let Some(caps) = PARENTHESIS_RE.captures(line) {
-        return parse_line(&caps[1], variables);
+}
+
+// Named constants, unless a variable takes the name
+fn constant(word: &str) -> Option<f64> {
+    match word {
+        "pi" | "π" => Some(std::f64::consts::PI),
+        "tau" => Some(std::f64::consts::TAU),
+        _ => None,
     }
-    
-    // Try to parse as a number with a unit
-    if let Some((value, unit)) = parse_unit_value(line) {
-        return Expr::UnitValue(value, unit);
+}
+
+// The name of a function like `mean(4, 8, 15)`
+fn statistic(word: &str) -> Option<Statistic> {
+    match word.to_lowercase().as_str() {
+        "mean" | "average" | "avg" => Some(Statistic::Mean),
+        "median" => Some(Statistic::Median),
+        "stdev" => Some(Statistic::Stdev),
+        "sum" => Some(Statistic::Sum),
+        "min" => Some(Statistic::Min),
+        "max" => Some(Statistic::Max),
+        "len" => Some(Statistic::Len),
+        _ => None,
     }
-    
-    // Check for the pattern "variable unit" (e.g., "z USD")
-    if let Some(caps) = VAR_UNIT_RE.captures(line) {
-        let var_name = caps[1].trim();
-        let unit = caps[2].trim();
-        
-        if variables.contains_key(var_name) {
-            return Expr::BinaryOp(
-                Box::new(Expr::Variable(var_name.to_string())),
-                Op::Multiply,
-                Box::new(Expr::UnitValue(1.0, unit.to_string()))
-            );
-        }
+}
+
+// The name of round(), floor() or ceil()
+fn rounding(word: &str) -> Option<Rounding> {
+    match word.to_lowercase().as_str() {
+        "round" => Some(Rounding::Round),
+        "floor" => Some(Rounding::Floor),
+        "ceil" => Some(Rounding::Ceil),
+        _ => None,
     }
-    
-    // Try to parse as a simple number
-    if let Ok(num) = line.parse::<f64>() {
-        return Expr::Number(num);
+}
+
+// Full weekday names, in any case
+fn weekday(word: &str) -> Option<Weekday> {
+    match word.to_lowercase().as_str() {
+        "monday" => Some(Weekday::Mon),
+        "tuesday" => Some(Weekday::Tue),
+        "wednesday" => Some(Weekday::Wed),
+        "thursday" => Some(Weekday::Thu),
+        "friday" => Some(Weekday::Fri),
+        "saturday" => Some(Weekday::Sat),
+        "sunday" => Some(Weekday::Sun),
+        _ => None,
     }
-    
-    // Check if it's a variable
-    if variables.contains_key(line) {
-        return Expr::Variable(line.to_string());
+}
+
+// `first` to `fifth`, or `last`, as in `last friday of next month`
+fn ordinal(word: &str) -> Option<Occurrence> {
+    match word.to_lowercase().as_str() {
+        "first" => Some(Occurrence::Nth(1)),
+        "second" => Some(Occurrence::Nth(2)),
+        "third" => Some(Occurrence::Nth(3)),
+        "fourth" => Some(Occurrence::Nth(4)),
+        "fifth" => Some(Occurrence::Nth(5)),
+        "last" => Some(Occurrence::Last),
+        _ => None,
     }
-    
-    // If all else fails, return an error expression
-    let msg = if line.contains('+') || line.contains('-') || line.contains('*') || line.contains('/') {
-        "Invalid expression".to_string()
-    } else if line.contains('%') {
-        "Invalid percentage".to_string()
-    } else if line.chars().all(|c| c.is_alphabetic()) {
-        format!("'{line}' not found")
-    } else {
-        "Invalid input".to_string()
+}
+
+fn month_name(word: &str) -> Option<u32> {
+    const MONTHS: [&str; 12] = [
+        "january", "february", "march", "april", "may", "june",
+        "july", "august", "september", "october", "november", "december",
+    ];
+    let word = word.to_lowercase();
+    MONTHS.iter()
+        .position(|month| *month == word || (word.len() == 3 && month.starts_with(word.as_str())))
+        .map(|i| i as u32 + 1)
+}
+
+const ONES: [&str; 20] = [
+    "zero", "one", "two", "three", "four", "five", "six", "seven", "eight", "nine", "ten",
+    "eleven", "twelve", "thirteen", "fourteen", "fifteen", "sixteen", "seventeen", "eighteen", "nineteen",
+];
+const TENS: [&str; 8] = ["twenty", "thirty", "forty", "fifty", "sixty", "seventy", "eighty", "ninety"];
+
+// What a word multiplies the number before it by: `million` in `2 million`
+fn scale_word(word: &str) -> Option<f64> {
+    match word.to_lowercase().as_str() {
+        "dozen" => Some(12.0),
+        "hundred" => Some(100.0),
+        "thousand" => Some(1e3),
+        "million" => Some(1e6),
+        "billion" => Some(1e9),
+        _ => None,
+    }
+}
+
+fn is_number_word(word: &str) -> bool {
+    ONES.contains(&word) || TENS.contains(&word) || scale_word(word).is_some() || ["a", "and", "half", "quarter"].contains(&word)
+}
+
+// The value of lowercase number words, or None unless they make one number. `-one` is the
+// second half of `twenty-one`. `half` and `quarter` only count with a quantity after them,
+// and `a` only before a scale: `a dozen`, `half a million`.
+fn words_value(words: &[String]) -> Option<f64> {
+    let (fraction, words) = match words.split_first() {
+        Some((first, rest)) if first == "half" => (0.5, rest),
+        Some((first, rest)) if first == "quarter" => (0.25, rest),
+        _ => (1.0, words),
     };
-    
-    Expr::Error(msg)
+    let mut total = 0.0;        // Thousands, millions and billions so far
+    let mut group: Option<f64> = None; // The part below them
+    let mut previous = "";
+    for (i, word) in words.iter().enumerate() {
+        let next = words.get(i + 1).map(String::as_str);
+        let small = |word: &str| ONES.iter().position(|w| *w == word).map(|n| n as f64)
+            .or_else(|| TENS.iter().position(|w| *w == word).map(|n| (n as f64 + 2.0) * 10.0));
+        match word.as_str() {
+            "a" if i == 0 && next.and_then(scale_word).is_some() => group = Some(1.0),
+            "and" if scale_word(previous).is_some() && next.and_then(small).is_some() => {}
+            ones if let Some(ones) = ones.strip_prefix('-') => {
+                let ones = small(ones).filter(|n| (1.0..10.0).contains(n))?;
+                if !TENS.contains(&previous) {
+                    return None;
+                }
+                group = Some(group? + ones);
+            }
+            word if let Some(n) = small(word) => {
+                // `twenty one` as well as `twenty-one`, but not `two three`
+                let follows_tens = TENS.contains(&previous) && (1.0..10.0).contains(&n);
+                if !(previous.is_empty() || follows_tens || scale_word(previous).is_some() || previous == "and") {
+                    return None;
+                }
+                group = Some(group.unwrap_or(0.0) + n);
+            }
+            "dozen" | "hundred" => group = Some(group? * scale_word(word)?),
+            word => {
+                total += group? * scale_word(word)?;
+                group = None;
+            }
+        }
+        previous = word;
+    }
+    if words.is_empty() || previous == "a" || previous == "and" {
+        return None;
+    }
+    Some((total + group.unwrap_or(0.0)) * fraction)
+}
+
+// The sides of the dice in `3d6`, written right after the count
+fn dice_sides(word: &str) -> Option<u32> {
+    let sides = word.strip_prefix(['d', 'D'])?;
+    if sides.is_empty() || !sides.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    sides.parse().ok()
+}
+
+// The word that starts `sum of lines 2..5`
+fn aggregate_word(word: &str) -> Option<Aggregate> {
+    match word.to_lowercase().as_str() {
+        "sum" | "total" => Some(Aggregate::Sum),
+        "average" | "avg" | "mean" => Some(Aggregate::Average),
+        _ => None,
+    }
+}
+
+// The parts a word fraction splits a value into
+fn fraction_word(word: &str) -> Option<f64> {
+    match word.to_lowercase().as_str() {
+        "half" => Some(2.0),
+        "third" => Some(3.0),
+        "quarter" => Some(4.0),
+        _ => None,
+    }
+}
+
+// Currency codes in setrate are case-insensitive
+fn is_currency_like(token: &Token) -> bool {
+    token.kind == TokenKind::Word && lexer::is_currency_code(&token.text.to_uppercase())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+
+    // How a line reads while no variables are defined
+    fn read(line: &str) -> Expr {
+        parse_line(line).reading(&HashMap::new()).clone()
+    }
     
     #[test]
     fn test_parse_number() {
-        let variables = HashMap::new();
-        match parse_line("42", &variables) {
+        match parse_line("42") {
             Expr::Number(n) => assert_eq!(n, 42.0),
             _ => panic!("Expected Number expression"),
         }
@@ -401,8 +1616,7 @@ mod tests {
     
     #[test]
     fn test_parse_unit_value() {
-        let variables = HashMap::new();
-        match parse_line("10 USD", &variables) {
+        match parse_line("10 USD") {
             Expr::UnitValue(v, u) => {
                 assert_eq!(v, 10.0);
                 assert_eq!(u, "USD");
@@ -413,8 +1627,7 @@ mod tests {
     
     #[test]
     fn test_parse_assignment() {
-        let variables = HashMap::new();
-        match parse_line("x = 42", &variables) {
+        match parse_line("x = 42") {
             Expr::Assignment(name, expr) => {
                 assert_eq!(name, "x");
                 match *expr {
@@ -428,8 +1641,7 @@ mod tests {
     
     #[test]
     fn test_parse_binary_op() {
-        let variables = HashMap::new();
-        match parse_line("5 + 3", &variables) {
+        match parse_line("5 + 3") {
             Expr::BinaryOp(left, Op::Add, right) => {
                 match *left {
                     Expr::Number(n) => assert_eq!(n, 5.0),
@@ -446,8 +1658,7 @@ mod tests {
     
     #[test]
     fn test_parse_conversion() {
-        let variables = HashMap::new();
-        match parse_line("10 ml in l", &variables) {
+        match parse_line("10 ml in l") {
             Expr::Convert(expr, unit) => {
                 assert_eq!(unit, "l");
                 match *expr {
@@ -464,8 +1675,7 @@ mod tests {
     
     #[test]
     fn test_parse_percentage() {
-        let variables = HashMap::new();
-        match parse_line("20% of 50", &variables) {
+        match parse_line("20% of 50") {
             Expr::PercentOf(percent, value) => {
                 match *percent {
                     Expr::Number(n) => assert_eq!(n, 20.0),
@@ -482,31 +1692,31 @@ mod tests {
     
     #[test]
     fn test_parse_date_expression() {
-        match parse_line("next friday", &HashMap::new()) {
-            Expr::DateOffset(day, amount, unit) => {
-                assert_eq!(day, "friday");
-                assert_eq!(amount, 0);
-                assert_eq!(unit, "days");
-            },
-            _ => panic!("Expected DateOffset expression"),
-        }
+        assert!(matches!(read("next friday"), Expr::Day(Day::Next(Weekday::Fri))));
+        assert!(matches!(read("this Friday"), Expr::Day(Day::This(Weekday::Fri))));
+        assert!(matches!(read("friday"), Expr::Day(Day::This(Weekday::Fri))));
         
-        match parse_line("next monday + 2 weeks", &HashMap::new()) {
-            Expr::DateOffset(day, amount, unit) => {
-                assert_eq!(day, "monday");
-                assert_eq!(amount, 2);
-                assert_eq!(unit, "weeks");
+        match read("next monday + 2 weeks") {
+            Expr::BinaryOp(day, Op::Add, offset) => {
+                assert!(matches!(*day, Expr::Day(Day::Next(Weekday::Mon))));
+                assert!(matches!(*offset, Expr::UnitValue(amount, ref unit) if amount == 2.0 && unit == "weeks"));
             },
-            _ => panic!("Expected DateOffset expression"),
+            other => panic!("Expected a date offset, got {:?}", other),
         }
+        
+        // A variable called "this" or "friday" is still the variable
+        let mut variables = HashMap::new();
+        variables.insert("this".to_string(), Value::Number(2.0));
+        variables.insert("friday".to_string(), Value::Number(5.0));
+        assert!(matches!(parse_line("friday").reading(&variables), Expr::Variable(name) if name == "friday"));
+        assert!(matches!(parse_line("this").reading(&variables), Expr::Variable(name) if name == "this"));
     }
     
     #[test]
     fn test_parse_parentheses() {
-        let variables = HashMap::new();
         
         // Test basic parentheses parsing
-        match parse_line("(5 + 3)", &variables) {
+        match parse_line("(5 + 3)") {
             Expr::BinaryOp(left, Op::Add, right) => {
                 match *left {
                     Expr::Number(n) => assert_eq!(n, 5.0),
@@ -521,7 +1731,7 @@ mod tests {
         }
         
         // Test nested parentheses
-        match parse_line("(2 * (3 + 4))", &variables) {
+        match parse_line("(2 * (3 + 4))") {
             Expr::BinaryOp(left, Op::Multiply, right) => {
                 match *left {
                     Expr::Number(n) => assert_eq!(n, 2.0),
@@ -545,7 +1755,7 @@ mod tests {
         }
         
         // Test order of operations with parentheses
-        match parse_line("2 + 3 * 4", &variables) {
+        match parse_line("2 + 3 * 4") {
             Expr::BinaryOp(left, Op::Add, right) => {
                 match *left {
                     Expr::Number(n) => assert_eq!(n, 2.0),
@@ -569,7 +1779,7 @@ mod tests {
         }
         
         // Test parentheses changing the order of operations
-        match parse_line("(2 + 3) * 4", &variables) {
+        match parse_line("(2 + 3) * 4") {
             Expr::BinaryOp(left, Op::Multiply, right) => {
                 match *left {
                     Expr::BinaryOp(inner_left, Op::Add, inner_right) => {
@@ -592,4 +1802,258 @@ mod tests {
             _ => panic!("Expected BinaryOp expression"),
         }
     }
-} 
\ No newline at end of file
+    
+    #[test]
+    fn test_parse_subtraction_is_left_associative() {
+        // 10 - 2 - 3 must be (10 - 2) - 3, not 10 - (2 - 3)
+        match parse_line("10 - 2 - 3") {
+            Expr::BinaryOp(left, Op::Subtract, right) => {
+                match *left {
+                    Expr::BinaryOp(_, Op::Subtract, _) => {},
+                    _ => panic!("Expected BinaryOp expression on left side"),
+                }
+                match *right {
+                    Expr::Number(n) => assert_eq!(n, 3.0),
+                    _ => panic!("Expected Number expression on right side"),
+                }
+            },
+            _ => panic!("Expected BinaryOp expression"),
+        }
+    }
+    
+    #[test]
+    fn test_power_precedence() {
+        let eval = |line: &str| format!("{}", crate::evaluator::evaluate(&parse_line(line), &mut HashMap::new(), &Default::default()));
+
+        // Tighter than everything but postfix units and %, and right associative
+        assert_eq!(eval("2 + 3 ^ 2"), "11");
+        assert_eq!(eval("2 * 3 ^ 2"), "18");
+        assert_eq!(eval("2 ^ 3 ^ 2"), "512");
+        assert_eq!(eval("(2 ^ 3) ^ 2"), "64");
+        assert_eq!(eval("-2 ^ 2"), "-4");
+        assert_eq!(eval("-2^2"), "-4");
+        assert_eq!(eval("(-2) ^ 2"), "4");
+        assert_eq!(eval("2 ^ -2"), "0.25");
+        assert_eq!(eval("18 / 3 ^ 2"), "2");
+        assert_eq!(eval("10 - 2 ^ 3"), "2");
+        // ** is the same operator
+        assert_eq!(eval("2 ** 10"), "1024");
+        assert_eq!(eval("2**3**2"), "512");
+        assert_eq!(eval("-2 ** 2"), "-4");
+        assert_eq!(eval("2 * 3 ** 2"), "18");
+        assert_eq!(eval("2 ** * 3"), "Error: Invalid expression");
+    }
+
+    #[test]
+    fn test_parse_negative_literals() {
+        match parse_line("3 * -2") {
+            Expr::BinaryOp(_, Op::Multiply, right) => match *right {
+                Expr::Number(n) => assert_eq!(n, -2.0),
+                _ => panic!("Expected negative Number on right side"),
+            },
+            _ => panic!("Expected BinaryOp expression"),
+        }
+        match parse_line("-5 km") {
+            Expr::UnitValue(v, u) => {
+                assert_eq!(v, -5.0);
+                assert_eq!(u, "km");
+            },
+            _ => panic!("Expected UnitValue expression"),
+        }
+    }
+    
+    #[test]
+    fn test_parse_percent_and_modulo() {
+        match parse_line("20%") {
+            Expr::Percentage(p) => assert_eq!(p, 20.0),
+            _ => panic!("Expected Percentage expression"),
+        }
+        match parse_line("10 % 3") {
+            Expr::BinaryOp(_, Op::Modulo, _) => {},
+            _ => panic!("Expected modulo BinaryOp expression"),
+        }
+        match parse_line("(2 + 3") {
+            Expr::Error(msg) => assert_eq!(msg, "Missing closing parenthesis"),
+            _ => panic!("Expected Error expression"),
+        }
+    }
+    
+    #[test]
+    fn test_parse_chained_conversion() {
+        match parse_line("10 USD in EUR in GBP") {
+            Expr::Convert(inner, unit) => {
+                assert_eq!(unit, "GBP");
+                match *inner {
+                    Expr::Convert(value, unit) => {
+                        assert_eq!(unit, "EUR");
+                        assert!(matches!(*value, Expr::UnitValue(v, ref u) if v == 10.0 && u == "USD"));
+                    },
+                    _ => panic!("Expected inner Convert expression"),
+                }
+            },
+            _ => panic!("Expected Convert expression"),
+        }
+    }
+
+    #[test]
+    fn test_parse_compound_durations() {
+        let eval = |line: &str| {
+            let mut scope = HashMap::new();
+            format!("{}", crate::evaluator::evaluate(&parse_line(line), &mut scope, &Default::default()))
+        };
+
+        assert_eq!(eval("1h30m"), "90 min");
+        assert_eq!(eval("2h 15m"), "135 min");
+        assert_eq!(eval("2 h 15 min"), "135 min");
+        assert_eq!(eval("1m30s"), "90 s");
+        assert_eq!(eval("1h30m + 45 min"), "135 min");
+        // A bare m is meters, with a hint when it was probably meant as minutes
+        assert_eq!(eval("1h30m + 45m"), "Error: Can't add a length (m) to a duration (min); for minutes write 45min");
+        assert_eq!(eval("1h30m + 45min"), "135 min");
+        assert_eq!(eval("90m in h"), "Error: Can't convert a length (m) to a duration (h); for minutes write 90min");
+        assert_eq!(eval("8h - 1h15m"), "6.75 h");
+        assert_eq!(eval("1h30m in h"), "1.50 h");
+        assert_eq!(eval("-1h30m"), "-90 min");
+        // On its own, or next to a length, m is still meters
+        assert_eq!(eval("90m"), "90 m");
+        assert_eq!(eval("90m + 1 km"), "1090 m");
+        assert_eq!(eval("1h30x"), "Error: Invalid duration 'h30x'");
+        assert_eq!(eval("30m1h"), "Error: Invalid duration; put larger units first, like 1h30m");
+    }
+
+    #[test]
+    fn test_parse_implicit_multiplication() {
+        let mut variables = HashMap::new();
+        variables.insert("x".to_string(), Value::Number(2.0));
+        variables.insert("r".to_string(), Value::Number(3.0));
+        variables.insert("m".to_string(), Value::Number(5.0));
+        let eval = |line: &str| {
+            let mut scope = variables.clone();
+            format!("{}", crate::evaluator::evaluate(&parse_line(line), &mut scope, &Default::default()))
+        };
+
+        assert_eq!(eval("2(3 + 4)"), "14");
+        assert_eq!(eval("2(x+1)"), "6");
+        assert_eq!(eval("(1 + 2)(3 + 4)"), "21");
+        assert_eq!(eval("(1 + 2) 3"), "9");
+        assert_eq!(eval("2x"), "4");
+        assert_eq!(eval("3 x ^ 2"), "12");
+        assert_eq!(eval("x(2)"), "4");
+        assert_eq!(eval("2 pi r"), "18.849556");
+        // A unit wins over a variable of the same name, glued or not
+        assert_eq!(eval("3 m"), "3 m");
+        assert_eq!(eval("3m"), "3 m");
+        assert_eq!(eval("3 USD"), "$3");
+        // Unknown words after a number are still units
+        assert_eq!(eval("3 apples"), "3 apples");
+    }
+
+    #[test]
+    fn test_one_parse_reads_every_way_the_variables_can_be() {
+        let eval = |line: &str, names: &[&str]| {
+            let mut scope = names.iter().map(|name| (name.to_string(), Value::Number(2.0))).collect();
+            format!("{}", crate::evaluator::evaluate(&parse_line(line), &mut scope, &Default::default()))
+        };
+        assert_eq!(eval("3 apples", &[]), "3 apples");
+        assert_eq!(eval("3 apples", &["apples"]), "6");
+        assert_eq!(eval("monthly rent * 10", &["monthly rent"]), "20");
+        assert_eq!(eval("monthly rent * 10", &["monthly"]), "20 rent");
+        assert_eq!(eval("monthly rent * 10", &[]), "Error: 'monthly' not found");
+        assert_eq!(eval("25% off 80", &[]), "60");
+        assert_eq!(eval("25% off 80", &["off"]), "Error: Ambiguous '%': write '10 % 3' or '10 mod 3' for modulo");
+        let names = ["alpha", "beta", "gamma", "delta", "epsilon", "zeta", "theta", "iota", "kappa", "lambda", "sigma", "omega"];
+        let line = names.map(|name| format!("3 {name}")).join(" + ");
+        assert_eq!(eval(&line, &names), "72");
+        assert_eq!(eval(&line, &names[..1]), "Error: Can't add gamma to beta");
+
+        // Any run of words could be one name or several: past the limit on readings, a line
+        // still has its reading without variables
+        assert_eq!(eval("the quick brown fox jumps over the lazy dog and runs away", &[]), "Error: 'the' not found");
+    }
+
+    #[test]
+    fn test_parse_line_references() {
+        assert!(matches!(read("line 3 * 2"), Expr::BinaryOp(left, Op::Multiply, _) if matches!(*left, Expr::LineRef(3))));
+        assert!(matches!(read("sum of lines 2..5"), Expr::LineAggregate(Aggregate::Sum, 2, 5)));
+        assert!(matches!(read("avg of lines 5..2"), Expr::Error(_)));
+        assert!(matches!(read("line 0"), Expr::Error(_)));
+    }
+
+    #[test]
+    fn test_parse_define_unit() {
+        match parse_line("defunit sprint = 2 week") {
+            Expr::DefineUnit(name, factor, unit) => {
+                assert_eq!(name, "sprint");
+                assert_eq!(factor, 2.0);
+                assert_eq!(unit, "week");
+            }
+            other => panic!("expected a unit definition, got {:?}", other),
+        }
+        assert!(matches!(parse_line("defunit sprint 2 week"), Expr::Error(_)));
+    }
+
+    #[test]
+    fn test_parse_natural_language_phrases() {
+        match read("how many ounces in 2 kg") {
+            Expr::Convert(value, unit) => {
+                assert!(matches!(*value, Expr::UnitValue(v, ref u) if v == 2.0 && u == "kg"));
+                assert_eq!(unit, "ounces");
+            }
+            other => panic!("Expected Convert, got {:?}", other),
+        }
+        match read("How many days until next friday") {
+            Expr::DateDiff(today, date, unit) => {
+                assert!(matches!(*date, Expr::Day(Day::Next(Weekday::Fri))));
+                assert!(matches!(*today, Expr::Day(Day::Today)));
+                assert_eq!(unit, "days");
+            }
+            other => panic!("Expected a date difference, got {:?}", other),
+        }
+        assert!(matches!(read("convert 5 kg to lb"), Expr::Convert(_, ref unit) if unit == "lb"));
+        
+        // The phrase doesn't take over an ordinary trailing conversion
+        assert!(matches!(read("how many oz in 2 kg in g"), Expr::Error(_)));
+        assert!(matches!(read("how many"), Expr::Error(_)));
+    }
+
+    #[test]
+    fn test_pathological_lines_are_errors_not_crashes() {
+        // The app parses on the main thread, whose stack is larger than a test thread's
+        let parse_on_main_stack = |line: String| {
+            std::thread::Builder::new().stack_size(8 << 20).spawn(move || parse_line(&line)).unwrap().join().unwrap()
+        };
+        let nested = |depth: usize| format!("{}1{}", "(".repeat(depth), ")".repeat(depth));
+        assert!(matches!(parse_on_main_stack(nested(250)), Expr::Number(n) if n == 1.0));
+        let deep = parse_line(&format!("{}1{}", "-(1 + ".repeat(30), ")".repeat(30)));
+        assert_eq!(crate::evaluator::evaluate(&deep, &mut HashMap::new(), &Default::default()), Value::Number(1.0));
+
+        let too_deep = |expr: Expr| matches!(expr, Expr::Error(msg) if msg == "Expression too deeply nested");
+        for line in [
+            nested(257),
+            "-".repeat(300) + "1",
+            "1".to_string() + &"^1".repeat(300),
+            "[".repeat(1_000),
+        ] {
+            assert!(too_deep(parse_on_main_stack(line.clone())), "{}…", &line[..10]);
+        }
+
+        // A flat chain doesn't nest, however long, up to the limit on tokens
+        let chain = parse_line(&format!("{}1", "1 + ".repeat(149)));
+        assert_eq!(crate::evaluator::evaluate(&chain, &mut HashMap::new(), &Default::default()), Value::Number(150.0));
+        let too_long = |expr: Expr| matches!(expr, Expr::Error(msg) if msg == "Expression too long");
+        for line in [
+            "(".repeat(100_000),
+            "-".repeat(10_000) + "1",
+            "1".to_string() + &"^1".repeat(10_000),
+            format!("{}1", "1 + ".repeat(10_000)),
+        ] {
+            assert!(too_long(parse_line(&line)), "{}…", &line[..10]);
+        }
+
+        // Long runs of anything else just fail to parse, or parse
+        for line in ["+".repeat(10_000), "*".repeat(10_000), "1 +".repeat(10_000), "%".repeat(1_000), ")".repeat(1_000)] {
+            assert!(matches!(parse_line(&line), Expr::Error(_)), "{line}");
+        }
+        assert!(matches!(parse_line(&"9".repeat(10_000)), Expr::Number(n) if n.is_infinite()));
+    }
+}
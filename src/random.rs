@@ -0,0 +1,73 @@
+use std::cell::RefCell;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// A small, fast generator (SplitMix64) for rand() and dice rolls. Nothing here needs to be
+// unpredictable, only evenly spread and repeatable from a seed.
+#[derive(Debug, Clone)]
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng(seed)
+    }
+
+    // Seeded from the clock, for a different sequence every session
+    pub fn from_time() -> Self {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64);
+        Rng(nanos)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    // Uniform in 0..1
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    // Uniform in 0..n
+    pub fn below(&mut self, n: u64) -> u64 {
+        (self.next_f64() * n as f64) as u64
+    }
+}
+
+// The generator expressions draw from. The app seeds it before evaluating each line, so a
+// line keeps its numbers when it's evaluated again and only changes when it's edited or
+// rerolled. Per thread, like the locale.
+thread_local! {
+    static CURRENT: RefCell<Rng> = RefCell::new(Rng::from_time());
+}
+
+pub fn seed(seed: u64) {
+    CURRENT.with(|rng| *rng.borrow_mut() = Rng::new(seed));
+}
+
+pub fn with<T>(f: impl FnOnce(&mut Rng) -> T) -> T {
+    CURRENT.with(|rng| f(&mut rng.borrow_mut()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_same_seed_same_numbers() {
+        let mut a = Rng::new(42);
+        let mut b = Rng::new(42);
+        for _ in 0..100 {
+            assert_eq!(a.next_u64(), b.next_u64());
+        }
+
+        seed(7);
+        let first: Vec<u64> = (0..10).map(|_| with(|rng| rng.below(6))).collect();
+        seed(7);
+        let second: Vec<u64> = (0..10).map(|_| with(|rng| rng.below(6))).collect();
+        assert_eq!(first, second);
+        assert!(first.iter().all(|&n| n < 6));
+    }
+}
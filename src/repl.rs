@@ -0,0 +1,423 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use crossterm::{
+    cursor::MoveToColumn,
+    event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+    queue,
+    style::Print,
+    terminal::{Clear, ClearType},
+};
+use cali::{Calculator, Value};
+
+// `cali --repl`: a single prompt instead of the notebook. Each line is evaluated on Enter
+// with the variables of the lines before it, and kept in a history that survives restarts.
+
+const PROMPT: &str = "> ";
+
+// Most lines kept in the history file
+const HISTORY_LIMIT: usize = 1000;
+
+// The variables defined so far, shared by every line of the session
+#[derive(Default)]
+pub struct Session {
+    calculator: Calculator,
+}
+
+impl Session {
+    // The result to print for a line, or None for blank lines and comments. Assignments
+    // define their variable for later lines, as they do in the notebook.
+    pub fn eval(&mut self, line: &str) -> Option<String> {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            return None;
+        }
+        match self.calculator.eval(line) {
+            Value::Assignment(name, value) => match *value {
+                Value::Error(msg) => Some(format!("Error: {}", msg)),
+                value => Some(format!("{} = {}", name, value)),
+            },
+            Value::Error(msg) => Some(format!("Error: {}", msg)),
+            value => Some(value.to_string()),
+        }
+    }
+}
+
+// Previous lines, oldest first
+#[derive(Debug, Default, PartialEq)]
+pub struct History {
+    entries: Vec<String>,
+}
+
+impl History {
+    // Where the history lives: $XDG_DATA_HOME/cali/history, by default under ~/.local/share
+    pub fn default_path() -> Option<PathBuf> {
+        let data = std::env::var_os("XDG_DATA_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".local/share")))?;
+        Some(data.join("cali").join("history"))
+    }
+
+    // A missing or unreadable file is an empty history
+    pub fn load(path: &Path) -> Self {
+        let mut history = Self::default();
+        for line in fs::read_to_string(path).unwrap_or_default().lines() {
+            history.push(line);
+        }
+        history
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut text = self.entries.join("\n");
+        text.push('\n');
+        fs::write(path, text)
+    }
+
+    // Remember a line, unless it's blank or repeats the one before. The oldest lines go
+    // once there are more than HISTORY_LIMIT.
+    pub fn push(&mut self, line: &str) {
+        let line = line.trim();
+        if line.is_empty() || self.entries.last().is_some_and(|last| last == line) {
+            return;
+        }
+        self.entries.push(line.to_string());
+        if self.entries.len() > HISTORY_LIMIT {
+            self.entries.drain(..self.entries.len() - HISTORY_LIMIT);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn get(&self, idx: usize) -> Option<&str> {
+        self.entries.get(idx).map(String::as_str)
+    }
+
+    // The newest entry before `before` that contains `query`
+    pub fn search(&self, query: &str, before: usize) -> Option<usize> {
+        self.entries[..before.min(self.entries.len())]
+            .iter()
+            .rposition(|entry| entry.contains(query))
+    }
+}
+
+// What a key press asks the prompt to do
+#[derive(Debug, PartialEq)]
+pub enum Action {
+    Edit,           // The line or the search changed; draw it again
+    Submit(String), // Evaluate this line
+    Exit,
+}
+
+// A reverse search started with Ctrl+R: the text typed so far and the entry it found
+#[derive(Debug, Default, PartialEq)]
+struct Search {
+    query: String,
+    found: Option<usize>,
+}
+
+// The line being typed, with readline-style editing and history recall
+#[derive(Debug, Default)]
+pub struct LineEditor {
+    pub buffer: String,
+    pub cursor: usize,            // Byte offset in `buffer`
+    recalled: Option<usize>,      // History entry shown by Up/Down, None for the new line
+    draft: String,                // The new line, kept while browsing the history
+    search: Option<Search>,
+}
+
+impl LineEditor {
+    pub fn handle_key(&mut self, key: KeyEvent, history: &History) -> Action {
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        if self.search.is_some() {
+            return self.handle_search_key(key, history);
+        }
+        match key.code {
+            KeyCode::Char('d') if ctrl => {
+                if self.buffer.is_empty() {
+                    return Action::Exit;
+                }
+                self.delete_forward();
+            }
+            KeyCode::Char('c') if ctrl => self.set_line(String::new()),
+            KeyCode::Char('r') if ctrl => self.search = Some(Search::default()),
+            KeyCode::Char('a') if ctrl => self.cursor = 0,
+            KeyCode::Char('e') if ctrl => self.cursor = self.buffer.len(),
+            KeyCode::Char('u') if ctrl => {
+                self.buffer.drain(..self.cursor);
+                self.cursor = 0;
+            }
+            KeyCode::Char(c) => {
+                self.buffer.insert(self.cursor, c);
+                self.cursor += c.len_utf8();
+            }
+            KeyCode::Enter => {
+                let line = std::mem::take(&mut self.buffer);
+                *self = Self::default();
+                if matches!(line.trim(), "exit" | "quit") {
+                    return Action::Exit;
+                }
+                return Action::Submit(line);
+            }
+            KeyCode::Backspace => {
+                if let Some((idx, _)) = self.buffer[..self.cursor].char_indices().next_back() {
+                    self.buffer.remove(idx);
+                    self.cursor = idx;
+                }
+            }
+            KeyCode::Delete => self.delete_forward(),
+            KeyCode::Left => {
+                if let Some((idx, _)) = self.buffer[..self.cursor].char_indices().next_back() {
+                    self.cursor = idx;
+                }
+            }
+            KeyCode::Right => {
+                if let Some(c) = self.buffer[self.cursor..].chars().next() {
+                    self.cursor += c.len_utf8();
+                }
+            }
+            KeyCode::Home => self.cursor = 0,
+            KeyCode::End => self.cursor = self.buffer.len(),
+            KeyCode::Up => {
+                let idx = self.recalled.unwrap_or(history.len());
+                if idx > 0 {
+                    self.recall(idx - 1, history);
+                }
+            }
+            KeyCode::Down => match self.recalled {
+                Some(idx) if idx + 1 < history.len() => self.recall(idx + 1, history),
+                Some(_) => {
+                    self.recalled = None;
+                    let draft = std::mem::take(&mut self.draft);
+                    self.set_line(draft);
+                }
+                None => {}
+            },
+            _ => {}
+        }
+        Action::Edit
+    }
+
+    // While searching, typing narrows the search, Ctrl+R looks further back, Enter runs
+    // the entry found and Esc or an arrow key keeps it for editing
+    fn handle_search_key(&mut self, key: KeyEvent, history: &History) -> Action {
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        let search = self.search.get_or_insert_with(Search::default);
+        match key.code {
+            KeyCode::Char('r') if ctrl => {
+                let before = search.found.unwrap_or(history.len());
+                if let Some(found) = history.search(&search.query, before) {
+                    search.found = Some(found);
+                }
+            }
+            KeyCode::Char('c' | 'g') if ctrl => {
+                self.search = None;
+            }
+            KeyCode::Char(c) => {
+                search.query.push(c);
+                search.found = history.search(&search.query, history.len());
+            }
+            KeyCode::Backspace => {
+                search.query.pop();
+                search.found = history.search(&search.query, history.len());
+            }
+            KeyCode::Enter => {
+                let line = search.found.and_then(|idx| history.get(idx)).unwrap_or_default().to_string();
+                *self = Self::default();
+                return Action::Submit(line);
+            }
+            _ => {
+                let found = search.found.and_then(|idx| history.get(idx)).map(str::to_string);
+                self.search = None;
+                if let Some(line) = found {
+                    self.set_line(line);
+                }
+            }
+        }
+        Action::Edit
+    }
+
+    fn delete_forward(&mut self) {
+        if self.cursor < self.buffer.len() {
+            self.buffer.remove(self.cursor);
+        }
+    }
+
+    fn recall(&mut self, idx: usize, history: &History) {
+        if self.recalled.is_none() {
+            self.draft = std::mem::take(&mut self.buffer);
+        }
+        self.recalled = Some(idx);
+        self.set_line(history.get(idx).unwrap_or_default().to_string());
+    }
+
+    fn set_line(&mut self, line: String) {
+        self.buffer = line;
+        self.cursor = self.buffer.len();
+    }
+
+    // The prompt line as shown, and the column the cursor goes in
+    pub fn render(&self, history: &History) -> (String, usize) {
+        match &self.search {
+            Some(search) => {
+                let found = search.found.and_then(|idx| history.get(idx)).unwrap_or_default();
+                let prefix = format!("(search '{}'): ", search.query);
+                let column = prefix.chars().count();
+                (format!("{}{}", prefix, found), column)
+            }
+            None => {
+                let column = PROMPT.len() + crate::app::column_at(&self.buffer, self.cursor);
+                (format!("{}{}", PROMPT, self.buffer), column)
+            }
+        }
+    }
+}
+
+// Run the prompt until Ctrl+D or `exit`. Only raw mode is needed, so results stay in the
+// scrollback like any other command's output.
+pub fn run() -> io::Result<()> {
+    let path = History::default_path();
+    let mut history = path.as_deref().map(History::load).unwrap_or_default();
+    let mut session = Session::default();
+    let mut editor = LineEditor::default();
+    let mut stdout = io::stdout();
+
+    let _guard = crate::term::RawModeGuard::new()?;
+    draw(&mut stdout, &editor, &history)?;
+    loop {
+        let Event::Key(key) = event::read()? else { continue };
+        if key.kind != KeyEventKind::Press {
+            continue;
+        }
+        match editor.handle_key(key, &history) {
+            Action::Edit => {}
+            Action::Exit => break,
+            Action::Submit(line) => {
+                // Leave the line as typed, or as found by a search, above the result
+                queue!(stdout, MoveToColumn(0), Clear(ClearType::CurrentLine), Print(PROMPT), Print(&line), Print("\r\n"))?;
+                if let Some(result) = session.eval(&line) {
+                    queue!(stdout, Print(result), Print("\r\n"))?;
+                }
+                history.push(&line);
+                if let Some(path) = &path {
+                    let _ = history.save(path);
+                }
+            }
+        }
+        draw(&mut stdout, &editor, &history)?;
+    }
+    queue!(stdout, Print("\r\n"))?;
+    stdout.flush()
+}
+
+fn draw(stdout: &mut io::Stdout, editor: &LineEditor, history: &History) -> io::Result<()> {
+    let (line, column) = editor.render(history);
+    queue!(stdout, MoveToColumn(0), Clear(ClearType::CurrentLine), Print(line), MoveToColumn(column as u16))?;
+    stdout.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(code: KeyCode) -> KeyEvent {
+        KeyEvent::new(code, KeyModifiers::NONE)
+    }
+
+    fn ctrl(c: char) -> KeyEvent {
+        KeyEvent::new(KeyCode::Char(c), KeyModifiers::CONTROL)
+    }
+
+    fn type_line(editor: &mut LineEditor, history: &History, text: &str) {
+        for c in text.chars() {
+            editor.handle_key(key(KeyCode::Char(c)), history);
+        }
+    }
+
+    #[test]
+    fn test_session_keeps_variables_between_lines() {
+        let mut session = Session::default();
+        assert_eq!(session.eval("price = 4 USD"), Some("price = $4".to_string()));
+        assert_eq!(session.eval("price * 3"), Some("$12".to_string()));
+        assert_eq!(session.eval("# just a note"), None);
+        assert_eq!(session.eval("missing + 1"), Some("Error: 'missing' not found".to_string()));
+    }
+
+    #[test]
+    fn test_history_skips_repeats_and_keeps_the_newest() {
+        let mut history = History::default();
+        for line in ["1 + 1", "1 + 1", "  ", "2 * 3"] {
+            history.push(line);
+        }
+        assert_eq!(history.entries, vec!["1 + 1", "2 * 3"]);
+        assert_eq!(history.search("1", 2), Some(0));
+        assert_eq!(history.search("*", 1), None);
+
+        for i in 0..HISTORY_LIMIT {
+            history.push(&i.to_string());
+        }
+        assert_eq!(history.len(), HISTORY_LIMIT);
+        assert_eq!(history.get(0), Some("0"));
+
+        let dir = crate::temp_dir::TempDir::new("history");
+        let path = dir.join("history");
+        history.save(&path).unwrap();
+        assert_eq!(History::load(&path), history);
+    }
+
+    #[test]
+    fn test_up_and_down_recall_history_and_keep_the_draft() {
+        let mut history = History::default();
+        history.push("x = 2");
+        history.push("x * 10");
+        let mut editor = LineEditor::default();
+        type_line(&mut editor, &history, "draft");
+
+        editor.handle_key(key(KeyCode::Up), &history);
+        assert_eq!(editor.buffer, "x * 10");
+        editor.handle_key(key(KeyCode::Up), &history);
+        editor.handle_key(key(KeyCode::Up), &history);
+        assert_eq!(editor.buffer, "x = 2");
+        editor.handle_key(key(KeyCode::Down), &history);
+        editor.handle_key(key(KeyCode::Down), &history);
+        assert_eq!((editor.buffer.as_str(), editor.cursor), ("draft", 5));
+
+        editor.handle_key(key(KeyCode::Left), &history);
+        editor.handle_key(key(KeyCode::Backspace), &history);
+        assert_eq!(editor.handle_key(key(KeyCode::Enter), &history), Action::Submit("drat".to_string()));
+        assert_eq!(editor.render(&history), ("> ".to_string(), 2));
+    }
+
+    #[test]
+    fn test_ctrl_r_searches_backwards() {
+        let mut history = History::default();
+        for line in ["rent = 1200", "food = 300", "rent * 12"] {
+            history.push(line);
+        }
+        let mut editor = LineEditor::default();
+        editor.handle_key(ctrl('r'), &history);
+        type_line(&mut editor, &history, "rent");
+        assert_eq!(editor.render(&history).0, "(search 'rent'): rent * 12");
+        editor.handle_key(ctrl('r'), &history);
+        assert_eq!(editor.render(&history).0, "(search 'rent'): rent = 1200");
+
+        // An arrow key keeps the entry for editing instead of running it
+        editor.handle_key(key(KeyCode::Right), &history);
+        assert_eq!(editor.buffer, "rent = 1200");
+        assert_eq!(editor.handle_key(key(KeyCode::Enter), &history), Action::Submit("rent = 1200".to_string()));
+    }
+
+    #[test]
+    fn test_exit_with_ctrl_d_or_exit() {
+        let history = History::default();
+        let mut editor = LineEditor::default();
+        assert_eq!(editor.handle_key(ctrl('d'), &history), Action::Exit);
+        type_line(&mut editor, &history, "exit");
+        assert_eq!(editor.handle_key(key(KeyCode::Enter), &history), Action::Exit);
+    }
+}
@@ -0,0 +1,32 @@
+// A directory for a test's files, removed again when it's dropped, whether or not the test
+// passed. Both the library and the binary declare this module for their tests.
+
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    // `name` tells tests apart and the process id tells test runs apart, so tests running at
+    // the same time never share a directory
+    pub fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("cali-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
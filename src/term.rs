@@ -0,0 +1,215 @@
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use crossterm::{
+    cursor::Show,
+    event::{DisableFocusChange, DisableMouseCapture, EnableFocusChange, EnableMouseCapture},
+    execute,
+    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+};
+
+// Put the terminal into the state the TUI needs: raw mode, alternate screen, mouse capture
+// and focus events
+pub fn enter() -> io::Result<()> {
+    enable_raw_mode()?;
+    execute!(io::stdout(), EnterAlternateScreen, EnableMouseCapture, EnableFocusChange)?;
+    Ok(())
+}
+
+// Undo everything `enter` did. Errors are ignored because this runs from the panic hook
+// and from drop, where there is nothing useful left to do with them.
+pub fn restore() {
+    let _ = disable_raw_mode();
+    let _ = execute!(io::stdout(), DisableFocusChange, LeaveAlternateScreen, DisableMouseCapture, Show);
+}
+
+// RAII guard around the terminal setup so every exit path (normal quit, `?` errors,
+// unwinding panics) leaves the user's shell usable
+pub struct TerminalGuard;
+
+impl TerminalGuard {
+    pub fn new() -> io::Result<Self> {
+        enter()?;
+        Ok(Self)
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        restore();
+    }
+}
+
+// Raw mode alone, for the prompt of `cali --repl`, which stays on the main screen
+pub struct RawModeGuard;
+
+impl RawModeGuard {
+    pub fn new() -> io::Result<Self> {
+        enable_raw_mode()?;
+        Ok(Self)
+    }
+}
+
+impl Drop for RawModeGuard {
+    fn drop(&mut self) {
+        let _ = disable_raw_mode();
+    }
+}
+
+// Restore the terminal before the default hook prints the panic message,
+// otherwise the message is lost on the alternate screen
+pub fn install_panic_hook() {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        restore();
+        release_stderr();
+        default_hook(info);
+    }));
+}
+
+// Anything a dependency writes straight to stderr (a TLS or proxy warning, say) would land
+// in the middle of the UI on the alternate screen. While the guard lives, stderr goes to a
+// file instead; dropping it points stderr back and says where the file is if it isn't empty,
+// or removes it if it is.
+pub struct StderrGuard {
+    path: PathBuf,
+}
+
+impl StderrGuard {
+    pub fn new(path: &Path) -> io::Result<Self> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        redirect_stderr(&File::create(path)?)?;
+        Ok(Self { path: path.to_path_buf() })
+    }
+
+    // Where stderr goes while the notebook is up: $XDG_CACHE_HOME/cali/stderr-<pid>.log, by
+    // default under ~/.cache. One file per process, so two notebooks open at once don't write
+    // over each other's.
+    pub fn default_path() -> Option<PathBuf> {
+        let cache = std::env::var_os("XDG_CACHE_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))?;
+        Some(cache.join("cali").join(format!("stderr-{}.log", std::process::id())))
+    }
+}
+
+impl Drop for StderrGuard {
+    fn drop(&mut self) {
+        release_stderr();
+        if fs::metadata(&self.path).is_ok_and(|file| file.len() > 0) {
+            eprintln!("cali: messages written to stderr while the notebook was open are in {}", self.path.display());
+        } else {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+// The real stderr while it is redirected, or -1
+#[cfg(unix)]
+static SAVED_STDERR: AtomicI32 = AtomicI32::new(-1);
+
+#[cfg(unix)]
+fn redirect_stderr(file: &File) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    // SAFETY: dup and dup2 only take file descriptors; both are open for the duration
+    let saved = unsafe { libc::dup(libc::STDERR_FILENO) };
+    if saved < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if unsafe { libc::dup2(file.as_raw_fd(), libc::STDERR_FILENO) } < 0 {
+        let error = io::Error::last_os_error();
+        unsafe { libc::close(saved) };
+        return Err(error);
+    }
+    SAVED_STDERR.store(saved, Ordering::SeqCst);
+    Ok(())
+}
+
+// Point stderr back where it was. Safe to call when it isn't redirected, so the panic hook
+// can call it before the panic message is printed.
+#[cfg(unix)]
+pub fn release_stderr() {
+    let saved = SAVED_STDERR.swap(-1, Ordering::SeqCst);
+    if saved >= 0 {
+        // SAFETY: `saved` came from dup and is closed only here, once
+        unsafe {
+            libc::dup2(saved, libc::STDERR_FILENO);
+            libc::close(saved);
+        }
+    }
+}
+
+// Without file descriptors to swap, stderr stays where it is
+#[cfg(not(unix))]
+fn redirect_stderr(_file: &File) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn release_stderr() {}
+
+// Flags set asynchronously by signal handlers and polled by the main loop
+pub struct Signals {
+    terminate: Arc<AtomicBool>,
+    suspend: Arc<AtomicBool>,
+    resumed: Arc<AtomicBool>,
+}
+
+impl Signals {
+    pub fn register() -> io::Result<Self> {
+        let signals = Self {
+            terminate: Arc::new(AtomicBool::new(false)),
+            suspend: Arc::new(AtomicBool::new(false)),
+            resumed: Arc::new(AtomicBool::new(false)),
+        };
+
+        #[cfg(unix)]
+        {
+            use signal_hook::consts::{SIGCONT, SIGHUP, SIGTERM, SIGTSTP};
+            use signal_hook::flag::register;
+
+            register(SIGTERM, Arc::clone(&signals.terminate))?;
+            register(SIGHUP, Arc::clone(&signals.terminate))?;
+            register(SIGTSTP, Arc::clone(&signals.suspend))?;
+            register(SIGCONT, Arc::clone(&signals.resumed))?;
+        }
+
+        Ok(signals)
+    }
+
+    // SIGTERM/SIGHUP should behave like Ctrl+Q
+    pub fn should_terminate(&self) -> bool {
+        self.terminate.load(Ordering::Relaxed)
+    }
+
+    // An external SIGTSTP (e.g. `kill -TSTP`) is pending
+    pub fn take_suspend(&self) -> bool {
+        self.suspend.swap(false, Ordering::Relaxed)
+    }
+
+    // The process was continued after being stopped and needs the terminal set up again
+    pub fn take_resumed(&self) -> bool {
+        self.resumed.swap(false, Ordering::Relaxed)
+    }
+}
+
+// Hand the terminal back to the shell and stop the process, as Ctrl+Z normally would.
+// Raw mode disables ISIG, so the terminal never sends SIGTSTP itself; the main loop calls
+// this on Ctrl+Z instead. Returns once the process is continued (SIGCONT).
+#[cfg(unix)]
+pub fn suspend() -> io::Result<()> {
+    restore();
+    signal_hook::low_level::emulate_default_handler(signal_hook::consts::SIGTSTP)
+}
+
+// Job control doesn't exist on this platform, so suspending is a no-op
+#[cfg(not(unix))]
+pub fn suspend() -> io::Result<()> {
+    Ok(())
+}
@@ -1,11 +1,54 @@
 use std::collections::HashMap;
-use crate::evaluator::{evaluate, Value};
+use chrono::NaiveDate;
+use crate::app::App;
+use crate::evaluator::{Context, FixedRates, Value};
 use crate::parser::{parse_line, Expr, Op};
+use crate::temp_dir::TempDir;
 
-#[cfg(test)]
+// Every test sees the same day, Friday 2024-05-17, and the same exchange rates, whatever
+// the date and whether the network is up. Per thread, so a rate one test sets stays there.
+thread_local! {
+    static CONTEXT: Context = Context::fixed(
+        NaiveDate::from_ymd_opt(2024, 5, 17).unwrap(),
+        FixedRates::default()
+            .with("USD", "EUR", 0.85)
+            .with("USD", "GBP", 0.72)
+            .with("USD", "CAD", 1.25)
+            .with("USD", "JPY", 115.0)
+            .with("USD", "AUD", 1.35),
+    );
+}
+
+pub fn context() -> Context {
+    CONTEXT.with(Context::clone)
+}
+
+// An app on the tests' day and rates
+pub fn new_app() -> App {
+    let mut app = App::new();
+    app.context = context();
+    app
+}
+
+// An app with just these lines, evaluated
+pub fn app_with_lines(lines: &[&str]) -> App {
+    let mut app = new_app();
+    app.clear();
+    for line in lines {
+        app.add_line(line.to_string());
+    }
+    app.evaluate_expressions();
+    app
+}
+
+#[allow(clippy::module_inception)]
 mod tests {
     use super::*;
     
+    fn evaluate(expr: &Expr, variables: &mut HashMap<String, Value>) -> Value {
+        crate::evaluator::evaluate(expr, variables, &context())
+    }
+    
     #[test]
     fn test_unit_preservation() {
         let mut variables = HashMap::new();
@@ -37,7 +80,7 @@ mod tests {
     #[test]
     fn test_evaluate_number() {
         let mut variables = HashMap::new();
-        let expr = parse_line("42", &variables);
+        let expr = parse_line("42");
         match evaluate(&expr, &mut variables) {
             Value::Number(n) => assert_eq!(n, 42.0),
             _ => panic!("Expected Number value"),
@@ -47,7 +90,7 @@ mod tests {
     #[test]
     fn test_evaluate_unit_value() {
         let mut variables = HashMap::new();
-        let expr = parse_line("10 USD", &variables);
+        let expr = parse_line("10 USD");
         match evaluate(&expr, &mut variables) {
             Value::Unit(v, u) => {
                 assert_eq!(v, 10.0);
@@ -62,21 +105,21 @@ mod tests {
         let mut variables = HashMap::new();
         
         // Addition
-        let expr = parse_line("5 + 3", &variables);
+        let expr = parse_line("5 + 3");
         match evaluate(&expr, &mut variables) {
             Value::Number(n) => assert_eq!(n, 8.0),
             _ => panic!("Expected Number value for addition"),
         }
         
         // Multiplication
-        let expr = parse_line("4 * 3", &variables);
+        let expr = parse_line("4 * 3");
         match evaluate(&expr, &mut variables) {
             Value::Number(n) => assert_eq!(n, 12.0),
             _ => panic!("Expected Number value for multiplication"),
         }
         
         // Division
-        let expr = parse_line("10 / 2", &variables);
+        let expr = parse_line("10 / 2");
         match evaluate(&expr, &mut variables) {
             Value::Number(n) => assert_eq!(n, 5.0),
             _ => panic!("Expected Number value for division"),
@@ -88,7 +131,7 @@ mod tests {
         let mut variables = HashMap::new();
         
         // Assign a value
-        let expr = parse_line("x = 42", &variables);
+        let expr = parse_line("x = 42");
         let result = evaluate(&expr, &mut variables);
         
         // Manual storage for the test
@@ -104,7 +147,7 @@ mod tests {
         }
         
         // Use the variable in an expression
-        let expr = parse_line("x + 8", &variables);
+        let expr = parse_line("x + 8");
         match evaluate(&expr, &mut variables) {
             Value::Number(n) => assert_eq!(n, 50.0),
             _ => panic!("Expected Number value for expression with variable"),
@@ -116,7 +159,7 @@ mod tests {
         let mut variables = HashMap::new();
         
         // Convert ml to l
-        let expr = parse_line("10 ml in l", &variables);
+        let expr = parse_line("10 ml in l");
         match evaluate(&expr, &mut variables) {
             Value::Unit(v, u) => {
                 assert_eq!(v, 0.01); // 10 ml = 0.01 l
@@ -126,7 +169,7 @@ mod tests {
         }
         
         // Convert cm to in
-        let expr = parse_line("10 cm in in", &variables);
+        let expr = parse_line("10 cm in in");
         match evaluate(&expr, &mut variables) {
             Value::Unit(v, u) => {
                 assert!((v - 3.937).abs() < 0.001); // 10 cm ≈ 3.937 in
@@ -141,14 +184,14 @@ mod tests {
         let mut variables = HashMap::new();
         
         // Simple percentage
-        let expr = parse_line("20% of 50", &variables);
+        let expr = parse_line("20% of 50");
         match evaluate(&expr, &mut variables) {
             Value::Number(n) => assert_eq!(n, 10.0), // 20% of 50 = 10
             _ => panic!("Expected Number value for percentage"),
         }
         
         // Percentage of a unit value
-        let expr = parse_line("20% of 50 USD", &variables);
+        let expr = parse_line("20% of 50 USD");
         match evaluate(&expr, &mut variables) {
             Value::Unit(v, u) => {
                 assert_eq!(v, 10.0); // 20% of 50 USD = 10 USD
@@ -167,7 +210,7 @@ mod tests {
             "total = price + discount".to_string(),
         ];
         
-        let results = crate::evaluator::evaluate_lines(&lines, &mut variables);
+        let results = crate::evaluator::evaluate_lines(&lines, &mut variables, &context());
         
         // Check that the variables were stored
         assert!(variables.contains_key("price"));
@@ -193,7 +236,7 @@ mod tests {
         let mut variables = HashMap::new();
         
         // Test USD to CAD conversion
-        let expr = parse_line("10 USD in CAD", &variables);
+        let expr = parse_line("10 USD in CAD");
         match evaluate(&expr, &mut variables) {
             Value::Unit(v, u) => {
                 // We can't check the exact value since it depends on the API response
@@ -205,7 +248,7 @@ mod tests {
         }
         
         // Test CAD to EUR conversion
-        let expr = parse_line("20 CAD in EUR", &variables);
+        let expr = parse_line("20 CAD in EUR");
         match evaluate(&expr, &mut variables) {
             Value::Unit(v, u) => {
                 // We can't check the exact value since it depends on the API response
@@ -222,7 +265,7 @@ mod tests {
         let mut variables = HashMap::new();
         
         // First check the current rate from USD to GBP
-        let expr = parse_line("10 USD in GBP", &variables);
+        let expr = parse_line("10 USD in GBP");
         let _original_rate = match evaluate(&expr, &mut variables) {
             Value::Unit(v, u) => {
                 assert_eq!(u, "GBP");
@@ -232,11 +275,11 @@ mod tests {
         };
         
         // Set a new custom rate
-        let expr = parse_line("setrate USD to GBP = 0.65", &variables);
+        let expr = parse_line("setrate USD to GBP = 0.65");
         evaluate(&expr, &mut variables);
         
         // Verify the new rate is used
-        let expr = parse_line("10 USD in GBP", &variables);
+        let expr = parse_line("10 USD in GBP");
         match evaluate(&expr, &mut variables) {
             Value::Unit(v, u) => {
                 assert_eq!(u, "GBP");
@@ -246,7 +289,7 @@ mod tests {
         }
         
         // Check the reverse direction works too
-        let expr = parse_line("20 GBP in USD", &variables);
+        let expr = parse_line("20 GBP in USD");
         match evaluate(&expr, &mut variables) {
             Value::Unit(v, u) => {
                 assert_eq!(u, "USD");
@@ -262,21 +305,21 @@ mod tests {
         let mut variables = HashMap::new();
         
         // First assign x = 10
-        let expr = parse_line("x = 10", &variables);
+        let expr = parse_line("x = 10");
         let result = evaluate(&expr, &mut variables);
         if let Value::Assignment(name, value) = result {
             variables.insert(name, (*value).clone());
         }
         
         // Then assign tax = 13%
-        let expr = parse_line("tax = 13%", &variables);
+        let expr = parse_line("tax = 13%");
         let result = evaluate(&expr, &mut variables);
         if let Value::Assignment(name, value) = result {
             variables.insert(name, (*value).clone());
         }
         
         // Now evaluate x * tax
-        let expr = parse_line("x * tax", &variables);
+        let expr = parse_line("x * tax");
         match evaluate(&expr, &mut variables) {
             Value::Number(n) => {
                 assert_eq!(n, 1.3); // 13% of 10 = 1.3
@@ -290,7 +333,7 @@ mod tests {
         let mut variables = HashMap::new();
         
         // First convert currency
-        let expr = parse_line("10 USD in CAD", &variables);
+        let expr = parse_line("10 USD in CAD");
         let result = evaluate(&expr, &mut variables);
         
         match result {
@@ -299,7 +342,7 @@ mod tests {
                 assert!(value > 0.0);
                 
                 // Now try with explicit unit value
-                let expr = parse_line(format!("{} CAD * 1.13", value).as_str(), &variables);
+                let expr = parse_line(format!("{} CAD * 1.13", value).as_str());
                 match evaluate(&expr, &mut variables) {
                     Value::Unit(n, unit) => {
                         assert_eq!(unit, "CAD");
@@ -317,7 +360,7 @@ mod tests {
         let mut variables = HashMap::new();
         
         // Assign x = 10 USD
-        let expr = parse_line("x = 10 USD", &variables);
+        let expr = parse_line("x = 10 USD");
         let result = evaluate(&expr, &mut variables);
         if let Value::Assignment(name, value) = result {
             variables.insert(name, (*value).clone());
@@ -333,7 +376,7 @@ mod tests {
         }
         
         // Convert x to CAD
-        let expr = parse_line("y = x to CAD", &variables);
+        let expr = parse_line("y = x to CAD");
         let result = evaluate(&expr, &mut variables);
         if let Value::Assignment(name, value) = result {
             variables.insert(name, (*value).clone());
@@ -352,7 +395,7 @@ mod tests {
                 _y_unit = unit;
                 
                 // Now calculate y * 1.13
-                let expr = parse_line("total = y * 1.13", &variables);
+                let expr = parse_line("total = y * 1.13");
                 let result = evaluate(&expr, &mut variables);
                 if let Value::Assignment(name, value) = result {
                     variables.insert(name, (*value).clone());
@@ -383,7 +426,7 @@ mod tests {
             "total = price * 1.05".to_string(),  // Simplified expression instead of price * (1 + tax)
         ];
         
-        let results = crate::evaluator::evaluate_lines(&lines, &mut variables);
+        let results = crate::evaluator::evaluate_lines(&lines, &mut variables, &context());
         
         // Check the results - comments should have empty results
         assert_eq!(results[0], "");  // Comment line
@@ -413,7 +456,7 @@ mod tests {
         let mut variables = HashMap::new();
         
         // Test seconds to minutes
-        let expr = parse_line("120 s in min", &variables);
+        let expr = parse_line("120 s in min");
         match evaluate(&expr, &mut variables) {
             Value::Unit(v, u) => {
                 assert_eq!(v, 2.0); // 120 seconds = 2 minutes
@@ -423,7 +466,7 @@ mod tests {
         }
         
         // Test minutes to hours
-        let expr = parse_line("90 min in h", &variables);
+        let expr = parse_line("90 min in h");
         match evaluate(&expr, &mut variables) {
             Value::Unit(v, u) => {
                 assert_eq!(v, 1.5); // 90 minutes = 1.5 hours
@@ -433,7 +476,7 @@ mod tests {
         }
         
         // Test days to hours
-        let expr = parse_line("2 day in h", &variables);
+        let expr = parse_line("2 day in h");
         match evaluate(&expr, &mut variables) {
             Value::Unit(v, u) => {
                 assert_eq!(v, 48.0); // 2 days = 48 hours
@@ -443,7 +486,7 @@ mod tests {
         }
         
         // Test milliseconds to seconds
-        let expr = parse_line("5000 ms in s", &variables);
+        let expr = parse_line("5000 ms in s");
         match evaluate(&expr, &mut variables) {
             Value::Unit(v, u) => {
                 assert_eq!(v, 5.0); // 5000 ms = 5 seconds
@@ -458,7 +501,7 @@ mod tests {
         let mut variables = HashMap::new();
         
         // Test KB to MB conversion
-        let expr = parse_line("2048 KB in MB", &variables);
+        let expr = parse_line("2048 KB in MB");
         match evaluate(&expr, &mut variables) {
             Value::Unit(v, u) => {
                 assert_eq!(v, 2.0); // 2048 KB = 2 MB
@@ -468,7 +511,7 @@ mod tests {
         }
         
         // Test bytes to bits
-        let expr = parse_line("16 B in bit", &variables);
+        let expr = parse_line("16 B in bit");
         match evaluate(&expr, &mut variables) {
             Value::Unit(v, u) => {
                 assert_eq!(v, 128.0); // 16 bytes = 128 bits
@@ -478,7 +521,7 @@ mod tests {
         }
         
         // Test GB to TB
-        let expr = parse_line("2048 GB in TB", &variables);
+        let expr = parse_line("2048 GB in TB");
         match evaluate(&expr, &mut variables) {
             Value::Unit(v, u) => {
                 assert_eq!(v, 2.0); // 2048 GB = 2 TB
@@ -493,7 +536,7 @@ mod tests {
         let mut variables = HashMap::new();
         
         // Test square meters to square centimeters using m2/cm2 notation
-        let expr = parse_line("2 m2 in cm2", &variables);
+        let expr = parse_line("2 m2 in cm2");
         match evaluate(&expr, &mut variables) {
             Value::Unit(v, u) => {
                 assert_eq!(v, 20000.0); // 2 m² = 20,000 cm²
@@ -503,7 +546,7 @@ mod tests {
         }
         
         // Test hectares to square meters
-        let expr = parse_line("0.5 ha in m2", &variables);
+        let expr = parse_line("0.5 ha in m2");
         match evaluate(&expr, &mut variables) {
             Value::Unit(v, u) => {
                 assert_eq!(v, 5000.0); // 0.5 ha = 5,000 m²
@@ -518,7 +561,7 @@ mod tests {
         let mut variables = HashMap::new();
         
         // Create a numeric variable
-        let expr = parse_line("z = 7", &variables);
+        let expr = parse_line("z = 7");
         let result = evaluate(&expr, &mut variables);
         if let Value::Assignment(name, value) = result {
             variables.insert(name, (*value).clone());
@@ -533,7 +576,7 @@ mod tests {
         }
         
         // Now try to convert z directly to CAD
-        let expr = parse_line("z to CAD", &variables);
+        let expr = parse_line("z to CAD");
         match evaluate(&expr, &mut variables) {
             Value::Unit(v, u) => {
                 assert_eq!(v, 7.0);
@@ -543,7 +586,7 @@ mod tests {
         }
         
         // Try converting z directly to USD and then to EUR
-        let expr = parse_line("z USD to EUR", &variables);
+        let expr = parse_line("z USD to EUR");
         match evaluate(&expr, &mut variables) {
             Value::Unit(v, u) => {
                 assert!(v > 0.0);
@@ -558,7 +601,7 @@ mod tests {
         let mut variables = HashMap::new();
         
         // Test minutes aliases
-        let expr = parse_line("60 minutes in h", &variables);
+        let expr = parse_line("60 minutes in h");
         match evaluate(&expr, &mut variables) {
             Value::Unit(v, u) => {
                 assert_eq!(v, 1.0); // 60 minutes = 1 hour
@@ -568,7 +611,7 @@ mod tests {
         }
         
         // Test mins alias
-        let expr = parse_line("60 mins in h", &variables);
+        let expr = parse_line("60 mins in h");
         match evaluate(&expr, &mut variables) {
             Value::Unit(v, u) => {
                 assert_eq!(v, 1.0); // 60 mins = 1 hour
@@ -578,7 +621,7 @@ mod tests {
         }
         
         // Test plural/singular forms
-        let expr = parse_line("1 day in hours", &variables);
+        let expr = parse_line("1 day in hours");
         match evaluate(&expr, &mut variables) {
             Value::Unit(v, u) => {
                 assert_eq!(v, 24.0); // 1 day = 24 hours
@@ -588,7 +631,7 @@ mod tests {
         }
         
         // Test other common aliases - kilograms to pounds
-        let expr = parse_line("1 kg in lb", &variables);
+        let expr = parse_line("1 kg in lb");
         match evaluate(&expr, &mut variables) {
             Value::Unit(v, u) => {
                 assert!((v - 2.20462).abs() < 0.001);
@@ -656,7 +699,7 @@ mod tests {
         variables.insert("fee".to_string(), Value::Unit(4.0, "GBP".to_string()));
         
         // Mock the exchange rate for GBP to USD
-        crate::currency::set_exchange_rate("GBP", "USD", 1.3); // 1 GBP = 1.3 USD
+        context().rates.set_rate("GBP", "USD", 1.3); // 1 GBP = 1.3 USD
         
         // Create expression: (price + fee) - 4%
         let complex_expr = Expr::BinaryOp(
@@ -683,10 +726,10 @@ mod tests {
     #[test]
     fn test_automatic_currency_conversion() {
         // Mock the exchange rates for testing
-        crate::currency::set_exchange_rate("USD", "EUR", 0.85); // 1 USD = 0.85 EUR
-        crate::currency::set_exchange_rate("EUR", "USD", 1.18); // 1 EUR = 1.18 USD
-        crate::currency::set_exchange_rate("USD", "CAD", 1.25); // 1 USD = 1.25 CAD
-        crate::currency::set_exchange_rate("CAD", "USD", 0.8); // 1 CAD = 0.8 USD
+        context().rates.set_rate("USD", "EUR", 0.85); // 1 USD = 0.85 EUR
+        context().rates.set_rate("EUR", "USD", 1.18); // 1 EUR = 1.18 USD
+        context().rates.set_rate("USD", "CAD", 1.25); // 1 USD = 1.25 CAD
+        context().rates.set_rate("CAD", "USD", 0.8); // 1 CAD = 0.8 USD
         
         let mut variables = HashMap::new();
         
@@ -728,42 +771,42 @@ mod tests {
         let mut variables = HashMap::new();
         
         // Test simple parenthesized expression
-        let expr = parse_line("(2 + 3)", &variables);
+        let expr = parse_line("(2 + 3)");
         match evaluate(&expr, &mut variables) {
             Value::Number(n) => assert_eq!(n, 5.0),
             _ => panic!("Expected Number value"),
         }
         
         // Test that BEDMAS is followed without parentheses
-        let expr = parse_line("2 + 3 * 4", &variables);
+        let expr = parse_line("2 + 3 * 4");
         match evaluate(&expr, &mut variables) {
             Value::Number(n) => assert_eq!(n, 14.0), // 2 + (3 * 4) = 2 + 12 = 14
             _ => panic!("Expected Number value"),
         }
         
         // Test that parentheses override default precedence
-        let expr = parse_line("(2 + 3) * 4", &variables);
+        let expr = parse_line("(2 + 3) * 4");
         match evaluate(&expr, &mut variables) {
             Value::Number(n) => assert_eq!(n, 20.0), // (2 + 3) * 4 = 5 * 4 = 20
             _ => panic!("Expected Number value"),
         }
         
         // Test nested parentheses
-        let expr = parse_line("2 * (3 + (4 - 1))", &variables);
+        let expr = parse_line("2 * (3 + (4 - 1))");
         match evaluate(&expr, &mut variables) {
             Value::Number(n) => assert_eq!(n, 12.0), // 2 * (3 + 3) = 2 * 6 = 12
             _ => panic!("Expected Number value"),
         }
         
         // Test more complex expressions with multiple operations
-        let expr = parse_line("(2 + 3) * 4 / 2 - 1", &variables);
+        let expr = parse_line("(2 + 3) * 4 / 2 - 1");
         match evaluate(&expr, &mut variables) {
             Value::Number(n) => assert_eq!(n, 9.0), // (5 * 4) / 2 - 1 = 20 / 2 - 1 = 10 - 1 = 9
             _ => panic!("Expected Number value"),
         }
         
         // Test parentheses with unit values
-        let expr = parse_line("(2 + 3) * 4 USD", &variables);
+        let expr = parse_line("(2 + 3) * 4 USD");
         match evaluate(&expr, &mut variables) {
             Value::Unit(n, u) => {
                 assert_eq!(n, 20.0);
@@ -778,7 +821,7 @@ mod tests {
         let mut variables = HashMap::new();
         
         // Test parentheses with unit values - basic
-        let expr = parse_line("(10 USD + 5 USD) * 2", &variables);
+        let expr = parse_line("(10 USD + 5 USD) * 2");
         match evaluate(&expr, &mut variables) {
             Value::Unit(v, u) => {
                 assert_eq!(v, 30.0); // (10 + 5) * 2 = 30
@@ -788,7 +831,7 @@ mod tests {
         }
         
         // Test nested parentheses with unit values
-        let expr = parse_line("10 USD * (1 + (5 / 100))", &variables);
+        let expr = parse_line("10 USD * (1 + (5 / 100))");
         match evaluate(&expr, &mut variables) {
             Value::Unit(v, u) => {
                 assert_eq!(v, 10.5); // 10 * 1.05 = 10.5
@@ -798,7 +841,7 @@ mod tests {
         }
         
         // Test currency conversion with parentheses
-        let expr = parse_line("(10 USD + 5 USD) in EUR", &variables);
+        let expr = parse_line("(10 USD + 5 USD) in EUR");
         match evaluate(&expr, &mut variables) {
             Value::Unit(v, u) => {
                 assert!(v > 0.0); // Should be a positive EUR value
@@ -808,7 +851,7 @@ mod tests {
         }
         
         // Test parentheses with different order of operations
-        let expr = parse_line("2 * (3 USD + 4 USD)", &variables);
+        let expr = parse_line("2 * (3 USD + 4 USD)");
         match evaluate(&expr, &mut variables) {
             Value::Unit(v, u) => {
                 assert_eq!(v, 14.0); // 2 * (3 + 4) = 2 * 7 = 14
@@ -817,4 +860,2754 @@ mod tests {
             _ => panic!("Expected Unit value"),
         }
     }
-} 
\ No newline at end of file
+
+    #[test]
+    fn test_conversion_inside_expressions() {
+        let mut variables = HashMap::new();
+        
+        // Conversion as an operand of +
+        let expr = parse_line("(2 ft in in) + 1 in");
+        match evaluate(&expr, &mut variables) {
+            Value::Unit(v, u) => {
+                assert!((v - 25.0).abs() < 0.001);
+                assert_eq!(u, "in");
+            },
+            other => panic!("Expected Unit value, got {:?}", other),
+        }
+        
+        let expr = parse_line("2 * (5 km in mi)");
+        match evaluate(&expr, &mut variables) {
+            Value::Unit(v, u) => {
+                assert!((v - 6.2137).abs() < 0.001);
+                assert_eq!(u, "mi");
+            },
+            other => panic!("Expected Unit value, got {:?}", other),
+        }
+        
+        let expr = parse_line("(100 USD in EUR) + 20 EUR");
+        match evaluate(&expr, &mut variables) {
+            Value::Unit(v, u) => {
+                assert!(v > 20.0);
+                assert_eq!(u, "EUR");
+            },
+            other => panic!("Expected Unit value, got {:?}", other),
+        }
+        
+        // Chained conversions apply left to right
+        let expr = parse_line("1 yd in ft in in");
+        match evaluate(&expr, &mut variables) {
+            Value::Unit(v, u) => {
+                assert!((v - 36.0).abs() < 0.001);
+                assert_eq!(u, "in");
+            },
+            other => panic!("Expected Unit value, got {:?}", other),
+        }
+        
+        // `in` is still the inch when it directly follows a number
+        for line in ["10 in in cm", "10 in to cm", "(10 in) in cm"] {
+            let expr = parse_line(line);
+            match evaluate(&expr, &mut variables) {
+                Value::Unit(v, u) => {
+                    assert!((v - 25.4).abs() < 0.001, "{line}");
+                    assert_eq!(u, "cm");
+                },
+                other => panic!("Expected Unit value for '{line}', got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_idle_tick_does_not_request_redraw() {
+        let mut app = new_app();
+        for i in 0..2000 {
+            app.add_line(format!("x{} = {} * 2", i, i));
+        }
+        app.evaluate_expressions();
+        
+        // Let the debounce period lapse so the first tick publishes the results
+        app.last_keystroke = std::time::Instant::now() - app.debounce_period;
+        app.update_on_tick();
+        app.needs_redraw = false;
+        
+        // Nothing changes while idle, so no tick should ask for a redraw
+        for _ in 0..10 {
+            app.update_on_tick();
+        }
+        assert!(!app.needs_redraw);
+        
+        // A status message appearing is a visible change
+        app.set_status_message("Saved".to_string());
+        assert!(app.needs_redraw);
+    }
+
+    // Build an app from document lines and evaluate it the way loading a file does
+    fn press(app: &mut crate::app::App, code: crossterm::event::KeyCode) {
+        app.handle_key(crossterm::event::KeyEvent::new(code, crossterm::event::KeyModifiers::NONE));
+    }
+    
+    #[test]
+    fn test_incremental_evaluation_is_proportional_to_dependents() {
+        // One input, five readers scattered through 1,000 unrelated lines
+        let mut lines = vec!["rate = 2".to_string()];
+        for i in 1..1000 {
+            if i % 200 == 0 {
+                lines.push(format!("r{} = rate * {}", i, i));
+            } else {
+                lines.push(format!("x{} = {} + 1", i, i));
+            }
+        }
+        let mut app = app_with_lines(&lines.iter().map(String::as_str).collect::<Vec<_>>());
+        assert_eq!(app.lines_evaluated, 1000);
+        assert_eq!(app.debounced_results[200], "400");
+        
+        // Change "rate = 2" into "rate = 3"
+        app.cursor_pos = (0, app.lines[0].len());
+        app.lines_evaluated = 0;
+        press(&mut app, crossterm::event::KeyCode::Backspace);
+        press(&mut app, crossterm::event::KeyCode::Char('3'));
+        
+        // Each keystroke evaluates the edited line plus its readers, never the whole document
+        assert!(app.lines_evaluated <= 2 * 5 + 2, "evaluated {} lines", app.lines_evaluated);
+        assert_eq!(app.debounced_results[200], "600");
+        assert_eq!(app.debounced_results[800], "2400");
+        assert_eq!(app.debounced_results[1], "2");
+    }
+    
+    #[test]
+    fn test_incremental_evaluation_follows_chains_and_deletions() {
+        let lines = ["a = 1", "b = a + 1", "c = b * 10", "a = 100", "d = a"];
+        let mut app = app_with_lines(&lines);
+        assert_eq!(app.debounced_results[2], "20");
+        // Lines below the redefinition see the new value
+        assert_eq!(app.debounced_results[4], "100");
+        assert_eq!(app.variables.get("a"), Some(&Value::Number(100.0)));
+        
+        // Edit "a = 1" into "a = 5": b and c follow transitively
+        app.cursor_pos = (0, app.lines[0].len());
+        press(&mut app, crossterm::event::KeyCode::Backspace);
+        press(&mut app, crossterm::event::KeyCode::Char('5'));
+        assert_eq!(app.debounced_results[1], "6");
+        assert_eq!(app.debounced_results[2], "60");
+        assert_eq!(app.debounced_results[4], "100");
+        
+        // Deleting the "a = 100" line lets d fall back to the first definition
+        app.cursor_pos = (4, 0);
+        press(&mut app, crossterm::event::KeyCode::Backspace);
+        assert_eq!(app.lines[3], "a = 100d = a");
+        app.cursor_pos = (3, 0);
+        for _ in 0.."a = 100".len() {
+            press(&mut app, crossterm::event::KeyCode::Delete);
+        }
+        assert_eq!(app.lines[3], "d = a");
+        assert_eq!(app.debounced_results[3], "5");
+        assert_eq!(app.variables.get("a"), Some(&Value::Number(5.0)));
+    }
+    
+    #[test]
+    fn test_variable_names_shadowing_units_and_keywords() {
+        // Keywords and currency codes can't be assigned to
+        match parse_line("in = 2") {
+            Expr::Error(msg) => assert_eq!(msg, "'in' is a keyword; choose another variable name"),
+            other => panic!("Expected Error expression, got {:?}", other),
+        }
+        match parse_line("USD = 5") {
+            Expr::Error(msg) => assert_eq!(msg, "'USD' is a currency code; choose another variable name"),
+            other => panic!("Expected Error expression, got {:?}", other),
+        }
+        
+        // Unit names are allowed with a warning, and still mean the unit after a number
+        let lines = ["m = 5", "s = 2", "10 m in ft", "m * s", "3 s in ms", "10 in in cm"];
+        let app = app_with_lines(&lines);
+        assert_eq!(app.variables.get("m"), Some(&Value::Number(5.0)));
+        assert_eq!(app.debounced_results[2], "32.808399 ft");
+        assert_eq!(app.debounced_results[3], "10");
+        assert_eq!(app.debounced_results[4], "3000 ms");
+        assert_eq!(app.debounced_results[5], "25.40 cm");
+        // Both warnings are shown, not just the last one
+        assert_eq!(
+            app.status_message.as_deref(),
+            Some("Warning: 2 variables are also units ('m' on line 1, 's' on line 2); after a number they still mean the unit")
+        );
+        let app = app_with_lines(&["x = 1", "s = 2"]);
+        assert_eq!(app.status_message.as_deref(), Some("Warning: 's' is also a unit; after a number it still means the unit"));
+    }
+    
+    #[test]
+    fn test_snake_case_and_multi_word_variables() {
+        let lines = [
+            "sales_tax_2024 = 8%",
+            "price = 50",
+            "price * sales_tax_2024",
+            "sales_tax_2024 of 200",
+            "tax_rate = 2",
+            "tax_rate * 3",
+            "monthly rent = 1800",
+            "monthly   rent * 12",
+            "rent = 5",
+            "monthly rent - rent",
+        ];
+        let mut app = app_with_lines(&lines);
+        assert_eq!(app.debounced_results[2], "4");
+        assert_eq!(app.debounced_results[3], "16");
+        assert_eq!(app.debounced_results[5], "6");
+        assert_eq!(app.debounced_results[7], "21600");
+        assert_eq!(app.debounced_results[9], "1795");
+        
+        // Editing a multi-word definition updates the lines that use it
+        app.cursor_pos = (6, app.lines[6].len());
+        press(&mut app, crossterm::event::KeyCode::Backspace);
+        assert_eq!(app.debounced_results[7], "2160");
+        assert_eq!(app.debounced_results[9], "175");
+    }
+    
+    #[test]
+    fn test_percent_sign_modulo_vs_percentage() {
+        let mut variables = HashMap::new();
+        variables.insert("x".to_string(), Value::Number(15.0));
+        variables.insert("y".to_string(), Value::Number(80.0));
+        
+        for (line, expected) in [("10 % 3", 1.0), ("10 %3", 1.0), ("10 mod 3", 1.0), ("15% of 80", 12.0), ("x% of y", 12.0), ("y + x%", 92.0)] {
+            let expr = parse_line(line);
+            match evaluate(&expr, &mut variables) {
+                Value::Number(n) => assert!((n - expected).abs() < 1e-9, "{line} = {n}"),
+                other => panic!("Expected Number value for '{line}', got {:?}", other),
+            }
+        }
+        
+        match parse_line("10%3") {
+            Expr::Error(msg) => assert!(msg.starts_with("Ambiguous '%'")),
+            other => panic!("Expected Error expression, got {:?}", other),
+        }
+        match evaluate(&parse_line("10 %"), &mut variables) {
+            Value::Percentage(p) => assert_eq!(p, 10.0),
+            other => panic!("Expected Percentage value, got {:?}", other),
+        }
+    }
+    
+    #[test]
+    fn test_modulo_and_power_for_units() {
+        let mut variables = HashMap::new();
+        
+        for (line, expected, unit) in [
+            ("10 km % 3", 1.0, "km"),
+            ("10 h % 3 h", 1.0, "h"),
+            ("90 min % 1 h", 30.0, "min"),
+            ("(2 m)^2", 4.0, "m2"),
+            ("(3 cm)^3", 27.0, "cm3"),
+            ("(5 kg)^1", 5.0, "kg"),
+        ] {
+            let expr = parse_line(line);
+            match evaluate(&expr, &mut variables) {
+                Value::Unit(v, u) => {
+                    assert!((v - expected).abs() < 1e-9, "{line} = {v}");
+                    assert_eq!(u, unit, "{line}");
+                },
+                other => panic!("Expected Unit value for '{line}', got {:?}", other),
+            }
+        }
+        
+        for (line, message) in [
+            ("10 km % 0", "Cannot use modulo with 0"),
+            ("10 h % 0 h", "Cannot use modulo with 0"),
+            ("10 h % 3 kg", "Can't take the remainder of a duration (h) divided by a mass (kg)"),
+            ("(2 kg)^2", "Can't raise a mass (kg) to the power of 2"),
+        ] {
+            let expr = parse_line(line);
+            match evaluate(&expr, &mut variables) {
+                Value::Error(msg) => assert_eq!(msg, message, "{line}"),
+                other => panic!("Expected Error value for '{line}', got {:?}", other),
+            }
+        }
+    }
+    
+    #[test]
+    fn test_assigned_values_keep_their_type() {
+        let lines = [
+            "start = today",
+            "due = start + 2 weeks",
+            "due - start",
+            "1 day + due - start",
+            "rate = 15%",
+            "rate of 200",
+            "200 + rate",
+            "dist = 5 km",
+            "dist * 2",
+            "bad = oops",
+            "bad + 1",
+            "deadline = next friday + 2 weeks",
+        ];
+        let mut app = app_with_lines(&lines);
+        assert!(matches!(app.variables.get("start"), Some(Value::Date(_))));
+        assert_eq!(app.debounced_results[2], "14 days");
+        assert_eq!(app.debounced_results[3], "15 days");
+        assert_eq!(app.variables.get("rate"), Some(&Value::Percentage(15.0)));
+        assert_eq!(app.debounced_results[5], "30");
+        assert_eq!(app.debounced_results[6], "230");
+        assert_eq!(app.debounced_results[8], "10 km");
+        assert_eq!(app.debounced_results[10], "Error: 'oops' not found");
+        assert!(matches!(app.variables.get("deadline"), Some(Value::Date(_))));
+        
+        // Re-assigning a different type updates the lines that use it
+        app.cursor_pos = (7, app.lines[7].len());
+        press(&mut app, crossterm::event::KeyCode::Backspace);
+        press(&mut app, crossterm::event::KeyCode::Backspace);
+        press(&mut app, crossterm::event::KeyCode::Backspace);
+        assert_eq!(app.lines[7], "dist = 5");
+        assert_eq!(app.debounced_results[8], "10");
+    }
+    
+    #[test]
+    fn test_date_arithmetic_follows_the_calendar() {
+        let mut variables = HashMap::new();
+        let date = |y, m, d| Value::Date(chrono::NaiveDate::from_ymd_opt(y, m, d).unwrap());
+        variables.insert("d".to_string(), date(2024, 1, 31));
+        
+        for (line, expected) in [
+            ("d + 1 month", date(2024, 2, 29)),
+            ("d - 1 year", date(2023, 1, 31)),
+            ("d + 3", date(2024, 2, 3)),
+            ("2 weeks + d", date(2024, 2, 14)),
+        ] {
+            let expr = parse_line(line);
+            assert_eq!(evaluate(&expr, &mut variables), expected, "{line}");
+        }
+        
+        let expr = parse_line("d + 1.5 days");
+        assert!(matches!(evaluate(&expr, &mut variables), Value::Error(_)));
+        let expr = parse_line("d + 2 kg");
+        assert!(matches!(evaluate(&expr, &mut variables), Value::Error(_)));
+    }
+    
+    fn type_text(app: &mut crate::app::App, text: &str) {
+        for c in text.chars() {
+            press(app, crossterm::event::KeyCode::Char(c));
+        }
+    }
+    
+    #[test]
+    fn test_forward_reference_names_the_defining_line() {
+        let lines = ["total * 2", "y = monthly rent", "total = 100", "monthly rent = 5"];
+        let mut app = app_with_lines(&lines);
+        assert_eq!(app.debounced_results[0], "Error: variable 'total' is defined later on line 3");
+        assert_eq!(app.debounced_results[1], "Error: variable 'monthly rent' is defined later on line 4");
+        
+        // Inserting a line above the definition updates the line number
+        app.cursor_pos = (2, 0);
+        press(&mut app, crossterm::event::KeyCode::Enter);
+        assert_eq!(app.debounced_results[0], "Error: variable 'total' is defined later on line 4");
+        
+        // Once the definition moves above the reader, the reader sees it
+        app.cursor_pos = (0, 0);
+        type_text(&mut app, "total = 7");
+        press(&mut app, crossterm::event::KeyCode::Enter);
+        assert_eq!(app.debounced_results[1], "14");
+    }
+    
+    #[test]
+    fn test_incremental_evaluation_matches_full_evaluation() {
+        let document = [
+            "total * 2",
+            "subtotal = price * qty",
+            "price = 10",
+            "qty = 3",
+            "total = subtotal + tax",
+            "tax = 5%",
+            "total = total + 1",
+            "monthly rent * 12",
+            "monthly rent = 1800",
+            "price = 20",
+            "subtotal = price * qty",
+            "subtotal * 2 + total",
+        ];
+        let full = app_with_lines(&document);
+        assert_eq!(full.debounced_results[0], "Error: variable 'total' is defined later on line 5");
+        
+        let assert_same = |app: &crate::app::App, path: &str| {
+            assert_eq!(app.lines, full.lines, "{path}");
+            assert_eq!(app.debounced_results, full.debounced_results, "{path}");
+            assert_eq!(app.variables, full.variables, "{path}");
+        };
+        
+        // Typed top to bottom
+        let mut top_down = app_with_lines(&[""]);
+        for (i, line) in document.iter().enumerate() {
+            if i > 0 {
+                press(&mut top_down, crossterm::event::KeyCode::Enter);
+            }
+            type_text(&mut top_down, line);
+        }
+        assert_same(&top_down, "top-down");
+        
+        // Typed bottom to top, each line inserted above the previous ones
+        let mut bottom_up = app_with_lines(&[""]);
+        for (i, line) in document.iter().rev().enumerate() {
+            bottom_up.cursor_pos = (0, 0);
+            type_text(&mut bottom_up, line);
+            if i > 0 {
+                press(&mut bottom_up, crossterm::event::KeyCode::Enter);
+            }
+        }
+        assert_same(&bottom_up, "bottom-up");
+        
+        // Pseudo-random lines erased and retyped, checking after every step
+        let mut seed: u64 = 42;
+        for step in 0..40 {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            let line_idx = (seed >> 33) as usize % document.len();
+            top_down.cursor_pos = (line_idx, top_down.lines[line_idx].len());
+            for _ in 0..top_down.lines[line_idx].len() {
+                press(&mut top_down, crossterm::event::KeyCode::Backspace);
+            }
+            type_text(&mut top_down, document[line_idx]);
+            assert_same(&top_down, &format!("retype step {step}, line {line_idx}"));
+        }
+    }
+    
+    #[test]
+    fn test_line_summary_shows_operands_and_rates() {
+        let lines = ["a = 120 USD", "b = 31.2 USD", "total = a + b", "10 km in mi", "total * 2 + a"];
+        let app = app_with_lines(&lines);
+        assert_eq!(app.line_summary(0), None);
+        assert_eq!(app.line_summary(2).as_deref(), Some("total = $151.20  (a=$120, b=$31.20)"));
+        assert_eq!(app.line_summary(3).as_deref(), Some("= 6.213727 mi  rate 1 km = 0.6214 mi"));
+        // Each variable is listed once, in order of first use
+        assert_eq!(app.line_summary(4).as_deref(), Some("= $422.40  (total=$151.20, a=$120)"));
+    }
+    
+    #[test]
+    fn test_raw_value_for_copying() {
+        let date = chrono::NaiveDate::from_ymd_opt(2024, 5, 17).unwrap();
+        assert_eq!(Value::Unit(1234.5678, "USD".to_string()).to_raw_string().as_deref(), Some("1234.5678"));
+        assert_eq!(Value::Unit(3.937007874015748, "in".to_string()).to_raw_string().as_deref(), Some("3.937007874015748"));
+        assert_eq!(Value::Percentage(15.0).to_raw_string().as_deref(), Some("15"));
+        assert_eq!(Value::Date(date).to_raw_string().as_deref(), Some("2024-05-17"));
+        assert_eq!(Value::Error("oops".to_string()).to_raw_string(), None);
+        
+        let lines = ["price = 1234.5678 USD", "price * 2"];
+        let app = app_with_lines(&lines);
+        assert_eq!(app.line_value(0), Some(&Value::Unit(1234.5678, "USD".to_string())));
+        assert_eq!(app.line_value(1).and_then(Value::to_raw_string).as_deref(), Some("2469.1356"));
+    }
+    
+    #[test]
+    fn test_section_headers_and_totals() {
+        use crate::app::{section_header, SectionHeader};
+        
+        assert_eq!(section_header("# --- Groceries ---"), Some(SectionHeader { title: "Groceries", totals: false }));
+        assert_eq!(section_header("## Rent"), Some(SectionHeader { title: "Rent", totals: false }));
+        assert_eq!(section_header("#= Groceries"), Some(SectionHeader { title: "Groceries", totals: true }));
+        assert_eq!(section_header("# just a note"), None);
+        assert_eq!(section_header("# ------"), None);
+        assert_eq!(section_header("10 + 5 # --- tip ---"), None);
+        
+        let lines = [
+            "#= Groceries",
+            "milk = 3.50 USD",
+            "# organic",
+            "bread = 4.25 USD",
+            "12",
+            "",
+            "#= Trips",
+            "5 km",
+            "800 m",
+            "2 kg",
+            "# --- Misc ---",
+            "7",
+            "#= Empty",
+        ];
+        let app = app_with_lines(&lines);
+        
+        assert_eq!(app.section_totals.get(&0), Some(&Value::Unit(7.75, "USD".to_string())));
+        assert_eq!(app.section_totals.get(&6), Some(&Value::Unit(5.8, "km".to_string())));
+        assert_eq!(app.section_totals.len(), 2);
+        assert_eq!(app.line_summary(0).as_deref(), Some("Groceries total = $7.75"));
+    }
+    
+    #[test]
+    fn test_section_totals_after_loading_a_file() {
+        // The blank line ends the first section, so the 50 USD below it isn't rent
+        let dir = TempDir::new("sections");
+        let path = dir.join("sheet.cali");
+        std::fs::write(&path, "#= Rent\nrent = 900 USD\n\n50 USD\n#= Food\n30 USD\n20 USD\n").unwrap();
+        let mut app = new_app();
+        crate::load_file_into_app(path.to_str().unwrap(), &mut app).unwrap();
+        
+        assert_eq!(app.section_totals.get(&0), Some(&Value::Unit(900.0, "USD".to_string())));
+        assert_eq!(app.section_totals.get(&4), Some(&Value::Unit(50.0, "USD".to_string())));
+    }
+    
+    #[test]
+    fn test_toggle_comment_on_current_line() {
+        use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+        
+        let lines = ["x = 10", "x * 2", "## header"];
+        let mut app = app_with_lines(&lines);
+        app.cursor_pos = (0, 4);
+        
+        // Commenting out the definition breaks the line that reads it
+        app.handle_key(KeyEvent::new(KeyCode::Char('/'), KeyModifiers::CONTROL));
+        assert_eq!(app.lines[0], "# x = 10");
+        assert_eq!(app.cursor_pos, (0, 6));
+        assert_eq!(app.debounced_results[1], "Error: 'x' not found");
+        
+        // Terminals that report Ctrl+/ as Ctrl+_ toggle it back
+        app.handle_key(KeyEvent::new(KeyCode::Char('_'), KeyModifiers::CONTROL));
+        assert_eq!(app.lines[0], "x = 10");
+        assert_eq!(app.cursor_pos, (0, 4));
+        assert_eq!(app.debounced_results[1], "20");
+        
+        // Existing hashes are kept
+        app.cursor_pos = (2, 0);
+        app.handle_key(KeyEvent::new(KeyCode::Char('/'), KeyModifiers::CONTROL));
+        assert_eq!(app.lines[2], "# ## header");
+        app.handle_key(KeyEvent::new(KeyCode::Char('/'), KeyModifiers::CONTROL));
+        assert_eq!(app.lines[2], "## header");
+    }
+    
+    #[test]
+    fn test_pinned_results_survive_edits_and_saving() {
+        use crossterm::event::KeyCode;
+        
+        let lines = ["price = 100 USD", "price * 2"];
+        let mut app = app_with_lines(&lines);
+        app.output_selected_idx = 1;
+        assert_eq!(app.toggle_pin_selected(), Ok(Some("$200".to_string())));
+        
+        // Change the assumption above; the live result moves, the pin doesn't
+        app.cursor_pos = (0, app.lines[0].len());
+        for _ in 0..5 {
+            press(&mut app, KeyCode::Backspace);
+        }
+        type_text(&mut app, "5 USD");
+        assert_eq!(app.lines[0], "price = 105 USD");
+        assert_eq!(app.results[1], "$210");
+        assert_eq!(app.pinned_result(1), Some("$200"));
+        
+        // Saved as a trailing comment and restored on load
+        assert_eq!(app.line_to_save(1), "price * 2  #[pinned] $200");
+        let mut loaded = new_app();
+        loaded.clear();
+        for line_idx in 0..app.lines.len() {
+            loaded.add_saved_line(&app.line_to_save(line_idx));
+        }
+        loaded.evaluate_expressions();
+        assert_eq!(loaded.lines[1], "price * 2");
+        assert_eq!(loaded.pinned_result(1), Some("$200"));
+        assert_eq!(loaded.results[1], "$210");
+        
+        // A comment the user wrote stays a comment, even one that talks about pins
+        for line in ["price * 3  # pinned: $300 last week", "price # pinned: see above", "price  #[pinned] "] {
+            loaded.add_saved_line(line);
+            assert_eq!(loaded.lines.last().map(String::as_str), Some(line));
+            assert_eq!(loaded.pinned_result(loaded.lines.len() - 1), None);
+        }
+        
+        // p again unpins, P clears everything
+        assert_eq!(app.toggle_pin_selected(), Ok(None));
+        assert_eq!(app.pinned_result(1), None);
+        app.output_selected_idx = 0;
+        assert_eq!(app.toggle_pin_selected(), Ok(Some("$105".to_string())));
+        assert_eq!(loaded.clear_pins(), 1);
+        assert_eq!(loaded.pinned_result(1), None);
+    }
+    
+    #[test]
+    fn test_results_that_move_are_marked_with_their_change() {
+        use crate::app::Change;
+        
+        let lines = ["price = 100 USD", "price * 2", "5", "days = 3", "today + days"];
+        let mut app = app_with_lines(&lines);
+        assert_eq!(app.line_change(1), None);
+        
+        app.cursor_pos = (0, "price = 10".len());
+        type_text(&mut app, "5");
+        assert_eq!(app.lines[0], "price = 1050 USD");
+        assert_eq!(app.line_change(1), Some(&Change::Delta(Value::Unit(1900.0, "USD".to_string()))));
+        // The line being typed on isn't marked, and lines that didn't move aren't either
+        assert_eq!(app.line_change(0), None);
+        assert_eq!(app.line_change(2), None);
+        
+        // A date has no delta, it just flashes
+        app.cursor_pos = (3, "days = 3".len());
+        type_text(&mut app, "0");
+        assert_eq!(app.line_change(4), Some(&Change::Flash));
+    }
+    
+    #[test]
+    fn test_conversion_hints_replace_missing_target_errors() {
+        let lines = ["250 km in", "250 km in me", "10 lb to k", "x = 3 ft in ", "5 USD in ", "250 km in mi", "250 km in zz"];
+        let app = app_with_lines(&lines);
+        
+        assert_eq!(app.conversion_hints(0), Some(&["mi", "m", "cm", "mm", "ft", "in", "yd"][..]));
+        assert_eq!(app.debounced_results[0], "→ mi, m, cm, mm, ft, in, yd");
+        // Aliases narrow the list too: "me" matches meters
+        assert_eq!(app.conversion_hints(1), Some(&["m"][..]));
+        assert_eq!(app.conversion_hints(2), Some(&["kg"][..]));
+        assert_eq!(app.conversion_hints(3), Some(&["mi", "km", "m", "cm", "mm", "in", "yd"][..]));
+        assert_eq!(app.debounced_results[4], "→ EUR, GBP, JPY, CAD, AUD, CNY, INR");
+        
+        // Gone once the line converts, and nothing to suggest keeps the error
+        assert_eq!(app.conversion_hints(5), None);
+        assert_eq!(app.conversion_hints(6), None);
+        assert!(app.debounced_results[6].starts_with("Error:"));
+    }
+    
+    #[test]
+    fn test_how_many_and_convert_phrases() {
+        let lines = [
+            "how many grams in 2 kg",
+            "How many ounces in 2 lb",
+            "convert 5 km to m",
+            "how many weeks until today + 14 days",
+            "how many days until next friday",
+            "how many parsecs in 2 kg",
+        ];
+        let app = app_with_lines(&lines);
+        
+        assert_eq!(app.debounced_results[0], "2000 g");
+        assert_eq!(app.debounced_results[1], "32 oz");
+        assert_eq!(app.debounced_results[2], "5000 m");
+        assert_eq!(app.debounced_results[3], "2 week");
+        match app.line_value(4) {
+            Some(Value::Unit(days, unit)) => assert!((1.0..=7.0).contains(days) && unit == "days"),
+            other => panic!("Expected a number of days, got {:?}", other),
+        }
+        assert_eq!(app.debounced_results[5], "Error: Can't convert a mass (kg) to parsecs");
+    }
+    
+    #[test]
+    fn test_percentages_are_formatted_like_numbers() {
+        assert_eq!(Value::Percentage(13.000000000000002).to_string(), "13%");
+        assert_eq!(Value::Percentage(100.0 / 3.0).to_string(), "33.333333%");
+        assert_eq!(Value::Percentage(12.5).to_string(), "12.50%");
+        assert_eq!(Value::Number(13.000000000000002).to_string(), "13");
+        assert_eq!(Value::Number(1.1234).to_string(), "1.1234");
+        assert_eq!(Value::Number(-0.000000001).to_string(), "-1e-9");
+        assert_eq!(Value::Number(0.1 + 0.2 - 0.3).to_string(), "0");
+        
+        let lines = ["tax = 13%", "(1/3 * 100)%", "rate = (0.13 * 100)%", "tax"];
+        let app = app_with_lines(&lines);
+        assert_eq!(app.debounced_results[0], "13%");
+        assert_eq!(app.debounced_results[1], "33.333333%");
+        assert_eq!(app.debounced_results[2], "13%");
+        assert_eq!(app.debounced_results[3], "13%");
+    }
+    
+    #[test]
+    fn test_locale_directive_changes_parsing_and_display() {
+        use crossterm::event::KeyCode;
+        
+        let lines = ["set locale de", "1.234,56 EUR + 2 EUR", "x = 3,5", "x * 2", "1.234", "1,5 km in m", "set locale xx"];
+        let mut app = app_with_lines(&lines);
+        assert_eq!(app.debounced_results[0], "");
+        assert_eq!(app.debounced_results[1], "1236,56 EUR");
+        assert_eq!(app.debounced_results[2], "3,50");
+        assert_eq!(app.debounced_results[3], "7");
+        assert_eq!(app.debounced_results[4], "1234");
+        assert_eq!(app.debounced_results[5], "1500 m");
+        assert_eq!(app.debounced_results[6], "Error: Unknown locale 'xx'; use en or de");
+        // Machine-friendly copies keep the dot
+        assert_eq!(app.line_value(2).and_then(Value::to_raw_string).as_deref(), Some("3.5"));
+        
+        // Back to en: every line is read again
+        app.cursor_pos = (0, app.lines[0].len());
+        press(&mut app, KeyCode::Backspace);
+        press(&mut app, KeyCode::Backspace);
+        type_text(&mut app, "en");
+        assert_eq!(app.debounced_results[1], "Error: Invalid expression");
+        assert_eq!(app.debounced_results[4], "1.234");
+        assert_eq!(app.debounced_results[3], "Error: Invalid expression");
+        
+        // en accepts grouped thousands
+        let lines = ["1,234.5 + 1"];
+        assert_eq!(app_with_lines(&lines).debounced_results[0], "1235.50");
+    }
+    
+    #[test]
+    fn test_defunit_adds_a_custom_unit() {
+        use crossterm::event::KeyCode;
+        
+        let lines = ["3 sprint in day", "defunit sprint = 2 week", "2 sprint + 7 day", "defunit m = 3 ft", "defunit x = 2"];
+        let mut app = app_with_lines(&lines);
+        assert_eq!(app.debounced_results[0], "42 day");
+        assert_eq!(app.debounced_results[1], "1 sprint = 2 week");
+        assert_eq!(app.debounced_results[2], "2.50 sprint");
+        assert_eq!(app.debounced_results[3], "Error: 'm' is a built-in unit");
+        assert_eq!(app.debounced_results[4], "Error: Usage: defunit sprint = 2 week");
+        
+        // Redefining the unit updates the lines that use it
+        app.cursor_pos = (1, app.lines[1].len() - " week".len());
+        press(&mut app, KeyCode::Backspace);
+        type_text(&mut app, "3");
+        assert_eq!(app.debounced_results[1], "1 sprint = 3 week");
+        assert_eq!(app.debounced_results[0], "63 day");
+        
+        // Without its definition the unit is unknown again
+        app.cursor_pos = (1, 0);
+        type_text(&mut app, "# ");
+        assert!(app.debounced_results[0].starts_with("Error"));
+    }
+    
+    #[test]
+    fn test_ad_hoc_units_ignore_plural_and_case() {
+        let mut variables = HashMap::new();
+        let mut eval = |line: &str| {
+            let expr = parse_line(line);
+            format!("{}", evaluate(&expr, &mut variables))
+        };
+        assert_eq!(eval("3 apples + 2 apple"), "5 apples");
+        assert_eq!(eval("5 Boxes - 2 boxes"), "3 Boxes");
+        assert_eq!(eval("2 glasses + 1 glass"), "3 glasses");
+        assert_eq!(eval("3 apples * 2"), "6 apples");
+        assert_eq!(eval("3 apples + 2 oranges"), "Error: Can't add oranges to apples");
+    }
+    
+    #[test]
+    fn test_line_references() {
+        use crossterm::event::KeyCode;
+        
+        let lines = ["10 USD", "# fees", "price = 20 USD", "line 1 * 2", "sum of lines 1..4", "average of lines 1..3", "line 7", "line 6"];
+        let mut app = app_with_lines(&lines);
+        assert_eq!(app.debounced_results[3], "$20");
+        assert_eq!(app.debounced_results[4], "$50");
+        assert_eq!(app.debounced_results[5], "$15");
+        assert_eq!(app.debounced_results[6], "Error: Line 7 refers to itself");
+        assert_eq!(app.debounced_results[7], "$15");
+        
+        // Changing a line updates the lines that refer to it
+        app.cursor_pos = (0, 2);
+        press(&mut app, KeyCode::Backspace);
+        assert_eq!(app.debounced_results[3], "$2");
+        assert_eq!(app.debounced_results[4], "$23");
+        
+        // References keep their numbers, so inserting a line above re-points them
+        app.cursor_pos = (0, 0);
+        press(&mut app, KeyCode::Enter);
+        assert_eq!(app.debounced_results[4], "Error: Line 1 has no result");
+        assert_eq!(app.debounced_results[5], "$21");
+        assert_eq!(app.debounced_results[7], "$1");
+        
+        let lines = ["line 2", "5"];
+        assert_eq!(app_with_lines(&lines).debounced_results[0], "Error: Line 2 is below this line");
+    }
+    
+    #[test]
+    fn test_line_references_survive_a_save_and_reload() {
+        let dir = TempDir::new("references");
+        let path = dir.join("sheet.cali");
+        let lines = ["a = 5", "", "b = 2", "line 3 * 10"];
+        let app = app_with_lines(&lines);
+        assert_eq!(app.debounced_results[3], "20");
+        crate::save_file_from_app(path.to_str().unwrap(), &app).unwrap();
+        
+        let mut loaded = new_app();
+        crate::load_file_into_app(path.to_str().unwrap(), &mut loaded).unwrap();
+        assert_eq!(loaded.lines, lines);
+        assert_eq!(loaded.debounced_results[3], "20");
+    }
+    
+    #[test]
+    fn test_solve_linear_equations() {
+        let lines = [
+            "2x + 6 = 20", "x * 1.13 = 150 USD", "rate = 5", "rate * n + 10% = 11", "x * x = 9",
+            "2x = 3 km", "apples + pears = 3", "solve y: 3y - 4 = 11", "y * 2", "x",
+        ];
+        let app = app_with_lines(&lines);
+        assert_eq!(app.debounced_results[0], "x = 7");
+        assert_eq!(app.debounced_results[1], "x = $132.74");
+        assert_eq!(app.debounced_results[3], "n = 2");
+        assert_eq!(app.debounced_results[4], "Error: Can't solve: x doesn't appear linearly");
+        assert_eq!(app.debounced_results[5], "x = 1.50 km");
+        assert_eq!(app.debounced_results[6], "Error: Can't solve for more than one unknown (apples, pears)");
+        // Only the `solve` form defines the variable
+        assert_eq!(app.debounced_results[7], "y = 5");
+        assert_eq!(app.debounced_results[8], "10");
+        assert_eq!(app.debounced_results[9], "Error: 'x' not found");
+        
+        let lines = ["solve z: z * z = 4"];
+        assert_eq!(app_with_lines(&lines).debounced_results[0], "Error: Can't solve: z doesn't appear linearly");
+    }
+    
+    #[test]
+    fn test_align_directive() {
+        use crate::app::Align;
+        use crossterm::event::KeyCode;
+        
+        let lines = ["set align left", "2 + 2", "set align middle"];
+        let mut app = app_with_lines(&lines);
+        assert_eq!(app.align, Align::Left);
+        assert_eq!(app.debounced_results[0], "");
+        assert_eq!(app.debounced_results[2], "Error: Usage: set align left|right");
+        
+        // Without the directive results go back to the right
+        app.cursor_pos = (0, 0);
+        type_text(&mut app, "# ");
+        assert_eq!(app.align, Align::Right);
+        press(&mut app, KeyCode::Backspace);
+        press(&mut app, KeyCode::Backspace);
+        assert_eq!(app.align, Align::Left);
+    }
+    
+    #[test]
+    fn test_modified_flag_tracks_edits() {
+        use crossterm::event::KeyCode;
+        
+        let lines = ["2 + 2"];
+        let mut app = app_with_lines(&lines);
+        assert!(!app.modified);
+        
+        // Moving around doesn't change anything
+        app.cursor_pos = (0, 0);
+        press(&mut app, KeyCode::Right);
+        assert!(!app.modified);
+        type_text(&mut app, "0");
+        assert!(app.modified);
+        
+        app.modified = false;
+        assert_eq!(app.toggle_pin_selected(), Ok(Some("22".to_string())));
+        assert!(app.modified);
+    }
+    
+    #[test]
+    fn test_no_color_values_are_plain_ascii() {
+        let lines = ["10 EUR", "5 GBP * 2", "3 USD", "12 km", "10% of 50 EUR"];
+        let mut app = new_app();
+        app.disable_color();
+        app.clear();
+        for line in lines {
+            app.add_line(line.to_string());
+        }
+        app.evaluate_expressions();
+        
+        let shown: Vec<String> = app.results.iter().map(|r| r.to_string()).collect();
+        assert_eq!(shown, vec!["EUR 10.00", "GBP 10.00", "$3", "12 km", "EUR 5.00"]);
+        for text in &shown {
+            assert!(text.is_ascii() && !text.contains('\x1b'), "{:?}", text);
+        }
+        crate::locale::set_ascii(false);
+    }
+    
+    #[test]
+    fn test_small_magnitudes_never_show_as_zero() {
+        // Just above and just below what 6 decimals can show
+        assert_eq!(Value::Number(0.0000006).to_string(), "0.000001");
+        assert_eq!(Value::Number(0.0000004).to_string(), "4e-7");
+        assert_eq!(Value::Number(1.0 / 3000000.0).to_string(), "3.33e-7");
+        assert_eq!(Value::Percentage(0.0000002).to_string(), "2e-7%");
+        assert_eq!(Value::Unit(0.0000004, "m3".to_string()).to_string(), "4e-7 m3");
+        
+        // Currencies show sub-cent amounts as under a cent
+        assert_eq!(Value::Unit(0.006, "USD".to_string()).to_string(), "$0.01");
+        assert_eq!(Value::Unit(0.004, "USD".to_string()).to_string(), "<$0.01");
+        assert_eq!(Value::Unit(-0.004, "EUR".to_string()).to_string(), "-<€0.01");
+        assert_eq!(Value::Unit(-0.004, "USD".to_string()).to_string(), "-<$0.01");
+        assert_eq!(Value::Unit(-0.0001, "JPY".to_string()).to_string(), "-<0.01 JPY");
+        assert_eq!(Value::Unit(-0.006, "USD".to_string()).to_string(), "$-0.01");
+        assert_eq!(Value::Unit(0.0001, "JPY".to_string()).to_string(), "<0.01 JPY");
+        assert_eq!(Value::Unit(0.0, "USD".to_string()).to_string(), "$0");
+        
+        let lines = ["1 / 3000000", "0.0004 ml in l", "0.001 ml in l"];
+        let app = app_with_lines(&lines);
+        assert_eq!(app.debounced_results, vec!["3.33e-7", "4e-7 l", "0.000001 l"]);
+    }
+    
+    #[test]
+    fn test_large_file_is_drawn_before_it_is_evaluated() {
+        use ratatui::{Terminal, backend::TestBackend};
+        
+        let mut lines = vec!["x0 = 1".to_string()];
+        for i in 1..10_000 {
+            lines.push(format!("x{} = x{} + 1", i, i - 1));
+        }
+        let dir = TempDir::new("large");
+        let path = dir.join("sheet.txt");
+        std::fs::write(&path, lines.join("\n")).unwrap();
+        
+        let mut app = new_app();
+        crate::load_file_into_app(path.to_str().unwrap(), &mut app).unwrap();
+        let mut terminal = Terminal::new(TestBackend::new(80, 24)).unwrap();
+        terminal.draw(|f| crate::ui::draw(f, &mut app)).unwrap();
+        assert!(app.lines_evaluated <= crate::app::CATCH_UP_CHUNK);
+        assert_eq!(app.debounced_results[2], "3");
+        assert!(app.debounced_results[9_999].is_empty());
+        
+        // An edit further down is evaluated right away, and again once the lines above it are in
+        app.cursor_pos = (9_000, app.lines[9_000].len());
+        type_text(&mut app, " + 1");
+        assert!(app.pending_lines() > 0);
+        while app.catch_up(crate::app::CATCH_UP_CHUNK) {}
+        
+        lines[9_000].push_str(" + 1");
+        let reference = app_with_lines(&lines.iter().map(String::as_str).collect::<Vec<_>>());
+        assert_eq!(app.debounced_results, reference.debounced_results);
+        assert_eq!(app.debounced_results[9_000], "9002");
+        assert_eq!(app.debounced_results[9_999], "10001");
+        assert_eq!(app.pending_lines(), 0);
+    }
+    
+    #[test]
+    fn test_lines_are_not_reparsed_when_only_values_change() {
+        use crossterm::event::KeyCode;
+        
+        let lines = ["price = 4", "qty = 3", "total = price * qty", "total + 1"];
+        let mut app = app_with_lines(&lines);
+        assert_eq!(app.lines_parsed, 4);
+        
+        // Only the edited line is parsed again; the two below are re-evaluated from their parse
+        app.cursor_pos = (0, 9);
+        press(&mut app, KeyCode::Backspace);
+        type_text(&mut app, "5");
+        assert_eq!(app.debounced_results[3], "16");
+        assert_eq!(app.lines_evaluated, 4 + 2 * 3);
+        assert_eq!(app.lines_parsed, 4 + 2);
+        
+        // Taking away a name the line mentions changes how it reads, but its parse already holds
+        // both readings, and the edited line is now a comment: nothing is parsed again
+        let lines = ["2 r", "r = 3", "2 r"];
+        let mut app = app_with_lines(&lines);
+        assert_eq!(app.debounced_results[2], "6");
+        app.cursor_pos = (1, 0);
+        type_text(&mut app, "# ");
+        assert_eq!(app.debounced_results[2], "2 r");
+        assert_eq!(app.lines_parsed, 3);
+    }
+    
+    #[test]
+    fn test_text_view_maps_screen_and_text_positions() {
+        use crate::app::TextView;
+        
+        for scroll in [0, 7] {
+            let view = TextView { area: (10, 5, 20, 6), scroll };
+            assert_eq!(view.text_position(11, 6), Some((scroll, 0)));
+            assert_eq!(view.text_position(15, 8), Some((scroll + 2, 4)));
+            // Borders map to the nearest text cell
+            assert_eq!(view.text_position(10, 5), Some((scroll, 0)));
+            assert_eq!(view.text_position(29, 10), Some((scroll + 3, 17)));
+            assert_eq!(view.text_position(30, 6), None);
+            assert_eq!(view.text_position(9, 6), None);
+            
+            assert_eq!(view.screen_cell(scroll + 2, 4), Some((15, 8)));
+            assert_eq!(view.screen_cell(scroll + 4, 0), None);
+            assert_eq!(view.screen_cell(scroll, 18), None);
+        }
+        assert_eq!(TextView { area: (0, 0, 20, 6), scroll: 3 }.screen_cell(2, 0), None);
+        
+        // Columns count display cells, not bytes
+        assert_eq!(crate::app::column_at("größe = 5", 7), 5);
+        assert_eq!(crate::app::byte_at_column("größe = 5", 5), 7);
+        assert_eq!(crate::app::column_at("日本 = 2", 6), 4);
+        assert_eq!(crate::app::byte_at_column("日本 = 2", 3), 3);
+        assert_eq!(crate::app::byte_at_column("ab", 10), 2);
+    }
+    
+    #[test]
+    fn test_mouse_clicks_after_scrolling_and_on_borders() {
+        let lines: Vec<String> = (0..20).map(|i| format!("größe{} = {}", i, i)).collect();
+        let mut app = app_with_lines(&lines.iter().map(String::as_str).collect::<Vec<_>>());
+        let area = (0, 2, 30, 7);
+        app.input_scroll = 10;
+        
+        // The 6th column of the 3rd visible row, past the two-byte characters
+        assert!(app.handle_mouse_click(6, 5, area));
+        assert_eq!(app.cursor_pos, (12, 7));
+        assert_eq!(app.selection, None);
+        
+        // Clicking the same spot again selects the word there
+        assert!(app.handle_mouse_click(6, 5, area));
+        assert_eq!(app.selection, Some((12, 0..9)));
+        assert_eq!(app.cursor_pos, (12, 9));
+        
+        // The bottom border lands on the last visible row, the left border at the line start
+        assert!(app.handle_mouse_click(0, 8, area));
+        assert_eq!(app.cursor_pos, (14, 0));
+        assert_eq!(app.selection, None);
+        assert!(!app.handle_mouse_click(31, 4, area));
+        
+        // Past the end of a short sheet, the click goes to its last line
+        let mut app = app_with_lines(&["1 + 1"]);
+        assert!(app.handle_mouse_click(3, 6, area));
+        assert_eq!(app.cursor_pos, (0, 2));
+    }
+    
+    #[test]
+    fn test_focus_cycles_both_ways() {
+        use crate::app::{cycle_focus, PanelFocus};
+        
+        let mut app = app_with_lines(&["1 + 1"]);
+        app.toggle_panel_focus(true);
+        assert!(app.panel_focus == PanelFocus::Output);
+        app.toggle_panel_focus(true);
+        assert!(app.panel_focus == PanelFocus::Input);
+        app.toggle_panel_focus(false);
+        assert!(app.panel_focus == PanelFocus::Output);
+        
+        // With three targets the direction matters
+        let order = ["input", "output", "variables"];
+        assert_eq!(cycle_focus(&order, "input", true), "output");
+        assert_eq!(cycle_focus(&order, "variables", true), "input");
+        assert_eq!(cycle_focus(&order, "input", false), "variables");
+        assert_eq!(cycle_focus(&order, "output", false), "input");
+    }
+    
+    #[test]
+    fn test_go_to_definition_and_back() {
+        use crossterm::event::{KeyEvent, KeyModifiers};
+        
+        let ctrl = |c| KeyEvent::new(crossterm::event::KeyCode::Char(c), KeyModifiers::CONTROL);
+        let lines = ["shipping = 5", "monthly rent = 1200", "shipping = 7", "shipping * 2 + monthly rent", "total * 2"];
+        let mut app = app_with_lines(&lines);
+        
+        // Right after the name counts as on it; the nearest definition above wins
+        app.cursor_pos = (3, 8);
+        app.handle_key(ctrl(']'));
+        assert_eq!(app.cursor_pos, (2, 0));
+        assert_eq!(app.status_message.as_deref(), Some("'shipping' is defined on lines 1, 3; jumped to line 3"));
+        
+        app.handle_key(ctrl('o'));
+        assert_eq!(app.cursor_pos, (3, 8));
+        app.handle_key(ctrl('O'));
+        assert_eq!(app.cursor_pos, (2, 0));
+        app.handle_key(ctrl('o'));
+        
+        // Multi-word names are found from any of their words
+        app.cursor_pos = (3, 24);
+        app.handle_key(ctrl('5'));
+        assert_eq!(app.cursor_pos, (1, 0));
+        assert_eq!(app.status_message.as_deref(), Some("'monthly rent' is defined on line 2"));
+        
+        app.cursor_pos = (4, 2);
+        app.handle_key(ctrl(']'));
+        assert_eq!(app.cursor_pos, (4, 2));
+        assert_eq!(app.status_message.as_deref(), Some("'total' isn't defined"));
+    }
+    
+    #[test]
+    fn test_rename_variable_replaces_whole_names_only() {
+        let lines = ["cost = 4", "shipping_cost = 2", "cost * 3 + shipping_cost  # cost per box", "monthly rent = 10", "monthly rent * 12"];
+        let mut app = app_with_lines(&lines);
+        
+        assert_eq!(app.rename_variable("cost", "unit cost"), Ok(2));
+        assert_eq!(app.lines[0], "unit cost = 4");
+        assert_eq!(app.lines[2], "unit cost * 3 + shipping_cost  # cost per box");
+        assert_eq!(app.debounced_results[2], "14");
+        assert!(app.variables.contains_key("unit cost") && !app.variables.contains_key("cost"));
+        assert!(app.modified);
+        
+        assert_eq!(app.rename_variable("monthly rent", "rent"), Ok(2));
+        assert_eq!(app.lines[4], "rent * 12");
+        
+        assert_eq!(app.rename_variable("rent", "shipping_cost"), Err("'shipping_cost' is already a variable".to_string()));
+        assert_eq!(app.rename_variable("rent", "km"), Err("'km' is a unit".to_string()));
+        assert_eq!(app.rename_variable("rent", "of"), Err("'of' isn't a valid variable name".to_string()));
+        assert_eq!(app.rename_variable("rent", "2x"), Err("'2x' isn't a valid variable name".to_string()));
+    }
+    
+    #[test]
+    fn test_rename_from_the_cursor() {
+        use crossterm::event::{KeyEvent, KeyModifiers};
+        
+        let lines = ["a = 2", "a * a"];
+        let mut app = app_with_lines(&lines);
+        app.cursor_pos = (1, 0);
+        app.handle_key(KeyEvent::new(crossterm::event::KeyCode::Char('r'), KeyModifiers::CONTROL));
+        assert!(app.input_mode == crate::app::InputMode::Rename);
+        for c in "width".chars() {
+            app.handle_status_input(KeyEvent::new(crossterm::event::KeyCode::Char(c), KeyModifiers::NONE));
+        }
+        let new_name = app.handle_status_input(KeyEvent::new(crossterm::event::KeyCode::Enter, KeyModifiers::NONE));
+        app.finish_rename(&new_name.unwrap());
+        assert_eq!(app.lines, vec!["width = 2", "width * width"]);
+        assert_eq!(app.status_message.as_deref(), Some("Renamed 3 occurrences of 'a' to 'width'"));
+    }
+    
+    #[test]
+    fn test_windows_files_load_cleanly_and_save_the_same_way() {
+        let dir = TempDir::new("crlf");
+        let input = dir.join("windows.txt");
+        let output = dir.join("saved.txt");
+        std::fs::write(&input, "\u{feff}price = 4 USD\r\nprice * 2 km\r\n10 kg in lb\r\n").unwrap();
+        
+        let mut app = new_app();
+        crate::load_file_into_app(input.to_str().unwrap(), &mut app).unwrap();
+        assert_eq!(app.lines, vec!["price = 4 USD", "price * 2 km", "10 kg in lb"]);
+        assert!(app.lines.iter().all(|line| !line.contains(['\r', '\u{feff}'])));
+        assert_eq!(app.debounced_results[0], "$4");
+        assert_eq!(app.debounced_results[2], "22.046244 lb");
+        
+        crate::save_file_from_app(output.to_str().unwrap(), &app).unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), std::fs::read(&input).unwrap());
+        
+        // Plain files stay plain
+        std::fs::write(&input, "1 + 1\n").unwrap();
+        crate::load_file_into_app(input.to_str().unwrap(), &mut app).unwrap();
+        crate::save_file_from_app(output.to_str().unwrap(), &app).unwrap();
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), "1 + 1\n");
+        
+        // Hand-written files keep their blank lines and indentation
+        for written in ["# rent\nrent = 900 USD\n  + 50 USD\n\n\n# food\n\tgroceries = 300 USD\n", "a = 1\r\n\r\n  b = 2\r\n"] {
+            std::fs::write(&input, written).unwrap();
+            crate::load_file_into_app(input.to_str().unwrap(), &mut app).unwrap();
+            crate::save_file_from_app(output.to_str().unwrap(), &app).unwrap();
+            assert_eq!(std::fs::read_to_string(&output).unwrap(), written);
+        }
+    }
+    
+    #[test]
+    fn test_binary_files_and_huge_lines_are_refused() {
+        let dir = TempDir::new("binary");
+        let path = dir.join("fixture");
+        let load = |bytes: &[u8], app: &mut crate::app::App| {
+            std::fs::write(&path, bytes).unwrap();
+            crate::load_file_into_app(path.to_str().unwrap(), app).map_err(|e| e.to_string())
+        };
+        
+        let mut app = app_with_lines(&["1 + 1"]);
+        let binary: Vec<u8> = (0..=255).cycle().take(4096).collect();
+        assert_eq!(load(&binary, &mut app), Err("not a text file".to_string()));
+        assert_eq!(load(&[0xff; 64], &mut app), Err("not a text file".to_string()));
+        let minified = format!("x = {}", "1+".repeat(512 * 1024));
+        assert_eq!(load(minified.as_bytes(), &mut app), Err("line 1 is too long (1048580 characters; the limit is 10000)".to_string()));
+        // Nothing was loaded
+        assert_eq!(app.lines, vec!["1 + 1"]);
+        
+        // A stray Latin-1 byte among plenty of text is read as Latin-1
+        let mut latin1 = b"# Caf\xe9 bill\n".to_vec();
+        latin1.extend_from_slice("total = 12 EUR\n".repeat(10).as_bytes());
+        assert_eq!(load(&latin1, &mut app), Ok(()));
+        assert_eq!(app.lines[0], "# Café bill");
+        assert_eq!(app.status_message.as_deref(), Some("Read 1 byte(s) that weren't UTF-8 as Latin-1"));
+    }
+
+    #[test]
+    fn test_off_discounts() {
+        context().rates.set_rate("EUR", "USD", 1.2);
+        let mut variables = HashMap::new();
+        variables.insert("payoff".to_string(), Value::Number(5.0));
+
+        for (line, expected) in [
+            ("25% off 80", Value::Number(60.0)),
+            ("25% off 80 USD", Value::Unit(60.0, "USD".to_string())),
+            ("10 EUR off 100 USD", Value::Unit(88.0, "USD".to_string())),
+            ("5 off 20 + 10", Value::Number(25.0)),
+            ("discount of 25% on 80", Value::Number(20.0)),
+            ("Discount of 10% on 50 USD", Value::Unit(5.0, "USD".to_string())),
+            ("payoff * 2", Value::Number(10.0)),
+        ] {
+            let expr = parse_line(line);
+            let result = evaluate(&expr, &mut variables);
+            assert_eq!(result.to_string(), expected.to_string(), "{line}");
+        }
+
+        // A variable named "off" is just a variable
+        variables.insert("off".to_string(), Value::Number(3.0));
+        let expr = parse_line("2 off");
+        assert_eq!(evaluate(&expr, &mut variables).to_string(), "6");
+    }
+
+    #[test]
+    fn test_tip_and_split() {
+        let mut variables = HashMap::new();
+        variables.insert("tips".to_string(), Value::Number(2.0));
+
+        for (line, expected) in [
+            ("18% tip on 84.50", "15.21"),
+            ("84.50 + 18% tip", "99.71"),
+            ("84.50 USD + 18% tip", "$99.71"),
+            ("99.71 split 4 ways", "24.9275"),
+            ("99.71 USD split 4 ways", "$24.92 each (+$0.03 remainder)"),
+            ("split 99.71 USD between 4", "$24.92 each (+$0.03 remainder)"),
+            ("100 USD split between 4", "$25"),
+            ("1000 JPY split 3 ways", "333.00 JPY each (+1.00 JPY remainder)"),
+            ("(99.71 USD split 4 ways) * 4", "$99.68"),
+            ("10 USD split 0 ways", "Error: Can't split 0 ways"),
+            ("tips * 3", "6"),
+        ] {
+            let expr = parse_line(line);
+            assert_eq!(evaluate(&expr, &mut variables).to_string(), expected, "{line}");
+        }
+
+        // A split assigned to a variable is worth one share
+        let lines = ["share = 10 USD split 3 ways", "share * 3"];
+        let app = app_with_lines(&lines);
+        assert_eq!(app.line_value(0).map(|v| v.to_string()).as_deref(), Some("$3.33 each (+$0.01 remainder)"));
+        assert_eq!(app.line_value(1).map(|v| v.to_string()).as_deref(), Some("$9.99"));
+    }
+
+    #[test]
+    fn test_statistics_over_lists() {
+        context().rates.set_rate("EUR", "USD", 1.2);
+        let mut variables = HashMap::new();
+        variables.insert("x".to_string(), Value::Number(10.0));
+
+        for (line, expected) in [
+            ("mean(4, 8, 15, 16, 23, 42)", "18"),
+            ("median(4, 8, 15, 16, 23, 42)", "15.50"),
+            ("median(3, 1, 2)", "2"),
+            ("stdev(2, 4, 4, 4, 5, 5, 7, 9)", "2.13809"),
+            ("sum(1, 2, x)", "13"),
+            ("min(3, -1, 2) + max(3, -1, 2)", "2"),
+            ("mean(10 USD, 10 EUR)", "$11"),
+            ("sum(1 km, 500 m)", "1.50 km"),
+            ("mean(10%, 20%)", "15%"),
+            ("mean(1 km, 2 kg)", "Error: Can't mix a length (km) and a mass (kg)"),
+            ("mean(1, 2 USD)", "$1.50"),
+            ("mean(1, 5%)", "Error: mean() can't mix number and percentage"),
+            ("mean()", "Error: mean() needs at least one value"),
+            ("stdev(5)", "Error: stdev() needs at least two values"),
+            ("max(100,200, 3)", "Error: Ambiguous '100,200': put a space after each comma in max(...)"),
+            ("sum(1,234, 5)", "Error: Ambiguous '1,234': put a space after each comma in sum(...)"),
+        ] {
+            let expr = parse_line(line);
+            assert_eq!(evaluate(&expr, &mut variables).to_string(), expected, "{line}");
+        }
+    }
+
+    #[test]
+    fn test_list_values() {
+        let lines = [
+            "prices = [12.50, 8.99, 30 USD]",
+            "sum(prices)",
+            "prices * 2",
+            "prices[3]",
+            "len(prices)",
+            "[1, 2, 3] + [10, 20, 30]",
+            "prices[4]",
+            "[1, 2] + [1, 2, 3]",
+            "[1 km, 2 kg] + 1 km",
+            "[1, 2, 3, 4, 5, 6, 7, 8, 9, 10]",
+            "mean(prices, 0.51)",
+        ];
+        let app = app_with_lines(&lines);
+        let results: Vec<String> = (0..lines.len())
+            .map(|i| app.line_value(i).map(|v| v.to_string()).unwrap_or_default())
+            .collect();
+        assert_eq!(results, [
+            "[12.50, 8.99, $30]",
+            "$51.49",
+            "[25, 17.98, $60]",
+            "$30",
+            "3",
+            "[11, 22, 33]",
+            "Error: Index 4 is out of range for a list of 3 items (they count from 1)",
+            "Error: Lists of 2 and 3 items can't be combined",
+            "Error: Can't add a length (km) to a mass (kg)",
+            "[1, 2, 3, 4, 5, 6, 7, 8, …] (10 items)",
+            "$13",
+        ]);
+    }
+
+    #[test]
+    fn test_rand_and_dice() {
+        crate::random::seed(1);
+        let mut variables = HashMap::new();
+        for _ in 0..50 {
+            let number = |line: &str, variables: &mut HashMap<String, Value>| match evaluate(&parse_line(line), variables) {
+                Value::Number(n) => n,
+                other => panic!("Expected a number for '{line}', got {other:?}"),
+            };
+            let roll = number("3d6 + 2", &mut variables);
+            assert!((5.0..=20.0).contains(&roll) && roll.fract() == 0.0, "{roll}");
+            assert!((0.0..1.0).contains(&number("rand()", &mut variables)));
+            assert!((10.0..20.0).contains(&number("rand(10, 20)", &mut variables)));
+        }
+        for (line, expected) in [
+            ("rand(1, 2, 3)", "Error: rand() takes no values, or a low and a high one"),
+            ("0d6", "Error: Roll between 1 and 1000 dice"),
+            ("2d0", "Error: Dice need at least one side"),
+        ] {
+            assert_eq!(evaluate(&parse_line(line), &mut variables).to_string(), expected);
+        }
+        let expr = parse_line("rand(5 USD, 10 USD)");
+        assert!(matches!(evaluate(&expr, &mut variables), Value::Unit(n, ref unit) if unit == "USD" && (5.0..10.0).contains(&n)));
+    }
+
+    #[test]
+    fn test_rolls_stay_put_until_edited_or_rerolled() {
+        use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+        let lines = ["roll = 100d6", "roll + 0", ""];
+        let mut app = app_with_lines(&lines);
+        app.seed_random(42);
+        let first = app.line_value(0).cloned();
+        assert_eq!(app.line_value(1), first.as_ref());
+
+        // Same seed, same numbers
+        let mut other = app_with_lines(&lines);
+        other.seed_random(42);
+        assert_eq!(other.line_value(0), first.as_ref());
+
+        // Typing elsewhere re-evaluates without rolling again
+        app.cursor_pos = (2, 0);
+        type_text(&mut app, "roll * 2");
+        assert_eq!(app.line_value(0), first.as_ref());
+
+        app.cursor_pos = (0, 0);
+        app.handle_key(KeyEvent::new(KeyCode::Char('g'), KeyModifiers::CONTROL));
+        assert_eq!(app.status_message.as_deref(), Some("Rolled again"));
+        let rerolled = app.line_value(0).cloned();
+        assert_ne!(rerolled, first);
+        assert_eq!(app.line_value(1), rerolled.as_ref());
+    }
+
+    #[test]
+    fn test_this_and_next_weekday() {
+        use chrono::{Datelike, NaiveDate, Weekday};
+        use crate::evaluator::resolve_day;
+        use crate::parser::Day;
+
+        let friday = NaiveDate::from_ymd_opt(2024, 5, 17).unwrap();
+        let date = |d: u32| NaiveDate::from_ymd_opt(2024, 5, d).unwrap();
+        assert_eq!(resolve_day(Day::This(Weekday::Fri), friday), friday);
+        assert_eq!(resolve_day(Day::Next(Weekday::Fri), friday), date(24));
+        assert_eq!(resolve_day(Day::This(Weekday::Sat), friday), date(18));
+        assert_eq!(resolve_day(Day::Next(Weekday::Sat), friday), date(18));
+        assert_eq!(resolve_day(Day::This(Weekday::Thu), friday), date(23));
+        assert_eq!(resolve_day(Day::Today, friday), friday);
+
+        // Offsets can be any expression, not just a literal amount
+        let mut variables = HashMap::new();
+        variables.insert("n".to_string(), Value::Number(10.0));
+        let date_of = |line: &str, variables: &mut HashMap<String, Value>| match evaluate(&parse_line(line), variables) {
+            Value::Date(date) => date,
+            other => panic!("Expected a date for '{line}', got {other:?}"),
+        };
+        let next_friday = date_of("next friday", &mut variables);
+        let this_friday = date_of("this friday", &mut variables);
+        assert_eq!(next_friday.weekday(), Weekday::Fri);
+        assert_eq!(next_friday, NaiveDate::from_ymd_opt(2024, 5, 24).unwrap());
+        assert_eq!(date_of("friday", &mut variables), this_friday);
+        assert_eq!(date_of("next friday + n days", &mut variables), next_friday + chrono::Duration::days(10));
+        assert_eq!(date_of("next friday + (2 * 5) days", &mut variables), next_friday + chrono::Duration::days(10));
+        assert_eq!(date_of("friday + 2 days", &mut variables), this_friday + chrono::Duration::days(2));
+        assert_eq!(date_of("next friday + 1 week * 2", &mut variables), next_friday + chrono::Duration::days(14));
+    }
+
+    #[test]
+    fn test_date_display_styles() {
+        use crate::locale::{set_date_style, DateStyle};
+        let date = Value::Date(chrono::NaiveDate::from_ymd_opt(2025, 9, 12).unwrap());
+
+        set_date_style(DateStyle::Iso);
+        assert_eq!(date.to_string(), "2025-09-12");
+        set_date_style(DateStyle::Weekday);
+        assert_eq!(date.to_string(), "Fri, 2025-09-12");
+        set_date_style(DateStyle::Long);
+        assert_eq!(date.to_string(), "September 12, 2025");
+        assert_eq!(date.to_raw_string().as_deref(), Some("2025-09-12"));
+        set_date_style(DateStyle::default());
+        assert_eq!(date.to_string(), "Fri, 2025-09-12");
+
+        let lines = ["set dates long", "today", "set dates weekly"];
+        let mut app = app_with_lines(&lines);
+        assert_eq!(app.results[1], "May 17, 2024");
+        assert_eq!(app.debounced_results[2], "Error: Usage: set dates iso|weekday|long");
+
+        // Removing the directive goes back to the default
+        app.cursor_pos = (0, 0);
+        press(&mut app, crossterm::event::KeyCode::Delete);
+        assert_eq!(app.results[1], "Fri, 2024-05-17");
+    }
+
+    #[test]
+    fn test_days_until_and_since() {
+        use crate::evaluator::date_difference;
+
+        let date = |y, m, d| NaiveDate::from_ymd_opt(y, m, d).unwrap();
+        let today = date(2025, 1, 15);
+        assert_eq!(date_difference(today, date(2025, 12, 25), "days"), Value::Unit(344.0, "days".to_string()));
+        assert_eq!(date_difference(today, date(2025, 1, 29), "weeks"), Value::Unit(2.0, "week".to_string()));
+        assert_eq!(date_difference(today, date(2025, 3, 15), "months"), Value::Unit(2.0, "month".to_string()));
+        assert_eq!(date_difference(date(2024, 1, 31), date(2024, 2, 29), "months"), Value::Unit(1.0, "month".to_string()));
+        assert_eq!(date_difference(date(2024, 1, 1), today, "years"), Value::Unit(1.0 + 14.0 / 365.0, "year".to_string()));
+        assert_eq!(date_difference(today, date(2025, 1, 10), "days"), Value::Unit(-5.0, "days".to_string()));
+        assert_eq!(date_difference(today, date(2024, 12, 15), "months"), Value::Unit(-1.0, "month".to_string()));
+        assert_eq!(date_difference(today, date(2025, 1, 16), "kg"), Value::Error("Can't count kg between dates".to_string()));
+
+        let variables = HashMap::new();
+        let mut results = HashMap::new();
+        for line in ["days until 2030-12-25", "Days since 2024-01-01", "how many days since 2024-01-01", "weeks until next friday"] {
+            let expr = parse_line(line);
+            results.insert(line, evaluate(&expr, &mut variables.clone()));
+        }
+        assert_eq!(results["days until 2030-12-25"], Value::Unit(2413.0, "days".to_string()));
+        assert_eq!(results["Days since 2024-01-01"], Value::Unit(137.0, "days".to_string()));
+        assert_eq!(results["how many days since 2024-01-01"], results["Days since 2024-01-01"]);
+        assert_eq!(results["weeks until next friday"], Value::Unit(1.0, "week".to_string()));
+
+        // Dates written out are dates, not subtractions
+        for (line, expected) in [
+            ("2025-12-25 - 2025-12-01", "24 days"),
+            ("2025-13-01", "Error: Invalid date 2025-13-01"),
+            ("2025 - 12 - 1", "2012"),
+            ("days until 5", "Error: Expected a date, not 5"),
+        ] {
+            let expr = parse_line(line);
+            assert_eq!(evaluate(&expr, &mut variables.clone()).to_string(), expected, "{line}");
+        }
+    }
+
+    #[test]
+    fn test_context_supplies_today_and_rates() {
+        let rates = FixedRates::default().with("USD", "EUR", 0.5).with("USD", "GBP", 0.25);
+        let context = Context::fixed(NaiveDate::from_ymd_opt(2030, 1, 1).unwrap(), rates);
+        let mut variables = HashMap::new();
+        let mut eval = |line: &str| crate::evaluator::evaluate(&parse_line(line), &mut variables, &context).to_string();
+
+        assert_eq!(eval("today"), "Tue, 2030-01-01");
+        assert_eq!(eval("10 USD in EUR"), "€5.00");
+        assert_eq!(eval("10 EUR in USD"), "$20");
+        // Pairs without a rate of their own go through USD
+        assert_eq!(eval("10 EUR in GBP"), "£5.00");
+        assert_eq!(eval("setrate EUR to GBP = 0.4"), "1 EUR = 0.4 GBP (rate set)");
+        assert_eq!(eval("10 EUR in GBP"), "£4.00");
+        assert_eq!(eval("setrate EUR to GBP = 0"), "Error: An exchange rate has to be more than 0");
+        assert_eq!(eval("setrate eur to gbp = -2"), "Error: An exchange rate has to be more than 0");
+        assert_eq!(eval("setrate eur to Eur = 2"), "Error: 1 EUR is always 1 EUR; set the rate between two different currencies");
+        
+        // Codes in any case are the same currencies
+        assert_eq!(eval("setrate usd to gbp = 0.3"), "1 USD = 0.3 GBP (rate set)");
+        assert_eq!(eval("10 USD in GBP"), "£3.00");
+
+        // Lines evaluated with the shared test context don't see those rates
+        assert_eq!(evaluate(&parse_line("10 EUR in GBP"), &mut HashMap::new()).to_string(), "£8.47");
+    }
+
+    #[test]
+    fn test_clamp() {
+        let mut variables = HashMap::new();
+        variables.insert("x".to_string(), Value::Number(42.0));
+
+        for (line, expected) in [
+            ("clamp(140, 0, 100)", "100"),
+            ("clamp(-5, 0, 100)", "0"),
+            ("clamp(x, 10, 20)", "20"),
+            ("clamp(x, 0, 100)", "42"),
+            ("clamp(2.5 km, 500 m, 2000 m)", "2 km"),
+            ("clamp(300 m, 0.5 km, 2 km)", "500 m"),
+            ("clamp(120%, 0%, 100%)", "100%"),
+            ("clamp(5 kg, 1, 3)", "3 kg"),
+            ("clamp(5 kg, 1 m, 3 m)", "Error: Can't mix a mass (kg) and a length (m)"),
+            ("clamp(50%, 0%, 1 m)", "Error: clamp() can't mix percentage and m"),
+            ("clamp(x, 100, 0)", "Error: clamp() bounds are the wrong way round: 100 is above 0"),
+            ("clamp(x, 0)", "Error: Usage: clamp(value, low, high)"),
+            ("x between 10 and 20", "Error: 'between' needs true/false values, which aren't supported yet; clamp(value, low, high) keeps a value in range"),
+        ] {
+            let expr = parse_line(line);
+            assert_eq!(evaluate(&expr, &mut variables.clone()).to_string(), expected, "{line}");
+        }
+    }
+
+    #[test]
+    fn test_rounding_to_a_step() {
+        let mut variables = HashMap::new();
+        variables.insert("price".to_string(), Value::Unit(12.3456, "USD".to_string()));
+
+        for (line, expected) in [
+            ("round(2.5)", "3"),
+            ("round(-2.5)", "-3"),
+            ("floor(2.7)", "2"),
+            ("ceil(2.1)", "3"),
+            ("round(1234, 100)", "1200"),
+            ("round(4.97, 0.05)", "4.95"),
+            ("ceil(4.97, 0.05)", "5"),
+            ("round(price, 0.01)", "$12.35"),
+            ("floor(1.7 km, 500 m)", "1.50 km"),
+            ("round(33%, 5%)", "35%"),
+            ("round(5 kg, 0)", "Error: round() needs a step above zero"),
+            ("ceil(5, -1)", "Error: ceil() needs a step above zero"),
+            ("round(5 kg, 1 m)", "Error: Can't mix a mass (kg) and a length (m)"),
+            ("round(1, 2, 3)", "Error: Usage: round(value) or round(value, step)"),
+        ] {
+            let expr = parse_line(line);
+            assert_eq!(evaluate(&expr, &mut variables.clone()).to_string(), expected, "{line}");
+        }
+
+        // A variable takes the name over
+        let mut floors = HashMap::new();
+        floors.insert("floor".to_string(), Value::Number(3.0));
+        assert_eq!(evaluate(&parse_line("floor(2)"), &mut floors.clone()), Value::Number(6.0));
+
+        // The decimal written, not its nearest binary fraction, decides which way a half goes
+        let round = |line: &str| match evaluate(&parse_line(line), &mut HashMap::new()) {
+            Value::Number(n) | Value::Unit(n, _) => n,
+            other => panic!("Expected a number for '{line}', got {other:?}"),
+        };
+        assert_eq!(round("round(2.675, 0.01)"), 2.68);
+        assert_eq!(round("round(1.005, 0.01)"), 1.01);
+        assert_eq!(round("round(0.285, 0.01)"), 0.29);
+        assert_eq!(round("floor(0.3, 0.1)"), 0.3);
+        assert_eq!(round("ceil(0.7, 0.1)"), 0.7);
+        assert_eq!(round("round(1.15, 0.1)"), 1.2);
+
+        // To the smallest coin of the currency
+        assert_eq!(round("round 12.3456 USD to cents"), 12.35);
+        assert_eq!(round("round(1234.5 JPY) to cents"), 1235.0);
+        assert_eq!(round("floor 2.9999 BHD to cents"), 2.999);
+        assert_eq!(
+            evaluate(&parse_line("round 12 km to cents"), &mut variables.clone()).to_string(),
+            "Error: Only money rounds to cents, not a km"
+        );
+    }
+
+    #[test]
+    fn test_slow_and_overflowing_lines() {
+        let lines = ["9^9^9^9", "x = 2 USD * 10^400", "[1, 2^2000]", "2 USD * 9^999 split 2"];
+        let app = app_with_lines(&lines);
+        for (line, result) in lines.iter().zip(&app.debounced_results) {
+            assert_eq!(result, "Error: Result too large", "{line}");
+        }
+
+        // A line that runs out of time is an error, and the app carries on
+        let mut app = new_app();
+        app.context.budget = std::time::Duration::ZERO;
+        app.clear();
+        app.add_line("sum(1000d1000, 1000d1000, 1000d1000, 1000d1000)".to_string());
+        app.evaluate_expressions();
+        assert_eq!(app.debounced_results[0], "Error: Evaluation took too long");
+
+        app.context.budget = std::time::Duration::from_secs(60);
+        press(&mut app, crossterm::event::KeyCode::End);
+        press(&mut app, crossterm::event::KeyCode::Enter);
+        type_text(&mut app, "1 + 1");
+        assert_eq!(app.results[1], "2");
+    }
+
+    #[test]
+    fn test_include_definitions_from_another_file() {
+        let dir = TempDir::new("includes");
+        std::fs::write(dir.join("constants.cali"), "# My constants\nhourly rate = 85 USD\ntax = 13%\nsetrate USD to EUR = 0.5\n").unwrap();
+        std::fs::write(dir.join("broken.cali"), "a = 2\nb = a +\nc = 3\n").unwrap();
+        
+        let lines = [
+            "include constants.cali",
+            "hourly rate * 10",
+            "tax",
+            "100 USD in EUR",
+            "include broken.cali",
+            "a + c",
+            "include missing.cali",
+            "hourly rate",
+        ];
+        let mut app = new_app();
+        app.base_dir = Some(dir.to_path_buf());
+        app.clear();
+        for line in &lines {
+            app.add_line(line.to_string());
+        }
+        app.evaluate_expressions();
+        
+        // The file's lines aren't shown, only what they defined
+        assert_eq!(app.lines.len(), lines.len());
+        assert_eq!(app.debounced_results[0], "Included 2 definitions");
+        assert_eq!(app.debounced_results[1], "$850");
+        assert_eq!(app.debounced_results[2], "13%");
+        assert_eq!(app.debounced_results[3], "€50.00");
+        // A bad line is the include's error, and the lines that worked still count
+        assert_eq!(app.debounced_results[4], "Error: broken.cali line 2: Expression ends with an operator");
+        assert_eq!(app.debounced_results[5], "5");
+        assert_eq!(app.debounced_results[6], format!("Error: Can't find {}", dir.join("missing.cali").display()));
+        assert_eq!(app.debounced_results[7], "$85");
+        
+        // Definitions go away with the include line
+        app.cursor_pos = (0, 0);
+        for _ in 0.."include constants.cali".len() {
+            press(&mut app, crossterm::event::KeyCode::Delete);
+        }
+        assert_eq!(app.debounced_results[1], "Error: 'hourly' not found");
+    }
+    
+    #[test]
+    fn test_evaluate_selected_part_of_a_line() {
+        let mut app = app_with_lines(&["price = 40", "total = price * 1.2 + 5"]);
+        let area = (0, 2, 40, 10);
+        let ctrl_e = crossterm::event::KeyEvent::new(crossterm::event::KeyCode::Char('e'), crossterm::event::KeyModifiers::CONTROL);
+        
+        // A selected variable evaluates to the value its line sees
+        app.handle_mouse_click(10, 4, area);
+        app.handle_mouse_click(10, 4, area);
+        assert_eq!(app.selection, Some((1, 8..13)));
+        app.handle_key(ctrl_e);
+        assert_eq!(app.status_message.as_deref(), Some("price = 40   (y: copy)"));
+        assert_eq!(app.selection, None);
+        
+        // Any other key drops the offer to copy, so `y` types again
+        press(&mut app, crossterm::event::KeyCode::End);
+        press(&mut app, crossterm::event::KeyCode::Char('y'));
+        assert_eq!(app.lines[1], "total = price * 1.2 + 5y");
+        
+        // Assignments inside the selection aren't kept
+        let variables = app.variables.clone();
+        app.selection = Some((1, 0..19));
+        app.handle_key(ctrl_e);
+        assert_eq!(app.status_message.as_deref(), Some("total = price * 1.2 = 48   (y: copy)"));
+        assert_eq!(app.variables, variables);
+        assert_eq!(app.lines[1], "total = price * 1.2 + 5y");
+        
+        // Half an expression shows the parse error, and no selection says how to make one
+        app.selection = Some((1, 14..21));
+        app.handle_key(ctrl_e);
+        assert!(app.status_message.as_deref().is_some_and(|msg| msg.starts_with("Error: '* 1.2 +':")), "{:?}", app.status_message);
+        app.handle_key(ctrl_e);
+        assert_eq!(app.status_message.as_deref(), Some("Select part of a line to evaluate it (double-click a word)"));
+    }
+    
+    #[test]
+    fn test_focus_changes() {
+        let mut app = app_with_lines(&["1 + 1"]);
+        assert!(app.focused);
+        assert_eq!(app.poll_timeout(), std::time::Duration::from_millis(100));
+        
+        // Out of focus the loop ticks slowly, and losing focus isn't worth a redraw
+        app.needs_redraw = false;
+        app.set_focused(false);
+        assert!(!app.focused);
+        assert!(!app.needs_redraw);
+        assert_eq!(app.poll_timeout(), std::time::Duration::from_secs(1));
+        
+        // A file still being evaluated keeps going either way
+        app.add_line("2 + 2".to_string());
+        app.defer_evaluation();
+        assert_eq!(app.poll_timeout(), std::time::Duration::ZERO);
+        app.catch_up(crate::app::CATCH_UP_CHUNK);
+        
+        // Coming back draws the screen afresh, but only on an actual change
+        app.set_focused(true);
+        assert!(app.needs_redraw);
+        assert_eq!(app.poll_timeout(), std::time::Duration::from_millis(100));
+        app.needs_redraw = false;
+        app.set_focused(true);
+        assert!(!app.needs_redraw);
+    }
+    
+    #[test]
+    fn test_more_lengths_speeds_and_masses() {
+        for (line, expected) in [
+            ("10 nmi in km", "18.52 km"),
+            ("1 nmi in m", "1852 m"),
+            ("1 au in km", "149597870.70 km"),
+            ("1 ly in au", "63241.077084 au"),
+            ("3 furlongs in yd", "660 yd"),
+            ("8 furlong in mi", "1 mi"),
+            ("2 fathoms in ft", "12 ft"),
+            ("15 hands in in", "60 in"),
+            ("5 thou in mm", "0.127 mm"),
+            ("10 fps in mps", "3.048 mps"),
+            ("100 mpmin in kmph", "6 kmph"),
+            ("100 mps in kmph", "360 kmph"),
+            ("10 mph in kmph", "16.0934 kmph"),
+            ("20 kn in kmph", "37.04 kmph"),
+            ("37.04 kmph in knots", "20 knot"),
+            ("1 lb in grains", "7000 grain"),
+            ("2 carats in mg", "400 mg"),
+            ("16 drams in floz", "2 floz"),
+        ] {
+            let mut variables = HashMap::new();
+            let expr = parse_line(line);
+            assert_eq!(evaluate(&expr, &mut variables).to_string(), expected, "{line}");
+        }
+        
+        // A knot for an hour covers a nautical mile
+        let mut variables = HashMap::new();
+        let knot = evaluate(&parse_line("1 knot in kmph"), &mut variables);
+        let nautical_mile = evaluate(&parse_line("1 nmi in km"), &mut variables);
+        assert!(matches!((knot, nautical_mile), (Value::Unit(a, _), Value::Unit(b, _)) if a == b));
+    }
+    
+    #[test]
+    fn test_pressure_energy_and_power_units() {
+        for (line, expected) in [
+            ("30 inHg in kPa", "101.591645 kPa"),
+            ("760 mmHg in atm", "1 atm"),
+            ("760 torr in mmHg", "760 mmHg"),
+            ("1 inHg in mmHg", "25.40 mmHg"),
+            ("2 atm in kPa", "202.65 kPa"),
+            ("100 kPa in Pa", "100000 Pa"),
+            ("10000 BTU in kWh", "2.930711 kWh"),
+            ("1 therm in kWh", "29.307107 kWh"),
+            ("1 therm in BTU", "100000 BTU"),
+            ("1500 Wh in kWh", "1.50 kWh"),
+            ("2 MWh in kWh", "2000 kWh"),
+            ("3 wh in J", "10800 J"),
+            ("1 kcal in cal", "1000 cal"),
+            ("1 cal in J", "4.184 J"),
+            ("2 kW in W", "2000 W"),
+            ("1 MW in kW", "1000 kW"),
+            ("3 BTU", "3 BTU"),
+        ] {
+            let mut variables = HashMap::new();
+            let expr = parse_line(line);
+            assert_eq!(evaluate(&expr, &mut variables).to_string(), expected, "{line}");
+        }
+        assert!(!crate::lexer::is_currency_code("BTU"));
+    }
+    
+    #[test]
+    fn test_cooking_measures_and_ingredients() {
+        for (line, expected) in [
+            ("2 sticks butter in g", "226.80 g"),
+            ("2 sticks in oz", "8 oz"),
+            ("1 dash in ml", "0.625 ml"),
+            ("2 pinches in tsp", "0.125 tsp"),
+            ("3 tsp in ml", "15 ml"),
+            ("2 cups flour in g", "250 g"),
+            ("500 g sugar in cups", "2.50 cup"),
+            ("1 cup water in g", "236.588 g"),
+            ("1 l milk in kg", "1.031329 kg"),
+            ("2 cups flour", "2 cups"),
+            ("2 cups in ml", "473.176 ml"),
+            ("2 cups flour in km", "Error: Can't convert a volume (cups) to a length (km)"),
+            ("2 cups sand in g", "Error: Unknown ingredient 'sand'; the known ones are flour, sugar, butter, water, milk, rice"),
+        ] {
+            let mut variables = HashMap::new();
+            let expr = parse_line(line);
+            assert_eq!(evaluate(&expr, &mut variables).to_string(), expected, "{line}");
+        }
+        
+        // A variable after the unit still multiplies
+        let mut variables = HashMap::new();
+        variables.insert("batches".to_string(), Value::Number(3.0));
+        let expr = parse_line("2 cups batches");
+        assert_eq!(evaluate(&expr, &mut variables).to_string(), "6 cups");
+    }
+    
+    #[test]
+    fn test_map_literals_and_lookups() {
+        let lines = [
+            "sizes = {S: 36, M: 40, L: 44}",
+            "sizes[M]",
+            "sizes[L] - sizes[S]",
+            "sizes[XL]",
+            "shoes = {38: 5 USD, 39: 5.5 USD}",
+            "shoes[39] * 2",
+            "pick = 38",
+            "shoes[pick]",
+            "nested = {eu: sizes, uk: {S: 8, M: 12}}",
+            "nested[uk][M]",
+            "nested",
+            "{}",
+            "{a: 1, a: 2}",
+            "prices = [3, 4]",
+            "prices[M]",
+        ];
+        let app = app_with_lines(&lines);
+        assert_eq!(app.debounced_results, [
+            "{S: 36, M: 40, L: 44}",
+            "40",
+            "8",
+            "Error: No key 'XL'; the keys are S, M, L",
+            "{38: $5, 39: $5.50}",
+            "$11",
+            "38",
+            "$5",
+            "{eu: {S: 36, M: 40, L: 44}, uk: {S: 8, M: 12}}",
+            "12",
+            "{eu: {S: 36, M: 40, L: 44}, uk: {S: 8, M: 12}}",
+            "{}",
+            "Error: 'a' appears twice in {...}",
+            "[3, 4]",
+            "Error: 'M' isn't a variable, and a list has no keys",
+        ]);
+    }
+    
+    #[test]
+    fn test_explain_a_result() {
+        let lines = ["rent = 1200 USD", "food = 300 USD", "rent + food", "1500 USD in EUR # budget", "", "1 mi in km", "1 / 0"];
+        let mut app = app_with_lines(&lines);
+        
+        // The operands of arithmetic, and the rate and factor of a conversion
+        assert_eq!(app.explain_line(2).as_deref(), Ok("rent + food = $1500; rent = $1200, food = $300"));
+        assert_eq!(app.explain_line(3).as_deref(), Ok("1500 USD in EUR = €1275.00; 1 USD = 0.85 EUR"));
+        assert_eq!(app.explain_line(5).as_deref(), Ok("1 mi in km = 1.60934 km; 1 mi = 1.60934 km"));
+        assert!(app.explain_line(4).is_err());
+        assert!(app.explain_line(6).is_err());
+        
+        // `e` in the output panel shows it, and `y` right after copies it
+        app.output_selected_idx = 3;
+        app.explain_selected();
+        assert_eq!(app.status_message.as_deref(), Some("1500 USD in EUR = €1275.00; 1 USD = 0.85 EUR   (y: copy)"));
+        let y = crossterm::event::KeyEvent::new(crossterm::event::KeyCode::Char('y'), crossterm::event::KeyModifiers::NONE);
+        assert!(app.take_copy_offer(&y));
+        assert!(!app.take_copy_offer(&y));
+    }
+    
+    #[test]
+    fn test_explained_rate_is_the_one_used() {
+        use crate::evaluator::{evaluate_with_trace, RateProvider};
+        use std::cell::Cell;
+        
+        // Rates that change every time they're asked for
+        struct Drifting(Cell<f64>);
+        impl RateProvider for Drifting {
+            fn rate(&self, from: &str, to: &str) -> Option<f64> {
+                if from == to {
+                    return Some(1.0);
+                }
+                let rate = self.0.get();
+                self.0.set(rate * 2.0);
+                Some(rate)
+            }
+            fn set_rate(&self, _from: &str, _to: &str, _rate: f64) -> bool {
+                false
+            }
+        }
+        
+        let rates = std::rc::Rc::new(Drifting(Cell::new(0.5)));
+        let context = Context { rates: rates.clone(), ..context() };
+        let mut variables = HashMap::new();
+        let (value, trace) = evaluate_with_trace(&parse_line("100 USD in EUR"), &mut variables, &context);
+        assert_eq!(value, Value::Unit(50.0, "EUR".to_string()));
+        assert_eq!(trace.rates, vec![("USD".to_string(), 0.5, "EUR".to_string())]);
+        // Explaining the result didn't ask for the rate again
+        assert_eq!(rates.0.get(), 1.0);
+    }
+    
+    #[test]
+    fn test_rate_update_failure_is_shown_once() {
+        // Rates that couldn't be brought up to date
+        struct Offline;
+        impl crate::evaluator::RateProvider for Offline {
+            fn rate(&self, from: &str, to: &str) -> Option<f64> {
+                (from == to).then_some(1.0)
+            }
+            fn set_rate(&self, _from: &str, _to: &str, _rate: f64) -> bool {
+                false
+            }
+            fn fetch_failure(&self) -> Option<String> {
+                Some("Exchange rate update failed: can't reach the rate service; using built-in rates".to_string())
+            }
+        }
+        
+        let mut app = app_with_lines(&["1 + 1"]);
+        app.update_on_tick();
+        assert_eq!(app.status_message, None);
+        
+        app.context.rates = std::rc::Rc::new(Offline);
+        app.update_on_tick();
+        assert_eq!(app.status_message.as_deref(), Some("Exchange rate update failed: can't reach the rate service; using built-in rates"));
+        app.clear_status_message();
+        app.update_on_tick();
+        assert_eq!(app.status_message, None);
+    }
+    
+    #[test]
+    fn test_currency_rounding() {
+        let lines = [
+            "# set currency rounding on", "a = 2.51 USD * 0.4", "b = 2.51 USD * 0.4", "c = 2.51 USD * 0.4", "a + b + c",
+            "0.125 USD * 1", "201 JPY / 2", "203 JPY / 2", "set currency rounding always",
+        ];
+        let mut app = app_with_lines(&lines);
+        assert_eq!(app.debounced_results[8], "Error: Usage: set currency rounding on|off");
+        
+        // Each line shows $1.00, but they add up to $3.01
+        assert_eq!(app.debounced_results[1], "$1.00");
+        assert_eq!(app.debounced_results[4], "$3.01");
+        assert_eq!(app.debounced_results[6], "100.50 JPY");
+        
+        // Rounded at every step, the total is what the lines show; halves go to the even coin
+        app.cursor_pos = (0, 0);
+        press(&mut app, crossterm::event::KeyCode::Delete);
+        press(&mut app, crossterm::event::KeyCode::Delete);
+        assert_eq!(app.status_message.as_deref(), Some("Currency rounding on"));
+        assert_eq!(&app.debounced_results[1..8], ["$1", "$1", "$1", "$3", "$0.12", "100.00 JPY", "102.00 JPY"]);
+    }
+    
+    #[test]
+    fn test_enter_in_output_edits_the_line() {
+        use crate::app::PanelFocus;
+        
+        let lines: Vec<String> = (1..=50).map(|n| format!("{n} * 2")).collect();
+        let mut app = app_with_lines(&lines.iter().map(String::as_str).collect::<Vec<_>>());
+        app.input_panel_area = Some((0, 0, 40, 12));
+        app.output_panel_area = Some((40, 0, 40, 12));
+        app.cursor_pos = (0, 0);
+        app.toggle_panel_focus(true);
+        
+        // Far below the visible lines: the input panel scrolls to show it
+        app.output_selected_idx = 40;
+        app.edit_selected_output_line();
+        assert!(app.panel_focus == PanelFocus::Input);
+        assert_eq!(app.cursor_pos, (40, 6));
+        assert_eq!(app.input_scroll, 31);
+        
+        // And back up above them
+        app.toggle_panel_focus(true);
+        app.output_selected_idx = 3;
+        app.edit_selected_output_line();
+        assert_eq!(app.cursor_pos, (3, 5));
+        assert_eq!(app.input_scroll, 3);
+    }
+    
+    #[test]
+    fn test_output_panel_paging() {
+        use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+        let key = |code| KeyEvent::new(code, KeyModifiers::NONE);
+        let ctrl = |c| KeyEvent::new(KeyCode::Char(c), KeyModifiers::CONTROL);
+        
+        // 30 results in a panel showing 10
+        let lines: Vec<String> = (1..=30).map(|n| n.to_string()).collect();
+        let mut app = app_with_lines(&lines.iter().map(String::as_str).collect::<Vec<_>>());
+        app.output_panel_area = Some((40, 0, 40, 12));
+        
+        app.navigate_output_panel(key(KeyCode::PageDown));
+        assert_eq!((app.output_selected_idx, app.output_scroll), (10, 1));
+        app.navigate_output_panel(ctrl('d'));
+        assert_eq!((app.output_selected_idx, app.output_scroll), (15, 6));
+        app.navigate_output_panel(ctrl('u'));
+        assert_eq!((app.output_selected_idx, app.output_scroll), (10, 6));
+        app.navigate_output_panel(key(KeyCode::PageUp));
+        assert_eq!((app.output_selected_idx, app.output_scroll), (0, 0));
+        
+        // The last result sits flush with the bottom border
+        app.navigate_output_panel(key(KeyCode::Char('G')));
+        assert_eq!((app.output_selected_idx, app.output_scroll), (29, 20));
+        app.navigate_output_panel(key(KeyCode::PageDown));
+        assert_eq!((app.output_selected_idx, app.output_scroll), (29, 20));
+        app.navigate_output_panel(key(KeyCode::Char('g')));
+        assert_eq!((app.output_selected_idx, app.output_scroll), (0, 0));
+        
+        // Exactly as many results as fit, or fewer: nothing ever scrolls
+        for count in [10, 4] {
+            let lines: Vec<String> = (1..=count).map(|n| n.to_string()).collect();
+            let mut app = app_with_lines(&lines.iter().map(String::as_str).collect::<Vec<_>>());
+            app.output_panel_area = Some((40, 0, 40, 12));
+            for code in [KeyCode::End, KeyCode::PageDown, KeyCode::Down, KeyCode::PageUp, KeyCode::End] {
+                app.navigate_output_panel(key(code));
+                assert_eq!(app.output_scroll, 0);
+            }
+            assert_eq!(app.output_selected_idx, count - 1);
+        }
+    }
+    
+    #[test]
+    fn test_whitespace_is_ignored() {
+        assert!(matches!(parse_line("10 USD in EUR  "), Expr::Convert(_, ref unit) if unit == "EUR"));
+        assert!(matches!(parse_line("  x   =   5 "), Expr::Assignment(ref name, _) if name == "x"));
+        
+        // Blank lines have no result, however they're evaluated
+        let lines = ["   ", "10 USD in EUR  ", "x   =   5", "\t", "y\t=\tx  *  2  ", "  defunit  sprint  =  2  week  ", "3 sprint in days "];
+        let mut app = app_with_lines(&lines);
+        app.update_on_tick();
+        let expected = ["", "€8.50", "5", "", "10", "1 sprint = 2 week", "42 day"];
+        assert_eq!(app.debounced_results, expected);
+        assert_eq!(app.results, expected);
+        let mut variables = HashMap::new();
+        assert_eq!(crate::evaluator::evaluate_lines(&lines.map(String::from)[..5], &mut variables, &context()), expected[..5]);
+        
+        // Editing a line that others depend on doesn't hand them whitespace either
+        app.cursor_pos = (2, 9);
+        type_text(&mut app, "0  ");
+        assert_eq!(app.debounced_results[2..5], ["50", "", "100"]);
+        let mut session = crate::repl::Session::default();
+        assert_eq!(session.eval(" \t "), None);
+        
+        // Nor does a trailing space roll the dice again
+        let mut app = app_with_lines(&["100d6"]);
+        let rolled = app.line_value(0).cloned();
+        app.cursor_pos = (0, 5);
+        type_text(&mut app, "  ");
+        assert_eq!(app.line_value(0), rolled.as_ref());
+    }
+    
+    #[test]
+    fn test_sum_of_selected_results() {
+        use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+        let shift = |code| KeyEvent::new(code, KeyModifiers::SHIFT);
+        let key = |code| KeyEvent::new(code, KeyModifiers::NONE);
+        
+        let lines = ["100 EUR", "20 USD", "", "1 / 0", "5 km", "today", "30 EUR"];
+        let mut app = app_with_lines(&lines);
+        app.toggle_panel_focus(true);
+        assert_eq!(app.output_selection_summary(), None);
+        
+        // Dollars are converted to euros; the blank line and the error are only selected
+        app.navigate_output_panel(shift(KeyCode::Down));
+        assert_eq!(app.output_selection(), 0..=1);
+        assert_eq!(app.output_selection_summary().as_deref(), Some("Sum: €117.00 | Avg: €58.50 | Count: 2 | 2 selected"));
+        app.navigate_output_panel(shift(KeyCode::Down));
+        app.navigate_output_panel(shift(KeyCode::Down));
+        assert_eq!(app.output_selection_summary().as_deref(), Some("Sum: €117.00 | Avg: €58.50 | Count: 2 | 4 selected"));
+        
+        // A length and a date can't be added to money
+        app.navigate_output_panel(key(KeyCode::End));
+        assert_eq!(app.output_selection(), 6..=6);
+        app.toggle_output_visual();
+        app.navigate_output_panel(key(KeyCode::Home));
+        assert_eq!(app.output_selection(), 0..=6);
+        assert_eq!(app.output_selection_summary().as_deref(), Some("Sum: €147.00 | Avg: €49.00 | Count: 3 | 7 selected"));
+        
+        // Starting from the errors, nothing adds up
+        app.collapse_output_selection();
+        app.output_selected_idx = 2;
+        app.navigate_output_panel(shift(KeyCode::Down));
+        assert_eq!(app.output_selection_summary().as_deref(), Some("Count: 0 | 2 selected"));
+        
+        // A plain move goes back to one result, and `y` then copies several a line each
+        app.navigate_output_panel(key(KeyCode::Up));
+        assert_eq!(app.output_selection(), 2..=2);
+        app.navigate_output_panel(shift(KeyCode::Up));
+        app.navigate_output_panel(shift(KeyCode::Up));
+        assert_eq!(app.selected_output_text().as_deref(), Ok("€100.00\n$20\n"));
+    }
+    
+    #[test]
+    fn test_read_only_mode() {
+        use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+        let ctrl = |c| KeyEvent::new(KeyCode::Char(c), KeyModifiers::CONTROL);
+        
+        let lines = ["price = 40", "price * 2"];
+        let mut app = app_with_lines(&lines);
+        app.toggle_read_only();
+        assert!(app.read_only);
+        
+        // Typing, deleting, new lines, commenting out, marking drafts and renaming are refused
+        app.cursor_pos = (0, 10);
+        type_text(&mut app, "0");
+        assert_eq!(app.status_message.as_deref(), Some("Read-only mode (Ctrl+Shift+L to edit)"));
+        for code in [KeyCode::Backspace, KeyCode::Delete, KeyCode::Enter] {
+            press(&mut app, code);
+        }
+        app.handle_key(ctrl('/'));
+        app.handle_key(ctrl('.'));
+        app.handle_key(ctrl('r'));
+        assert!(app.input_mode == crate::app::InputMode::Normal);
+        assert_eq!(app.lines, lines);
+        assert_eq!(app.cursor_pos, (0, 10));
+        assert_eq!(app.debounced_results[1], "80");
+        assert!(!app.modified);
+        assert!(!app.can_edit());
+        
+        // Moving around still works
+        press(&mut app, KeyCode::Down);
+        press(&mut app, KeyCode::Home);
+        assert_eq!(app.cursor_pos, (1, 0));
+        app.handle_key(ctrl(']'));
+        assert_eq!(app.cursor_pos.0, 0);
+        
+        app.toggle_read_only();
+        assert_eq!(app.status_message.as_deref(), Some("Read-only mode off"));
+        app.cursor_pos = (0, 10);
+        type_text(&mut app, "0");
+        assert_eq!(app.debounced_results[1], "800");
+    }
+    
+    #[test]
+    fn test_of_needs_a_percentage() {
+        let lines = [
+            "tax = 8%", "tax of 200", "price = 50 USD", "price of 200", "count = 3", "rent = 1200", "count of rent",
+            "half of 200", "third of 90 EUR", "quarter of price + 1 USD", "Half of 3 km",
+        ];
+        let app = app_with_lines(&lines);
+        assert_eq!(app.debounced_results[1], "16");
+        assert_eq!(app.debounced_results[3], "Error: 'price' is $50, not a percentage; did you mean 'price * 200'?");
+        assert_eq!(app.debounced_results[6], "Error: 'count' is 3, not a percentage; did you mean 'count * rent'?");
+        assert_eq!(app.debounced_results[7..], ["100", "€30.00", "$13.50", "1.50 km"]);
+    }
+    
+    #[test]
+    fn test_flipping_an_input_reuses_earlier_results() {
+        use crossterm::event::KeyCode;
+        use crate::evaluator::{FixedRates, RateProvider};
+        use std::cell::Cell;
+        use std::rc::Rc;
+        
+        // Rates that count how often they're asked for
+        struct Counted(FixedRates, Cell<usize>);
+        impl RateProvider for Counted {
+            fn rate(&self, from: &str, to: &str) -> Option<f64> {
+                self.1.set(self.1.get() + 1);
+                self.0.rate(from, to)
+            }
+            fn set_rate(&self, from: &str, to: &str, rate: f64) -> bool {
+                self.0.set_rate(from, to, rate)
+            }
+            fn generation(&self) -> u64 {
+                self.0.generation()
+            }
+        }
+        
+        let mut lines = vec!["hours = 160".to_string(), "wage = 25 USD".to_string()];
+        lines.extend((2..500).map(|n| format!("hours * wage * {n} in EUR")));
+        let mut app = new_app();
+        let rates = Rc::new(Counted(FixedRates::default().with("USD", "EUR", 0.85), Cell::new(0)));
+        app.context.rates = rates.clone();
+        app.clear();
+        for line in lines {
+            app.add_line(line);
+        }
+        app.evaluate_expressions();
+        assert_eq!(app.debounced_results[2], "€6800.00");
+        
+        // 160 -> 170 and back, through "hours = 10" on the way
+        let flip = |app: &mut crate::app::App, digit: char| {
+            app.cursor_pos = (0, 10);
+            press(app, KeyCode::Backspace);
+            press(app, KeyCode::Char(digit));
+        };
+        flip(&mut app, '7');
+        assert_eq!(app.debounced_results[2], "€7225.00");
+        flip(&mut app, '6');
+        assert!(rates.1.get() > 0);
+        
+        // Every state has been seen, so nothing is converted again
+        rates.1.set(0);
+        flip(&mut app, '7');
+        assert_eq!(app.debounced_results[2], "€7225.00");
+        flip(&mut app, '6');
+        assert_eq!(app.debounced_results[499], "€1696600.00");
+        assert_eq!(rates.1.get(), 0);
+        
+        // A new rate is a new state
+        app.context.rates.set_rate("USD", "EUR", 0.5);
+        flip(&mut app, '7');
+        assert_eq!(app.debounced_results[2], "€4250.00");
+        assert!(rates.1.get() > 0);
+    }
+    
+    #[test]
+    fn test_base_currency() {
+        let lines = [
+            "fee = 10 EUR", "price = 100 USD", "fee + price", "price + fee", "sum of lines 3..4",
+            "# set base currency usd", "set base currency euros",
+        ];
+        let mut app = app_with_lines(&lines);
+        assert_eq!(app.debounced_results[6], "Error: Usage: set base currency EUR");
+        
+        // Without a base currency the left-hand side's currency wins, so order matters
+        assert_eq!(app.debounced_results[2], "€95.00");
+        assert_eq!(app.debounced_results[3], "$111.76");
+        assert_eq!(app.debounced_results[4], "€190.00");
+        
+        // With one, mixed currencies add up in it either way round, and so do line sums
+        app.cursor_pos = (5, 0);
+        press(&mut app, crossterm::event::KeyCode::Delete);
+        press(&mut app, crossterm::event::KeyCode::Delete);
+        assert_eq!(app.status_message.as_deref(), Some("Mixed currencies add up in USD"));
+        assert_eq!(app.debounced_results[2], "$111.76");
+        assert_eq!(app.debounced_results[3], "$111.76");
+        assert_eq!(app.debounced_results[4], "$223.53");
+        assert_eq!(app.debounced_results[1], "$100");
+    }
+    
+    #[test]
+    fn test_tutorial_evaluates_cleanly() {
+        // Rates of its own, since the tutorial's setrate changes them
+        let mut app = crate::app::App::new();
+        app.context = Context::fixed(
+            NaiveDate::from_ymd_opt(2024, 5, 17).unwrap(),
+            FixedRates::default().with("USD", "EUR", 0.85),
+        );
+        app.open_tutorial();
+        assert!(!app.modified);
+        assert_eq!(app.status_message.as_deref(), Some("This is the tutorial: press Ctrl+N for a blank sheet"));
+        for (line, result) in app.lines.iter().zip(&app.debounced_results) {
+            let comment = line.trim().is_empty() || line.starts_with('#');
+            assert_eq!(result.is_empty(), comment, "{line} = {result}");
+            assert!(!result.starts_with("Error"), "{line} = {result}");
+        }
+        let result = |line: &str| &app.debounced_results[app.lines.iter().position(|l| l == line).unwrap()];
+        assert_eq!(result("price in CHF"), "22.50 CHF");
+        assert_eq!(result("days until next friday"), "7 days");
+        assert_eq!(app.section_totals.values().next().map(Value::to_string).as_deref(), Some("$1690"));
+        
+        // Ctrl+N leaves it without asking; with unsaved changes it asks first
+        assert!(app.new_sheet());
+        assert_eq!(app.lines, [""]);
+        type_text(&mut app, "1 + 1");
+        assert!(!app.new_sheet());
+        assert_eq!(app.status_message.as_deref(), Some("Unsaved changes: press Ctrl+N again to discard them"));
+        assert!(app.new_sheet());
+        assert_eq!(app.lines, [""]);
+        assert_eq!(app.debounced_results, [""]);
+    }
+    
+    #[test]
+    fn test_incomplete_and_chained_assignments() {
+        let lines = ["x =", "x * 2", "y = = 5", "a = b = 5", "monthly rent = base rent = 900", "bad = 5 km + 2 kg", "bad * 2"];
+        let app = app_with_lines(&lines);
+        assert_eq!(app.debounced_results[0], "Error: Incomplete assignment: x = what?");
+        // Lines reading it say what's missing, but it's not a variable they could use
+        assert_eq!(app.debounced_results[1], "Error: Incomplete assignment: x = what?");
+        assert_eq!(app.debounced_results[2], "Error: Incomplete assignment: y = what?");
+        assert_eq!(app.debounced_results[3], "Error: One assignment per line: define b on a line of its own, then a = b");
+        assert_eq!(app.debounced_results[4], "Error: One assignment per line: define base rent on a line of its own, then monthly rent = base rent");
+        // A definition that failed explains itself to its readers, but isn't a usable variable
+        assert_eq!(app.debounced_results[6], "Error: Can't add a mass (kg) to a length (km)");
+        for name in ["x", "y", "a", "b", "bad"] {
+            assert!(!app.variables.contains_key(name), "{name}");
+        }
+    }
+    
+    #[test]
+    fn test_errors_name_what_doesnt_go_together() {
+        let mut variables = HashMap::new();
+        for (line, message) in [
+            ("2 kg + 3 km", "Can't add a length (km) to a mass (kg)"),
+            ("2 kg - 3 km", "Can't subtract a length (km) from a mass (kg)"),
+            ("5 h % 2 kg", "Can't take the remainder of a duration (h) divided by a mass (kg)"),
+            ("(3 m)^4", "Can't raise a length (m) to the power of 4"),
+            ("3 apples + 2 kg", "Can't add a mass (kg) to apples"),
+            ("10 USD + 2 kg", "Can't add a mass (kg) to an amount of money (USD)"),
+            ("5 kg in m", "Can't convert a mass (kg) to a length (m)"),
+            ("5 kg in parsecs", "Can't convert a mass (kg) to parsecs"),
+            ("today in km", "Can't convert Fri, 2024-05-17 to a length (km)"),
+            ("mean(1 km, 2 kg)", "Can't mix a length (km) and a mass (kg)"),
+            ("today * 2", "Can't multiply Fri, 2024-05-17 by 2"),
+            ("10 / 2 km", "Can't divide 10 by 2 km"),
+            ("2 ^ 3 km", "Can't raise 2 to the power of 3 km"),
+            ("today + today", "Can't add Fri, 2024-05-17 to Fri, 2024-05-17"),
+            ("20% - today", "Can't subtract Fri, 2024-05-17 from 20%"),
+        ] {
+            let expr = parse_line(line);
+            match evaluate(&expr, &mut variables) {
+                Value::Error(msg) => assert_eq!(msg, message, "{line}"),
+                other => panic!("Expected an error for '{line}', got {:?}", other),
+            }
+        }
+    }
+    
+    #[test]
+    fn test_preload_files_before_the_sheet() {
+        let dir = TempDir::new("preload");
+        std::fs::write(dir.join("prelude.cali"), "# Shared\nhourly rate = 85 USD\nsetrate USD to EUR = 0.5\ndefunit sprint = 2 week\n").unwrap();
+        std::fs::write(dir.join("hours.cali"), "billable = 30 h\nhourly rate * 2\ninclude shifts.cali\n").unwrap();
+        std::fs::write(dir.join("shifts.cali"), "defunit shift = 8 h\n").unwrap();
+        std::fs::write(dir.join("broken.cali"), "a = 2\nb = a +\n").unwrap();
+        std::fs::write(dir.join("sheet.cali"), "hourly rate * 10 in EUR\n1 sprint in days\nbillable in min\nhourly rate = 100 USD\nhourly rate\n2 shift in h\n").unwrap();
+        
+        let mut app = new_app();
+        assert_eq!(app.preload(&dir.join("prelude.cali")), Ok(3));
+        assert_eq!(app.preload(&dir.join("hours.cali")), Ok(2));
+        crate::load_file_into_app(dir.join("sheet.cali").to_str().unwrap(), &mut app).unwrap();
+        
+        // The preloaded lines aren't shown, but the sheet's lines see what they defined
+        assert_eq!(app.lines.len(), 6);
+        assert_eq!(app.debounced_results[0], "€425.00");
+        assert_eq!(app.debounced_results[1], "14 day");
+        assert_eq!(app.debounced_results[2], "1800 min");
+        assert_eq!(app.debounced_results[4], "$100");
+        assert_eq!(app.debounced_results[5], "16 h");
+        assert_eq!(app.variables.get("billable"), Some(&Value::Unit(30.0, "h".to_string())));
+        assert_eq!(app.variables.get("hourly rate"), Some(&Value::Unit(100.0, "USD".to_string())));
+        
+        // Without the line redefining it, the preloaded value is back
+        app.cursor_pos = (3, 0);
+        type_text(&mut app, "# ");
+        app.evaluate_expressions();
+        assert_eq!(app.variables.get("hourly rate"), Some(&Value::Unit(85.0, "USD".to_string())));
+        assert_eq!(app.debounced_results[4], "$85");
+        
+        assert_eq!(new_app().preload(&dir.join("broken.cali")), Err("broken.cali line 2: Expression ends with an operator".to_string()));
+    }
+    
+    #[test]
+    fn test_loaded_errors_are_counted_and_jumped_to() {
+        let dir = TempDir::new("errors");
+        let path = dir.join("sheet.cali");
+        std::fs::write(&path, "a = 1\nb = a +\na * 2\nmissing + 1\n# fine\nc = 3\n5 kg in km\n").unwrap();
+        let mut app = new_app();
+        crate::load_file_into_app(path.to_str().unwrap(), &mut app).unwrap();
+        assert_eq!(app.status_message.as_deref(), Some("Loaded 7 lines, 3 with errors (press F4 to jump to the first error)"));
+        
+        // The cursor starts at the end, so the first F4 goes round to the top
+        app.jump_to_error(true);
+        assert_eq!(app.cursor_pos, (1, 7));
+        assert_eq!(app.status_message.as_deref(), Some("Error 1 of 3 on line 2 (went round to the top)"));
+        app.jump_to_error(true);
+        app.jump_to_error(true);
+        assert_eq!((app.cursor_pos.0, app.output_selected_idx), (6, 6));
+        assert_eq!(app.status_message.as_deref(), Some("Error 3 of 3 on line 7"));
+        app.jump_to_error(true);
+        assert_eq!(app.cursor_pos.0, 1);
+        app.jump_to_error(false);
+        assert_eq!(app.cursor_pos.0, 6);
+        assert_eq!(app.status_message.as_deref(), Some("Error 3 of 3 on line 7 (went round to the bottom)"));
+        app.jump_to_error(false);
+        assert_eq!(app.cursor_pos.0, 3);
+        
+        // Without errors there is nowhere to go
+        let mut app = app_with_lines(&["1 + 1"]);
+        app.jump_to_error(true);
+        assert_eq!(app.status_message.as_deref(), Some("No lines with errors"));
+    }
+    
+    #[test]
+    fn test_default_unit_for_the_lines_below() {
+        let lines = [
+            "a = 5",
+            "set default currency EUR",
+            "price = 120",
+            "price + 30 EUR",
+            "price in USD",
+            "2 kg",
+            "unset default",
+            "7",
+            "set default unit kg",
+            "3 * 4",
+            "a",
+            "set default unit parsecs",
+        ];
+        let mut app = app_with_lines(&lines);
+        
+        // The line above the directive stays a plain number, and explicit units win below it
+        assert_eq!(app.debounced_results[0], "5");
+        assert_eq!(app.debounced_results[1], "");
+        assert_eq!(app.debounced_results[2], "€120.00");
+        assert_eq!(app.debounced_results[3], "€150.00");
+        assert_eq!(app.debounced_results[4], "$141.18");
+        assert_eq!(app.debounced_results[5], "2 kg");
+        assert_eq!(app.debounced_results[7], "7");
+        assert_eq!(app.debounced_results[9], "12 kg");
+        assert_eq!(app.debounced_results[10], "5 kg");
+        assert_eq!(app.variables.get("a"), Some(&Value::Number(5.0)));
+        assert_eq!(app.debounced_results[11], "Error: Usage: set default currency EUR, set default unit kg or unset default");
+        
+        // A line inserted above the directive is outside it, and one below is inside
+        app.cursor_pos = (0, 5);
+        press(&mut app, crossterm::event::KeyCode::Enter);
+        type_text(&mut app, "10");
+        app.evaluate_expressions();
+        assert_eq!(app.debounced_results[1], "10");
+        assert_eq!(app.debounced_results[3], "€120.00");
+        
+        // Without the directive, the numbers are plain again
+        app.cursor_pos = (2, 0);
+        type_text(&mut app, "# ");
+        app.evaluate_expressions();
+        assert_eq!(app.debounced_results[3], "120");
+        assert_eq!(app.debounced_results[4], "€150.00");
+    }
+    
+    #[test]
+    fn test_results_that_arent_numbers_are_errors_where_they_start() {
+        let lines = [
+            "(-8) ^ 0.5",
+            "(-8) ** (1/3)",
+            "0 ^ -1",
+            "big = 10^300 * 10^300",
+            "(-4 m) ^ 0.5",
+            "x = (-9) ^ 0.5",
+            "x + 1",
+            "(8) ^ (1/3)",
+        ];
+        let app = app_with_lines(&lines);
+        assert_eq!(app.debounced_results[..7], [
+            "Error: Square root of a negative number",
+            "Error: Can't raise a negative number to a fractional power",
+            "Error: Cannot divide by 0",
+            "Error: Result too large",
+            "Error: Can't raise a length (m) to the power of 0.5",
+            "Error: Square root of a negative number",
+            "Error: Square root of a negative number",
+        ]);
+        assert_eq!(app.debounced_results[7], "2");
+        // Neither a NaN nor an infinity was stored
+        assert!(!app.variables.contains_key("x") && !app.variables.contains_key("big"));
+        
+        // Values from outside can still be infinite; combining them says why there's no answer
+        let undefined = crate::evaluator::apply_binary_op(Value::Number(f64::INFINITY), &Op::Subtract, Value::Number(f64::INFINITY));
+        assert_eq!(undefined, Value::Error("Result is undefined (inf - inf)".to_string()));
+    }
+    
+    #[test]
+    fn test_convert_the_selected_result() {
+        use crossterm::event::{KeyEvent, KeyModifiers};
+        
+        let lines = ["price = 151.20 USD", "price + oops"];
+        let mut app = app_with_lines(&lines);
+        app.panel_focus = crate::app::PanelFocus::Output;
+        app.start_convert();
+        assert!(app.input_mode == crate::app::InputMode::Convert);
+        
+        // A unit that doesn't fit keeps the prompt open, with what was typed
+        let type_unit = |app: &mut crate::app::App, unit: &str| {
+            app.status_input.clear();
+            for c in unit.chars() {
+                app.handle_status_input(KeyEvent::new(crossterm::event::KeyCode::Char(c), KeyModifiers::NONE));
+            }
+            let target = app.handle_status_input(KeyEvent::new(crossterm::event::KeyCode::Enter, KeyModifiers::NONE));
+            app.finish_convert(&target.unwrap());
+        };
+        type_unit(&mut app, "kg");
+        assert!(app.input_mode == crate::app::InputMode::Convert);
+        assert_eq!(app.convert_error.as_deref(), Some("Can't convert an amount of money (USD) to a mass (kg)"));
+        assert_eq!(app.status_input, "kg");
+        type_unit(&mut app, "= 5");
+        assert_eq!(app.convert_error.as_deref(), Some("'= 5' isn't a unit"));
+        
+        type_unit(&mut app, "EUR");
+        assert!(app.input_mode == crate::app::InputMode::Normal);
+        assert_eq!(app.status_message.as_deref(), Some("$151.20 = €128.52   (y: copy)"));
+        // The sheet is left as it was
+        assert_eq!(app.lines, lines);
+        assert!(!app.modified);
+        
+        app.output_selected_idx = 1;
+        app.start_convert();
+        assert!(app.input_mode == crate::app::InputMode::Normal);
+        assert_eq!(app.status_message.as_deref(), Some("Only numbers and amounts can be converted"));
+    }
+    
+    #[test]
+    fn test_diff_reports_the_results_that_changed() {
+        let dir = TempDir::new("diff");
+        std::fs::write(dir.join("old.cali"), "# Budget\nrent = 1200 USD\nfood = 400 USD\nfees = 12 USD\nrent + food\nweight = 2 kg\n").unwrap();
+        std::fs::write(dir.join("new.cali"), "# Budget\nrent = 1250 USD\ntip = 20 USD\nfood = 400 USD\nrent + food\nweight = 2500 g\n").unwrap();
+        
+        let load = |name: &str| {
+            let mut app = new_app();
+            crate::load_file_into_app(dir.join(name).to_str().unwrap(), &mut app).unwrap();
+            app
+        };
+        let report = crate::diff::compare(&mut load("old.cali"), &mut load("new.cali"));
+        // food moved down a line but is paired by name; the same 2 kg in grams differs
+        // as a value, and the relative change is worked out in one unit
+        assert_eq!(report, vec![
+            "line 2: rent  $1200 → $1250 (+4.2%)",
+            "line 3 (added): tip  $20",
+            "old line 4 (removed): fees  $12",
+            "line 5: rent + food  $1600 → $1650 (+3.1%)",
+            "line 6: weight  2 kg → 2500 g (+25.0%)",
+        ]);
+        
+        // Identical sheets report nothing, whatever the file names
+        assert!(crate::diff::compare(&mut load("old.cali"), &mut load("old.cali")).is_empty());
+    }
+    
+    #[cfg(unix)]
+    #[test]
+    fn test_stderr_goes_to_a_file_until_the_guard_is_dropped() {
+        use std::io::Write;
+        // Redirecting stderr affects the whole process, tests running alongside included, so
+        // the test runs itself again in a process of its own
+        const DIR: &str = "CALI_STDERR_TEST_DIR";
+        let Some(dir) = std::env::var_os(DIR).map(std::path::PathBuf::from) else {
+            let dir = TempDir::new("stderr");
+            let output = std::process::Command::new(std::env::current_exe().unwrap())
+                .args(["--exact", "tests::tests::test_stderr_goes_to_a_file_until_the_guard_is_dropped"])
+                .env(DIR, &*dir)
+                .output()
+                .unwrap();
+            let report = String::from_utf8_lossy(&output.stdout);
+            assert!(output.status.success() && report.contains("1 passed"), "{report}");
+            return;
+        };
+        let path = dir.join("cali").join("stderr.log");
+        
+        // Written to the descriptor itself, as a dependency would, not through eprintln!
+        let guard = crate::term::StderrGuard::new(&path).unwrap();
+        std::io::stderr().write_all(b"warning from a dependency\n").unwrap();
+        let mut app = new_app();
+        app.stderr_log = Some(path.clone());
+        app.open_log();
+        assert!(app.log.contains(&"stderr: warning from a dependency".to_string()));
+        
+        // More output shows while the log is open
+        std::io::stderr().write_all(b"another warning\n").unwrap();
+        app.update_on_tick();
+        assert_eq!(app.log.last().map(String::as_str), Some("stderr: another warning"));
+        
+        drop(guard);
+        std::io::stderr().write_all(b"back on the terminal\n").unwrap();
+        let captured = std::fs::read_to_string(&path).unwrap();
+        assert!(captured.contains("warning from a dependency"));
+        assert!(!captured.contains("back on the terminal"));
+        // Releasing again does nothing
+        crate::term::release_stderr();
+        
+        // A file nothing was written to is removed
+        drop(crate::term::StderrGuard::new(&path).unwrap());
+        assert!(!path.exists());
+    }
+    
+    #[test]
+    fn test_saving_creates_directories_and_explains_failures() {
+        let dir = TempDir::new("save");
+        let app = app_with_lines(&["rent = 1200"]);
+        let path = |rest: &str| dir.join(rest).to_str().unwrap().to_string();
+        
+        // Missing directories are made, and the topmost one is reported
+        assert_eq!(crate::save_file_from_app(&path("notes/2025/budget.cali"), &app), Ok(Some(dir.join("notes"))));
+        assert_eq!(std::fs::read_to_string(dir.join("notes/2025/budget.cali")).unwrap(), "rent = 1200\n");
+        assert_eq!(crate::save_file_from_app(&path("notes/2025/budget.cali"), &app), Ok(None));
+        
+        assert_eq!(crate::save_file_from_app(&path("notes"), &app), Err(format!("'{}' is a directory", path("notes"))));
+        assert_eq!(crate::save_file_from_app(&path("notes/"), &app), Err(format!("'{}' doesn't name a file", path("notes/"))));
+        assert_eq!(
+            crate::save_file_from_app(&path("notes/2025/budget.cali/copy.cali"), &app),
+            Err(format!("'{}' is a file, not a directory", path("notes/2025/budget.cali")))
+        );
+        assert_eq!(
+            crate::save_file_from_app(&path("notes/2025/budget.cali/more/copy.cali"), &app),
+            Err(format!("'{}' is a file, not a directory", path("notes/2025/budget.cali")))
+        );
+    }
+    
+    #[test]
+    fn test_draft_lines_stay_out_of_variables_and_totals() {
+        use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+        let lines = ["#= Costs", "price = 40", "~ price = 42", "price * 2", "?? 100", "sum of lines 2..5", "line 3"];
+        let mut app = app_with_lines(&lines);
+        
+        // The draft shows its own result, but the line below still sees price = 40
+        assert_eq!(app.debounced_results[2], "(draft) 42");
+        assert_eq!(app.debounced_results[3], "80");
+        assert_eq!(app.debounced_results[4], "(draft) 100");
+        assert_eq!(app.debounced_results[5], "120");
+        assert_eq!(app.debounced_results[6], "Error: Line 3 has no result");
+        assert_eq!(app.variables.get("price"), Some(&Value::Number(40.0)));
+        assert_eq!(app.section_totals.get(&0), Some(&Value::Number(240.0)));
+        
+        // Ctrl+. takes the marker off, and the lines below follow
+        app.cursor_pos = (2, 4);
+        app.handle_key(KeyEvent::new(KeyCode::Char('.'), KeyModifiers::CONTROL));
+        app.evaluate_expressions();
+        assert_eq!(app.lines[2], "price = 42");
+        assert_eq!(app.cursor_pos, (2, 2));
+        assert_eq!(app.debounced_results[2], "42");
+        assert_eq!(app.debounced_results[3], "84");
+        assert_eq!(app.debounced_results[6], "42");
+        
+        // ...and puts it back, which is just text, so it is saved as written
+        app.handle_key(KeyEvent::new(KeyCode::Char('.'), KeyModifiers::CONTROL));
+        app.evaluate_expressions();
+        assert_eq!(app.lines[2], "~ price = 42");
+        assert_eq!(app.line_to_save(2), "~ price = 42");
+        assert_eq!(app.debounced_results[3], "80");
+    }
+    
+    // Variations of a line of the kind people type by accident: spacing off, cut short,
+    // a character missing or doubled, caps lock on
+    fn near_misses(line: &str) -> Vec<String> {
+        let mut lines = vec![
+            line.to_string(),
+            line.replace(' ', "  "),
+            line.replace(' ', "\t"),
+            line.replace(' ', ""),
+            line.to_uppercase(),
+            format!("  {line}  "),
+        ];
+        for (i, c) in line.char_indices() {
+            let rest = &line[i + c.len_utf8()..];
+            lines.push(line[..i].to_string());
+            lines.push(format!("{}{}", &line[..i], rest));
+            lines.push(format!("{}{c}{c}{}", &line[..i], rest));
+        }
+        lines
+    }
+    
+    #[test]
+    fn test_near_misses_are_values_or_errors() {
+        let mut variables = HashMap::new();
+        variables.insert("price".to_string(), Value::Unit(19.99, "USD".to_string()));
+        variables.insert("x".to_string(), Value::Unit(0.9144, "m".to_string()));
+        
+        // Nothing panics, and whatever comes out is a value or an error that says something
+        let mut checked = 0;
+        for seed in include_str!("near_misses.txt").lines().filter(|line| !line.starts_with('#')) {
+            for line in near_misses(seed) {
+                let mut scope = variables.clone();
+                let value = match evaluate(&parse_line(&line), &mut scope) {
+                    Value::Assignment(_, value) => *value,
+                    value => value,
+                };
+                match value {
+                    Value::Error(msg) => assert!(!msg.trim().is_empty(), "{line:?} gave an empty error"),
+                    Value::Number(n) | Value::Percentage(n) | Value::Unit(n, _) => assert!(!n.is_nan(), "{line:?} gave NaN"),
+                    _ => {}
+                }
+                checked += 1;
+            }
+        }
+        assert!(checked > 2000, "only {checked} lines checked");
+    }
+    
+    #[test]
+    fn test_spacing_and_glued_units() {
+        let eval = |line: &str| {
+            let mut variables = HashMap::new();
+            evaluate(&parse_line(line), &mut variables).to_string()
+        };
+        for line in ["10 USD in EUR", "10 USD  in  EUR", "10\tUSD\tin\tEUR", "10USD in EUR", "  10usd in eur  "] {
+            assert_eq!(eval(line), "€8.50", "{line:?}");
+        }
+        assert_eq!(eval("5kg in g"), "5000 g");
+        assert_eq!(eval("100mb in gb"), "0.097656 GB");
+        // Pairs without a conversion of their own go through one they share
+        assert_eq!(eval("3ft in cm"), "91.44 cm");
+        assert_eq!(eval("1 mi in cm"), "160934 cm");
+        assert_eq!(eval("2 lb in mg"), eval("2 lb in g").replace(" g", "").parse::<f64>().map(|g| format!("{} mg", g * 1000.0)).unwrap());
+        
+        for line in ["5 +", "5 *", "x = 5 -", "(5 +", "2 ^", "-", "10 mod"] {
+            assert_eq!(eval(line), "Error: Expression ends with an operator", "{line:?}");
+        }
+    }
+    
+    #[test]
+    fn test_per_period_pricing() {
+        let eval = |line: &str| {
+            let mut variables = HashMap::new();
+            evaluate(&parse_line(line), &mut variables).to_string()
+        };
+        assert_eq!(eval("120 USD per year in monthly"), "$10.00/month");
+        assert_eq!(eval("120 USD per year in per month"), "$10.00/month");
+        assert_eq!(eval("15 USD per month in yearly"), "$180.00/year");
+        assert_eq!(eval("15 USD monthly in per year"), "$180.00/year");
+        assert_eq!(eval("120 USD per year in EUR per month"), "€8.50/month");
+        assert_eq!(eval("70 USD per week in daily"), "$10.00/day");
+        // Written as the currency is anywhere else, only always to the cent
+        assert_eq!(eval("120 CAD per year in monthly"), "10.00 CAD/month");
+        
+        // For rates a month is exactly a twelfth of a year, though `1 month in days` is 30.44
+        assert_eq!(eval("1 USD per month in per year"), "$12.00/year");
+        assert_eq!(eval("1 month in days"), "30.44 day");
+        
+        assert_eq!(eval("10 USD per kg in per month"), "Error: 'per' takes a time: per hour, day, week, month or year");
+        assert_eq!(eval("10 kg in per month"), "Error: Can't convert a mass (kg) to an amount per month");
+        assert_eq!(eval("120 USD per year in EUR"), "Error: Can't convert an amount per year (USD/year) to an amount of money (EUR)");
+        assert_eq!(app_with_lines(&["set locale de", "120 USD per year in monthly"]).debounced_results[1], "10,00 USD/month");
+    }
+    
+    #[test]
+    fn test_running_total_in_the_header() {
+        let sheet = |directive: &str| {
+            let lines = [
+                directive,
+                "rent = 1200 USD",
+                "groceries = 85 EUR",
+                "rent",
+                "~ 500 USD",
+                "3 km",
+                "20 USD in EUR",
+                "oops + 1",
+            ];
+            app_with_lines(&lines)
+        };
+        let mut app = sheet("set running total USD");
+        // rent counts once, where it's set, not again where it's repeated; the draft, the
+        // distance and the error aren't money that counts
+        assert_eq!(app.running_total.as_deref(), Some("$1320"));
+        
+        // It follows edits: 85 EUR becomes 850 EUR
+        app.cursor_pos = (2, "groceries = 85".len());
+        type_text(&mut app, "0");
+        assert_eq!(app.running_total.as_deref(), Some("$2220"));
+        
+        // Turning it off takes the total away
+        app.cursor_pos = (0, app.lines[0].len());
+        for _ in 0.."USD".len() {
+            press(&mut app, crossterm::event::KeyCode::Backspace);
+        }
+        type_text(&mut app, "off");
+        assert_eq!(app.running_total, None);
+        assert_eq!(app.status_message.as_deref(), Some("Running total off"));
+        
+        let app = sheet("set running total EUR");
+        assert_eq!(app.running_total.as_deref(), Some("€1122.00"));
+        let app = sheet("set running total miles");
+        assert_eq!(app.running_total, None);
+        assert_eq!(app.debounced_results[0], "Error: Usage: set running total USD or set running total off");
+    }
+    
+    // Opening a large sheet without money: the first frame is drawn quickly and nothing asks
+    // for exchange rates, even for a made-up code like FOO
+    #[cfg(feature = "startup-bench")]
+    #[test]
+    fn test_first_frame_of_a_large_sheet() {
+        use crate::evaluator::{LiveRates, RateProvider};
+        use ratatui::{Terminal, backend::TestBackend};
+        use std::cell::Cell;
+        use std::rc::Rc;
+        
+        // The live rates, counting how often they're asked for
+        struct Counted(Cell<usize>);
+        impl RateProvider for Counted {
+            fn rate(&self, from: &str, to: &str) -> Option<f64> {
+                self.0.set(self.0.get() + 1);
+                LiveRates.rate(from, to)
+            }
+            fn set_rate(&self, from: &str, to: &str, rate: f64) -> bool {
+                self.0.set(self.0.get() + 1);
+                LiveRates.set_rate(from, to, rate)
+            }
+        }
+        
+        let kinds = ["a{i} = {i} * 3", "a{i} / 7 + 2", "{i} km in mi", "5 FOO + {i} FOO", "{i}% of 250", "today + {i} days", "# note {i}"];
+        let lines: Vec<String> = (0..1000).map(|i| kinds[i % kinds.len()].replace("{i}", &i.to_string())).collect();
+        let dir = TempDir::new("startup");
+        let path = dir.join("sheet.txt");
+        std::fs::write(&path, lines.join("\n")).unwrap();
+        
+        let started = std::time::Instant::now();
+        let mut app = crate::app::App::new();
+        let rates = Rc::new(Counted(Cell::new(0)));
+        app.context.rates = rates.clone();
+        crate::load_file_into_app(path.to_str().unwrap(), &mut app).unwrap();
+        let mut terminal = Terminal::new(TestBackend::new(120, 40)).unwrap();
+        terminal.draw(|f| crate::ui::draw(f, &mut app)).unwrap();
+        let first_frame = started.elapsed();
+        while app.catch_up(crate::app::CATCH_UP_CHUNK) {}
+        
+        assert_eq!(app.debounced_results[3], "8.00 FOO");
+        assert_eq!(rates.0.get(), 0, "a sheet without money looked up exchange rates");
+        if !cfg!(debug_assertions) {
+            assert!(first_frame < std::time::Duration::from_millis(50), "first frame took {first_frame:?}");
+        }
+    }
+
+    #[test]
+    fn test_numbers_in_words() {
+        let value = |line: &str| {
+            let mut variables = HashMap::new();
+            evaluate(&parse_line(line), &mut variables)
+        };
+        assert_eq!(value("two dozen * 3"), Value::Number(72.0));
+        assert_eq!(value("half a million / 4"), Value::Number(125000.0));
+        assert_eq!(value("twenty-one + three hundred and five"), Value::Number(326.0));
+        assert_eq!(value("two million three hundred thousand"), Value::Number(2300000.0));
+        assert_eq!(value("2 million + a dozen"), Value::Number(2000012.0));
+        assert_eq!(value("seven km"), Value::Unit(7.0, "km".to_string()));
+        // `half` needs a quantity after it, and `half of` is still a fraction
+        assert!(matches!(value("half"), Value::Error(_)));
+        assert_eq!(value("half of 10"), Value::Number(5.0));
+
+        // A variable of the same name wins
+        let mut variables = HashMap::new();
+        variables.insert("dozen".to_string(), Value::Number(13.0));
+        assert_eq!(evaluate(&parse_line("dozen * 2"), &mut variables), Value::Number(26.0));
+    }
+
+    #[test]
+    fn test_weekday_of_month() {
+        use chrono::{NaiveDate, Weekday};
+        use crate::evaluator::weekday_of_month;
+        use crate::parser::{MonthOf, Occurrence};
+
+        let mut variables = HashMap::new();
+        let date = |y: i32, m: u32, d: u32| NaiveDate::from_ymd_opt(y, m, d).unwrap();
+        assert_eq!(
+            evaluate(&parse_line("first monday of september 2025"), &mut variables),
+            Value::Date(date(2025, 9, 1))
+        );
+        assert_eq!(
+            evaluate(&parse_line("Last Friday of 2025-02"), &mut variables),
+            Value::Date(date(2025, 2, 28))
+        );
+        assert_eq!(
+            evaluate(&parse_line("fifth friday of february 2025"), &mut variables),
+            Value::Error("February 2025 has no fifth Friday".to_string())
+        );
+
+        // A month without a year is the coming one
+        let today = date(2024, 5, 17);
+        assert_eq!(weekday_of_month(Occurrence::Nth(2), Weekday::Tue, MonthOf::Named(3, None), today), Ok(date(2025, 3, 11)));
+        assert_eq!(weekday_of_month(Occurrence::Nth(1), Weekday::Mon, MonthOf::Named(5, None), today), Ok(date(2024, 5, 6)));
+        assert_eq!(weekday_of_month(Occurrence::Last, Weekday::Sun, MonthOf::Next, today), Ok(date(2024, 6, 30)));
+
+        // `second` is an ordinal only before a weekday and `of`; otherwise it's the unit
+        assert!(matches!(
+            parse_line("second monday of next month").reading(&variables),
+            Expr::WeekdayOfMonth(Occurrence::Nth(2), Weekday::Mon, MonthOf::Next)
+        ));
+        assert_eq!(evaluate(&parse_line("90 second to minutes"), &mut variables), Value::Unit(1.5, "min".to_string()));
+        assert_eq!(evaluate(&parse_line("2 minutes to second"), &mut variables), Value::Unit(120.0, "s".to_string()));
+    }
+}
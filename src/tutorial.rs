@@ -0,0 +1,26 @@
+use std::fs;
+use std::path::PathBuf;
+
+// The sheet opened on the first run and by `cali --tutorial`. Every line evaluates without
+// an error, so the output panel shows what each kind of line does.
+pub const TUTORIAL: &str = include_str!("tutorial.cali");
+
+// Left next to the history once the tutorial has been shown, so it's only shown once
+fn marker_path() -> Option<PathBuf> {
+    Some(crate::repl::History::default_path()?.with_file_name("tutorial-shown"))
+}
+
+// Whether cali hasn't shown the tutorial on this machine yet
+pub fn first_run() -> bool {
+    marker_path().is_some_and(|path| !path.exists())
+}
+
+// Remember that the tutorial was shown. Failing to is harmless: it's shown again next time.
+pub fn mark_shown() {
+    if let Some(path) = marker_path()
+        && let Some(dir) = path.parent()
+        && fs::create_dir_all(dir).is_ok()
+    {
+        let _ = fs::write(path, "");
+    }
+}
@@ -1,26 +1,134 @@
 use ratatui::{
+    buffer::Buffer,
     layout::{Constraint, Direction, Layout, Rect},
     style::{Color, Modifier, Style},
     text::{Line, Span},
-    widgets::{Block, Borders, List, ListItem, Paragraph},
+    widgets::{block::{Position, Title}, Block, Borders, Clear, List, ListItem, Paragraph, Wrap},
     prelude::Alignment,
     Frame,
 };
-use crate::app::App;
-use regex::Regex;
-use once_cell::sync::Lazy;
-
-// Define regex patterns for syntax highlighting
-static NUMBER_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"(\d+(?:\.\d+)?)").unwrap());
-static PERCENTAGE_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"(\d+(?:\.\d+)?%)").unwrap());
-static UNIT_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"\b([A-Za-z][A-Za-z0-9_]*)\b").unwrap());
-static OPERATOR_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"([\+\-\*/\^=])").unwrap());
-static BRACKET_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"([\(\)\[\]\{\}])").unwrap());
-static KEYWORD_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"\b(to|in|of|what|is|next)\b").unwrap());
-static SPECIAL_WORD_REGEX: Lazy<Regex> = Lazy::new(|| 
-    Regex::new(r"\b(monday|tuesday|wednesday|thursday|friday|saturday|sunday|week|month|day|weeks|months|days)\b").unwrap()
-);
-static COMMENT_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"(#.*)").unwrap());
+use std::collections::{HashMap, HashSet};
+use crate::app::{Align, App, Change, TextView, byte_at_column, column_at, draft_body, section_header};
+use crate::evaluator::{is_unit_name, Value};
+use crate::lexer::{tokenize, longest_name, is_currency_code, is_date_word, is_keyword, is_phrase_word, Token, TokenKind};
+
+// Upper bound on cached lines before the cache is flushed, so text that was edited
+// away doesn't accumulate forever
+const MAX_CACHED_LINES: usize = 4096;
+
+// Colors of results by kind, so a date, an amount and a plain number are told apart at a
+// glance. Kept on the app so a theme can replace them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ResultColors {
+    pub currency: Color,
+    pub unit: Color,
+    pub number: Color,
+    pub percentage: Color,
+    pub date: Color,
+}
+
+impl Default for ResultColors {
+    fn default() -> Self {
+        Self {
+            currency: Color::Green,
+            unit: Color::Cyan,
+            number: Color::Reset,
+            percentage: Color::Magenta,
+            date: Color::Blue,
+        }
+    }
+}
+
+impl ResultColors {
+    // None for errors, which have their own style
+    pub fn for_value(&self, value: &Value) -> Option<Color> {
+        match value {
+            Value::Number(_) => Some(self.number),
+            Value::Percentage(_) => Some(self.percentage),
+            Value::Unit(_, unit) | Value::Rate(_, _, unit) if is_currency_code(unit) => Some(self.currency),
+            Value::Unit(_, _) | Value::Rate(_, _, _) => Some(self.unit),
+            Value::Date(_) => Some(self.date),
+            Value::Assignment(_, value) | Value::Split(value, _) => self.for_value(value),
+            Value::List(items) => items.first().map_or(Some(self.number), |item| self.for_value(item)),
+            Value::Map(entries) => entries.first().map_or(Some(self.number), |(_, value)| self.for_value(value)),
+            Value::Error(_) => None,
+            // Value can gain kinds of result; they show like numbers until they get a color
+            _ => Some(self.number),
+        }
+    }
+}
+
+// How the panels are colored. Errors and the selection are marked with glyphs as well, so
+// no theme has to carry them by color alone.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Theme {
+    pub results: ResultColors,
+    pub selection: Style, // Selected rows of the output panel
+    pub error: Style,     // Error results and status messages
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self {
+            results: ResultColors::default(),
+            selection: Style::default().bg(Color::DarkGray),
+            error: Style::default().fg(Color::White).bg(Color::Red),
+        }
+    }
+}
+
+impl Theme {
+    // For --high-contrast: bright colors, a reversed selection and black on yellow errors,
+    // none of which depend on telling red from gray
+    pub fn high_contrast() -> Self {
+        Self {
+            results: ResultColors {
+                currency: Color::LightGreen,
+                unit: Color::LightCyan,
+                number: Color::Reset,
+                percentage: Color::LightMagenta,
+                date: Color::LightYellow,
+            },
+            selection: Style::default().add_modifier(Modifier::REVERSED),
+            error: Style::default().fg(Color::Black).bg(Color::Yellow).add_modifier(Modifier::BOLD),
+        }
+    }
+}
+
+// Highlighted lines keyed by their text. An edited line has a different key, so edits
+// invalidate their own entry and unchanged lines are never highlighted twice.
+#[derive(Default)]
+pub struct HighlightCache {
+    lines: HashMap<String, Line<'static>>,
+    variable_names: HashSet<String>, // Variables the cached lines were highlighted against
+}
+
+impl HighlightCache {
+    pub fn get(&mut self, text: &str, variables: &HashMap<String, Value>) -> Line<'static> {
+        if let Some(line) = self.lines.get(text) {
+            return line.clone();
+        }
+
+        if self.lines.len() >= MAX_CACHED_LINES {
+            self.lines.clear();
+        }
+
+        let line = highlight_syntax(text, variables);
+        self.lines.insert(text.to_string(), line.clone());
+        line
+    }
+
+    // Defined variables get their own color, so cached lines are stale once the set of
+    // variable names changes
+    pub fn sync_variables(&mut self, variables: &HashMap<String, Value>) {
+        let unchanged = self.variable_names.len() == variables.len()
+            && variables.keys().all(|name| self.variable_names.contains(name));
+        if !unchanged {
+            self.lines.clear();
+            self.variable_names = variables.keys().cloned().collect();
+        }
+    }
+}
 
 pub fn draw(f: &mut Frame, app: &mut App) {
     // Create main layout with header, content, and status areas
@@ -34,7 +142,10 @@ pub fn draw(f: &mut Frame, app: &mut App) {
         .split(f.size());
     
     // Draw the branding in the header
-    draw_header(f, main_chunks[0]);
+    draw_header(f, app, main_chunks[0]);
+    
+    // Highlighting depends on the defined variables
+    app.highlight_cache.sync_variables(&app.variables);
     
     // Split the content area into two horizontal panels
     let content_chunks = Layout::default()
@@ -61,29 +172,91 @@ pub fn draw(f: &mut Frame, app: &mut App) {
     
     // Draw the status bar
     draw_status_bar(f, app, main_chunks[2]);
+    
+    if let Some(line_idx) = app.full_result
+        && let Some(result) = app.results.get(line_idx)
+    {
+        draw_full_result(f, line_idx, result, main_chunks[1]);
+    }
+    if app.show_log {
+        draw_log(f, &app.log, main_chunks[1]);
+    }
+
+    if !app.color {
+        strip_colors(f.buffer_mut());
+    }
+}
+
+// Drop every color from the drawn frame for NO_COLOR/--no-color. Highlights that relied on
+// a background (the selection, the status bar) are shown reversed instead, and borders and
+// markers fall back to ASCII.
+fn strip_colors(buffer: &mut Buffer) {
+    for cell in &mut buffer.content {
+        if cell.bg != Color::Reset {
+            cell.modifier.insert(Modifier::REVERSED);
+        }
+        cell.set_fg(Color::Reset).set_bg(Color::Reset);
+        if let Some(ascii) = ascii_symbol(cell.symbol()) {
+            cell.set_symbol(ascii);
+        }
+    }
+}
+
+// ASCII stand-ins for the symbols the UI draws itself
+fn ascii_symbol(symbol: &str) -> Option<&'static str> {
+    Some(match symbol {
+        "─" => "-",
+        "│" => "|",
+        "┌" | "┐" | "└" | "┘" => "+",
+        "▲" => "^",
+        "▼" => "v",
+        "↑" => "^",
+        "↓" => "v",
+        "→" => ">",
+        "●" => "*",
+        "…" => "~",
+        "✖" => "x",
+        "▶" => ">",
+        _ => return None,
+    })
 }
 
 // Function to draw the header with Cali branding
-fn draw_header(f: &mut Frame, area: Rect) {
+fn draw_header(f: &mut Frame, app: &App, area: Rect) {
     // Create a block for the header with no borders
     let header_block = Block::default()
         .style(Style::default());
     
-    // Create a paragraph with the Cali text and version
-    let header = Paragraph::new(Line::from(vec![
+    // Create a paragraph with the Cali text and version, and a padlock in read-only mode
+    let mut spans = vec![
         Span::styled("Cali", Style::default().fg(Color::Cyan).add_modifier(Modifier::BOLD)),
         Span::styled(format!(" v{}", env!("CARGO_PKG_VERSION")), Style::default().fg(Color::DarkGray)),
-    ]))
+    ];
+    if app.read_only {
+        let padlock = if crate::locale::ascii() { "  [read-only]" } else { "  🔒 read-only" };
+        spans.push(Span::styled(padlock, Style::default().fg(Color::Yellow)));
+    }
+    let header = Paragraph::new(Line::from(spans))
     .block(header_block)
     .alignment(Alignment::Left);
 
     f.render_widget(header, area);
+    
+    // The `set running total` sum, on the right
+    if let Some(total) = &app.running_total {
+        let total = Paragraph::new(Line::from(vec![
+            Span::styled("Total ", Style::default().fg(Color::DarkGray)),
+            Span::styled(total.clone(), Style::default().fg(app.theme.results.currency).add_modifier(Modifier::BOLD)),
+        ]))
+        .alignment(Alignment::Right);
+        f.render_widget(total, Rect { height: 1, ..area });
+    }
 }
 
-fn draw_input_panel(f: &mut Frame, app: &App, area: Rect) {
+fn draw_input_panel(f: &mut Frame, app: &mut App, area: Rect) {
     // Create a block for the input area with a style based on focus
     let input_block = Block::default()
-        .title("Input")
+        .title(panel_title("Input", app.panel_focus == crate::app::PanelFocus::Input))
         .borders(Borders::ALL)
         .style(Style::default().fg(if app.panel_focus == crate::app::PanelFocus::Input {
             Color::Cyan
@@ -94,14 +267,15 @@ fn draw_input_panel(f: &mut Frame, app: &App, area: Rect) {
     let inner_area = input_block.inner(area);
     let visible_lines = inner_area.height as usize;
 
+    let cache = &mut app.highlight_cache;
+    let variables = &app.variables;
     let items: Vec<ListItem> = app.lines
         .iter()
         .skip(app.input_scroll)
         .take(visible_lines)
-        .enumerate()
-        .map(|(_, line)| {
+        .map(|line| {
             // Apply syntax highlighting to this line
-            let highlighted_line = highlight_syntax(line);
+            let highlighted_line = cache.get(line, variables);
             ListItem::new(highlighted_line)
         })
         .collect();
@@ -113,23 +287,26 @@ fn draw_input_panel(f: &mut Frame, app: &App, area: Rect) {
 
     f.render_widget(input_list, area);
 
-    // Only show cursor in the input panel if it has focus and cursor is in visible area
-    if app.panel_focus == crate::app::PanelFocus::Input && 
-       app.lines.len() > app.cursor_pos.0 && 
-       app.cursor_pos.0 >= app.input_scroll && 
-       app.cursor_pos.0 < app.input_scroll + visible_lines {
-        let line = &app.lines[app.cursor_pos.0];
-        let cursor_x = if app.cursor_pos.1 <= line.len() { 
-            app.cursor_pos.1 as u16 
-        } else { 
-            line.len() as u16 
-        };
+    let view = TextView { area: (area.x, area.y, area.width, area.height), scroll: app.input_scroll };
+    
+    // A word picked with a double-click shows reversed while the panel has focus
+    if app.panel_focus == crate::app::PanelFocus::Input
+        && let Some((line_idx, range)) = &app.selection
+        && let Some(line) = app.lines.get(*line_idx)
+    {
+        for column in column_at(line, range.start)..column_at(line, range.end) {
+            if let Some((x, y)) = view.screen_cell(*line_idx, column) {
+                f.buffer_mut().get_mut(x, y).modifier.insert(Modifier::REVERSED);
+            }
+        }
+    }
 
-        // Cursor is in input area, offset by border and scroll position
-        f.set_cursor(
-            area.x + cursor_x + 1, // +1 for border
-            area.y + (app.cursor_pos.0 - app.input_scroll) as u16 + 1, // +1 for border
-        );
+    // Only show cursor in the input panel if it has focus and cursor is in visible area
+    if app.panel_focus == crate::app::PanelFocus::Input
+        && let Some(line) = app.lines.get(app.cursor_pos.0)
+        && let Some((x, y)) = view.screen_cell(app.cursor_pos.0, column_at(line, app.cursor_pos.1))
+    {
+        f.set_cursor(x, y);
     }
 
     // Draw scroll indicators if needed
@@ -149,183 +326,200 @@ fn draw_input_panel(f: &mut Frame, app: &App, area: Rect) {
     }
 }
 
-// Function to apply syntax highlighting to a line of text
-fn highlight_syntax(text: &str) -> Line {
-    // Start with an empty list of spans
-    let mut spans = Vec::new();
-    
-    // Keep track of which parts of the text have been processed
-    let mut processed_indices = vec![false; text.len()];
-    
-    // Find and highlight comments (both full line and inline)
-    for captures in COMMENT_REGEX.captures_iter(text) {
-        if let Some(m) = captures.get(1) {
-            mark_as_processed(&mut processed_indices, m.start(), m.end());
-            spans.push((m.start(), m.end(), Span::styled(
-                m.as_str().to_string(),
-                Style::default().fg(Color::DarkGray)
-            )));
-            
-            // If it starts at the beginning of the line, it's a full comment line
-            if m.start() == 0 {
-                return Line::from(spans.into_iter().map(|(_, _, span)| span).collect::<Vec<_>>());
-            }
-        }
+// How a word was classified, used as context for the word that follows it
+#[derive(Clone, Copy, PartialEq)]
+enum WordClass {
+    Number,
+    Unit,
+    Keyword,
+    Other,
+}
+
+// Function to apply syntax highlighting to a line of text. Tokens come from the same
+// tokenizer the parser uses and each one is classified exactly once, left to right.
+fn highlight_syntax(text: &str, variables: &HashMap<String, Value>) -> Line<'static> {
+    // Section headers stand out from ordinary comments
+    if section_header(text).is_some() {
+        return Line::from(Span::styled(text.to_string(), header_style()));
     }
-    
-    // Find and highlight percentages (must come before numbers)
-    for captures in PERCENTAGE_REGEX.captures_iter(text) {
-        if let Some(m) = captures.get(1) {
-            mark_as_processed(&mut processed_indices, m.start(), m.end());
-            spans.push((m.start(), m.end(), Span::styled(
-                m.as_str().to_string(),
-                Style::default().fg(Color::LightGreen).add_modifier(Modifier::BOLD)
-            )));
-        }
+    // A draft's marker is dimmed; the rest is highlighted as usual
+    if let Some(body) = draft_body(text).filter(|body| !body.is_empty()) {
+        let marker_end = text.trim_end().len() - body.len();
+        let mut line = highlight_syntax(&text[marker_end..], variables);
+        line.spans.insert(0, Span::styled(text[..marker_end].to_string(), Style::default().fg(Color::DarkGray)));
+        return line;
     }
     
-    // Find and highlight numbers, but only if they're not already marked as processed
-    for captures in NUMBER_REGEX.captures_iter(text) {
-        if let Some(m) = captures.get(1) {
-            // Skip if already processed (e.g., part of a percentage)
-            if is_already_processed(&processed_indices, m.start(), m.end()) {
-                continue;
-            }
-            
-            mark_as_processed(&mut processed_indices, m.start(), m.end());
-            spans.push((m.start(), m.end(), Span::styled(
-                m.as_str().to_string(),
-                Style::default().fg(Color::LightYellow)
-            )));
-        }
-    }
+    let tokens = tokenize(text);
+    let mut spans = Vec::with_capacity(tokens.len());
     
-    // Find and highlight operators
-    for captures in OPERATOR_REGEX.captures_iter(text) {
-        if let Some(m) = captures.get(1) {
-            // Skip if already processed
-            if is_already_processed(&processed_indices, m.start(), m.end()) {
-                continue;
-            }
-            
-            mark_as_processed(&mut processed_indices, m.start(), m.end());
-            spans.push((m.start(), m.end(), Span::styled(
-                m.as_str().to_string(),
-                Style::default().fg(Color::LightRed)
-            )));
-        }
-    }
+    // Class and text of the last non-whitespace token
+    let mut prev: Option<(WordClass, &str)> = None;
+    // Tokens before this index belong to a multi-word variable name
+    let mut name_end = 0;
+    let definition_end = definition_end(&tokens);
     
-    // Find and highlight brackets
-    for captures in BRACKET_REGEX.captures_iter(text) {
-        if let Some(m) = captures.get(1) {
-            // Skip if already processed
-            if is_already_processed(&processed_indices, m.start(), m.end()) {
-                continue;
+    for (i, token) in tokens.iter().enumerate() {
+        // The name a line defines stands out from the names it uses
+        if i < definition_end {
+            if token.kind != TokenKind::Whitespace {
+                prev = Some((WordClass::Other, token.text));
             }
-            
-            mark_as_processed(&mut processed_indices, m.start(), m.end());
-            spans.push((m.start(), m.end(), Span::styled(
-                m.as_str().to_string(),
-                Style::default().fg(Color::Red).add_modifier(Modifier::BOLD)
-            )));
+            spans.push(Span::styled(token.text.to_string(), definition_style()));
+            continue;
         }
-    }
-    
-    // Find and highlight keywords
-    for captures in KEYWORD_REGEX.captures_iter(text) {
-        if let Some(m) = captures.get(1) {
-            // Skip if already processed
-            if is_already_processed(&processed_indices, m.start(), m.end()) {
-                continue;
-            }
-            
-            mark_as_processed(&mut processed_indices, m.start(), m.end());
-            spans.push((m.start(), m.end(), Span::styled(
-                m.as_str().to_string(),
-                Style::default().fg(Color::LightBlue)
-            )));
+        if i < name_end {
+            spans.push(Span::styled(token.text.to_string(), Style::default().fg(Color::Magenta)));
+            continue;
         }
+        if token.kind == TokenKind::Word
+            && !matches!(prev, Some((WordClass::Number, _)))
+            && let Some((name, end)) = longest_name(&tokens, i, |name| variables.contains_key(name))
+            && name.contains(' ')
+        {
+            name_end = end;
+            prev = Some((WordClass::Other, token.text));
+            spans.push(Span::styled(token.text.to_string(), Style::default().fg(Color::Magenta)));
+            continue;
+        }
+        
+        // A % glued to the value before it is a percentage, the same rule the parser uses.
+        // A number and its % are styled as one.
+        let is_percentage = match token.kind {
+            TokenKind::Number => tokens.get(i + 1).is_some_and(|next| next.text == "%"),
+            TokenKind::Operator => token.text == "%" && i > 0 && match tokens[i - 1].kind {
+                TokenKind::Number | TokenKind::Word => true,
+                TokenKind::Bracket => tokens[i - 1].text == ")",
+                _ => false,
+            },
+            _ => false,
+        };
+        
+        let (class, style) = match token.kind {
+            TokenKind::Comment => (WordClass::Other, Style::default().fg(Color::DarkGray)),
+            _ if is_percentage => (WordClass::Other, Style::default().fg(Color::LightGreen).add_modifier(Modifier::BOLD)),
+            TokenKind::Number => (WordClass::Number, Style::default().fg(Color::LightYellow)),
+            TokenKind::Operator if token.text == "=" => (WordClass::Other, Style::default().fg(Color::DarkGray)),
+            TokenKind::Operator => (WordClass::Other, Style::default().fg(Color::LightRed)),
+            TokenKind::Bracket => (WordClass::Other, Style::default().fg(Color::Red).add_modifier(Modifier::BOLD)),
+            TokenKind::Word => classify_word(token.text, prev, variables),
+            TokenKind::Whitespace | TokenKind::Unknown => (WordClass::Other, Style::default().fg(Color::White)),
+        };
+        
+        if token.kind != TokenKind::Whitespace {
+            prev = Some((class, token.text));
+        }
+        spans.push(Span::styled(token.text.to_string(), style));
     }
     
-    // Find and highlight special words (days, units)
-    for captures in SPECIAL_WORD_REGEX.captures_iter(text) {
-        if let Some(m) = captures.get(1) {
-            // Skip if already processed
-            if is_already_processed(&processed_indices, m.start(), m.end()) {
-                continue;
-            }
-            
-            mark_as_processed(&mut processed_indices, m.start(), m.end());
-            spans.push((m.start(), m.end(), Span::styled(
-                m.as_str().to_string(),
-                Style::default().fg(Color::LightMagenta)
-            )));
-        }
+    Line::from(spans)
+}
+
+// Where the name of an assignment (`monthly rent = 1800`) ends: the words before a leading
+// `=`, as the parser reads them. Zero when the line isn't an assignment.
+fn definition_end(tokens: &[Token]) -> usize {
+    let mut words = tokens.iter().enumerate().filter(|(_, t)| t.kind != TokenKind::Whitespace);
+    let Some((_, first)) = words.clone().next() else { return 0 };
+    if ["setrate", "defunit"].iter().any(|command| first.text.eq_ignore_ascii_case(command)) {
+        return 0;
+    }
+    match words.find(|(_, t)| t.kind != TokenKind::Word) {
+        Some((i, t)) if t.text == "=" => tokens[..i].iter().rposition(|t| t.kind != TokenKind::Whitespace).map_or(0, |last| last + 1),
+        _ => 0,
     }
+}
+
+fn definition_style() -> Style {
+    Style::default().fg(Color::Cyan).add_modifier(Modifier::BOLD)
+}
+
+fn header_style() -> Style {
+    Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD)
+}
+
+// The output row of a section header: a rule in the header color, led by the section's
+// total when it has one
+fn section_rule(total: Option<&Value>, width: usize) -> Line<'static> {
+    let mut spans = Vec::new();
+    let mut used = 0;
+    if let Some(total) = total {
+        let text = format!("{} ", total);
+        used = text.chars().count();
+        spans.push(Span::styled(text, Style::default().fg(Color::DarkGray)));
+    }
+    spans.push(Span::styled("─".repeat(width.saturating_sub(used)), header_style()));
+    Line::from(spans)
+}
+
+// The annotation after a result that moved: " ▲ +$3.10" in green or " ▼ -$3.10" in red
+fn delta_span(delta: &Value) -> Span<'static> {
+    let (amount, magnitude) = match delta {
+        Value::Number(n) => (*n, Value::Number(n.abs())),
+        Value::Unit(n, unit) => (*n, Value::Unit(n.abs(), unit.clone())),
+        other => return Span::raw(format!(" {}", other)),
+    };
+    if amount >= 0.0 {
+        Span::styled(format!(" ▲ +{}", magnitude), Style::default().fg(Color::Green))
+    } else {
+        Span::styled(format!(" ▼ -{}", magnitude), Style::default().fg(Color::Red))
+    }
+}
+
+// Pick the style for a word based on what it is and what came before it
+fn classify_word(word: &str, prev: Option<(WordClass, &str)>, variables: &HashMap<String, Value>) -> (WordClass, Style) {
+    let unit_style = Style::default().fg(if is_currency_code(word) { Color::LightGreen } else { Color::LightCyan });
     
-    // Find and highlight units
-    for captures in UNIT_REGEX.captures_iter(text) {
-        if let Some(m) = captures.get(1) {
-            // Skip if already processed
-            if is_already_processed(&processed_indices, m.start(), m.end()) {
-                continue;
-            }
-            
-            // Check if this is a currency unit (3 letters, all uppercase)
-            let is_currency = m.as_str().len() == 3 && m.as_str().chars().all(|c| c.is_ascii_uppercase());
-            
-            mark_as_processed(&mut processed_indices, m.start(), m.end());
-            spans.push((m.start(), m.end(), Span::styled(
-                m.as_str().to_string(),
-                Style::default().fg(if is_currency { Color::LightGreen } else { Color::LightCyan })
-            )));
-        }
+    // Weekdays and periods keep their color even when used as a unit (2 weeks)
+    if is_date_word(word) {
+        let class = if matches!(prev, Some((WordClass::Number, _))) { WordClass::Unit } else { WordClass::Other };
+        return (class, Style::default().fg(Color::LightMagenta));
     }
     
-    // Add any remaining unprocessed text as plain spans
-    let mut start = 0;
-    for i in 0..text.len() {
-        if !processed_indices[i] && (i == 0 || processed_indices[i-1]) {
-            start = i;
+    match prev {
+        // A word right after a number is its unit, even if it's spelled like `in` (10 in),
+        // unless it's a variable that isn't also a unit (2x multiplies)
+        Some((WordClass::Number, _))
+            if (!is_keyword(word) || word == "in" || word == "to")
+                && !(variables.contains_key(word) && !is_unit_name(word) && !is_currency_code(word)) =>
+        {
+            return (WordClass::Unit, unit_style);
         }
-        
-        if !processed_indices[i] && (i == text.len() - 1 || processed_indices[i+1]) {
-            spans.push((start, i+1, Span::styled(
-                text[start..=i].to_string(),
-                Style::default().fg(Color::White)
-            )));
+        // The target of a conversion (5 km in miles, how many ounces in 2 kg)
+        Some((WordClass::Keyword, "in" | "to")) if !is_keyword(word) && !variables.contains_key(word) => {
+            return (WordClass::Unit, unit_style);
+        }
+        Some((WordClass::Keyword, prev_word)) if prev_word.eq_ignore_ascii_case("many") && !variables.contains_key(word) => {
+            return (WordClass::Unit, unit_style);
         }
+        _ => {}
     }
     
-    // Sort spans by start position
-    spans.sort_by_key(|(start, _, _)| *start);
-    
-    // Extract just the spans for the Line
-    Line::from(spans.into_iter().map(|(_, _, span)| span).collect::<Vec<_>>())
-}
-
-// Helper function to mark indices as processed
-fn mark_as_processed(processed: &mut Vec<bool>, start: usize, end: usize) {
-    for i in start..end {
-        processed[i] = true;
+    if is_keyword(word) {
+        (WordClass::Keyword, Style::default().fg(Color::LightBlue))
+    } else if variables.contains_key(word) {
+        (WordClass::Other, Style::default().fg(Color::Magenta))
+    } else if is_phrase_word(word) {
+        (WordClass::Keyword, Style::default().fg(Color::LightBlue))
+    } else if is_currency_code(word) {
+        (WordClass::Unit, unit_style)
+    } else {
+        (WordClass::Other, Style::default().fg(Color::White))
     }
 }
 
-// Helper function to check if a range is already processed
-fn is_already_processed(processed: &Vec<bool>, start: usize, end: usize) -> bool {
-    for i in start..end {
-        if processed[i] {
-            return true;
-        }
+// The focused panel's title is marked, so it's obvious where keys will go
+fn panel_title(name: &str, focused: bool) -> String {
+    if focused {
+        format!("● {}", name)
+    } else {
+        name.to_string()
     }
-    false
 }
 
-fn draw_output_panel(f: &mut Frame, app: &App, area: Rect) {
+fn draw_output_panel(f: &mut Frame, app: &mut App, area: Rect) {
     // Create a block for the output area with a style based on focus
     let output_block = Block::default()
-        .title("Output")
+        .title(panel_title("Output", app.panel_focus == crate::app::PanelFocus::Output))
         .borders(Borders::ALL)
         .style(Style::default().fg(if app.panel_focus == crate::app::PanelFocus::Output {
             Color::Cyan
@@ -340,20 +534,58 @@ fn draw_output_panel(f: &mut Frame, app: &App, area: Rect) {
     // Render the block
     f.render_widget(output_block, area);
 
+    // Pins and change markers of the visible lines, looked up before the cache borrows the app
+    let pins: Vec<Option<String>> = (app.output_scroll..app.output_scroll + visible_lines)
+        .map(|line_idx| app.pinned_result(line_idx).map(str::to_string))
+        .collect();
+    let changes: Vec<Option<Change>> = (app.output_scroll..app.output_scroll + visible_lines)
+        .map(|line_idx| app.line_change(line_idx).cloned())
+        .collect();
+    let hinted: Vec<bool> = (app.output_scroll..app.output_scroll + visible_lines)
+        .map(|line_idx| app.conversion_hints(line_idx).is_some())
+        .collect();
+    let drafts: Vec<bool> = (app.output_scroll..app.output_scroll + visible_lines)
+        .map(|line_idx| app.is_draft(line_idx))
+        .collect();
+    let colors: Vec<Option<Color>> = (app.output_scroll..app.output_scroll + visible_lines)
+        .map(|line_idx| app.line_value(line_idx).and_then(|value| app.theme.results.for_value(value)))
+        .collect();
+
     // Convert result lines to styled list items, only for visible lines
+    let theme = app.theme;
+    let selection = app.output_selection();
+    let cache = &mut app.highlight_cache;
+    let variables = &app.variables;
     let items: Vec<ListItem> = app.results
         .iter()
         .skip(app.output_scroll)
         .take(visible_lines)
         .enumerate()
         .map(|(idx, result)| {
-            // Check if this is the selected line
+            let line_idx = idx + app.output_scroll;
+            
+            // Check if this is one of the selected lines
             let is_selected = app.panel_focus == crate::app::PanelFocus::Output && 
-                            idx + app.output_scroll == app.output_selected_idx;
+                            selection.contains(&line_idx);
+            
+            // A selected row starts with a marker, so it doesn't take color to find
+            let marker = is_selected.then(|| Span::styled("▶ ", theme.selection.add_modifier(Modifier::BOLD)));
+            let width = (inner_area.width as usize).saturating_sub(marker.as_ref().map_or(0, Span::width));
+            
+            // Section headers continue across the output panel as a rule
+            if app.lines.get(line_idx).is_some_and(|line| section_header(line).is_some()) {
+                let mut rule = section_rule(app.section_totals.get(&line_idx), width);
+                rule.spans.splice(0..0, marker);
+                return ListItem::new(rule).style(if is_selected {
+                    theme.selection
+                } else {
+                    Style::default()
+                });
+            }
             
             // Style based on content and selection
             let line_style = if is_selected {
-                Style::default().bg(Color::DarkGray).add_modifier(Modifier::BOLD)
+                theme.selection.add_modifier(Modifier::BOLD)
             } else if result.starts_with("Error:") {
                 Style::default().fg(Color::Red)
             } else {
@@ -361,40 +593,70 @@ fn draw_output_panel(f: &mut Frame, app: &App, area: Rect) {
             };
             
             // Apply styling to the line
-            if result.starts_with("Error:") {
-                // For error messages, style with red background and white text
-                ListItem::new(Line::from(Span::styled(result.clone(), 
+            let mut line = if result.starts_with("Error:") {
+                // Errors stand out by color and by a mark in front
+                Line::from(Span::styled(format!("✖ {}", result),
                     if is_selected {
-                        Style::default()
-                            .fg(Color::White)
-                            .bg(Color::Red)
-                            .add_modifier(Modifier::BOLD)
+                        theme.error.add_modifier(Modifier::BOLD)
                     } else {
-                        Style::default()
-                            .fg(Color::White)
-                            .bg(Color::Red)
+                        theme.error
                     }
-                )))
+                ))
             } else if result.is_empty() {
                 // Empty result, just create an empty line with the appropriate style
-                ListItem::new(Line::from(Span::styled("", line_style)))
+                Line::from(Span::styled("", line_style))
+            } else if hinted[idx] {
+                // Suggested conversion targets, dimmed so they don't read as a result
+                Line::from(Span::styled(result.clone(), line_style.fg(Color::DarkGray).add_modifier(Modifier::ITALIC)))
+            } else if drafts[idx] {
+                // A draft's result counts for nothing else, and looks it
+                Line::from(Span::styled(result.clone(), line_style.fg(Color::DarkGray)))
+            } else if let Some(color) = colors[idx] {
+                // Colored by the kind of value, on top of the selection background
+                Line::from(Span::styled(result.clone(), line_style.fg(color)))
             } else {
                 // Apply syntax highlighting for normal results
-                let highlighted = highlight_syntax(result);
+                let highlighted = cache.get(result, variables);
                 
                 // If this is the selected line in output focus mode, apply background highlight to all spans
                 if is_selected {
                     let styled_spans = highlighted.spans.iter().map(|span| {
                         let mut style = span.style;
-                        style = style.bg(Color::DarkGray).add_modifier(Modifier::BOLD);
+                        style = style.patch(theme.selection).add_modifier(Modifier::BOLD);
                         Span::styled(span.content.clone(), style)
                     }).collect::<Vec<_>>();
                     
-                    ListItem::new(Line::from(styled_spans))
+                    Line::from(styled_spans)
                 } else {
-                    ListItem::new(highlighted)
+                    highlighted
+                }
+            };
+            
+            // A result that just moved shows by how much, or flashes if it changed kind
+            match &changes[idx] {
+                Some(Change::Delta(delta)) => line.spans.push(delta_span(delta)),
+                Some(Change::Flash) => {
+                    for span in &mut line.spans {
+                        span.style = span.style.add_modifier(Modifier::REVERSED);
+                    }
                 }
+                None => {}
+            }
+            
+            // A pinned result follows the live one, dimmed
+            if let Some(pinned) = &pins[idx] {
+                line.spans.push(Span::styled(format!("  (pinned: {})", pinned), Style::default().fg(Color::DarkGray)));
             }
+            
+            // Anything wider than the panel ends in an ellipsis; `o` shows the rest
+            truncate_line(&mut line, width);
+            
+            // Values line up on the right like a spreadsheet column; errors and hints stay left
+            if app.align == Align::Right && colors[idx].is_some() {
+                align_right(&mut line, width, line_style);
+            }
+            line.spans.splice(0..0, marker);
+            ListItem::new(line)
         })
         .collect();
 
@@ -439,7 +701,7 @@ fn draw_output_panel(f: &mut Frame, app: &App, area: Rect) {
             
             // Create a blank paragraph with the highlight style
             let highlight = Paragraph::new("")
-                .style(Style::default().bg(Color::DarkGray));
+                .style(theme.selection);
             
             // Render the highlight underneath the text
             f.render_widget(highlight, highlight_area);
@@ -447,27 +709,109 @@ fn draw_output_panel(f: &mut Frame, app: &App, area: Rect) {
     }
 }
 
+// The whole of a result too long for the output panel, wrapped in a box over the panels
+fn draw_full_result(f: &mut Frame, line_idx: usize, result: &str, area: Rect) {
+    let width = (area.width * 3 / 4).max(area.width.min(20));
+    let text_width = width.saturating_sub(2).max(1);
+    // A spare row for where wrapping at spaces leaves lines short
+    let rows = (column_at(result, result.len()) as u16).div_ceil(text_width) + 1;
+    let height = (rows + 2).min(area.height);
+    let popup = Rect {
+        x: area.x + (area.width - width) / 2,
+        y: area.y + (area.height - height) / 2,
+        width,
+        height,
+    };
+    
+    let block = Block::default()
+        .title(format!("Line {}", line_idx + 1))
+        .title(Title::from("Esc: Close | y: Copy").position(Position::Bottom).alignment(Alignment::Right))
+        .borders(Borders::ALL)
+        .style(Style::default().fg(Color::Cyan));
+    let style = if result.starts_with("Error:") {
+        Style::default().fg(Color::Red)
+    } else {
+        Style::default().fg(Color::White)
+    };
+    f.render_widget(Clear, popup);
+    f.render_widget(Paragraph::new(result.to_string()).style(style).wrap(Wrap { trim: false }).block(block), popup);
+}
+
+// The log view: the newest lines that fit, in a box over the panels
+fn draw_log(f: &mut Frame, lines: &[String], area: Rect) {
+    let popup = Rect {
+        x: area.x + area.width / 16,
+        y: area.y + area.height / 8,
+        width: area.width - area.width / 8,
+        height: area.height - area.height / 4,
+    };
+    let rows = popup.height.saturating_sub(2) as usize;
+    let text: Vec<Line> = if lines.is_empty() {
+        vec![Line::styled("Nothing logged", Style::default().fg(Color::DarkGray))]
+    } else {
+        lines[lines.len().saturating_sub(rows)..].iter()
+            .map(|line| {
+                let mut line = Line::raw(line.clone());
+                truncate_line(&mut line, popup.width.saturating_sub(2) as usize);
+                line
+            })
+            .collect()
+    };
+    let block = Block::default()
+        .title("Log")
+        .title(Title::from("Esc: Close").position(Position::Bottom).alignment(Alignment::Right))
+        .borders(Borders::ALL)
+        .style(Style::default().fg(Color::Cyan));
+    f.render_widget(Clear, popup);
+    f.render_widget(Paragraph::new(text).style(Style::default().fg(Color::White)).block(block), popup);
+}
+
 fn draw_status_bar(f: &mut Frame, app: &App, area: Rect) {
     match app.input_mode {
         crate::app::InputMode::Normal => {
-            // Normal mode: display status message or keybinds
+            // Normal mode: a status message, a summary of the current line, or keybinds on the
+            // left, and where the cursor is on the right
             let status_text = match &app.status_message {
-                Some(message) => message.as_str(),
+                Some(message) => message.clone(),
                 None => match app.panel_focus {
-                    crate::app::PanelFocus::Input => "Tab: Switch Panel | Ctrl+S: Save | Ctrl+Q: Quit",
-                    crate::app::PanelFocus::Output => "Tab: Switch Panel | ↑/k: Up | ↓/j: Down | g/Home: Top | G/End: Bottom | Enter/y: Copy"
+                    crate::app::PanelFocus::Input => app.line_summary(app.cursor_pos.0)
+                        .unwrap_or_else(|| "Tab: Switch Panel | Ctrl+S: Save | Ctrl+Q: Quit".to_string()),
+                    crate::app::PanelFocus::Output => app.output_selection_summary().unwrap_or_else(|| "Tab: Switch Panel | ↑/k: Up | ↓/j: Down | g/Home: Top | G/End: Bottom | Enter: Edit Line | y: Copy | Y: Copy Raw".to_string())
                 }
             };
             
-            let status_bar = Paragraph::new(status_text)
-                .style(Style::default().fg(Color::Cyan).add_modifier(Modifier::BOLD))
-                .block(Block::default());
+            // The left side always keeps at least half the bar
+            let width = area.width as usize;
+            let left_width = status_text.chars().count().min(width / 2);
+            let segments = fit_segments(&status_segments(app), width.saturating_sub(left_width + 1));
+            let segments_width = segments.chars().count();
+            let status_text = truncate_to_width(&status_text, width.saturating_sub(segments_width + 1));
+            let padding = width.saturating_sub(status_text.chars().count() + segments_width);
+            
+            // Errors say so in words, and are colored as errors on top
+            let status_style = if status_text.starts_with("Error:") {
+                app.theme.error
+            } else {
+                Style::default().fg(Color::Cyan).add_modifier(Modifier::BOLD)
+            };
+            let status_bar = Paragraph::new(Line::from(vec![
+                Span::styled(status_text, status_style),
+                Span::raw(" ".repeat(padding)),
+                Span::styled(segments, Style::default().fg(Color::DarkGray)),
+            ]));
             
             f.render_widget(status_bar, area);
         },
-        crate::app::InputMode::FilePath => {
-            // Input mode: show input field for file path
-            let prompt = "Enter file path to save to: ";
+        crate::app::InputMode::FilePath | crate::app::InputMode::Rename | crate::app::InputMode::Convert => {
+            // Input mode: show input field for file path, the new name or the unit to convert to
+            let prompt = match (&app.rename_from, &app.convert_from) {
+                (Some(name), _) if app.input_mode == crate::app::InputMode::Rename => format!("Rename '{}' to: ", name),
+                (_, Some(value)) if app.input_mode == crate::app::InputMode::Convert => match &app.convert_error {
+                    Some(error) => format!("{} | Convert {} to: ", error, value),
+                    None => format!("Convert {} to: ", value),
+                },
+                _ => "Enter file path to save to: ".to_string(),
+            };
             let input_text = format!("{}{}", prompt, app.status_input);
             
             let status_bar = Paragraph::new(input_text)
@@ -478,9 +822,541 @@ fn draw_status_bar(f: &mut Frame, app: &App, area: Rect) {
             
             // Set cursor position at the end of input
             f.set_cursor(
-                area.x + (prompt.len() + app.status_input.len()) as u16,
+                area.x + (prompt.chars().count() + app.status_input.chars().count()) as u16,
                 area.y,
             );
         }
     }
-} 
\ No newline at end of file
+} 
+
+// The permanent part of the status bar, in display order, each with how important it is
+// to keep when space runs out (higher stays longer)
+fn status_segments(app: &App) -> Vec<(u8, String)> {
+    let (line_idx, byte_idx) = app.cursor_pos;
+    let column = app.lines.get(line_idx).map_or(0, |line| line[..byte_idx.min(line.len())].chars().count());
+    let panel = match app.panel_focus {
+        crate::app::PanelFocus::Input => "Input",
+        crate::app::PanelFocus::Output => "Output",
+    };
+    let mut segments = vec![
+        (0, panel.to_string()),
+        (2, format!("Ln {}, Col {}", line_idx + 1, column + 1)),
+        (1, format!("{} lines", app.lines.len())),
+    ];
+    if app.modified {
+        segments.push((3, "modified".to_string()));
+    }
+    let pending = app.pending_lines();
+    if pending > 0 {
+        let total = app.lines.len();
+        segments.push((4, format!("evaluating {}/{}", total.saturating_sub(pending), total)));
+    }
+    segments
+}
+
+// Join segments with " | ", dropping the least important ones until they fit in `width`
+fn fit_segments(segments: &[(u8, String)], width: usize) -> String {
+    let mut kept: Vec<&(u8, String)> = segments.iter().collect();
+    loop {
+        let joined = kept.iter().map(|(_, text)| text.as_str()).collect::<Vec<_>>().join(" | ");
+        if joined.chars().count() <= width {
+            return joined;
+        }
+        let least = kept.iter().enumerate().min_by_key(|(_, (priority, _))| *priority).map(|(i, _)| i);
+        match least {
+            Some(i) => {
+                kept.remove(i);
+            }
+            None => return String::new(),
+        }
+    }
+}
+
+// Pad a line on the left so it ends at `width`. The value, its unit and any annotation
+// move as one block.
+fn align_right(line: &mut Line, width: usize, style: Style) {
+    let padding = width.saturating_sub(line.width());
+    if padding > 0 {
+        line.spans.insert(0, Span::styled(" ".repeat(padding), style));
+    }
+}
+
+// Cut a line down to `width` cells, ending in an ellipsis when anything was removed. Wide
+// characters are kept or dropped whole.
+fn truncate_line(line: &mut Line<'static>, width: usize) {
+    if line.width() <= width {
+        return;
+    }
+    let mut room = width.saturating_sub(1);
+    let mut kept = Vec::new();
+    for span in line.spans.drain(..) {
+        let span_width = span.width();
+        if span_width <= room {
+            room -= span_width;
+            kept.push(span);
+            continue;
+        }
+        let mut text = span.content[..byte_at_column(&span.content, room)].to_string();
+        if width > 0 {
+            text.push('…');
+        }
+        kept.push(Span::styled(text, span.style));
+        break;
+    }
+    line.spans = kept;
+}
+
+// Cut text down to `width` characters, ending in an ellipsis when anything was removed
+fn truncate_to_width(text: &str, width: usize) -> String {
+    if text.chars().count() <= width {
+        return text.to_string();
+    }
+    if width == 0 {
+        return String::new();
+    }
+    let mut truncated: String = text.chars().take(width.saturating_sub(1)).collect();
+    truncated.push('…');
+    truncated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_strip_colors_leaves_plain_ascii_cells() {
+        let mut buffer = Buffer::empty(Rect::new(0, 0, 4, 1));
+        buffer.set_string(0, 0, "─▲", Style::default().fg(Color::Green));
+        buffer.set_string(2, 0, "ab", Style::default().bg(Color::DarkGray).add_modifier(Modifier::BOLD));
+        strip_colors(&mut buffer);
+        
+        let symbols: String = buffer.content.iter().map(|cell| cell.symbol()).collect();
+        assert_eq!(symbols, "-^ab");
+        assert!(buffer.content.iter().all(|cell| cell.fg == Color::Reset && cell.bg == Color::Reset));
+        assert_eq!(buffer.content[0].modifier, Modifier::empty());
+        assert_eq!(buffer.content[2].modifier, Modifier::BOLD | Modifier::REVERSED);
+    }
+    
+    #[test]
+    fn test_result_colors_follow_value_kind() {
+        let colors = ResultColors::default();
+        assert_eq!(colors.for_value(&Value::Unit(3.5, "USD".to_string())), Some(Color::Green));
+        assert_eq!(colors.for_value(&Value::Unit(3.5, "km".to_string())), Some(Color::Cyan));
+        assert_eq!(colors.for_value(&Value::Percentage(20.0)), Some(Color::Magenta));
+        assert_eq!(colors.for_value(&Value::Number(2.0)), Some(Color::Reset));
+        let date = chrono::NaiveDate::from_ymd_opt(2025, 1, 31).unwrap();
+        assert_eq!(colors.for_value(&Value::Assignment("due".to_string(), Box::new(Value::Date(date)))), Some(Color::Blue));
+        assert_eq!(colors.for_value(&Value::Error("Cannot divide by 0".to_string())), None);
+    }
+    
+    #[test]
+    fn test_align_right_keeps_unit_attached() {
+        let mut line = Line::from(vec![Span::raw("12.50 USD"), Span::raw(" ▲ +1")]);
+        align_right(&mut line, 20, Style::default());
+        assert_eq!(line.width(), 20);
+        assert_eq!(line.spans[0].content, "      ");
+        assert_eq!(line.spans[1].content, "12.50 USD");
+        
+        // Too wide to pad: left as is
+        let mut line = Line::from("1234567890");
+        align_right(&mut line, 5, Style::default());
+        assert_eq!(line.spans.len(), 1);
+    }
+    
+    #[test]
+    fn test_status_segments_drop_least_important_first() {
+        let segments = vec![
+            (0, "Input".to_string()),
+            (2, "Ln 12, Col 8".to_string()),
+            (1, "45 lines".to_string()),
+            (3, "modified".to_string()),
+        ];
+        assert_eq!(fit_segments(&segments, 80), "Input | Ln 12, Col 8 | 45 lines | modified");
+        assert_eq!(fit_segments(&segments, 40), "Ln 12, Col 8 | 45 lines | modified");
+        assert_eq!(fit_segments(&segments, 30), "Ln 12, Col 8 | modified");
+        assert_eq!(fit_segments(&segments, 10), "modified");
+        assert_eq!(fit_segments(&segments, 5), "");
+    }
+    
+    #[test]
+    fn test_truncate_to_width() {
+        assert_eq!(truncate_to_width("total = $151.20", 40), "total = $151.20");
+        assert_eq!(truncate_to_width("total = $151.20", 8), "total =…");
+        assert_eq!(truncate_to_width("total", 0), "");
+    }
+    
+    // Color of each non-whitespace span, in order
+    fn colors(text: &str, variables: &HashMap<String, Value>) -> Vec<(String, Option<Color>)> {
+        highlight_syntax(text, variables).spans.iter()
+            .filter(|span| !span.content.trim().is_empty())
+            .map(|span| (span.content.to_string(), span.style.fg))
+            .collect()
+    }
+    
+    #[test]
+    fn test_highlight_variables_are_not_units() {
+        let mut variables = HashMap::new();
+        variables.insert("price".to_string(), Value::Unit(10.0, "USD".to_string()));
+        
+        // The old regex cascade colored every identifier as a unit
+        assert_eq!(colors("price * 2 USD", &variables), vec![
+            ("price".to_string(), Some(Color::Magenta)),
+            ("*".to_string(), Some(Color::LightRed)),
+            ("2".to_string(), Some(Color::LightYellow)),
+            ("USD".to_string(), Some(Color::LightGreen)),
+        ]);
+        
+        // Unknown identifiers are plain text rather than units
+        assert_eq!(colors("income", &variables), vec![("income".to_string(), Some(Color::White))]);
+        
+        // A variable after a number is multiplied, not a unit
+        assert_eq!(colors("2price", &variables), vec![
+            ("2".to_string(), Some(Color::LightYellow)),
+            ("price".to_string(), Some(Color::Magenta)),
+        ]);
+    }
+    
+    #[test]
+    fn test_highlight_multi_word_variable() {
+        let mut variables = HashMap::new();
+        variables.insert("monthly rent".to_string(), Value::Number(1800.0));
+        assert_eq!(colors("monthly  rent * 12", &variables), vec![
+            ("monthly".to_string(), Some(Color::Magenta)),
+            ("rent".to_string(), Some(Color::Magenta)),
+            ("*".to_string(), Some(Color::LightRed)),
+            ("12".to_string(), Some(Color::LightYellow)),
+        ]);
+        // Only the whole name is a variable
+        assert_eq!(colors("monthly", &variables), vec![("monthly".to_string(), Some(Color::White))]);
+    }
+    
+    #[test]
+    fn test_highlight_in_as_unit_and_keyword() {
+        let variables = HashMap::new();
+        assert_eq!(colors("10 in in cm", &variables), vec![
+            ("10".to_string(), Some(Color::LightYellow)),
+            ("in".to_string(), Some(Color::LightCyan)),
+            ("in".to_string(), Some(Color::LightBlue)),
+            ("cm".to_string(), Some(Color::LightCyan)),
+        ]);
+    }
+    
+    #[test]
+    fn test_highlight_percentage_and_comment() {
+        let variables = HashMap::new();
+        assert_eq!(colors("20% of 50 # tip", &variables), vec![
+            ("20".to_string(), Some(Color::LightGreen)),
+            ("%".to_string(), Some(Color::LightGreen)),
+            ("of".to_string(), Some(Color::LightBlue)),
+            ("50".to_string(), Some(Color::LightYellow)),
+            ("# tip".to_string(), Some(Color::DarkGray)),
+        ]);
+    }
+    
+    #[test]
+    fn test_highlight_percent_agrees_with_parser() {
+        let mut variables = HashMap::new();
+        variables.insert("x".to_string(), Value::Number(15.0));
+        let percent = |text: &str| colors(text, &variables).into_iter()
+            .find(|(t, _)| t == "%")
+            .and_then(|(_, color)| color);
+        assert_eq!(percent("10 % 3"), Some(Color::LightRed));
+        assert_eq!(percent("10 %3"), Some(Color::LightRed));
+        assert_eq!(percent("15% of 80"), Some(Color::LightGreen));
+        assert_eq!(percent("x% of 80"), Some(Color::LightGreen));
+        assert_eq!(colors("10 mod 3", &variables)[1], ("mod".to_string(), Some(Color::LightBlue)));
+    }
+    
+    #[test]
+    fn test_highlight_section_header() {
+        let variables = HashMap::new();
+        assert_eq!(colors("# --- Groceries ---", &variables), vec![("# --- Groceries ---".to_string(), Some(Color::Yellow))]);
+        assert_eq!(colors("# note", &variables), vec![("# note".to_string(), Some(Color::DarkGray))]);
+        
+        let rule = section_rule(Some(&Value::Number(12.0)), 8);
+        let text: String = rule.spans.iter().map(|span| span.content.as_ref()).collect();
+        assert_eq!(text, "12 ─────");
+    }
+
+    #[test]
+    fn test_delta_span() {
+        let up = delta_span(&Value::Unit(3.1, "USD".to_string()));
+        assert_eq!((up.content.as_ref(), up.style.fg), (" ▲ +$3.10", Some(Color::Green)));
+        let down = delta_span(&Value::Number(-2.0));
+        assert_eq!((down.content.as_ref(), down.style.fg), (" ▼ -2", Some(Color::Red)));
+    }
+
+    #[test]
+    fn test_highlight_natural_language_phrase() {
+        let variables = HashMap::new();
+        assert_eq!(colors("how many ounces in 2 kg", &variables), vec![
+            ("how".to_string(), Some(Color::LightBlue)),
+            ("many".to_string(), Some(Color::LightBlue)),
+            ("ounces".to_string(), Some(Color::LightCyan)),
+            ("in".to_string(), Some(Color::LightBlue)),
+            ("2".to_string(), Some(Color::LightYellow)),
+            ("kg".to_string(), Some(Color::LightCyan)),
+        ]);
+    }
+
+    #[test]
+    fn test_highlight_unicode_lines() {
+        let variables = HashMap::new();
+        // Byte-indexed bookkeeping used to panic or split characters on these
+        for line in ["größe = 5 €", "€8.50", "café * 2 # ünïcödé", "5 km → mi"] {
+            let highlighted = highlight_syntax(line, &variables);
+            let rebuilt: String = highlighted.spans.iter().map(|span| span.content.as_ref()).collect();
+            assert_eq!(rebuilt, line);
+        }
+    }
+
+    #[test]
+    fn test_highlight_assignments() {
+        let mut variables = HashMap::new();
+        variables.insert("monthly rent".to_string(), Value::Number(1800.0));
+        variables.insert("rent".to_string(), Value::Number(1800.0));
+        let definition = Some(Color::Cyan);
+        
+        assert_eq!(colors("monthly rent = 1800", &variables), vec![
+            ("monthly".to_string(), definition),
+            ("rent".to_string(), definition),
+            ("=".to_string(), Some(Color::DarkGray)),
+            ("1800".to_string(), Some(Color::LightYellow)),
+        ]);
+        let name = &highlight_syntax("yearly = rent * 12", &variables).spans[0];
+        assert_eq!(name.style, definition_style());
+        assert!(name.style.add_modifier.contains(Modifier::BOLD));
+        // Names used on the right are variables, known or not
+        assert_eq!(colors("yearly = rent * 12 + bonus", &variables)[2..5], [
+            ("rent".to_string(), Some(Color::Magenta)),
+            ("*".to_string(), Some(Color::LightRed)),
+            ("12".to_string(), Some(Color::LightYellow)),
+        ]);
+        assert_eq!(colors("yearly = rent * 12 + bonus", &variables)[6], ("bonus".to_string(), Some(Color::White)));
+        
+        // An equation or a command defines no variable, but its = is still dimmed
+        assert_eq!(colors("2x = 10", &variables)[2], ("=".to_string(), Some(Color::DarkGray)));
+        assert_eq!(colors("x + 1 = 10", &variables)[0], ("x".to_string(), Some(Color::White)));
+        assert_eq!(colors("defunit sprint = 2 week", &variables)[1], ("sprint".to_string(), Some(Color::White)));
+        assert_eq!(colors("rent * 2", &variables)[0], ("rent".to_string(), Some(Color::Magenta)));
+    }
+
+    // Draw the app on a terminal of the given size and return its rows as text
+    fn render(app: &mut App, width: u16, height: u16) -> (Vec<String>, ratatui::buffer::Buffer) {
+        use ratatui::{Terminal, backend::TestBackend};
+        let mut terminal = Terminal::new(TestBackend::new(width, height)).unwrap();
+        terminal.draw(|f| draw(f, app)).unwrap();
+        let buffer = terminal.backend().buffer().clone();
+        let rows = (0..height)
+            .map(|y| (0..width).map(|x| buffer.get(x, y).symbol()).collect::<String>().trim_end().to_string())
+            .collect();
+        (rows, buffer)
+    }
+    
+    fn app_with(lines: &[&str]) -> App {
+        let mut app = crate::tests::app_with_lines(lines);
+        // Errors show straight away instead of after a pause in typing
+        app.debounce_period = std::time::Duration::ZERO;
+        app.update_on_tick();
+        app
+    }
+    
+    fn assert_snapshot(rows: &[String], expected: &[&str]) {
+        let header = format!("Cali v{}", env!("CARGO_PKG_VERSION"));
+        let expected: Vec<String> = expected.iter().map(|row| row.replace("Cali v{version}", &header)).collect();
+        assert!(rows == expected, "got:\n{}\nexpected:\n{}", rows.join("\n"), expected.join("\n"));
+    }
+    
+    #[test]
+    fn test_draw_sheet() {
+        let mut app = app_with(&["# Groceries", "milk = 3.50 USD", "bread = 2.25 USD", "milk + bread", "10 km in mi", "20% of 50 # tip"]);
+        let (rows, buffer) = render(&mut app, 60, 12);
+        assert_snapshot(&rows, &[
+            "Cali v{version}",
+            "",
+            "┌● Input─────────────────────┐┌Output──────────────────────┐",
+            "│# Groceries                 ││                            │",
+            "│milk = 3.50 USD             ││                       $3.50│",
+            "│bread = 2.25 USD            ││                       $2.25│",
+            "│milk + bread                ││                       $5.75│",
+            "│10 km in mi                 ││                 6.213727 mi│",
+            "│20% of 50 # tip             ││                          10│",
+            "│                            ││                            │",
+            "└────────────────────────────┘└────────────────────────────┘",
+            "Tab: Switch Panel | Ctrl+S: S… Input | Ln 1, Col 1 | 6 lines",
+        ]);
+        // Highlighting reaches the screen: the comment is dim, the defined name stands out
+        assert_eq!(buffer.get(1, 3).fg, Color::DarkGray);
+        assert_eq!(buffer.get(1, 4).fg, Color::Cyan);
+        assert_eq!(buffer.get(6, 4).fg, Color::DarkGray);
+    }
+    
+    #[test]
+    fn test_draw_errors_and_dates() {
+        let mut app = app_with(&["rent = 1800", "rent * 12", "rent + oops", "5 kg in km", "next friday"]);
+        let (rows, _) = render(&mut app, 60, 12);
+        assert_snapshot(&rows, &[
+            "Cali v{version}",
+            "",
+            "┌● Input─────────────────────┐┌Output──────────────────────┐",
+            "│rent = 1800                 ││                        1800│",
+            "│rent * 12                   ││                       21600│",
+            "│rent + oops                 ││✖ Error: 'oops' not found   │",
+            "│5 kg in km                  ││✖ Error: Can't convert a ma…│",
+            "│next friday                 ││             Fri, 2024-05-24│",
+            "│                            ││                            │",
+            "│                            ││                            │",
+            "└────────────────────────────┘└────────────────────────────┘",
+            "Tab: Switch Panel | Ctrl+S: S… Input | Ln 1, Col 1 | 5 lines",
+        ]);
+    }
+    
+    #[test]
+    fn test_draw_selected_output_line() {
+        let mut app = app_with(&["a = 2", "qty = a * 3", "a + qty", "100 USD in EUR"]);
+        app.toggle_panel_focus(true);
+        app.output_selected_idx = 2;
+        let (rows, buffer) = render(&mut app, 60, 12);
+        assert_snapshot(&rows, &[
+            "Cali v{version}",
+            "",
+            "┌Input───────────────────────┐┌● Output────────────────────┐",
+            "│a = 2                       ││                           2│",
+            "│qty = a * 3                 ││                           6│",
+            "│a + qty                     ││▶                          8│",
+            "│100 USD in EUR              ││                      €85.00│",
+            "│                            ││                            │",
+            "│                            ││                            │",
+            "│                            ││                            │",
+            "└────────────────────────────┘└────────────────────────────┘",
+            "Tab: Switch Panel | ↑/k: Up | ↓/j: Do… Ln 1, Col 1 | 4 lines",
+        ]);
+        // The whole row of the selected result is highlighted, and only that row
+        assert!((31..59).all(|x| buffer.get(x, 5).bg == Color::DarkGray));
+        assert!((31..59).all(|x| buffer.get(x, 4).bg != Color::DarkGray));
+    }
+    
+    #[test]
+    fn test_draw_file_path_prompt() {
+        let mut app = app_with(&["total = 42"]);
+        app.set_input_mode(crate::app::InputMode::FilePath);
+        app.status_input = "~/budget.cali".to_string();
+        let (rows, _) = render(&mut app, 60, 8);
+        assert_snapshot(&rows, &[
+            "Cali v{version}",
+            "",
+            "┌● Input─────────────────────┐┌Output──────────────────────┐",
+            "│total = 42                  ││                          42│",
+            "│                            ││                            │",
+            "│                            ││                            │",
+            "└────────────────────────────┘└────────────────────────────┘",
+            "Enter file path to save to: ~/budget.cali",
+        ]);
+    }
+    
+    #[test]
+    fn test_draw_section_totals() {
+        let mut app = app_with(&["#= Trip", "flights = 420 USD", "hotel = 380 USD", "", "#= Food", "12.50 EUR", "8 EUR"]);
+        let (rows, _) = render(&mut app, 60, 12);
+        assert_snapshot(&rows, &[
+            "Cali v{version}",
+            "",
+            "┌● Input─────────────────────┐┌Output──────────────────────┐",
+            "│#= Trip                     ││$800 ───────────────────────│",
+            "│flights = 420 USD           ││                        $420│",
+            "│hotel = 380 USD             ││                        $380│",
+            "│                            ││                            │",
+            "│#= Food                     ││€20.50 ─────────────────────│",
+            "│12.50 EUR                   ││                      €12.50│",
+            "│8 EUR                       ││                       €8.00│",
+            "└────────────────────────────┘└────────────────────────────┘",
+            "Trip total = $800              Input | Ln 1, Col 1 | 7 lines",
+        ]);
+    }
+    
+    #[test]
+    fn test_truncate_line_keeps_wide_characters_whole() {
+        let mut line = Line::from(vec![Span::raw("ab"), Span::raw("日本語")]);
+        truncate_line(&mut line, 5);
+        assert_eq!(line.spans.iter().map(|span| span.content.as_ref()).collect::<String>(), "ab日…");
+        let mut line = Line::from("日本語");
+        truncate_line(&mut line, 6);
+        assert_eq!(line.spans[0].content, "日本語");
+    }
+    
+    #[test]
+    fn test_draw_full_result() {
+        let mut app = app_with(&["1 + 1", "5 kg in km", "[1000000, 2000000, 3000000, 4000000]"]);
+        app.panel_focus = crate::app::PanelFocus::Output;
+        render(&mut app, 60, 12);
+        assert!(!app.open_full_result());
+        
+        app.output_selected_idx = 1;
+        assert!(app.open_full_result());
+        let (rows, _) = render(&mut app, 60, 12);
+        assert_snapshot(&rows, &[
+            "Cali v{version}",
+            "",
+            "┌Input───────────────────────┐┌● Output────────────────────┐",
+            "│1 + 1                       ││                           2│",
+            "│5 kg i┌Line 2─────────────────────────────────────┐ert a …│",
+            "│[10000│Error: Can't convert a mass (kg) to a      │00000,…│",
+            "│      │length (km)                                │       │",
+            "│      │                                           │       │",
+            "│      └───────────────────────Esc: Close | y: Copy┘       │",
+            "│                            ││                            │",
+            "└────────────────────────────┘└────────────────────────────┘",
+            "Tab: Switch Panel | ↑/k: Up | ↓/j: Do… Ln 1, Col 1 | 3 lines",
+        ]);
+        app.handle_full_result_key(crossterm::event::KeyEvent::from(crossterm::event::KeyCode::Esc));
+        assert_eq!(app.full_result, None);
+        
+        // Copying as usual still takes the whole result
+        app.output_selected_idx = 2;
+        assert!(app.result_truncated(2));
+        assert_eq!(app.selected_output_text().unwrap(), "[1000000, 2000000, 3000000, 4000000]");
+    }
+    
+    #[test]
+    fn test_errors_and_selection_are_marked_not_only_colored() {
+        let mut app = app_with(&["1 + 1", "5 kg in km", "3 * 3"]);
+        app.theme = Theme::high_contrast();
+        app.toggle_panel_focus(true);
+        app.output_selected_idx = 1;
+        app.set_status_error("no permission to write to 'budget.cali'");
+        let (rows, buffer) = render(&mut app, 60, 8);
+        assert_snapshot(&rows, &[
+            "Cali v{version}",
+            "",
+            "┌Input───────────────────────┐┌● Output────────────────────┐",
+            "│1 + 1                       ││                           2│",
+            "│5 kg in km                  ││▶ ✖ Error: Can't convert a …│",
+            "│3 * 3                       ││                           9│",
+            "└────────────────────────────┘└────────────────────────────┘",
+            "Error: no permission to write to 'bud… Ln 1, Col 1 | 3 lines",
+        ]);
+        // The theme's colors come on top: a reversed selection, black on yellow errors
+        assert!(buffer.get(31, 4).modifier.contains(Modifier::REVERSED));
+        assert_eq!((buffer.get(33, 4).fg, buffer.get(33, 4).bg), (Color::Black, Color::Yellow));
+        assert_eq!(buffer.get(0, 7).bg, Color::Yellow);
+        
+        // The marks are only drawn; copying takes the results as they are
+        app.output_anchor = Some(2);
+        assert_eq!(app.selected_output_text().unwrap(), "Error: Can't convert a mass (kg) to a length (km)\n9");
+        app.output_anchor = None;
+        
+        // Without colors they fall back to ASCII like the other symbols
+        app.disable_color();
+        let (rows, _) = render(&mut app, 60, 8);
+        assert!(rows[4].ends_with("|> x Error: Can't convert a ~|"), "{}", rows[4]);
+    }
+    
+    #[test]
+    fn test_draw_running_total() {
+        let mut app = app_with(&["set running total USD", "rent = 1200 USD", "food = 310.50 USD"]);
+        let (rows, buffer) = render(&mut app, 60, 6);
+        let header = format!("Cali v{}", env!("CARGO_PKG_VERSION"));
+        assert_eq!(rows[0], format!("{header}{}Total $1510.50", " ".repeat(60 - header.len() - "Total $1510.50".len())));
+        assert_eq!(buffer.get(59, 0).fg, Color::Green);
+    }
+}